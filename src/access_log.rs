@@ -0,0 +1,220 @@
+use actix_rt::time::delay_for;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::micropub::random_id;
+
+/// How often the current hour's object is rewritten.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether a photo was served from the derivative cache, kept in the
+/// request extensions by the photo handler.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// Resized without consulting or filling a cache, e.g. for an old
+    /// version or under load.
+    Bypass,
+}
+
+/// Which cache a hit came from, kept alongside the [`CacheStatus`].
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheSource {
+    Disk,
+    S3,
+}
+
+impl CacheStatus {
+    /// The value of the X-Cache header.
+    pub fn as_header(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+impl CacheSource {
+    /// The value of the X-Cache-Source header.
+    pub fn as_header(self) -> &'static str {
+        match self {
+            CacheSource::Disk => "disk",
+            CacheSource::S3 => "s3",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AccessEntry<'a> {
+    timestamp: DateTime<Utc>,
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    status: u16,
+    bytes: Option<u64>,
+    duration_ms: u64,
+    remote_addr: Option<&'a str>,
+    referrer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_source: Option<CacheSource>,
+}
+
+/// Structured access logs, written as JSON lines to one S3 object per hour.
+///
+/// Each process writes its own objects, named for the hour and a random
+/// instance id. The current hour is kept in memory and rewritten every
+/// minute, so a crash loses at most a minute of entries.
+pub struct AccessLog {
+    client: S3Client,
+    bucket: String,
+    prefix: String,
+    instance: String,
+    buffer: Mutex<Buffer>,
+}
+
+#[derive(Default)]
+struct Buffer {
+    hour: String,
+    lines: Vec<String>,
+    dirty: bool,
+    /// Earlier hours which haven't been written in full yet.
+    finished: Vec<(String, Vec<String>)>,
+}
+
+/// The size of the response body, if it is known.
+pub fn response_bytes<B: MessageBody>(res: &ServiceResponse<B>) -> Option<u64> {
+    match res.response().body().size() {
+        BodySize::Sized(n) => Some(n as u64),
+        BodySize::Sized64(n) => Some(n),
+        BodySize::Empty | BodySize::None => Some(0),
+        BodySize::Stream => res
+            .response()
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+    }
+}
+
+impl AccessLog {
+    /// Parse the log configuration: `s3:<prefix>` for a prefix in the media
+    /// bucket, or `s3://<bucket>/<prefix>` for a dedicated bucket.
+    pub fn parse(value: &str, client: &S3Client, bucket: &str) -> Option<AccessLog> {
+        let (bucket, prefix) = if let Some(location) = value.strip_prefix("s3://") {
+            let mut parts = location.splitn(2, '/');
+            (parts.next()?, parts.next().unwrap_or_default())
+        } else {
+            (bucket, value.strip_prefix("s3:")?)
+        };
+
+        Some(AccessLog {
+            client: client.clone(),
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            instance: random_id(),
+            buffer: Mutex::new(Buffer::default()),
+        })
+    }
+
+    /// Add an entry for the response.
+    pub fn record<B: MessageBody>(&self, res: &ServiceResponse<B>, started: Instant) {
+        let req = res.request();
+        let headers = req.headers();
+        let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+        let connection_info = req.connection_info();
+        let now = Utc::now();
+        let entry = AccessEntry {
+            timestamp: now,
+            method: req.method().as_str(),
+            path: req.path(),
+            query: req.query_string(),
+            status: res.status().as_u16(),
+            bytes: response_bytes(res),
+            duration_ms: started.elapsed().as_millis() as u64,
+            remote_addr: connection_info.remote(),
+            referrer: header_str(header::REFERER),
+            user_agent: header_str(header::USER_AGENT),
+            cache: req.extensions().get::<CacheStatus>().copied(),
+            cache_source: req.extensions().get::<CacheSource>().copied(),
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize access log entry: {}", e);
+                return;
+            }
+        };
+
+        let hour = now.format("%Y-%m-%d/%H").to_string();
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.hour != hour {
+            let previous = std::mem::replace(&mut buffer.lines, Vec::new());
+            if !previous.is_empty() {
+                let previous_hour = std::mem::replace(&mut buffer.hour, hour);
+                buffer.finished.push((previous_hour, previous));
+            } else {
+                buffer.hour = hour;
+            }
+        }
+        buffer.lines.push(line);
+        buffer.dirty = true;
+    }
+
+    /// Write finished hours and the current hour to S3.
+    async fn flush(&self) {
+        let (finished, current) = {
+            let mut buffer = self.buffer.lock().unwrap();
+            let finished = std::mem::replace(&mut buffer.finished, Vec::new());
+            let current = if buffer.dirty {
+                buffer.dirty = false;
+                Some((buffer.hour.clone(), buffer.lines.join("\n")))
+            } else {
+                None
+            };
+            (finished, current)
+        };
+
+        let objects = finished
+            .into_iter()
+            .map(|(hour, lines)| (hour, lines.join("\n")))
+            .chain(current);
+        for (hour, body) in objects {
+            let key = format!("{}/{}-{}.jsonl", self.prefix, hour, self.instance);
+            let request = PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.trim_start_matches('/').to_string(),
+                body: Some(format!("{}\n", body).into_bytes().into()),
+                content_type: Some("application/x-ndjson".to_string()),
+                ..Default::default()
+            };
+            if let Err(e) = self.client.put_object(request).await {
+                error!("Failed to write access log {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Periodically write the logs to S3.
+    pub async fn flush_forever(log: Arc<AccessLog>) {
+        loop {
+            delay_for(FLUSH_INTERVAL).await;
+            log.flush().await;
+        }
+    }
+}
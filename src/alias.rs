@@ -0,0 +1,235 @@
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::error;
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    CopyObjectRequest, HeadObjectOutput, HeadObjectRequest, PutObjectRequest, S3Client, S3,
+};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+use crate::cdn::Cdn;
+use crate::disk_cache::DiskCache;
+use crate::index::MediaIndex;
+use crate::media;
+use crate::metadata;
+use crate::micropub::{authorize, invalidate, key_for_url, MicropubError};
+use crate::oauth;
+use crate::policy::Permission;
+use crate::trash;
+use crate::SiteConfig;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/alias").route(web::post().to(handle_alias)));
+}
+
+#[derive(Deserialize)]
+struct AliasRequest {
+    /// The URL of the object to rename.
+    from: String,
+    /// Its new URL, or key, e.g. `file/notes.zip`.
+    to: String,
+}
+
+#[derive(Serialize)]
+struct AliasResponse {
+    url: String,
+}
+
+/// Give an object a new key, leaving a redirect to it at the old one.
+///
+/// The object is copied to the new key and replaced by an empty object whose
+/// website redirect location points at the new URL. The media handlers answer
+/// requests for it with a 301.
+async fn handle_alias(
+    req: HttpRequest,
+    alias: web::Json<AliasRequest>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    cdn: web::Data<Cdn>,
+    disk_cache: Option<web::Data<DiskCache>>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let key = match key_for_url(&site, &alias.from) {
+        Some(key) => key,
+        None => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "Unknown URL",
+            ))
+        }
+    };
+    let new_key = key_for_url(&site, &alias.to)
+        .unwrap_or_else(|| alias.to.trim_start_matches('/').to_string());
+    if !is_valid_alias(&site, &key, &new_key) {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "The new key must be a different name under the same prefix",
+        ));
+    }
+
+    let head = match find(&site, &s3_client, &key).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+        Err(e) => return HttpResponse::InternalServerError().body(e),
+    };
+    let stored = head.metadata.unwrap_or_default();
+    if let Some(author) = stored.get("author") {
+        if author != access_token.me() {
+            return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+        }
+    }
+    if head.website_redirect_location.is_some() {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "The URL has already been renamed",
+        ));
+    }
+
+    match find(&site, &s3_client, &new_key).await {
+        Ok(None) => (),
+        Ok(Some(_)) => {
+            return HttpResponse::Conflict().json(MicropubError::with_description(
+                "conflict",
+                format!("{} is already in use", new_key),
+            ))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e),
+    }
+
+    if let Err(e) = copy(&site, &s3_client, &key, &new_key).await {
+        return HttpResponse::InternalServerError().body(e);
+    }
+    if site.metadata_sidecar() {
+        let sidecar = metadata::sidecar_key(&key);
+        if let Err(e) = copy(
+            &site,
+            &s3_client,
+            &sidecar,
+            &metadata::sidecar_key(&new_key),
+        )
+        .await
+        {
+            error!("Failed to copy sidecar for {}: {}", key, e);
+        }
+    }
+
+    // Keep the author, so only they can rename or delete the redirect.
+    let url = format!("{}/{}", site.media_url(), new_key);
+    let mut redirect_metadata = HashMap::new();
+    if let Some(author) = stored.get("author") {
+        redirect_metadata.insert("author".to_string(), author.clone());
+    }
+    let redirect = PutObjectRequest {
+        body: Some(Vec::new().into()),
+        metadata: Some(redirect_metadata),
+        website_redirect_location: Some(url.clone()),
+        ..site.put_object_request_for(&key)
+    };
+    if let Err(e) = s3_client.put_object(redirect).await {
+        return HttpResponse::InternalServerError().body(format!("{}", e));
+    }
+
+    invalidate(
+        &site,
+        &s3_client,
+        &http_client,
+        &cdn,
+        disk_cache.as_deref(),
+        &key,
+        &alias.from,
+    )
+    .await;
+
+    if let Some(index) = index {
+        if let Err(e) = index.rename(&key, &new_key, &url).await {
+            error!("Failed to rename {} in the index: {}", key, e);
+        }
+    }
+
+    HttpResponse::Created()
+        .header(header::LOCATION, url.as_str())
+        .json(AliasResponse { url })
+}
+
+/// Whether `new_key` can stand in for `key`: the same prefix, so it's served
+/// the same way, and a name that's usable in a URL.
+fn is_valid_alias(site: &SiteConfig, key: &str, new_key: &str) -> bool {
+    let mut segments = key.splitn(2, '/');
+    let mut new_segments = new_key.splitn(2, '/');
+    let prefix = segments.next();
+    if prefix != new_segments.next()
+        || prefix.map_or(true, |prefix| media::is_hidden(site, prefix))
+        || key == new_key
+    {
+        return false;
+    }
+
+    let name = match new_segments.next() {
+        Some(name) => name,
+        None => return false,
+    };
+    // Photo paths below the prefix are taken by sizes and originals.
+    if prefix == Some("photo") && name.contains('/') {
+        return false;
+    }
+    name.split('/')
+        .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+}
+
+/// Look up an object, or None if there's no such object.
+pub(crate) async fn find(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+) -> Result<Option<HeadObjectOutput>, String> {
+    let (bucket, key) = site.locate(key);
+    let request = HeadObjectRequest {
+        bucket,
+        key,
+        ..Default::default()
+    };
+    match s3_client.head_object(request).await {
+        Ok(head) => Ok(Some(head)),
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Copy an object and its metadata to a new key.
+pub(crate) async fn copy(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    new_key: &str,
+) -> Result<(), String> {
+    let (bucket, source_key) = site.locate(key);
+    let defaults = site.put_object_request_for(new_key);
+    let request = CopyObjectRequest {
+        copy_source: trash::copy_source(&bucket, &source_key),
+        bucket: defaults.bucket,
+        key: defaults.key,
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        acl: defaults.acl,
+        ..Default::default()
+    };
+    s3_client
+        .copy_object(request)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,65 @@
+use serde::Deserialize;
+
+use std::fs;
+use std::io;
+
+use crate::oauth::AccessToken;
+
+/// A static key for clients that can't get an access token, like a CI
+/// pipeline. It is sent in the `X-Api-Key` header.
+#[derive(Deserialize, Clone)]
+pub struct ApiKey {
+    /// Identifies the key in object metadata and the audit log.
+    name: String,
+    key: String,
+    /// Space separated scopes granted to the key.
+    #[serde(default)]
+    scope: String,
+    /// Profile URL that uploads are attributed to. Defaults to `api-key:<name>`.
+    me: Option<String>,
+}
+
+/// Read the keys from a JSON file containing a list of keys.
+pub fn load(path: &str) -> io::Result<Vec<ApiKey>> {
+    let keys: Vec<ApiKey> = serde_json::from_slice(&fs::read(path)?)?;
+    for (i, key) in keys.iter().enumerate() {
+        if key.name.is_empty() || key.key.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Each API key needs a name and a key",
+            ));
+        }
+        if keys[..i].iter().any(|k| k.name == key.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Duplicate API key name {}", key.name),
+            ));
+        }
+    }
+    Ok(keys)
+}
+
+impl ApiKey {
+    /// Compare in constant time, so the key can't be guessed a byte at a time.
+    pub fn matches(&self, key: &str) -> bool {
+        let expected = self.key.as_bytes();
+        let actual = key.as_bytes();
+        expected.len() == actual.len()
+            && expected
+                .iter()
+                .zip(actual)
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+
+    /// The token that requests with this key act as.
+    pub fn access_token(&self) -> AccessToken {
+        AccessToken::for_api_key(
+            &self.name,
+            self.me
+                .clone()
+                .unwrap_or_else(|| format!("api-key:{}", self.name)),
+            self.scope.clone(),
+        )
+    }
+}
@@ -0,0 +1,135 @@
+use actix_web::HttpResponse;
+use derive_more::Display;
+
+use std::collections::VecDeque;
+use std::io::{Cursor, Read};
+
+use crate::micropub::MicropubError;
+use crate::SiteConfig;
+
+/// A file unpacked from an archive.
+pub struct Entry {
+    /// The entry's filename, without the folders it was in.
+    pub name: String,
+    pub content_type: mime::Mime,
+    pub data: Vec<u8>,
+}
+
+/// Why an archive can't be expanded.
+#[derive(Display, Debug)]
+pub enum ArchiveError {
+    #[display(fmt = "Invalid zip archive: {}", _0)]
+    Invalid(String),
+    #[display(fmt = "Archives may have at most {} files", _0)]
+    TooManyEntries(usize),
+    #[display(fmt = "Archives may unpack to at most {} bytes", _0)]
+    TooLarge(u64),
+}
+
+impl ArchiveError {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            ArchiveError::Invalid(_) => HttpResponse::BadRequest()
+                .json(MicropubError::with_description("invalid_request", self)),
+            ArchiveError::TooManyEntries(_) | ArchiveError::TooLarge(_) => {
+                HttpResponse::PayloadTooLarge()
+                    .json(MicropubError::with_description("too_large", self))
+            }
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ArchiveError::Invalid(e.to_string())
+    }
+}
+
+/// Whether an upload is a zip archive.
+pub fn is_archive(content_type: &mime::Mime, filename: Option<&str>) -> bool {
+    let essence = content_type.essence_str();
+    essence == "application/zip"
+        || essence == "application/x-zip-compressed"
+        || filename.map_or(false, |f| f.to_ascii_lowercase().ends_with(".zip"))
+}
+
+/// A zip archive being unpacked a file at a time, so only one file is held
+/// in memory at once.
+///
+/// Reading and decompressing block, so both are meant to run on a blocking
+/// thread.
+pub struct Unpacker {
+    archive: zip::ZipArchive<Cursor<Vec<u8>>>,
+    /// The index and path of each file left to unpack.
+    files: VecDeque<(usize, String)>,
+}
+
+impl Unpacker {
+    /// Open a zip archive, if it has at most ARCHIVE_MAX_ENTRIES files and
+    /// says they unpack to at most ARCHIVE_MAX_BYTES. Folders and hidden
+    /// files, like `__MACOSX/` and `.DS_Store`, are skipped.
+    pub fn new(site: &SiteConfig, data: Vec<u8>) -> Result<Unpacker, ArchiveError> {
+        let max_entries = site.archive_max_entries();
+        let max_bytes = site.archive_max_bytes();
+        let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+
+        let mut files = VecDeque::new();
+        let mut total: u64 = 0;
+        for i in 0..archive.len() {
+            let file = archive.by_index(i)?;
+            let path = file.name().to_string();
+            if file.is_dir()
+                || path
+                    .split('/')
+                    .any(|part| part.starts_with('.') || part == "__MACOSX")
+            {
+                continue;
+            }
+            if files.len() == max_entries {
+                return Err(ArchiveError::TooManyEntries(max_entries));
+            }
+            total = total.saturating_add(file.size());
+            if total > max_bytes {
+                return Err(ArchiveError::TooLarge(max_bytes));
+            }
+            files.push_back((i, path));
+        }
+        Ok(Unpacker { archive, files })
+    }
+
+    /// The filename of the next file, without the folders it's in.
+    pub fn next_name(&self) -> Option<&str> {
+        self.files
+            .front()
+            .map(|(_, path)| path.rsplit('/').next().unwrap_or_default())
+    }
+
+    /// Unpack the next file, if there's one left.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, ArchiveError> {
+        let (i, path) = match self.files.pop_front() {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let file = self.archive.by_index(i)?;
+        let size = file.size();
+
+        // The sizes in the archive can't be trusted, so stop reading past it.
+        let mut data = Vec::new();
+        file.take(size + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| ArchiveError::Invalid(e.to_string()))?;
+        if data.len() as u64 > size {
+            return Err(ArchiveError::Invalid(format!(
+                "{} is bigger than the archive says",
+                path
+            )));
+        }
+
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+        Ok(Some(Entry {
+            content_type: mime_guess::from_path(&name).first_or_octet_stream(),
+            name,
+            data,
+        }))
+    }
+}
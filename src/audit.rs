@@ -0,0 +1,268 @@
+use actix_web::error::BlockingError;
+use actix_web::web;
+use chrono::{Duration, Utc};
+use futures::lock::Mutex;
+use log::{error, info};
+use tokio::io::AsyncReadExt;
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    GetObjectError, GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3,
+};
+
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::events::MediaEvent;
+use crate::micropub::random_id;
+
+/// Number of entries kept in memory when logging to stdout.
+const RECENT_ENTRIES: usize = 1000;
+
+/// Number of daily S3 objects searched when querying.
+const S3_QUERY_DAYS: i64 = 7;
+
+/// Where audit entries are written.
+pub enum AuditSink {
+    /// Log JSON to stdout. Only recent entries can be queried.
+    Stdout,
+    /// Append JSON lines to a local file.
+    File(PathBuf),
+    /// Write each entry as its own JSON object, grouped by day under the
+    /// prefix.
+    S3 {
+        client: S3Client,
+        bucket: String,
+        prefix: String,
+    },
+}
+
+/// An append-only record of every create and delete.
+pub struct AuditLog {
+    sink: Option<AuditSink>,
+    // Recent entries for the stdout sink.
+    lock: Mutex<VecDeque<MediaEvent>>,
+}
+
+impl AuditSink {
+    /// Parse the sink configuration: `stdout`, `file:<path>`, or `s3:<prefix>`.
+    pub fn parse(value: &str, client: &S3Client, bucket: &str) -> Option<AuditSink> {
+        if value == "stdout" {
+            Some(AuditSink::Stdout)
+        } else if let Some(path) = value.strip_prefix("file:") {
+            Some(AuditSink::File(PathBuf::from(path)))
+        } else if let Some(prefix) = value.strip_prefix("s3:") {
+            Some(AuditSink::S3 {
+                client: client.clone(),
+                bucket: bucket.to_string(),
+                prefix: prefix.trim_end_matches('/').to_string(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(sink: Option<AuditSink>) -> AuditLog {
+        AuditLog {
+            sink,
+            lock: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Append an entry to the log.
+    pub async fn record(&self, event: &MediaEvent) {
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit entry: {}", e);
+                return;
+            }
+        };
+
+        let result = match &self.sink {
+            None => Ok(()),
+            Some(AuditSink::Stdout) => {
+                let mut recent = self.lock.lock().await;
+                recent.push_back(event.clone());
+                if recent.len() > RECENT_ENTRIES {
+                    recent.pop_front();
+                }
+                info!(target: "audit", "{}", line);
+                Ok(())
+            }
+            Some(AuditSink::File(path)) => {
+                let path = path.clone();
+                web::block(move || {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .and_then(|mut f| writeln!(f, "{}", line))
+                })
+                .await
+                .map_err(|e| e.to_string())
+            }
+            Some(AuditSink::S3 {
+                client,
+                bucket,
+                prefix,
+            }) => {
+                let key = format!(
+                    "{}/{}/{}-{}.json",
+                    prefix,
+                    event.timestamp.format("%Y-%m-%d"),
+                    event.timestamp.format("%H%M%S%6f"),
+                    random_id()
+                );
+                put_s3(client, bucket, &key, line).await
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Failed to write audit entry for {}: {}", event.key, e);
+        }
+    }
+
+    /// The most recent entries for the user, newest first.
+    pub async fn recent(&self, author: &str, limit: usize) -> Result<Vec<MediaEvent>, String> {
+        let mut entries = Vec::new();
+        match &self.sink {
+            None => (),
+            Some(AuditSink::Stdout) => {
+                let recent = self.lock.lock().await;
+                entries.extend(recent.iter().rev().cloned());
+            }
+            Some(AuditSink::File(path)) => {
+                let path = path.clone();
+                match web::block(move || std::fs::read_to_string(path)).await {
+                    Ok(contents) => entries.extend(parse(&contents)),
+                    Err(BlockingError::Error(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+            Some(AuditSink::S3 {
+                client,
+                bucket,
+                prefix,
+            }) => {
+                'days: for days_ago in 0..S3_QUERY_DAYS {
+                    let day = Utc::now() - Duration::days(days_ago);
+                    let day_prefix = format!("{}/{}/", prefix, day.format("%Y-%m-%d"));
+                    for key in list_s3(client, bucket, &day_prefix).await? {
+                        if let Some(contents) = read_s3(client, bucket, &key).await? {
+                            entries.extend(parse(&contents).filter(|e| e.author == author));
+                        }
+                        if entries.len() >= limit {
+                            break 'days;
+                        }
+                    }
+
+                    // Entries were once appended to a single object per day.
+                    let key = format!("{}/{}.jsonl", prefix, day.format("%Y-%m-%d"));
+                    if let Some(contents) = read_s3(client, bucket, &key).await? {
+                        entries.extend(parse(&contents).filter(|e| e.author == author));
+                    }
+                    if entries.len() >= limit {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(entries
+            .into_iter()
+            .filter(|e| e.author == author)
+            .take(limit)
+            .collect())
+    }
+}
+
+/// Parse JSON lines, newest (last) first.
+fn parse(contents: &str) -> impl Iterator<Item = MediaEvent> + '_ {
+    contents
+        .lines()
+        .rev()
+        .filter_map(|l| serde_json::from_str(l).ok())
+}
+
+/// Read an object as a string, returning None if it doesn't exist.
+async fn read_s3(client: &S3Client, bucket: &str, key: &str) -> Result<Option<String>, String> {
+    let resp = client
+        .get_object(GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        })
+        .await;
+
+    let body = match resp {
+        Ok(resp) => resp.body,
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut contents = String::new();
+    if let Some(body) = body {
+        body.into_async_read()
+            .read_to_string(&mut contents)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(Some(contents))
+}
+
+/// The keys under the prefix, newest first.
+async fn list_s3(client: &S3Client, bucket: &str, prefix: &str) -> Result<Vec<String>, String> {
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_string(),
+            prefix: Some(prefix.to_string()),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = client
+            .list_objects_v2(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        keys.extend(
+            response
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|object| object.key),
+        );
+        match response.next_continuation_token {
+            Some(token) if response.is_truncated == Some(true) => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+    // Keys start with the time of the entry.
+    keys.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(keys)
+}
+
+/// Write an entry as its own object, so concurrent writers never overwrite
+/// each other.
+async fn put_s3(client: &S3Client, bucket: &str, key: &str, line: String) -> Result<(), String> {
+    client
+        .put_object(PutObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            body: Some(line.into_bytes().into()),
+            content_type: Some("application/json".to_string()),
+            ..Default::default()
+        })
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,91 @@
+use log::error;
+
+use rusoto_core::Region;
+use rusoto_events::{EventBridge, EventBridgeClient, PutEventsRequest, PutEventsRequestEntry};
+use rusoto_sns::{PublishInput, Sns, SnsClient};
+use rusoto_sqs::{SendMessageRequest, Sqs, SqsClient};
+
+use crate::events::MediaEvent;
+
+/// The `source` of events sent to EventBridge.
+const EVENT_SOURCE: &str = "s3-media-endpoint";
+
+/// Publishes events to SNS, SQS, and EventBridge.
+#[derive(Clone, Default)]
+pub struct AwsPublisher {
+    sns: Option<(SnsClient, String)>,
+    sqs: Option<(SqsClient, String)>,
+    event_bridge: Option<(EventBridgeClient, String)>,
+}
+
+impl AwsPublisher {
+    pub fn new(
+        topic_arn: Option<String>,
+        queue_url: Option<String>,
+        event_bus: Option<String>,
+    ) -> AwsPublisher {
+        AwsPublisher {
+            sns: topic_arn.map(|arn| (SnsClient::new(Region::default()), arn)),
+            sqs: queue_url.map(|url| (SqsClient::new(Region::default()), url)),
+            event_bridge: event_bus.map(|bus| (EventBridgeClient::new(Region::default()), bus)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sns.is_some() || self.sqs.is_some() || self.event_bridge.is_some()
+    }
+
+    /// Send the event to every configured destination, logging failures.
+    pub async fn deliver(self, event: MediaEvent) {
+        let message = match serde_json::to_string(&event) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Failed to serialize event: {}", e);
+                return;
+            }
+        };
+        let event_type = format!("{:?}", event.event);
+
+        if let Some((client, topic_arn)) = &self.sns {
+            let request = PublishInput {
+                topic_arn: Some(topic_arn.clone()),
+                subject: Some(format!("{} {}", event_type, event.key)),
+                message: message.clone(),
+                ..Default::default()
+            };
+            if let Err(e) = client.publish(request).await {
+                error!("Failed to publish {} to SNS: {}", event.key, e);
+            }
+        }
+
+        if let Some((client, queue_url)) = &self.sqs {
+            let request = SendMessageRequest {
+                queue_url: queue_url.clone(),
+                message_body: message.clone(),
+                ..Default::default()
+            };
+            if let Err(e) = client.send_message(request).await {
+                error!("Failed to send {} to SQS: {}", event.key, e);
+            }
+        }
+
+        if let Some((client, event_bus)) = &self.event_bridge {
+            let request = PutEventsRequest {
+                entries: vec![PutEventsRequestEntry {
+                    event_bus_name: Some(event_bus.clone()),
+                    source: Some(EVENT_SOURCE.to_string()),
+                    detail_type: Some(format!("Media {}", event_type)),
+                    detail: Some(message),
+                    ..Default::default()
+                }],
+            };
+            match client.put_events(request).await {
+                Ok(resp) if resp.failed_entry_count.unwrap_or(0) > 0 => {
+                    error!("EventBridge rejected the event for {}", event.key)
+                }
+                Ok(_) => (),
+                Err(e) => error!("Failed to put {} to EventBridge: {}", event.key, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use rusoto_s3::{GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+use tokio::io::AsyncReadExt;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::classify;
+use crate::geo::SIMPLIFIED_PREFIX;
+use crate::hls::HLS_PREFIX;
+use crate::metadata::SIDECAR_PREFIX;
+use crate::multipart;
+use crate::originals::ORIGINALS_PREFIX;
+use crate::peaks::PEAKS_PREFIX;
+use crate::previews::PREVIEW_PREFIX;
+use crate::SiteConfig;
+
+/// The name of the manifest within a backup.
+const MANIFEST: &str = "manifest.json";
+
+/// Objects are stored below this within a backup, by their logical key.
+const OBJECTS: &str = "objects/";
+
+/// Derivatives which are generated again from the originals, so they're
+/// left out of backups.
+const GENERATED_PREFIXES: &[&str] = &[HLS_PREFIX, PREVIEW_PREFIX, PEAKS_PREFIX, SIMPLIFIED_PREFIX];
+
+/// Where a backup is written to or restored from.
+pub enum Archive {
+    /// A tarball on this machine.
+    Tarball(PathBuf),
+    /// A prefix of another bucket, e.g. `s3://backups/media/`.
+    Bucket { bucket: String, prefix: String },
+}
+
+impl Archive {
+    /// Parse an `s3://bucket/prefix` URL, or else a file path.
+    pub fn parse(location: &str) -> Archive {
+        match location.strip_prefix("s3://") {
+            Some(rest) => {
+                let mut parts = rest.splitn(2, '/');
+                Archive::Bucket {
+                    bucket: parts.next().unwrap_or_default().to_string(),
+                    prefix: parts.next().unwrap_or_default().to_string(),
+                }
+            }
+            None => Archive::Tarball(PathBuf::from(location)),
+        }
+    }
+}
+
+/// Everything needed to put a backed up object back as it was.
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    /// The object's metadata, still encoded as it was stored.
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    /// Where a renamed object redirects to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redirect: Option<String>,
+    size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    created_at: DateTime<Utc>,
+    media_url: String,
+    objects: Vec<ManifestEntry>,
+}
+
+/// A backup being written.
+enum Writer<'a> {
+    Tarball(tar::Builder<File>),
+    Bucket { bucket: &'a str, prefix: &'a str },
+}
+
+impl Writer<'_> {
+    async fn write(
+        &mut self,
+        site: &SiteConfig,
+        s3_client: &S3Client,
+        path: &str,
+        content_type: Option<String>,
+        data: Vec<u8>,
+    ) -> Result<(), String> {
+        match self {
+            Writer::Tarball(tarball) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(Utc::now().timestamp() as u64);
+                tarball
+                    .append_data(&mut header, path, data.as_slice())
+                    .map_err(|e| format!("Failed to write {}: {}", path, e))
+            }
+            Writer::Bucket { bucket, prefix } => {
+                let put_request = PutObjectRequest {
+                    bucket: bucket.to_string(),
+                    key: format!("{}{}", prefix, path),
+                    content_type,
+                    ..Default::default()
+                };
+                multipart::put_object(site, s3_client, put_request, data)
+                    .await
+                    .map_err(|e| format!("Failed to write {}: {}", path, e))
+            }
+        }
+    }
+}
+
+/// Copy every original, with its metadata and sidecar, to `archive`,
+/// followed by a manifest listing them. Returns how many were copied.
+///
+/// Objects are copied one at a time, so a backup never holds more than one
+/// in memory.
+pub async fn backup(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    archive: &Archive,
+) -> Result<usize, String> {
+    let mut writer = match archive {
+        Archive::Tarball(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            Writer::Tarball(tar::Builder::new(file))
+        }
+        Archive::Bucket { bucket, prefix } => Writer::Bucket { bucket, prefix },
+    };
+
+    let mut manifest = Manifest {
+        created_at: Utc::now(),
+        media_url: site.media_url().to_string(),
+        objects: Vec::new(),
+    };
+    for key in backed_up_keys(site, s3_client).await? {
+        let (bucket, object_key) = site.locate(&key);
+        let (mut entry, data) = read_object(s3_client, &bucket, &object_key).await?;
+        entry.key = key;
+        let path = format!("{}{}", OBJECTS, entry.key);
+        writer
+            .write(site, s3_client, &path, entry.content_type.clone(), data)
+            .await?;
+        info!("Backed up {}", entry.key);
+        manifest.objects.push(entry);
+    }
+
+    let body = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    let content_type = Some("application/json".to_string());
+    writer
+        .write(site, s3_client, MANIFEST, content_type, body)
+        .await?;
+    if let Writer::Tarball(tarball) = writer {
+        tarball.into_inner().map_err(|e| e.to_string())?;
+    }
+    Ok(manifest.objects.len())
+}
+
+/// Put every object in a backup back into the bucket under its old key,
+/// overwriting whatever is there. Returns how many were restored.
+///
+/// Derivatives aren't backed up; they're generated again as they're
+/// requested, or by `backfill`.
+pub async fn restore(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    archive: &Archive,
+) -> Result<usize, String> {
+    let mut restored = 0;
+    match archive {
+        Archive::Tarball(path) => {
+            let manifest = read_manifest(path)?;
+            let mut entries: HashMap<String, ManifestEntry> = manifest
+                .objects
+                .into_iter()
+                .map(|entry| (entry.key.clone(), entry))
+                .collect();
+
+            let mut tarball = open_tarball(path)?;
+            for file in tarball.entries().map_err(|e| e.to_string())? {
+                let mut file = file.map_err(|e| e.to_string())?;
+                let name = file.path().map_err(|e| e.to_string())?;
+                let key = match name.to_string_lossy().strip_prefix(OBJECTS) {
+                    Some(key) => key.to_string(),
+                    None => continue,
+                };
+                let entry = match entries.remove(&key) {
+                    Some(entry) => entry,
+                    None => {
+                        error!("{} isn't in the manifest", key);
+                        continue;
+                    }
+                };
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+                put_back(site, s3_client, entry, data).await?;
+                restored += 1;
+            }
+            for key in entries.keys() {
+                error!("{} is in the manifest but not the backup", key);
+            }
+        }
+        Archive::Bucket { bucket, prefix } => {
+            let (_, data) =
+                read_object(s3_client, bucket, &format!("{}{}", prefix, MANIFEST)).await?;
+            let manifest: Manifest = serde_json::from_slice(&data)
+                .map_err(|e| format!("Failed to read the manifest: {}", e))?;
+            for entry in manifest.objects {
+                let key = format!("{}{}{}", prefix, OBJECTS, entry.key);
+                let (_, data) = read_object(s3_client, bucket, &key).await?;
+                put_back(site, s3_client, entry, data).await?;
+                restored += 1;
+            }
+        }
+    }
+    Ok(restored)
+}
+
+/// The logical keys of every original, kept original, and sidecar.
+async fn backed_up_keys(site: &SiteConfig, s3_client: &S3Client) -> Result<Vec<String>, String> {
+    let mut prefixes = classify::prefixes(site);
+    prefixes.push(ORIGINALS_PREFIX.to_string());
+    prefixes.push(SIDECAR_PREFIX.to_string());
+
+    let mut keys = Vec::new();
+    for prefix in prefixes {
+        let logical_prefix = format!("{}/", prefix);
+        let (bucket, prefix) = site.locate(&logical_prefix);
+        let route_prefix = prefix[..prefix.len() - logical_prefix.len()].to_string();
+        let mut continuation_token = None;
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: bucket.clone(),
+                prefix: Some(prefix.clone()),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+            let response = s3_client
+                .list_objects_v2(request)
+                .await
+                .map_err(|e| format!("Failed to list {}: {}", prefix, e))?;
+            for object in response.contents.unwrap_or_default() {
+                let key = match object.key {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if let Some(key) = key.strip_prefix(&route_prefix) {
+                    let generated = GENERATED_PREFIXES
+                        .iter()
+                        .any(|generated| key.starts_with(&format!("{}/", generated)));
+                    if !generated {
+                        keys.push(key.to_string());
+                    }
+                }
+            }
+
+            if response.is_truncated != Some(true) {
+                break;
+            }
+            continuation_token = response.next_continuation_token;
+        }
+    }
+    Ok(keys)
+}
+
+async fn read_object(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+) -> Result<(ManifestEntry, Vec<u8>), String> {
+    let request = GetObjectRequest {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        ..Default::default()
+    };
+    let response = s3_client
+        .get_object(request)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", key, e))?;
+
+    let mut data = Vec::new();
+    if let Some(body) = response.body {
+        body.into_async_read()
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", key, e))?;
+    }
+    let entry = ManifestEntry {
+        key: key.to_string(),
+        content_type: response.content_type,
+        metadata: response.metadata.unwrap_or_default(),
+        redirect: response.website_redirect_location,
+        size: data.len() as u64,
+    };
+    Ok((entry, data))
+}
+
+async fn put_back(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    entry: ManifestEntry,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    let put_request = PutObjectRequest {
+        content_type: entry.content_type,
+        metadata: Some(entry.metadata),
+        website_redirect_location: entry.redirect,
+        ..site.put_object_request_for(&entry.key)
+    };
+    multipart::put_object(site, s3_client, put_request, data)
+        .await
+        .map_err(|e| format!("Failed to restore {}: {}", entry.key, e))?;
+    info!("Restored {}", entry.key);
+    Ok(())
+}
+
+fn open_tarball(path: &Path) -> Result<tar::Archive<File>, String> {
+    File::open(path)
+        .map(tar::Archive::new)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
+
+/// Find the manifest in a tarball. It's written last, so the whole tarball
+/// is read once before anything is restored.
+fn read_manifest(path: &Path) -> Result<Manifest, String> {
+    let mut tarball = open_tarball(path)?;
+    for file in tarball.entries().map_err(|e| e.to_string())? {
+        let mut file = file.map_err(|e| e.to_string())?;
+        if file.path().map_err(|e| e.to_string())?.as_os_str() != MANIFEST {
+            continue;
+        }
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        return serde_json::from_slice(&data)
+            .map_err(|e| format!("Failed to read the manifest: {}", e));
+    }
+    Err(format!("{} has no manifest", path.display()))
+}
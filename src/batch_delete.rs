@@ -0,0 +1,213 @@
+use actix_web::client::Client;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::future;
+
+use rusoto_s3::{Delete, DeleteObjectsRequest, HeadObjectOutput, ObjectIdentifier, S3Client, S3};
+
+use serde::Serialize;
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::alias;
+use crate::cdn::Cdn;
+use crate::delete;
+use crate::disk_cache::DiskCache;
+use crate::events;
+use crate::index::MediaIndex;
+use crate::metadata;
+use crate::micropub::{authorize, forget_deleted, key_for_url, MicropubError};
+use crate::oauth;
+use crate::originals;
+use crate::policy::Permission;
+use crate::trash;
+use crate::SiteConfig;
+
+/// The most URLs deleted by one request, which is also the most keys S3
+/// deletes in one DeleteObjects call.
+const MAX_BATCH: usize = 1000;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/delete").route(web::post().to(handle_batch_delete)));
+}
+
+#[derive(Serialize)]
+struct BatchDeleteResponse {
+    results: Vec<DeleteResult>,
+}
+
+#[derive(Serialize)]
+struct DeleteResult {
+    url: String,
+    deleted: bool,
+    #[serde(flatten)]
+    error: Option<MicropubError>,
+}
+
+/// An object found for one of the URLs, ready to be deleted.
+struct Found {
+    key: String,
+    head: HeadObjectOutput,
+}
+
+/// Delete every URL in a JSON array.
+///
+/// Each URL is checked as `?action=delete` would, then the objects are
+/// removed with as few DeleteObjects calls as their buckets allow. Every URL
+/// gets a result of its own, so one bad URL doesn't spoil the rest.
+async fn handle_batch_delete(
+    req: HttpRequest,
+    urls: web::Json<Vec<String>>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    cdn: web::Data<Cdn>,
+    disk_cache: Option<web::Data<DiskCache>>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Delete).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let urls = urls.into_inner();
+    if urls.is_empty() || urls.len() > MAX_BATCH {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            format!("Between 1 and {} URLs may be deleted at once", MAX_BATCH),
+        ));
+    }
+
+    let mut found: Vec<Result<Found, MicropubError>> = future::join_all(
+        urls.iter()
+            .map(|url| find(&site, &s3_client, &access_token, url)),
+    )
+    .await;
+
+    // Group the keys to delete by bucket, noting which URL each belongs to.
+    // Sidecars and originals go too, but their failures aren't reported.
+    let mut buckets: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut owners: HashMap<(String, String), usize> = HashMap::new();
+    for (i, result) in found.iter().enumerate() {
+        let found = match result {
+            Ok(found) => found,
+            Err(_) => continue,
+        };
+        let (bucket, object_key) = site.locate(&found.key);
+        if owners.contains_key(&(bucket.clone(), object_key.clone())) {
+            continue;
+        }
+        owners.insert((bucket.clone(), object_key.clone()), i);
+        buckets.entry(bucket).or_default().push(object_key);
+        // Sidecars were copied to the trash with their objects.
+        if site.metadata_sidecar() {
+            buckets
+                .entry(site.s3_bucket().to_owned())
+                .or_default()
+                .push(metadata::sidecar_key(&found.key));
+        }
+        if site.trash_days() > 0 {
+            continue;
+        }
+        if site.keep_originals() {
+            let (bucket, original) = site.locate(&originals::original_key(&found.key));
+            buckets.entry(bucket).or_default().push(original);
+        }
+    }
+
+    for (bucket, keys) in buckets {
+        for chunk in keys.chunks(MAX_BATCH) {
+            let request = DeleteObjectsRequest {
+                bucket: bucket.clone(),
+                delete: Delete {
+                    objects: chunk
+                        .iter()
+                        .map(|key| ObjectIdentifier {
+                            key: key.clone(),
+                            version_id: None,
+                        })
+                        .collect(),
+                    quiet: Some(true),
+                },
+                ..Default::default()
+            };
+            let failures: Vec<(String, String)> = match s3_client.delete_objects(request).await {
+                Ok(output) => output
+                    .errors
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|e| Some((e.key?, e.message.or(e.code).unwrap_or_default())))
+                    .collect(),
+                Err(e) => chunk
+                    .iter()
+                    .map(|key| (key.clone(), e.to_string()))
+                    .collect(),
+            };
+            for (key, message) in failures {
+                if let Some(&i) = owners.get(&(bucket.clone(), key)) {
+                    found[i] = Err(MicropubError::with_description("server_error", message));
+                }
+            }
+        }
+    }
+
+    let mut results = Vec::with_capacity(urls.len());
+    for (url, result) in urls.into_iter().zip(found) {
+        let error = match result {
+            Ok(Found { key, head }) => {
+                forget_deleted(
+                    &site,
+                    &s3_client,
+                    &http_client,
+                    &cdn,
+                    disk_cache.as_deref(),
+                    &publisher,
+                    index.as_deref(),
+                    &access_token,
+                    &url,
+                    key,
+                    head,
+                )
+                .await;
+                None
+            }
+            Err(e) => Some(e),
+        };
+        results.push(DeleteResult {
+            url,
+            deleted: error.is_none(),
+            error,
+        });
+    }
+
+    HttpResponse::Ok().json(BatchDeleteResponse { results })
+}
+
+/// Find the object behind `url` and check it may be deleted. In trash mode,
+/// it's copied to the trash before it's deleted.
+async fn find(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    access_token: &oauth::AccessToken,
+    url: &str,
+) -> Result<Found, MicropubError> {
+    let key = key_for_url(site, url)
+        .ok_or_else(|| MicropubError::with_description("invalid_request", "Unknown URL"))?;
+    let head = match alias::find(site, s3_client, &key).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return Err(MicropubError::new("not_found")),
+        Err(e) => return Err(MicropubError::with_description("server_error", e)),
+    };
+    if !delete::is_author(head.metadata.as_ref(), access_token) {
+        return Err(MicropubError::new("forbidden"));
+    }
+
+    if site.trash_days() > 0 {
+        if let Err(e) = trash::copy_to_trash(site, s3_client, &key, &head).await {
+            return Err(MicropubError::with_description("server_error", e));
+        }
+    }
+    Ok(Found { key, head })
+}
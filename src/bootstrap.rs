@@ -0,0 +1,132 @@
+use log::info;
+
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{
+    AbortIncompleteMultipartUpload, BucketLifecycleConfiguration, CORSConfiguration, CORSRule,
+    CreateBucketConfiguration, CreateBucketRequest, HeadBucketRequest, LifecycleExpiration,
+    LifecycleRule, LifecycleRuleFilter, PutBucketCorsRequest,
+    PutBucketLifecycleConfigurationRequest, S3Client, S3,
+};
+
+use std::time::Duration;
+
+use crate::expiry;
+use crate::media::DERIVATIVE_PREFIX;
+use crate::staging::STAGING_PREFIX;
+use crate::trash::TRASH_PREFIX;
+use crate::SiteConfig;
+
+/// Create the bucket if it doesn't exist and apply a basic configuration.
+///
+/// Only a missing bucket is configured, so changes made to an existing
+/// bucket are never overwritten.
+pub async fn create_bucket(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    region: &Region,
+    multipart_max_age: Duration,
+) -> Result<(), String> {
+    let bucket = site.s3_bucket().to_owned();
+    let head = s3_client
+        .head_bucket(HeadBucketRequest {
+            bucket: bucket.clone(),
+            ..Default::default()
+        })
+        .await;
+    match head {
+        Ok(_) => return Ok(()),
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => (),
+        Err(RusotoError::Service(_)) => (),
+        Err(e) => return Err(format!("Failed to check for bucket {}: {}", bucket, e)),
+    }
+
+    info!("Creating bucket {}", bucket);
+    // us-east-1 is the default and must not be given as a constraint.
+    let create_bucket_configuration = match region {
+        Region::UsEast1 => None,
+        region => Some(CreateBucketConfiguration {
+            location_constraint: Some(region.name().to_string()),
+        }),
+    };
+    s3_client
+        .create_bucket(CreateBucketRequest {
+            bucket: bucket.clone(),
+            create_bucket_configuration,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| format!("Failed to create bucket {}: {}", bucket, e))?;
+
+    // Let browsers fetch media directly from the bucket.
+    let cors_configuration = CORSConfiguration {
+        cors_rules: vec![CORSRule {
+            allowed_methods: vec!["GET".to_string(), "HEAD".to_string()],
+            allowed_origins: vec!["*".to_string()],
+            allowed_headers: Some(vec!["*".to_string()]),
+            expose_headers: Some(vec!["ETag".to_string()]),
+            max_age_seconds: Some(3600),
+            ..Default::default()
+        }],
+    };
+    s3_client
+        .put_bucket_cors(PutBucketCorsRequest {
+            bucket: bucket.clone(),
+            cors_configuration,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| format!("Failed to configure CORS on {}: {}", bucket, e))?;
+
+    let mut rules = vec![LifecycleRule {
+        id: Some("abort-incomplete-uploads".to_string()),
+        status: "Enabled".to_string(),
+        filter: Some(LifecycleRuleFilter {
+            prefix: Some(String::new()),
+            ..Default::default()
+        }),
+        abort_incomplete_multipart_upload: Some(AbortIncompleteMultipartUpload {
+            days_after_initiation: Some(days(multipart_max_age)),
+        }),
+        ..Default::default()
+    }];
+    rules.push(expire("expire-staging", STAGING_PREFIX, days(multipart_max_age)));
+    if site.trash_days() > 0 {
+        rules.push(expire("expire-trash", TRASH_PREFIX, i64::from(site.trash_days())));
+    }
+    if let Some(max_idle) = site.derivative_max_idle() {
+        rules.push(expire("expire-derivatives", DERIVATIVE_PREFIX, days(max_idle)));
+    }
+    rules.extend(expiry::rules(site));
+    s3_client
+        .put_bucket_lifecycle_configuration(PutBucketLifecycleConfigurationRequest {
+            bucket: bucket.clone(),
+            lifecycle_configuration: Some(BucketLifecycleConfiguration { rules }),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| format!("Failed to configure lifecycle rules on {}: {}", bucket, e))?;
+
+    Ok(())
+}
+
+/// A rule expiring objects under `prefix` after `days`.
+fn expire(id: &str, prefix: &str, days: i64) -> LifecycleRule {
+    LifecycleRule {
+        id: Some(id.to_string()),
+        status: "Enabled".to_string(),
+        filter: Some(LifecycleRuleFilter {
+            prefix: Some(format!("{}/", prefix)),
+            ..Default::default()
+        }),
+        expiration: Some(LifecycleExpiration {
+            days: Some(days),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Lifecycle rules count whole days, and at least one.
+fn days(duration: Duration) -> i64 {
+    ((duration.as_secs() + 86_399) / 86_400).max(1) as i64
+}
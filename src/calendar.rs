@@ -0,0 +1,193 @@
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use rusoto_s3::{ListObjectsV2Request, S3Client, S3};
+use serde::Serialize;
+
+use crate::classify::UrlStyle;
+use crate::feed::escape;
+use crate::index::{ListFilter, MediaIndex};
+use crate::keys;
+use crate::SiteConfig;
+
+/// The most photos listed for a month.
+const MONTH_LIMIT: i64 = 500;
+
+/// Keys laid out like this can be listed by month without an index.
+const DATED_TEMPLATE: &str = "{classification}/{yyyy}/{mm}/";
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/media/photos/{yyyy}/{mm}").route(web::get().to(serve_month)));
+}
+
+#[derive(Serialize)]
+struct Month {
+    year: i32,
+    month: u32,
+    photos: Vec<Photo>,
+}
+
+#[derive(Serialize)]
+struct Photo {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    caption: Option<String>,
+    /// When it was taken, with KEY_DATE=exif, or else uploaded.
+    date: String,
+}
+
+/// List the photos of a month, oldest first, for archive pages.
+///
+/// The index is used if there is one. Otherwise the bucket is listed, which
+/// only works if KEY_TEMPLATE files photos by year and month. Browsers get a
+/// simple HTML page; everything else gets JSON.
+async fn serve_month(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    config: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    index: Option<web::Data<MediaIndex>>,
+) -> Result<HttpResponse, Error> {
+    let (yyyy, mm) = path.into_inner();
+    let start = match (yyyy.len(), mm.len(), yyyy.parse(), mm.parse()) {
+        (4, 2, Ok(year), Ok(month)) => NaiveDate::from_ymd_opt(year, month, 1),
+        _ => None,
+    }
+    .ok_or_else(|| ErrorNotFound("Not found"))?;
+    let end = match start.month() {
+        12 => NaiveDate::from_ymd(start.year() + 1, 1, 1),
+        month => NaiveDate::from_ymd(start.year(), month + 1, 1),
+    };
+
+    let photos = match index {
+        Some(index) => {
+            let filter = ListFilter {
+                classification: Some("photo"),
+                since: Some(DateTime::from_utc(start.and_hms(0, 0, 0), Utc)),
+                until: Some(DateTime::from_utc(end.and_hms(0, 0, 0), Utc)),
+                limit: MONTH_LIMIT,
+                ..Default::default()
+            };
+            let mut records = index
+                .list(&filter)
+                .await
+                .map_err(ErrorInternalServerError)?;
+            records.reverse();
+            records
+                .into_iter()
+                .map(|record| Photo {
+                    url: record.url,
+                    alt: record.alt,
+                    caption: record.caption,
+                    date: record.created_at.to_rfc3339(),
+                })
+                .collect()
+        }
+        None => list_dated_keys(&config, &s3_client, &yyyy, &mm).await?,
+    };
+
+    let month = Month {
+        year: start.year(),
+        month: start.month(),
+        photos,
+    };
+    let wants_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.contains("text/html"));
+    let mut resp = HttpResponse::Ok();
+    resp.header(header::CACHE_CONTROL, "max-age=300")
+        .header(header::VARY, "Accept");
+    if wants_html {
+        Ok(resp
+            .content_type("text/html; charset=utf-8")
+            .body(month_html(&month, start)))
+    } else {
+        Ok(resp.json(month))
+    }
+}
+
+/// List the photos filed under the month by KEY_TEMPLATE.
+async fn list_dated_keys(
+    config: &SiteConfig,
+    s3_client: &S3Client,
+    yyyy: &str,
+    mm: &str,
+) -> Result<Vec<Photo>, Error> {
+    let template = keys::template(config, UrlStyle::Resized);
+    if !template.starts_with(DATED_TEMPLATE) {
+        return Err(ErrorNotFound("Not found"));
+    }
+
+    let logical_prefix = format!("photo/{}/{}/", yyyy, mm);
+    let (bucket, prefix) = config.locate(&logical_prefix);
+    let route_prefix = prefix[..prefix.len() - logical_prefix.len()].to_string();
+    let request = ListObjectsV2Request {
+        bucket,
+        prefix: Some(prefix),
+        max_keys: Some(MONTH_LIMIT),
+        ..Default::default()
+    };
+    let response = s3_client
+        .list_objects_v2(request)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut photos: Vec<Photo> = response
+        .contents
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|object| {
+            let key = object.key?;
+            let key = key.strip_prefix(&route_prefix)?.strip_prefix("photo/")?;
+            Some(Photo {
+                url: format!(
+                    "{}/photo/{}x{}/{}",
+                    config.media_url(),
+                    config.default_width(),
+                    config.default_height(),
+                    key
+                ),
+                alt: None,
+                caption: None,
+                date: object.last_modified?,
+            })
+        })
+        .collect();
+    photos.sort_by(|a, b| a.date.cmp(&b.date));
+    Ok(photos)
+}
+
+fn month_html(month: &Month, start: NaiveDate) -> String {
+    let title = start.format("%B %Y").to_string();
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n");
+    html.push_str(&format!("<title>{}</title>\n", escape(&title)));
+    html.push_str("<style>body{font-family:sans-serif}");
+    html.push_str("img{max-width:100%;max-height:90vh}</style>\n");
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&format!("<h1>{}</h1>\n", escape(&title)));
+    for photo in &month.photos {
+        html.push_str("<figure class=\"h-entry\">\n");
+        html.push_str(&format!(
+            "<img class=\"u-photo\" src=\"{}\" alt=\"{}\" loading=\"lazy\">\n",
+            escape(&photo.url),
+            escape(photo.alt.as_deref().unwrap_or(""))
+        ));
+        if let Some(caption) = &photo.caption {
+            html.push_str(&format!(
+                "<figcaption class=\"p-name\">{}</figcaption>\n",
+                escape(caption)
+            ));
+        }
+        html.push_str("</figure>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
@@ -0,0 +1,45 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Tells work on the blocking pool that whoever wanted it has gone away.
+///
+/// actix drops a handler's future when the client disconnects or the route
+/// times out. That stops anything async, like an S3 download, but not a
+/// closure already handed to `web::block`, so those check the token instead.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// A token, and the guard that cancels it when dropped. Keep the guard in
+    /// the handler's future.
+    pub fn new() -> (CancelToken, CancelGuard) {
+        let token = CancelToken::default();
+        (token.clone(), CancelGuard(token))
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// An error to bail out with, if the work was cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Request cancelled",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Cancels its token when dropped.
+pub struct CancelGuard(CancelToken);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        (self.0).0.store(true, Ordering::Relaxed);
+    }
+}
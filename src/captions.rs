@@ -0,0 +1,182 @@
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use log::error;
+
+use rusoto_s3::{ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+
+use serde::Serialize;
+
+use crate::alias;
+use crate::micropub::{key_for_url, MicropubError};
+use crate::oauth;
+use crate::SiteConfig;
+
+/// Key prefix for caption tracks. Each video gets a directory below this
+/// named after its key.
+pub const CAPTION_PREFIX: &str = "video/captions";
+
+/// Longest track name, e.g. a language tag or a filename.
+const MAX_TRACK_LENGTH: usize = 64;
+
+/// A caption track listed in a video's info.
+#[derive(Serialize)]
+pub struct CaptionTrack {
+    /// The language given when it was uploaded, or its filename.
+    pub label: String,
+    pub url: String,
+}
+
+fn caption_prefix(key: &str) -> String {
+    format!("{}/{}/", CAPTION_PREFIX, key)
+}
+
+/// Whether an upload is a WebVTT or SubRip file, going by its name.
+fn is_caption_file(filename: Option<&str>) -> bool {
+    let extension = filename
+        .and_then(|name| name.rsplit('.').next())
+        .map(str::to_ascii_lowercase);
+    matches!(extension.as_deref(), Some("vtt") | Some("srt"))
+}
+
+fn is_track_name(track: &str) -> bool {
+    !track.is_empty()
+        && track.len() <= MAX_TRACK_LENGTH
+        && track
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Store a caption track for the video at `video_url`, the upload's `for`
+/// field.
+///
+/// Tracks are named by the `lang` field, or else the filename. SubRip files
+/// are converted, so every track is served as WebVTT.
+pub(crate) async fn attach(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    access_token: &oauth::AccessToken,
+    video_url: &str,
+    lang: Option<&str>,
+    filename: Option<&str>,
+    body: Vec<u8>,
+) -> HttpResponse {
+    if !is_caption_file(filename) {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "Captions must be a .vtt or .srt file",
+        ));
+    }
+    let track = lang.or_else(|| {
+        filename
+            .and_then(|name| name.rsplit('/').next())
+            .and_then(|name| name.rsplitn(2, '.').nth(1))
+    });
+    let track = match track.filter(|track| is_track_name(track)) {
+        Some(track) => track,
+        None => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "The track name may only have letters, digits, - and _",
+            ))
+        }
+    };
+
+    let key = match key_for_url(site, video_url) {
+        Some(key) => key,
+        None => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "Unknown URL",
+            ))
+        }
+    };
+    let head = match alias::find(site, s3_client, &key).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+        Err(e) => return HttpResponse::InternalServerError().body(e),
+    };
+    let is_video = head
+        .content_type
+        .as_deref()
+        .map_or(false, |t| t.starts_with("video/"));
+    if !is_video {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "Captions can only be added to videos",
+        ));
+    }
+    if let Some(author) = head.metadata.unwrap_or_default().get("author") {
+        if author != access_token.me() {
+            return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+        }
+    }
+
+    let text = String::from_utf8_lossy(&body);
+    let vtt = if text.trim_start_matches('\u{feff}').starts_with("WEBVTT") {
+        text.into_owned()
+    } else {
+        srt_to_vtt(&text)
+    };
+
+    let caption_key = format!("{}{}.vtt", caption_prefix(&key), track);
+    let put_request = PutObjectRequest {
+        body: Some(vtt.into_bytes().into()),
+        content_type: Some("text/vtt".to_string()),
+        ..site.put_object_request_for(&caption_key)
+    };
+    if let Err(e) = s3_client.put_object(put_request).await {
+        return HttpResponse::InternalServerError().body(format!("{}", e));
+    }
+
+    let url = format!("{}/{}", site.media_url(), caption_key);
+    HttpResponse::Created()
+        .header(header::LOCATION, url.as_str())
+        .json(CaptionTrack {
+            label: track.to_string(),
+            url,
+        })
+}
+
+/// Convert SubRip to WebVTT, which differs mostly in the header and the
+/// decimal separator of the timings.
+fn srt_to_vtt(srt: &str) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for line in srt.trim_start_matches('\u{feff}').lines() {
+        if line.contains("-->") {
+            vtt.push_str(&line.replace(',', "."));
+        } else {
+            vtt.push_str(line);
+        }
+        vtt.push('\n');
+    }
+    vtt
+}
+
+/// The caption tracks of the video at `key`.
+pub async fn list(site: &SiteConfig, s3_client: &S3Client, key: &str) -> Vec<CaptionTrack> {
+    let logical_prefix = caption_prefix(key);
+    let (bucket, prefix) = site.locate(&logical_prefix);
+    let request = ListObjectsV2Request {
+        bucket,
+        prefix: Some(prefix.clone()),
+        ..Default::default()
+    };
+    let contents = match s3_client.list_objects_v2(request).await {
+        Ok(response) => response.contents.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to list captions of {}: {}", key, e);
+            return Vec::new();
+        }
+    };
+
+    contents
+        .into_iter()
+        .filter_map(|object| {
+            let name = object.key?.strip_prefix(&prefix)?.to_string();
+            Some(CaptionTrack {
+                label: name.trim_end_matches(".vtt").to_string(),
+                url: format!("{}/{}{}", site.media_url(), logical_prefix, name),
+            })
+        })
+        .collect()
+}
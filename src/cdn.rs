@@ -0,0 +1,106 @@
+use actix_web::client::Client;
+use actix_web::http::header;
+use log::{error, info};
+use serde::Serialize;
+
+use rusoto_cloudfront::{
+    CloudFront, CloudFrontClient, CreateInvalidationRequest, InvalidationBatch, Paths,
+};
+use rusoto_core::Region;
+
+use chrono::Utc;
+
+/// A CDN in front of the media URLs.
+pub enum CdnBackend {
+    /// Create invalidations on a CloudFront distribution.
+    CloudFront {
+        client: CloudFrontClient,
+        distribution_id: String,
+    },
+    /// POST `{"urls": [...]}` to a purge API, e.g. a small function fronting another CDN.
+    Http { url: String, token: Option<String> },
+}
+
+/// Removes stale copies of media from the CDN.
+pub struct Cdn {
+    backend: Option<CdnBackend>,
+}
+
+#[derive(Serialize)]
+struct PurgeRequest<'a> {
+    urls: &'a [String],
+}
+
+impl CdnBackend {
+    /// Parse the CDN configuration: `cloudfront:<distribution id>` or an http(s) URL.
+    pub fn parse(value: &str, token: Option<String>) -> Option<CdnBackend> {
+        if let Some(distribution_id) = value.strip_prefix("cloudfront:") {
+            Some(CdnBackend::CloudFront {
+                // CloudFront is a global service managed from us-east-1.
+                client: CloudFrontClient::new(Region::UsEast1),
+                distribution_id: distribution_id.to_string(),
+            })
+        } else if value.starts_with("http://") || value.starts_with("https://") {
+            Some(CdnBackend::Http {
+                url: value.to_string(),
+                token,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl Cdn {
+    pub fn new(backend: Option<CdnBackend>) -> Cdn {
+        Cdn { backend }
+    }
+
+    /// Invalidate the cached copies of `urls`, logging failures.
+    pub async fn invalidate(&self, client: &Client, urls: Vec<String>) {
+        if urls.is_empty() {
+            return;
+        }
+
+        match &self.backend {
+            None => (),
+            Some(CdnBackend::CloudFront {
+                client,
+                distribution_id,
+            }) => {
+                let paths: Vec<String> = urls.iter().map(|url| path_of(url).to_string()).collect();
+                let request = CreateInvalidationRequest {
+                    distribution_id: distribution_id.clone(),
+                    invalidation_batch: InvalidationBatch {
+                        caller_reference: format!("{}", Utc::now().timestamp_nanos()),
+                        paths: Paths {
+                            quantity: paths.len() as i64,
+                            items: Some(paths),
+                        },
+                    },
+                };
+                match client.create_invalidation(request).await {
+                    Ok(_) => info!("Invalidated {} paths on CloudFront", urls.len()),
+                    Err(e) => error!("Failed to create CloudFront invalidation: {}", e),
+                }
+            }
+            Some(CdnBackend::Http { url, token }) => {
+                let mut request = client.post(url);
+                if let Some(token) = token {
+                    request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+                }
+                match request.send_json(&PurgeRequest { urls: &urls }).await {
+                    Ok(resp) if resp.status().is_success() => (),
+                    Ok(resp) => error!("CDN purge of {:?} returned {}", urls, resp.status()),
+                    Err(e) => error!("CDN purge of {:?} failed: {}", urls, e),
+                }
+            }
+        }
+    }
+}
+
+/// The path portion of a URL, which is what CloudFront invalidates.
+fn path_of(url: &str) -> &str {
+    let rest = url.splitn(2, "://").nth(1).unwrap_or(url);
+    rest.find('/').map_or("/", |i| &rest[i..])
+}
@@ -0,0 +1,412 @@
+use actix_web::HttpResponse;
+use derive_more::Display;
+use log::warn;
+
+use crate::geo;
+use crate::media;
+use crate::metadata;
+use crate::micropub::MicropubError;
+use crate::SiteConfig;
+
+/// Prefixes which can't hold uploads, because they're used for something else
+/// or collide with a serving route. Hidden prefixes, including the logs', are
+/// reserved too.
+const RESERVED_PREFIXES: &[&str] = &[metadata::SIDECAR_PREFIX, "info", "page"];
+
+/// The prefixes used when no rules are configured.
+const DEFAULT_PREFIXES: &[&str] = &["photo", "audio", "video", "map", "file"];
+
+/// How an upload's key and URL are formed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UrlStyle {
+    /// `<prefix>/<id>.<ext>`, served as stored.
+    Extension,
+    /// `<prefix>/<id>/<filename>`, keeping the uploaded name.
+    Filename,
+    /// `photo/<id>.<ext>`, linked at the default size. Only photos are resized.
+    Resized,
+}
+
+/// What kind of media an upload is, and where it goes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Classification {
+    /// The media type recorded in the index and events, e.g. `photo`.
+    pub name: String,
+    /// The first part of the key.
+    pub prefix: String,
+    pub style: UrlStyle,
+}
+
+enum Pattern {
+    /// A filename extension, e.g. `.gpx`.
+    Extension(String),
+    /// A MIME type, or a `type/*` or `*` wildcard.
+    Mime(String),
+}
+
+struct Rule {
+    pattern: Pattern,
+    classification: Classification,
+}
+
+/// What uploads of one classification may be.
+struct Limits {
+    name: String,
+    max_bytes: Option<u64>,
+    /// MIME types or `type/*` wildcards. Empty allows any type.
+    types: Vec<String>,
+}
+
+/// Why an upload isn't accepted for its classification.
+#[derive(Display, Debug)]
+pub enum Restriction {
+    #[display(fmt = "{} uploads may be at most {} bytes", _0, _1)]
+    TooLarge(String, u64),
+    #[display(fmt = "{} uploads may not be {}", _0, _1)]
+    UnsupportedType(String, String),
+}
+
+impl UrlStyle {
+    fn parse(value: &str) -> Option<UrlStyle> {
+        match value {
+            "extension" => Some(UrlStyle::Extension),
+            "filename" => Some(UrlStyle::Filename),
+            "resized" => Some(UrlStyle::Resized),
+            _ => None,
+        }
+    }
+
+    /// The style used when a rule doesn't give one.
+    fn default_for(name: &str) -> UrlStyle {
+        match name {
+            "photo" => UrlStyle::Resized,
+            "audio" | "video" | "map" => UrlStyle::Extension,
+            _ => UrlStyle::Filename,
+        }
+    }
+}
+
+impl Classification {
+    fn new(name: &str, prefix: Option<&str>, style: Option<UrlStyle>) -> Classification {
+        Classification {
+            name: name.to_string(),
+            prefix: prefix.unwrap_or(name).to_string(),
+            style: style.unwrap_or_else(|| UrlStyle::default_for(name)),
+        }
+    }
+}
+
+impl Pattern {
+    fn matches(&self, content_type: &mime::Mime, filename: Option<&str>) -> bool {
+        match self {
+            Pattern::Extension(ext) => filename
+                .and_then(|f| f.rsplit('.').next().filter(|e| *e != f))
+                .map_or(false, |e| e.eq_ignore_ascii_case(ext)),
+            Pattern::Mime(pattern) => mime_matches(pattern, content_type),
+        }
+    }
+}
+
+impl Restriction {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            Restriction::TooLarge(..) => HttpResponse::PayloadTooLarge()
+                .json(MicropubError::with_description("too_large", self)),
+            Restriction::UnsupportedType(..) => HttpResponse::UnsupportedMediaType().json(
+                MicropubError::with_description("unsupported_media_type", self),
+            ),
+        }
+    }
+}
+
+/// Whether `content_type` is the MIME type `pattern`, or matches it as a
+/// `type/*` or `*` wildcard.
+fn mime_matches(pattern: &str, content_type: &mime::Mime) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.strip_suffix("/*") {
+        Some(type_) => content_type.type_().as_str().eq_ignore_ascii_case(type_),
+        None => content_type.essence_str().eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Parse CLASSIFICATION_RULES, e.g.
+/// `image/svg+xml=file, .gpx=map prefix:maps style:filename`.
+///
+/// Each rule matches a MIME type, wildcard, or extension and names the
+/// classification, optionally followed by its key prefix and URL style.
+/// Invalid rules are logged and skipped.
+fn rules(site: &SiteConfig) -> Vec<Rule> {
+    site.classification_rules()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let rule = parse_rule(site, entry);
+            if rule.is_none() {
+                warn!("Ignoring invalid classification rule {:?}", entry);
+            }
+            rule
+        })
+        .collect()
+}
+
+fn parse_rule(site: &SiteConfig, entry: &str) -> Option<Rule> {
+    let mut parts = entry.splitn(2, '=');
+    let pattern = parts.next()?.trim();
+    let mut words = parts.next()?.split_whitespace();
+    let name = words.next()?;
+
+    let mut prefix = None;
+    let mut style = None;
+    for word in words {
+        if let Some(value) = word.strip_prefix("prefix:") {
+            prefix = Some(value);
+        } else if let Some(value) = word.strip_prefix("style:") {
+            style = Some(UrlStyle::parse(value)?);
+        } else {
+            return None;
+        }
+    }
+
+    let classification = Classification::new(name, prefix, style);
+    let valid_segment = |s: &str| !s.is_empty() && !s.contains('/');
+    if !valid_segment(&classification.name)
+        || !valid_segment(&classification.prefix)
+        || is_reserved(site, &classification.prefix)
+        // Only the photo routes resize.
+        || (classification.style == UrlStyle::Resized && classification.prefix != "photo")
+    {
+        return None;
+    }
+
+    let pattern = match pattern.strip_prefix('.') {
+        Some(ext) if !ext.is_empty() => Pattern::Extension(ext.to_string()),
+        Some(_) => return None,
+        None if pattern == "*" || pattern.contains('/') => Pattern::Mime(pattern.to_string()),
+        None => return None,
+    };
+
+    Some(Rule {
+        pattern,
+        classification,
+    })
+}
+
+/// Whether uploads can't be given the prefix. Derivatives are stored under
+/// the first segment of DERIVATIVE_PREFIX.
+fn is_reserved(site: &SiteConfig, prefix: &str) -> bool {
+    RESERVED_PREFIXES.contains(&prefix)
+        || media::DERIVATIVE_PREFIX.split('/').next() == Some(prefix)
+        || media::is_hidden(site, prefix)
+}
+
+/// Classify an upload by the first matching rule, falling back to `map` for
+/// GPS traces and then its MIME top-level type.
+pub fn classify(
+    site: &SiteConfig,
+    content_type: &mime::Mime,
+    filename: Option<&str>,
+) -> Classification {
+    if let Some(rule) = rules(site)
+        .into_iter()
+        .find(|rule| rule.pattern.matches(content_type, filename))
+    {
+        return rule.classification;
+    }
+    if geo::is_trace(content_type, filename) {
+        return Classification::new("map", None, None);
+    }
+
+    let name = match content_type.type_() {
+        mime::IMAGE => "photo",
+        mime::AUDIO => "audio",
+        mime::VIDEO => "video",
+        _ => "file",
+    };
+    Classification::new(name, None, None)
+}
+
+/// Every prefix uploads may be stored under.
+pub fn prefixes(site: &SiteConfig) -> Vec<String> {
+    let mut prefixes: Vec<String> = DEFAULT_PREFIXES.iter().map(|p| p.to_string()).collect();
+    for rule in rules(site) {
+        if !prefixes.contains(&rule.classification.prefix) {
+            prefixes.push(rule.classification.prefix);
+        }
+    }
+    prefixes
+}
+
+/// The name of every classification uploads may have.
+pub fn names(site: &SiteConfig) -> Vec<String> {
+    let mut names: Vec<String> = DEFAULT_PREFIXES.iter().map(|p| p.to_string()).collect();
+    for rule in rules(site) {
+        if !names.contains(&rule.classification.name) {
+            names.push(rule.classification.name);
+        }
+    }
+    names
+}
+
+/// Parse CLASSIFICATION_LIMITS, e.g.
+/// `photo=max:31457280 type:image/jpeg type:image/png, video=max:2147483648`.
+///
+/// Each entry names a classification, followed by the most bytes an upload
+/// may have and the MIME types or wildcards it may be. Invalid entries are
+/// logged and skipped.
+fn limits(site: &SiteConfig) -> Vec<Limits> {
+    site.classification_limits()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let limits = parse_limits(entry);
+            if limits.is_none() {
+                warn!("Ignoring invalid classification limits {:?}", entry);
+            }
+            limits
+        })
+        .collect()
+}
+
+fn parse_limits(entry: &str) -> Option<Limits> {
+    let mut parts = entry.splitn(2, '=');
+    let name = parts.next()?.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut limits = Limits {
+        name: name.to_string(),
+        max_bytes: None,
+        types: Vec::new(),
+    };
+    for word in parts.next()?.split_whitespace() {
+        if let Some(value) = word.strip_prefix("max:") {
+            limits.max_bytes = Some(value.parse().ok()?);
+        } else if let Some(value) = word.strip_prefix("type:") {
+            if value != "*" && !value.contains('/') {
+                return None;
+            }
+            limits.types.push(value.to_string());
+        } else {
+            return None;
+        }
+    }
+    Some(limits)
+}
+
+fn limits_for(site: &SiteConfig, classification: &str) -> Option<Limits> {
+    limits(site)
+        .into_iter()
+        .find(|limits| limits.name == classification)
+}
+
+/// The most bytes an upload of the named classification may have.
+pub fn max_bytes(site: &SiteConfig, classification: &str) -> Option<u64> {
+    limits_for(site, classification).and_then(|limits| limits.max_bytes)
+}
+
+/// The MIME types or wildcards uploads of the named classification may be.
+/// Empty allows any type.
+pub fn accepted_types(site: &SiteConfig, classification: &str) -> Vec<String> {
+    limits_for(site, classification).map_or_else(Vec::new, |limits| limits.types)
+}
+
+/// Check an upload against the limits for its classification. The size is
+/// None when it isn't known yet.
+pub fn check_limits(
+    site: &SiteConfig,
+    classification: &str,
+    content_type: &mime::Mime,
+    size: Option<u64>,
+) -> Result<(), Restriction> {
+    let limits = match limits_for(site, classification) {
+        Some(limits) => limits,
+        None => return Ok(()),
+    };
+
+    if !limits.types.is_empty()
+        && !limits
+            .types
+            .iter()
+            .any(|pattern| mime_matches(pattern, content_type))
+    {
+        return Err(Restriction::UnsupportedType(
+            limits.name,
+            content_type.essence_str().to_string(),
+        ));
+    }
+    match (size, limits.max_bytes) {
+        (Some(size), Some(max_bytes)) if size > max_bytes => {
+            Err(Restriction::TooLarge(limits.name, max_bytes))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rule, UrlStyle};
+    use crate::SiteConfig;
+
+    fn site() -> SiteConfig {
+        SiteConfig::new("media", "http://localhost/media", "http://localhost/token")
+    }
+
+    #[test]
+    fn rules_name_a_classification_with_an_optional_prefix_and_style() {
+        let rule = parse_rule(&site(), ".gpx = map prefix:tracks style:filename").unwrap();
+        assert!(rule.pattern.matches(&mime::TEXT_XML, Some("ride.GPX")));
+        assert!(!rule.pattern.matches(&mime::TEXT_XML, Some("gpx")));
+        assert_eq!(rule.classification.name, "map");
+        assert_eq!(rule.classification.prefix, "tracks");
+        assert_eq!(rule.classification.style, UrlStyle::Filename);
+
+        let rule = parse_rule(&site(), "image/svg+xml=file").unwrap();
+        let svg = "image/svg+xml".parse().unwrap();
+        assert!(rule.pattern.matches(&svg, None));
+        assert_eq!(rule.classification.prefix, "file");
+    }
+
+    #[test]
+    fn malformed_rules_are_refused() {
+        for entry in &[
+            "image/png",
+            "png=photo",
+            ".=file",
+            "*=",
+            "*=photo style:square",
+            "*=photo size:big",
+            "*=file prefix:a/b",
+        ] {
+            assert!(parse_rule(&site(), entry).is_none(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn reserved_prefixes_are_refused() {
+        for prefix in &[
+            "trash",
+            "quarantine",
+            "originals",
+            "shares",
+            "staging",
+            "meta",
+            "derivatives",
+            "info",
+            "page",
+        ] {
+            let entry = format!("*=file prefix:{}", prefix);
+            assert!(parse_rule(&site(), &entry).is_none(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn only_photos_are_resized() {
+        assert!(parse_rule(&site(), "image/*=picture style:resized").is_none());
+        assert!(parse_rule(&site(), "image/*=photo style:resized").is_some());
+    }
+}
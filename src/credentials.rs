@@ -0,0 +1,73 @@
+use hyper::Client;
+use hyper_tls::HttpsConnector;
+use rusoto_core::credential::{AutoRefreshingProvider, DefaultCredentialsProvider};
+use rusoto_core::{HttpClient, Region};
+use rusoto_s3::S3Client;
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+
+use std::time::Duration;
+
+use crate::limits::{LimitedDispatcher, S3Limiter};
+
+/// A role to assume before talking to S3, e.g. to reach a bucket in another account.
+#[derive(Clone)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    pub external_id: Option<String>,
+    pub session_name: String,
+    pub session_duration: Option<Duration>,
+}
+
+/// Connection settings shared by every S3 client.
+#[derive(Clone, Default)]
+pub struct S3Connections {
+    pub limiter: S3Limiter,
+    /// Idle connections kept open to each host, or None for no limit.
+    pub pool_size: Option<usize>,
+}
+
+/// Create an S3 client for `region`.
+///
+/// Without a role, credentials come from the default provider chain. With
+/// one, the default chain is used to call STS and the temporary credentials
+/// are refreshed shortly before they expire.
+pub fn s3_client(
+    region: Region,
+    role: Option<&AssumeRole>,
+    connections: &S3Connections,
+) -> S3Client {
+    let mut builder = Client::builder();
+    if let Some(pool_size) = connections.pool_size {
+        builder.pool_max_idle_per_host(pool_size);
+    }
+    let http_client = HttpClient::from_builder(builder, HttpsConnector::new());
+    let dispatcher = LimitedDispatcher::new(http_client, connections.limiter.clone());
+
+    match role {
+        Some(role) => S3Client::new_with(dispatcher, role_provider(region.clone(), role), region),
+        None => {
+            let provider =
+                DefaultCredentialsProvider::new().expect("Failed to create credentials provider");
+            S3Client::new_with(dispatcher, provider, region)
+        }
+    }
+}
+
+/// Temporary credentials for `role`, from STS, refreshed shortly before they
+/// expire.
+pub fn role_provider(
+    region: Region,
+    role: &AssumeRole,
+) -> AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider> {
+    let sts = StsClient::new(region);
+    let provider = StsAssumeRoleSessionCredentialsProvider::new(
+        sts,
+        role.role_arn.clone(),
+        role.session_name.clone(),
+        role.external_id.clone(),
+        role.session_duration,
+        None,
+        None,
+    );
+    AutoRefreshingProvider::new(provider).expect("Failed to create STS provider")
+}
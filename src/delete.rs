@@ -0,0 +1,123 @@
+use actix_web::client::Client;
+use actix_web::HttpResponse;
+
+use log::error;
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{DeleteObjectRequest, HeadObjectRequest, S3Client, S3};
+
+use std::collections::HashMap;
+
+use crate::cdn::Cdn;
+use crate::disk_cache::DiskCache;
+use crate::events;
+use crate::index::MediaIndex;
+use crate::metadata;
+use crate::micropub::{forget_deleted, key_for_url, MicropubError};
+use crate::oauth;
+use crate::originals;
+use crate::trash;
+use crate::SiteConfig;
+
+/// Whether the object with `metadata` was uploaded by the token's owner.
+///
+/// Objects without an author, e.g. ones stored by other tools, belong to no
+/// one, so they can't be deleted or restored with a token.
+pub(crate) fn is_author(
+    metadata: Option<&HashMap<String, String>>,
+    access_token: &oauth::AccessToken,
+) -> bool {
+    metadata
+        .and_then(|metadata| metadata.get("author"))
+        .map(String::as_str)
+        == Some(access_token.me())
+}
+
+/// Delete the object at `url`, provided it belongs to the token's owner.
+///
+/// Objects are moved to the trash unless TRASH_DAYS is 0.
+pub(crate) async fn delete_url(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    cdn: &Cdn,
+    disk_cache: Option<&DiskCache>,
+    publisher: &events::Publisher,
+    index: Option<&MediaIndex>,
+    access_token: &oauth::AccessToken,
+    url: &str,
+) -> HttpResponse {
+    let key = match key_for_url(site, url) {
+        Some(key) => key,
+        None => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "Unknown URL",
+            ))
+        }
+    };
+
+    let (bucket, object_key) = site.locate(&key);
+    let head = match s3_client
+        .head_object(HeadObjectRequest {
+            bucket: bucket.clone(),
+            key: object_key.clone(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(head) => head,
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => {
+            return HttpResponse::NotFound().json(MicropubError::new("not_found"))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    if !is_author(head.metadata.as_ref(), access_token) {
+        return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+    }
+
+    if site.trash_days() > 0 {
+        if let Err(e) = trash::move_to_trash(site, s3_client, &key, &head).await {
+            return HttpResponse::InternalServerError().body(e);
+        }
+    } else {
+        let delete_request = DeleteObjectRequest {
+            bucket,
+            key: object_key,
+            ..Default::default()
+        };
+        if let Err(e) = s3_client.delete_object(delete_request).await {
+            return HttpResponse::InternalServerError().body(format!("{}", e));
+        }
+
+        if site.metadata_sidecar() {
+            let sidecar_request = DeleteObjectRequest {
+                bucket: site.s3_bucket().to_owned(),
+                key: metadata::sidecar_key(&key),
+                ..Default::default()
+            };
+            if let Err(e) = s3_client.delete_object(sidecar_request).await {
+                error!("Failed to delete sidecar for {}: {}", key, e);
+            }
+        }
+        originals::delete(site, s3_client, &key).await;
+    }
+
+    forget_deleted(
+        site,
+        s3_client,
+        http_client,
+        cdn,
+        disk_cache,
+        publisher,
+        index,
+        access_token,
+        url,
+        key,
+        head,
+    )
+    .await;
+
+    HttpResponse::NoContent().finish()
+}
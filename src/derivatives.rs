@@ -0,0 +1,447 @@
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    CopyObjectRequest, DeleteObjectRequest, GetObjectRequest, HeadObjectRequest,
+    ListObjectsV2Error, ListObjectsV2Request, Object, S3Client, S3,
+};
+use tokio::io::AsyncReadExt;
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::cdn::Cdn;
+use crate::disk_cache::DiskCache;
+use crate::media::{self, DERIVATIVE_PREFIX};
+use crate::micropub::{authorize, MicropubError};
+use crate::oauth;
+use crate::policy::Permission;
+use crate::trash;
+use crate::SiteConfig;
+
+/// How often the background sweep looks for stale derivatives.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/admin/purge").route(web::post().to(handle_purge)));
+    cfg.service(web::resource("/admin/backfill").route(web::post().to(handle_backfill)));
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    /// Either a photo (`photo/abc.jpg`) or a single derivative key.
+    key: String,
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    purged: usize,
+}
+
+/// Delete cached derivatives of a photo.
+async fn handle_purge(
+    req: HttpRequest,
+    query: web::Query<PurgeQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    cdn: web::Data<Cdn>,
+    disk_cache: Option<web::Data<DiskCache>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+
+    if let Some(disk_cache) = &disk_cache {
+        if query.key.starts_with(DERIVATIVE_PREFIX) {
+            disk_cache.remove(&format!("{}/{}", site.s3_bucket(), query.key));
+        } else if let Some(filename) = query.key.strip_prefix("photo/") {
+            disk_cache.purge(site.s3_bucket(), filename);
+        }
+    }
+
+    let result = if query.key.starts_with(DERIVATIVE_PREFIX) {
+        delete(&s3_client, site.s3_bucket(), &query.key)
+            .await
+            .map(|_| vec![query.key.clone()])
+    } else if let Some(filename) = query.key.strip_prefix("photo/") {
+        purge(&s3_client, site.s3_bucket(), filename).await
+    } else {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "Only photos have derivatives",
+        ));
+    };
+
+    match result {
+        Ok(purged) => {
+            let urls = purged.iter().filter_map(|key| url_for(&site, key)).collect();
+            cdn.invalidate(&http_client, urls).await;
+            HttpResponse::Ok().json(PurgeResponse {
+                purged: purged.len(),
+            })
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+/// What a backfill did.
+#[derive(Serialize, Default)]
+pub struct BackfillReport {
+    /// Photos which were missing a size.
+    pub photos: usize,
+    /// Derivatives generated.
+    pub generated: usize,
+    /// Photos which couldn't be resized.
+    pub failed: usize,
+}
+
+/// Generate the missing derivatives of every photo.
+async fn handle_backfill(
+    req: HttpRequest,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+
+    if !site.derivative_cache() {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "Derivatives aren't cached",
+        ));
+    }
+
+    match backfill(&site, &s3_client).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+/// Generate the default size and WARM_WIDTHS of every photo which doesn't
+/// have them cached, e.g. after the default size changes.
+pub async fn backfill(site: &SiteConfig, s3_client: &S3Client) -> Result<BackfillReport, String> {
+    let cached: HashSet<String> = list(s3_client, site.s3_bucket())
+        .await
+        .map_err(|e| format!("Failed to list derivatives: {}", e))?
+        .into_iter()
+        .filter_map(|object| object.key)
+        .collect();
+    let sizes = media::warm_sizes(site);
+
+    let mut report = BackfillReport::default();
+    let (bucket, prefix) = site.locate("photo/");
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.clone(),
+            prefix: Some(prefix.clone()),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = s3_client
+            .list_objects_v2(request)
+            .await
+            .map_err(|e| format!("Failed to list {}: {}", prefix, e))?;
+
+        for object in response.contents.unwrap_or_default() {
+            let key = match object.key {
+                Some(key) => key,
+                None => continue,
+            };
+            let filename = &key[prefix.len()..];
+            let missing: Vec<(u32, u32)> = sizes
+                .iter()
+                .copied()
+                .filter(|(width, height)| {
+                    !cached.contains(&media::derivative_key(site, *width, *height, filename))
+                })
+                .collect();
+            if filename.is_empty() || missing.is_empty() {
+                continue;
+            }
+
+            report.photos += 1;
+            match generate_missing(site, s3_client, &bucket, &key, filename, missing).await {
+                Ok(generated) => report.generated += generated,
+                Err(e) => {
+                    error!("Failed to backfill {}: {}", key, e);
+                    report.failed += 1;
+                }
+            }
+        }
+
+        if response.is_truncated != Some(true) {
+            info!(
+                "Generated {} derivatives of {} photos",
+                report.generated, report.photos
+            );
+            return Ok(report);
+        }
+        continuation_token = response.next_continuation_token;
+    }
+}
+
+async fn generate_missing(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    filename: &str,
+    sizes: Vec<(u32, u32)>,
+) -> Result<usize, String> {
+    let request = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    let resp = s3_client
+        .get_object(request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut data = Vec::new();
+    resp.body
+        .ok_or_else(|| "Empty object".to_string())?
+        .into_async_read()
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let source = media::Source {
+        filename,
+        e_tag: resp.e_tag.as_deref().unwrap_or_default(),
+        last_modified: resp.last_modified.as_deref(),
+    };
+    media::generate(site, s3_client, &source, data, sizes).await
+}
+
+/// Delete every cached size of the photo `filename`, returning the deleted keys.
+///
+/// Only the photo's key is listed under each size, rather than every
+/// derivative.
+pub async fn purge(
+    s3_client: &S3Client,
+    bucket: &str,
+    filename: &str,
+) -> Result<Vec<String>, String> {
+    let mut purged = Vec::new();
+    for size_prefix in size_prefixes(s3_client, bucket).await? {
+        let key = format!("{}{}", size_prefix, filename);
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(key.clone()),
+            max_keys: Some(1),
+            ..Default::default()
+        };
+        let response = s3_client
+            .list_objects_v2(request)
+            .await
+            .map_err(|e| format!("Failed to list {}: {}", key, e))?;
+        let exists = response
+            .contents
+            .unwrap_or_default()
+            .iter()
+            .any(|object| object.key.as_deref() == Some(key.as_str()));
+        if exists {
+            delete(s3_client, bucket, &key).await?;
+            purged.push(key);
+        }
+    }
+    Ok(purged)
+}
+
+/// The prefix of each size derivatives are cached at, e.g.
+/// `derivatives/photo/1000x0/`.
+async fn size_prefixes(s3_client: &S3Client, bucket: &str) -> Result<Vec<String>, String> {
+    let mut prefixes = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(format!("{}/", DERIVATIVE_PREFIX)),
+            delimiter: Some("/".to_string()),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = s3_client
+            .list_objects_v2(request)
+            .await
+            .map_err(|e| format!("Failed to list {}: {}", DERIVATIVE_PREFIX, e))?;
+        prefixes.extend(
+            response
+                .common_prefixes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|prefix| prefix.prefix),
+        );
+
+        if response.is_truncated != Some(true) {
+            return Ok(prefixes);
+        }
+        continuation_token = response.next_continuation_token;
+    }
+}
+
+/// The public URL a derivative was served from.
+pub fn url_for(site: &SiteConfig, key: &str) -> Option<String> {
+    let rest = key.strip_prefix(DERIVATIVE_PREFIX)?.trim_start_matches('/');
+    let mut parts = rest.splitn(2, '/');
+    let size = parts.next()?;
+    let filename = parts.next()?;
+    // Enlarged derivatives are requested with ?upscale, which is not part of the path.
+    let size = size.splitn(2, "-up").next()?;
+    Some(format!("{}/photo/{}/{}", site.media_url(), size, filename))
+}
+
+/// Delete derivatives whose original is gone or which haven't been served in `max_idle`.
+pub async fn sweep(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+) -> Result<usize, RusotoError<ListObjectsV2Error>> {
+    let bucket = site.s3_bucket();
+    let cutoff = site
+        .derivative_max_idle().and_then(|d| ChronoDuration::from_std(d).ok()).map(|d| Utc::now() - d);
+    let mut sources: HashMap<String, bool> = HashMap::new();
+    let mut swept = 0;
+
+    for object in list(s3_client, bucket).await? {
+        let key = match object.key {
+            Some(key) => key,
+            None => continue,
+        };
+
+        let idle = match (cutoff, object.last_modified.as_deref()) {
+            (Some(cutoff), Some(last_modified)) => DateTime::parse_from_rfc3339(last_modified)
+                .map(|t| t < cutoff)
+                .unwrap_or(false),
+            _ => false,
+        };
+
+        // Keys look like derivatives/photo/{size}/{filename}.
+        let filename = key
+            .strip_prefix(DERIVATIVE_PREFIX)
+            .and_then(|rest| rest.trim_start_matches('/').splitn(2, '/').nth(1))
+            .unwrap_or_default()
+            .to_string();
+        let orphaned = if idle {
+            false
+        } else {
+            if !sources.contains_key(&filename) {
+                let exists = source_exists(site, s3_client, &filename).await;
+                sources.insert(filename.clone(), exists);
+            }
+            !sources[&filename]
+        };
+
+        if idle || orphaned {
+            match delete(s3_client, bucket, &key).await {
+                Ok(()) => swept += 1,
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+
+    Ok(swept)
+}
+
+/// Periodically remove stale derivatives.
+pub async fn sweep_forever(site: SiteConfig, s3_client: S3Client) {
+    loop {
+        delay_for(SWEEP_INTERVAL).await;
+        match sweep(&site, &s3_client).await {
+            Ok(swept) => info!("Removed {} stale derivatives", swept),
+            Err(e) => error!("Failed to list derivatives: {}", e),
+        }
+    }
+}
+
+/// Reset a derivative's last-modified time so the sweep knows it's still in use.
+///
+/// S3 doesn't record when an object was last read, so serving a derivative copies
+/// it onto itself once it's halfway to expiring.
+pub async fn touch(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: String,
+    content_type: Option<String>,
+    metadata: HashMap<String, String>,
+) {
+    let defaults = site.put_object_request();
+    let request = CopyObjectRequest {
+        bucket: defaults.bucket.clone(),
+        copy_source: trash::copy_source(&defaults.bucket, &key),
+        key: key.clone(),
+        content_type,
+        metadata: Some(metadata),
+        metadata_directive: Some("REPLACE".to_string()),
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        acl: defaults.acl,
+        ..Default::default()
+    };
+    if let Err(e) = s3_client.copy_object(request).await {
+        error!("Failed to touch derivative {}: {}", key, e);
+    }
+}
+
+async fn list(
+    s3_client: &S3Client,
+    bucket: &str,
+) -> Result<Vec<Object>, RusotoError<ListObjectsV2Error>> {
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(format!("{}/", DERIVATIVE_PREFIX)),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = s3_client.list_objects_v2(request).await?;
+        objects.extend(response.contents.unwrap_or_default());
+
+        if response.is_truncated != Some(true) {
+            return Ok(objects);
+        }
+        continuation_token = response.next_continuation_token;
+    }
+}
+
+async fn source_exists(site: &SiteConfig, s3_client: &S3Client, filename: &str) -> bool {
+    let (bucket, key) = site.locate(&format!("photo/{}", filename));
+    let request = HeadObjectRequest {
+        bucket,
+        key,
+        ..Default::default()
+    };
+    match s3_client.head_object(request).await {
+        Ok(_) => true,
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => false,
+        Err(RusotoError::Service(_)) => false,
+        // Keep the derivative if we can't tell.
+        Err(_) => true,
+    }
+}
+
+async fn delete(s3_client: &S3Client, bucket: &str, key: &str) -> Result<(), String> {
+    let request = DeleteObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    s3_client
+        .delete_object(request)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to delete {}: {}", key, e))
+}
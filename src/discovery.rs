@@ -0,0 +1,116 @@
+use actix_web::http::header;
+use actix_web::middleware::DefaultHeaders;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+use crate::classify;
+use crate::SiteConfig;
+
+const DISCOVERY_PATH: &str = "/.well-known/micropub-media";
+
+/// Actions the media endpoint takes with `?action=`.
+const ACTIONS: &[&str] = &["delete", "undo"];
+
+/// Read-only resources alongside the media endpoint, by name.
+const QUERIES: &[(&str, &str)] = &[
+    ("list", "/micropub/media/list"),
+    ("search", "/micropub/media/search"),
+    ("stats", "/micropub/media/stats"),
+    ("versions", "/micropub/media/versions"),
+    ("original", "/micropub/media/original"),
+];
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource(DISCOVERY_PATH).route(web::get().to(handle_discovery)));
+}
+
+/// Points clients at the discovery document from responses of the media
+/// endpoint. The link is relative, so it works wherever the endpoint is
+/// mounted.
+pub fn link() -> DefaultHeaders {
+    DefaultHeaders::new().header(
+        header::LINK,
+        format!("<..{}>; rel=\"describedby\"", DISCOVERY_PATH),
+    )
+}
+
+#[derive(Serialize)]
+struct Discovery {
+    media_endpoint: String,
+    /// The most bytes any upload may have, when every classification has a
+    /// limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_upload_size: Option<u64>,
+    /// Types any upload may be, or `*/*`.
+    accepted_types: Vec<String>,
+    classifications: BTreeMap<String, Accepted>,
+    max_part_size: usize,
+    max_sideload_size: usize,
+    /// Days an upload may ask to be deleted after with `expires-in`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    expires_in: Vec<u32>,
+    actions: &'static [&'static str],
+    queries: BTreeMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct Accepted {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_upload_size: Option<u64>,
+    accepted_types: Vec<String>,
+}
+
+/// Describe what the media endpoint accepts and what else it can do.
+async fn handle_discovery(req: HttpRequest, site: web::Data<SiteConfig>) -> HttpResponse {
+    // The endpoint may be mounted under a prefix.
+    let base = req.path().trim_end_matches(DISCOVERY_PATH);
+
+    let classifications: BTreeMap<String, Accepted> = classify::names(&site)
+        .into_iter()
+        .map(|name| {
+            let mut accepted_types = classify::accepted_types(&site, &name);
+            if accepted_types.is_empty() {
+                accepted_types.push("*/*".to_string());
+            }
+            let accepted = Accepted {
+                max_upload_size: classify::max_bytes(&site, &name),
+                accepted_types,
+            };
+            (name, accepted)
+        })
+        .collect();
+
+    let max_upload_size = classifications
+        .values()
+        .map(|accepted| accepted.max_upload_size)
+        .collect::<Option<Vec<u64>>>()
+        .and_then(|sizes| sizes.into_iter().max());
+    let mut accepted_types: Vec<String> = Vec::new();
+    for accepted in classifications.values() {
+        for t in &accepted.accepted_types {
+            if !accepted_types.contains(t) {
+                accepted_types.push(t.clone());
+            }
+        }
+    }
+    if accepted_types.iter().any(|t| t == "*/*") {
+        accepted_types = vec!["*/*".to_string()];
+    }
+
+    HttpResponse::Ok().json(Discovery {
+        media_endpoint: format!("{}/micropub/media", base),
+        max_upload_size,
+        accepted_types,
+        classifications,
+        max_part_size: site.session_part_max_bytes(),
+        max_sideload_size: site.sideload_max_bytes(),
+        expires_in: site.expiry_days().collect(),
+        actions: ACTIONS,
+        queries: QUERIES
+            .iter()
+            .map(|(name, path)| (*name, format!("{}{}", base, path)))
+            .collect(),
+    })
+}
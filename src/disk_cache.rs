@@ -0,0 +1,305 @@
+use actix_web::web;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::events::checksum;
+use crate::micropub::random_id;
+
+/// Extension of files still being written.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Resized photos kept on local disk, for single-node deployments that don't
+/// store derivatives in S3.
+///
+/// Each derivative is one file, named for a hash of its key, holding a line
+/// of JSON headers followed by the image. Files are written under a temporary
+/// name and renamed into place, so a reader never sees half a file. The least
+/// recently used files are removed once the directory grows past its limit.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<Entries>,
+}
+
+/// What's known about a cached derivative, besides the image itself.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Cached {
+    /// The bucket and key of the derivative, since tenants share the cache,
+    /// e.g. `media/derivatives/photo/1000x0/abc.jpg`.
+    pub key: String,
+    pub content_type: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Default)]
+struct Entries {
+    files: HashMap<String, Entry>,
+    /// File names by when they were last used.
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+    total_bytes: u64,
+}
+
+struct Entry {
+    key: String,
+    size: u64,
+    used: u64,
+}
+
+impl DiskCache {
+    /// Use `dir` for the cache, picking up whatever's already there.
+    pub fn open<P: Into<PathBuf>>(dir: P, max_bytes: u64) -> io::Result<DiskCache> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut found = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let path = dir_entry.path();
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(TEMP_SUFFIX) {
+                // Left behind by a crash.
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            let metadata = dir_entry.metadata()?;
+            match read_header(&path) {
+                Ok((cached, _)) => {
+                    found.push((metadata.modified()?, name, cached.key, metadata.len()))
+                }
+                Err(e) => {
+                    warn!("Removing unreadable cache file {}: {}", path.display(), e);
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        // Oldest first, so the most recently written are the last to go.
+        found.sort();
+        let mut entries = Entries::default();
+        for (_, name, key, size) in found {
+            entries.insert(name, key, size);
+        }
+        info!(
+            "Disk cache at {} holds {} derivatives ({} bytes)",
+            dir.display(),
+            entries.files.len(),
+            entries.total_bytes
+        );
+
+        let cache = DiskCache {
+            dir,
+            max_bytes,
+            entries: Mutex::new(entries),
+        };
+        let victims = cache.entries.lock().unwrap().evict(max_bytes);
+        cache.remove_files(victims);
+        Ok(cache)
+    }
+
+    /// The derivative with `key` and its image, if it's cached.
+    pub async fn get(&self, key: &str) -> Option<(Cached, Vec<u8>)> {
+        let name = file_name(key);
+        if !self.entries.lock().unwrap().touch(&name) {
+            return None;
+        }
+
+        let path = self.dir.join(&name);
+        match web::block(move || read_file(&path)).await {
+            Ok(found) => Some(found),
+            Err(e) => {
+                warn!("Failed to read {} from the disk cache: {}", key, e);
+                self.entries.lock().unwrap().remove(&name);
+                None
+            }
+        }
+    }
+
+    /// The derivative with `key` and the length of its image, without reading
+    /// the image.
+    pub async fn head(&self, key: &str) -> Option<(Cached, u64)> {
+        let name = file_name(key);
+        if !self.entries.lock().unwrap().touch(&name) {
+            return None;
+        }
+
+        let path = self.dir.join(&name);
+        match web::block(move || read_header(&path)).await {
+            Ok(found) => Some(found),
+            Err(e) => {
+                warn!("Failed to read {} from the disk cache: {}", key, e);
+                self.entries.lock().unwrap().remove(&name);
+                None
+            }
+        }
+    }
+
+    /// Cache a derivative, making room for it if needed.
+    pub async fn put(&self, cached: Cached, body: Vec<u8>) {
+        let name = file_name(&cached.key);
+        let key = cached.key.clone();
+        let path = self.dir.join(&name);
+        let temp = self
+            .dir
+            .join(format!(".{}.{}{}", name, random_id(), TEMP_SUFFIX));
+        let written = web::block(move || write_file(&temp, &path, &cached, &body)).await;
+
+        let size = match written {
+            Ok(size) => size,
+            Err(e) => {
+                error!("Failed to write {} to the disk cache: {}", key, e);
+                return;
+            }
+        };
+        let victims = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.insert(name, key, size);
+            entries.evict(self.max_bytes)
+        };
+        self.remove_files(victims);
+    }
+
+    /// Forget the derivative with `key`.
+    pub fn remove(&self, key: &str) {
+        let name = file_name(key);
+        if self.entries.lock().unwrap().remove(&name).is_some() {
+            self.remove_files(vec![name]);
+        }
+    }
+
+    /// Forget every cached size of the photo `filename` in `bucket`,
+    /// returning their keys.
+    pub fn purge(&self, bucket: &str, filename: &str) -> Vec<String> {
+        let prefix = format!("{}/", bucket);
+        let suffix = format!("/{}", filename);
+        let purged: Vec<(String, String)> = {
+            let mut entries = self.entries.lock().unwrap();
+            let names: Vec<String> = entries
+                .files
+                .iter()
+                .filter(|(_, entry)| entry.key.starts_with(&prefix) && entry.key.ends_with(&suffix))
+                .map(|(name, _)| name.clone())
+                .collect();
+            names
+                .into_iter()
+                .filter_map(|name| entries.remove(&name).map(|key| (name, key)))
+                .collect()
+        };
+        let (names, keys) = purged.into_iter().unzip();
+        self.remove_files(names);
+        keys
+    }
+
+    fn remove_files(&self, names: Vec<String>) {
+        for name in names {
+            if let Err(e) = fs::remove_file(self.dir.join(&name)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Failed to remove {} from the disk cache: {}", name, e);
+                }
+            }
+        }
+    }
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn insert(&mut self, name: String, key: String, size: u64) {
+        self.remove(&name);
+        let used = self.tick();
+        self.lru.insert(used, name.clone());
+        self.total_bytes += size;
+        self.files.insert(name, Entry { key, size, used });
+    }
+
+    /// Mark the file as just used, returning false if there's no such file.
+    fn touch(&mut self, name: &str) -> bool {
+        let used = self.tick();
+        match self.files.get_mut(name) {
+            Some(entry) => {
+                self.lru.remove(&entry.used);
+                entry.used = used;
+                self.lru.insert(used, name.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the file, returning its key.
+    fn remove(&mut self, name: &str) -> Option<String> {
+        let entry = self.files.remove(name)?;
+        self.lru.remove(&entry.used);
+        self.total_bytes -= entry.size;
+        Some(entry.key)
+    }
+
+    /// Forget the least recently used files until everything fits in
+    /// `max_bytes`, returning the names of the files to delete.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut victims = Vec::new();
+        while self.total_bytes > max_bytes {
+            let name = match self.lru.values().next() {
+                Some(name) => name.clone(),
+                None => break,
+            };
+            self.remove(&name);
+            victims.push(name);
+        }
+        victims
+    }
+}
+
+fn file_name(key: &str) -> String {
+    checksum(key.as_bytes())
+}
+
+/// Read the headers of a cached file, and the length of the image after them.
+fn read_header(path: &Path) -> io::Result<(Cached, u64)> {
+    let file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut line = String::new();
+    let header_length = BufReader::new(file).read_line(&mut line)? as u64;
+    let cached =
+        serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((cached, length.saturating_sub(header_length)))
+}
+
+fn read_file(path: &Path) -> io::Result<(Cached, Vec<u8>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let cached =
+        serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    Ok((cached, body))
+}
+
+/// Write the file under `temp` and move it to `path`, returning its size.
+fn write_file(temp: &Path, path: &Path, cached: &Cached, body: &[u8]) -> io::Result<u64> {
+    let result = (|| -> io::Result<u64> {
+        let mut file = File::create(temp)?;
+        let header = serde_json::to_vec(cached)?;
+        file.write_all(&header)?;
+        file.write_all(b"\n")?;
+        file.write_all(body)?;
+        file.sync_all()?;
+        fs::rename(temp, path)?;
+        Ok((header.len() + 1 + body.len()) as u64)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(temp);
+    }
+    result
+}
@@ -0,0 +1,186 @@
+use actix_web::client::Client;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::error;
+
+use rusoto_s3::S3Client;
+
+use serde::Deserialize;
+
+use std::collections::HashMap;
+
+use crate::alias;
+use crate::events;
+use crate::index::MediaIndex;
+use crate::keys;
+use crate::media;
+use crate::metadata;
+use crate::micropub::{
+    authorize, created, inspect_stored, key_for_url, record_upload, Inspected, MicropubError,
+    NewObject, Placement,
+};
+use crate::moderation::ModerationBackend;
+use crate::oauth;
+use crate::policy::Permission;
+use crate::quarantine;
+use crate::SiteConfig;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/duplicate").route(web::post().to(handle_duplicate)));
+}
+
+#[derive(Deserialize)]
+struct DuplicateRequest {
+    /// The URL of the object to copy.
+    url: String,
+}
+
+/// Copy an object to a new key of its own, without downloading it.
+///
+/// The copy keeps the original's type, filename, and metadata, but is
+/// otherwise unrelated: deleting or renaming one leaves the other alone.
+async fn handle_duplicate(
+    req: HttpRequest,
+    duplicate: web::Json<DuplicateRequest>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let key = match key_for_url(&site, &duplicate.url) {
+        Some(key) if !media::is_hidden(&site, key.split('/').next().unwrap_or_default()) => key,
+        _ => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "Unknown URL",
+            ))
+        }
+    };
+
+    let head = match alias::find(&site, &s3_client, &key).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+        Err(e) => return HttpResponse::InternalServerError().body(e),
+    };
+    let stored = metadata::decode_all(head.metadata.unwrap_or_default());
+    if let Some(author) = stored.get("author") {
+        if author != access_token.me() {
+            return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+        }
+    }
+    if head.website_redirect_location.is_some() {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "The URL has been renamed",
+        ));
+    }
+
+    let content_type: mime::Mime = head
+        .content_type
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let filename = stored.get("filename").cloned();
+    let checksum = stored.get("sha256").cloned();
+    let placement = match Placement::unused(
+        &site,
+        &s3_client,
+        &content_type,
+        filename.as_deref(),
+        access_token.me(),
+        checksum.as_deref(),
+        keys::date_for(&site, None),
+    )
+    .await
+    {
+        Ok(placement) => placement,
+        Err(e) => return e.response(),
+    };
+    let object_key = placement.object_key();
+
+    // The copy is published anew, so it's moderated like any other upload.
+    if ModerationBackend::for_upload(&site, &placement.classification).is_some() {
+        let size = head.content_length.unwrap_or_default() as u64;
+        let inspected = inspect_stored(
+            &site,
+            &s3_client,
+            &http_client,
+            &placement,
+            &content_type,
+            &key,
+            size,
+        );
+        match inspected.await {
+            Ok(Inspected {
+                rejection: None, ..
+            }) => (),
+            Ok(Inspected {
+                rejection: Some(reason),
+                ..
+            }) if site.quarantine() => {
+                let held = quarantine::HeldCopy {
+                    from: &key,
+                    size,
+                    key: &object_key,
+                    author: access_token.me(),
+                    reason: &reason,
+                    content_type: content_type.to_string(),
+                    metadata: stored
+                        .iter()
+                        .map(|(name, value)| (name.clone(), metadata::encode(value)))
+                        .collect(),
+                };
+                return quarantine::hold_copy(&site, &s3_client, held).await;
+            }
+            Ok(Inspected {
+                rejection: Some(reason),
+                ..
+            }) => return quarantine::rejected(&object_key, access_token.me(), &reason),
+            Err(resp) => return resp,
+        }
+    }
+
+    if let Err(e) = alias::copy(&site, &s3_client, &key, &object_key).await {
+        return HttpResponse::InternalServerError().body(e);
+    }
+    let mut extra_metadata: HashMap<String, String> = HashMap::new();
+    if site.metadata_sidecar() {
+        let sidecar = metadata::sidecar_key(&key);
+        let copied = alias::copy(
+            &site,
+            &s3_client,
+            &sidecar,
+            &metadata::sidecar_key(&object_key),
+        )
+        .await;
+        if let Err(e) = copied {
+            error!("Failed to copy sidecar for {}: {}", key, e);
+        }
+    } else {
+        for field in site.metadata_fields() {
+            if let Some(value) = stored.get(field) {
+                extra_metadata.insert(field.to_string(), value.clone());
+            }
+        }
+    }
+
+    let url = placement.url(&site);
+    let object = NewObject {
+        placement,
+        url: url.clone(),
+        content_type,
+        filename,
+        size: head.content_length.unwrap_or_default() as u64,
+        checksum,
+        extra_metadata,
+    };
+    record_upload(&site, &publisher, index.as_deref(), &access_token, object).await;
+
+    created(&url, None)
+}
@@ -0,0 +1,795 @@
+use actix_http::{Error, Request};
+use actix_rt::time::timeout;
+use actix_service::ServiceFactory;
+use actix_web::client::Client;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{guard, web, HttpResponse, Scope};
+
+use futures::future::{err, ok, Either};
+use futures::FutureExt;
+use log::info;
+use serde_json::json;
+
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{ListMultipartUploadsError, ListObjectsV2Request, Object, S3Client, S3};
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::access_log::AccessLog;
+use crate::api_keys::{self, ApiKey};
+use crate::audit::{AuditLog, AuditSink};
+use crate::aws_events::AwsPublisher;
+use crate::backup::{self, Archive};
+use crate::cdn::{Cdn, CdnBackend};
+use crate::credentials::{self, AssumeRole, S3Connections};
+use crate::derivatives::BackfillReport;
+use crate::disk_cache::DiskCache;
+use crate::expect::{self, ExpectSite};
+use crate::import::{self, ImportReport, Source};
+use crate::index::MediaIndex;
+use crate::limits::S3Limiter;
+use crate::mode::{Mode, ServiceMode};
+use crate::oauth::VerificationService;
+use crate::progress::Progress;
+use crate::reconcile::{self, ReconcileReport};
+use crate::redirects::{self, Redirects};
+use crate::replica::ReadBuckets;
+use crate::reporting::{ErrorReporter, ReportBackend, ReportDelivery};
+use crate::retry::S3Policy;
+use crate::robots::Robots;
+use crate::security_headers::{self, SecurityHeaders};
+use crate::session::Sessions;
+use crate::tenant::{self, Tenant};
+use crate::undo::RecentUploads;
+use crate::upload_form::FormSigner;
+use crate::{
+    bootstrap, classify, derivatives, diagnostics, env_or, events, expiry, gc, notifications,
+    routes, share, staging, trash, SiteConfig,
+};
+
+/// The media endpoint and everything it shares between requests.
+///
+/// Mount it in an actix application with [`MediaEndpoint::scope`]:
+///
+/// ```ignore
+/// let endpoint = MediaEndpoint::builder().build().await?;
+/// endpoint.spawn_tasks();
+/// HttpServer::new(move || App::new().service(endpoint.scope("/media-endpoint")))
+/// ```
+#[derive(Clone)]
+pub struct MediaEndpoint {
+    site_config: SiteConfig,
+    region: Region,
+    s3_client: S3Client,
+    read_buckets: ReadBuckets,
+    s3_limiter: S3Limiter,
+    aws_publisher: AwsPublisher,
+    audit_log: web::Data<AuditLog>,
+    access_log: Option<Arc<AccessLog>>,
+    cdn: web::Data<Cdn>,
+    media_index: Option<web::Data<MediaIndex>>,
+    disk_cache: Option<web::Data<DiskCache>>,
+    service_mode: web::Data<ServiceMode>,
+    sessions: web::Data<Sessions>,
+    progress: web::Data<Progress>,
+    recent_uploads: web::Data<RecentUploads>,
+    form_signer: web::Data<FormSigner>,
+    tenants: Vec<Tenant>,
+    api_keys: Arc<Vec<ApiKey>>,
+    redirects: Redirects,
+    /// Where S3 notifications of objects stored out of band are received.
+    s3_events_queue: Option<String>,
+    error_reporter: ErrorReporter,
+    // Taken by spawn_tasks.
+    report_delivery: Arc<Mutex<Option<ReportDelivery>>>,
+    multipart_max_age: Duration,
+    /// Whether the periodic index check fixes what it finds.
+    index_repair: bool,
+}
+
+/// Configures a [`MediaEndpoint`].
+///
+/// Anything not set is read from the environment, just like the binary does.
+#[derive(Default)]
+pub struct MediaEndpointBuilder {
+    site_config: Option<SiteConfig>,
+    region: Option<Region>,
+    s3_client: Option<S3Client>,
+    tenants: Option<Vec<Tenant>>,
+    mode: Option<Mode>,
+}
+
+impl MediaEndpoint {
+    pub fn builder() -> MediaEndpointBuilder {
+        MediaEndpointBuilder::default()
+    }
+
+    pub fn site_config(&self) -> &SiteConfig {
+        &self.site_config
+    }
+
+    /// A scope serving every route under `path`, for the default site.
+    ///
+    /// Call this from the `HttpServer` factory, since it creates HTTP clients
+    /// which belong to the worker.
+    pub fn scope(&self, path: &str) -> Scope {
+        self.build_scope(
+            web::scope(path),
+            self.site_config.clone(),
+            VerificationService::for_site(&self.site_config).api_keys(self.api_keys.clone()),
+        )
+    }
+
+    /// A scope for each configured tenant.
+    ///
+    /// These must be registered before the default site's scope.
+    pub fn tenant_scopes(&self) -> Vec<Scope> {
+        self.tenants
+            .iter()
+            .map(|tenant| {
+                let mut scope = web::scope(tenant.path.trim_end_matches('/'));
+                if let Some(host) = &tenant.host {
+                    scope = scope.guard(guard::Host(host.clone()));
+                }
+                self.build_scope(
+                    scope,
+                    tenant.site_config(&self.site_config),
+                    tenant
+                        .verification_service(&self.site_config)
+                        .api_keys(self.api_keys.clone()),
+                )
+            })
+            .collect()
+    }
+
+    /// Checks the credentials of requests sent with `Expect: 100-continue`
+    /// before the client is told to send the body.
+    ///
+    /// Register it with `HttpService::build().expect(...)`. Like `scope`, call
+    /// this from the server factory.
+    pub fn expect_handler(
+        &self,
+    ) -> impl ServiceFactory<
+        Config = (),
+        Request = Request,
+        Response = Request,
+        Error = Error,
+        InitError = (),
+    > {
+        let tenants = self
+            .tenants
+            .iter()
+            .map(|tenant| ExpectSite {
+                host: tenant.host.clone(),
+                path: tenant.path.trim_end_matches('/').to_string(),
+                verification_service: tenant
+                    .verification_service(&self.site_config)
+                    .api_keys(self.api_keys.clone()),
+            })
+            .collect();
+        expect::handler(
+            tenants,
+            VerificationService::for_site(&self.site_config).api_keys(self.api_keys.clone()),
+        )
+    }
+
+    fn build_scope(
+        &self,
+        scope: Scope,
+        site_config: SiteConfig,
+        verification_service: VerificationService,
+    ) -> Scope {
+        let mut scope = scope;
+        if let Some(media_index) = &self.media_index {
+            scope = scope.app_data(media_index.clone());
+        }
+        if let Some(disk_cache) = &self.disk_cache {
+            scope = scope.app_data(disk_cache.clone());
+        }
+
+        let served_index = self.media_index.clone();
+        let reporter = self.error_reporter.clone();
+        let security_headers = SecurityHeaders::for_site(&site_config);
+        let robots = Robots::for_site(&site_config);
+        let guard_mode = self.service_mode.clone();
+        let access_log = self.access_log.clone();
+        let s3_limiter = self.s3_limiter.clone();
+        let redirects = self.redirects.clone();
+        let redirect_site = site_config.clone();
+        let timeouts = site_config.clone();
+        scope
+            // Dropping the handler's future cancels whatever it was waiting on.
+            // Streaming bodies aren't covered once the response has started.
+            .wrap_fn(move |req, srv| {
+                let limit = timeouts.route_timeout(req.match_info().unprocessed());
+                match limit {
+                    Some(limit) => {
+                        let http_req = req.request().clone();
+                        Either::Left(timeout(limit, srv.call(req)).map(move |res| {
+                            res.unwrap_or_else(|_| {
+                                Ok(ServiceResponse::new(
+                                    http_req,
+                                    HttpResponse::GatewayTimeout().finish(),
+                                ))
+                            })
+                        }))
+                    }
+                    None => Either::Right(srv.call(req)),
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let served_index = served_index.clone();
+                srv.call(req).map(move |res| {
+                    if let (Some(index), Ok(res)) = (&served_index, &res) {
+                        index.count_response(res);
+                    }
+                    res
+                })
+            })
+            .wrap_fn(move |req, srv| {
+                let objects = security_headers::serves_objects(req.match_info().unprocessed());
+                let security_headers = security_headers.clone();
+                srv.call(req).map(move |res| {
+                    res.map(|mut res| {
+                        security_headers.apply(&mut res, objects);
+                        res
+                    })
+                })
+            })
+            .wrap_fn(move |req, srv| {
+                let noindex = robots.is_noindex(req.match_info().unprocessed());
+                let robots = robots.clone();
+                srv.call(req).map(move |res| {
+                    res.map(|mut res| {
+                        if noindex {
+                            robots.apply(&mut res);
+                        }
+                        res
+                    })
+                })
+            })
+            .wrap_fn(move |req, srv| {
+                let reporter = reporter.clone();
+                srv.call(req).map(move |res| {
+                    if let Ok(res) = &res {
+                        reporter.report_response(res);
+                    }
+                    res
+                })
+            })
+            // Turn requests away quickly rather than queue them behind S3.
+            .wrap_fn(move |req, srv| {
+                if s3_limiter.is_saturated() {
+                    let resp = HttpResponse::ServiceUnavailable()
+                        .header(header::RETRY_AFTER, "1")
+                        .finish();
+                    Either::Left(ok(req.into_response(resp)))
+                } else {
+                    Either::Right(srv.call(req))
+                }
+            })
+            // Old permalinks are answered before anything else is looked up.
+            .wrap_fn(move |req, srv| {
+                let target = if redirects.is_empty() {
+                    None
+                } else {
+                    redirects.target(&redirect_site, req.path())
+                };
+                match target {
+                    Some(location) => {
+                        let resp = HttpResponse::MovedPermanently()
+                            .header(header::LOCATION, location)
+                            .finish();
+                        Either::Left(ok(req.into_response(resp)))
+                    }
+                    None => Either::Right(srv.call(req)),
+                }
+            })
+            .wrap_fn(move |req, srv| match guard_mode.check(&req) {
+                Some(resp) => Either::Left(err(resp.into())),
+                None => Either::Right(srv.call(req)),
+            })
+            .wrap_fn(move |req, srv| {
+                let access_log = access_log.clone();
+                let started = Instant::now();
+                srv.call(req).map(move |res| {
+                    if let (Some(access_log), Ok(res)) = (&access_log, &res) {
+                        access_log.record(res, started);
+                    }
+                    res
+                })
+            })
+            .data(Client::new())
+            .data(site_config)
+            .data(self.s3_client.clone())
+            .data(self.read_buckets.clone())
+            .app_data(self.audit_log.clone())
+            .app_data(self.sessions.clone())
+            .app_data(self.progress.clone())
+            .app_data(self.recent_uploads.clone())
+            .app_data(self.form_signer.clone())
+            .app_data(self.cdn.clone())
+            .app_data(self.service_mode.clone())
+            .data(events::Publisher::new(
+                Client::new(),
+                self.aws_publisher.clone(),
+                self.audit_log.clone(),
+            ))
+            .data(verification_service)
+            .configure(routes)
+    }
+
+    /// Send panics to the error reporter, if one is configured.
+    ///
+    /// This replaces the process-wide panic hook, so it is left to the
+    /// application to call.
+    pub fn report_panics(&self) {
+        self.error_reporter.report_panics();
+    }
+
+    /// Log the effective configuration, with secrets redacted.
+    pub fn log_configuration(&self) {
+        let summary = diagnostics::summary(
+            &self.site_config,
+            vec![
+                ("Region", self.region.name().into()),
+                ("Mode", json!(self.service_mode.get())),
+                ("Tenants", self.tenants.len().into()),
+                ("ApiKeys", self.api_keys.len().into()),
+                ("Redirects", self.redirects.len().into()),
+                ("S3EventsQueue", self.s3_events_queue.is_some().into()),
+                ("MultipartMaxAge", self.multipart_max_age.as_secs().into()),
+            ],
+        );
+        info!("Starting with {}", summary);
+    }
+
+    /// Create and configure the bucket if it doesn't exist.
+    pub async fn create_bucket(&self) -> io::Result<()> {
+        bootstrap::create_bucket(
+            &self.site_config,
+            &self.s3_client,
+            &self.region,
+            self.multipart_max_age,
+        )
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    /// Install a lifecycle rule for each of EXPIRY_DAYS, replacing any old
+    /// ones, so expiring uploads are deleted.
+    pub async fn install_expiry_rules(&self) -> io::Result<()> {
+        expiry::install_rules(&self.site_config, &self.s3_client)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    /// Store a file as if it had been uploaded, returning its URL.
+    pub async fn upload(
+        &self,
+        data: Vec<u8>,
+        content_type: mime::Mime,
+        filename: Option<&str>,
+    ) -> Result<String, String> {
+        let placement = import::store(
+            &self.site_config,
+            &self.s3_client,
+            &Client::new(),
+            data,
+            &content_type,
+            filename,
+            "",
+        )
+        .await?;
+        Ok(placement.url(&self.site_config))
+    }
+
+    /// List stored media, optionally only one classification.
+    pub async fn list(&self, classification: Option<&str>) -> Result<Vec<Object>, String> {
+        let classifications = match classification {
+            Some(classification) => vec![classification.to_string()],
+            None => classify::prefixes(&self.site_config),
+        };
+
+        let mut objects = Vec::new();
+        for classification in classifications {
+            let (bucket, prefix) = self.site_config.locate(&format!("{}/", classification));
+            let mut continuation_token = None;
+            loop {
+                let request = ListObjectsV2Request {
+                    bucket: bucket.clone(),
+                    prefix: Some(prefix.clone()),
+                    continuation_token: continuation_token.take(),
+                    ..Default::default()
+                };
+                let response = self
+                    .s3_client
+                    .list_objects_v2(request)
+                    .await
+                    .map_err(|e| format!("Failed to list {}: {}", prefix, e))?;
+                objects.extend(response.contents.unwrap_or_default());
+
+                if response.is_truncated != Some(true) {
+                    break;
+                }
+                continuation_token = response.next_continuation_token;
+            }
+        }
+        Ok(objects)
+    }
+
+    /// Delete the cached derivatives of a photo, e.g. `photo/abc.jpg`.
+    pub async fn purge(&self, key: &str) -> Result<usize, String> {
+        let filename = key
+            .strip_prefix("photo/")
+            .ok_or_else(|| "Only photos have derivatives".to_string())?;
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.purge(self.site_config.s3_bucket(), filename);
+        }
+        derivatives::purge(&self.s3_client, self.site_config.s3_bucket(), filename)
+            .await
+            .map(|purged| purged.len())
+    }
+
+    /// Generate the missing cached sizes of every photo.
+    pub async fn backfill(&self) -> Result<BackfillReport, String> {
+        if !self.site_config.derivative_cache() {
+            return Err("Derivatives aren't cached; set DERIVATIVE_CACHE=true".to_string());
+        }
+        derivatives::backfill(&self.site_config, &self.s3_client).await
+    }
+
+    /// Run every cleanup task once.
+    pub async fn collect_garbage(&self) -> Result<(), String> {
+        let site = &self.site_config;
+
+        let aborted = self
+            .abort_stale_uploads()
+            .await
+            .map_err(|e| format!("Failed to list multipart uploads: {}", e))?;
+        info!("Aborted {} multipart uploads", aborted);
+
+        if site.derivative_cache() {
+            let swept = derivatives::sweep(site, &self.s3_client)
+                .await
+                .map_err(|e| format!("Failed to list derivatives: {}", e))?;
+            info!("Removed {} stale derivatives", swept);
+        }
+
+        if site.trash_days() > 0 {
+            let purged = trash::purge_expired(site, &self.s3_client, self.trash_max_age())
+                .await
+                .map_err(|e| format!("Failed to list the trash: {}", e))?;
+            info!("Permanently deleted {} objects from the trash", purged);
+        }
+
+        let removed = share::remove_expired(site, &self.s3_client).await?;
+        info!("Removed {} expired share links", removed);
+
+        Ok(())
+    }
+
+    /// Copy every original and a manifest of their metadata to a tarball or
+    /// another bucket, e.g. `s3://backups/media/`.
+    pub async fn backup(&self, destination: &str) -> Result<usize, String> {
+        backup::backup(
+            &self.site_config,
+            &self.s3_client,
+            &Archive::parse(destination),
+        )
+        .await
+    }
+
+    /// Put back everything in a backup, then add it to the index, if there
+    /// is one.
+    pub async fn restore(&self, source: &str) -> Result<usize, String> {
+        let restored =
+            backup::restore(&self.site_config, &self.s3_client, &Archive::parse(source)).await?;
+        if let Some(index) = &self.media_index {
+            reconcile::reconcile(&self.site_config, &self.s3_client, index, true).await?;
+        }
+        Ok(restored)
+    }
+
+    /// Import a directory tree or another bucket, e.g. `s3://old-bucket/`,
+    /// as uploads by `author`.
+    pub async fn import(&self, source: &str, author: &str) -> Result<ImportReport, String> {
+        import::import(
+            &self.site_config,
+            &self.s3_client,
+            &Client::new(),
+            self.media_index.as_deref(),
+            &Source::parse(source),
+            author,
+        )
+        .await
+    }
+
+    /// Check the index against the bucket, fixing the differences if `repair`.
+    pub async fn reconcile(&self, repair: bool) -> Result<ReconcileReport, String> {
+        let index = self
+            .media_index
+            .as_ref()
+            .ok_or_else(|| "There's no index; set INDEX_DATABASE_URL".to_string())?;
+        reconcile::reconcile(&self.site_config, &self.s3_client, index, repair).await
+    }
+
+    /// Abort multipart uploads older than MULTIPART_MAX_AGE.
+    pub async fn abort_stale_uploads(
+        &self,
+    ) -> Result<usize, RusotoError<ListMultipartUploadsError>> {
+        gc::abort_stale_uploads(
+            &self.s3_client,
+            self.site_config.s3_bucket(),
+            self.multipart_max_age,
+        )
+        .await
+    }
+
+    fn trash_max_age(&self) -> Duration {
+        Duration::from_secs(u64::from(self.site_config.trash_days()) * 24 * 60 * 60)
+    }
+
+    /// Start the background cleanup tasks on the current arbiter.
+    pub fn spawn_tasks(&self) {
+        let site = &self.site_config;
+
+        if let Some(delivery) = self.report_delivery.lock().unwrap().take() {
+            actix_rt::spawn(delivery.deliver_forever());
+        }
+
+        if let Some(access_log) = &self.access_log {
+            actix_rt::spawn(AccessLog::flush_forever(access_log.clone()));
+        }
+
+        if let Some(media_index) = &self.media_index {
+            actix_rt::spawn(MediaIndex::flush_served_forever(media_index.clone()));
+            actix_rt::spawn(reconcile::reconcile_forever(
+                site.clone(),
+                self.s3_client.clone(),
+                media_index.clone(),
+                self.index_repair,
+            ));
+        }
+
+        actix_rt::spawn(gc::sweep_forever(
+            self.s3_client.clone(),
+            site.s3_bucket().to_string(),
+            self.multipart_max_age,
+        ));
+        actix_rt::spawn(staging::purge_forever(
+            site.clone(),
+            self.s3_client.clone(),
+            self.multipart_max_age,
+        ));
+
+        if site.derivative_cache() {
+            actix_rt::spawn(derivatives::sweep_forever(
+                site.clone(),
+                self.s3_client.clone(),
+            ));
+        }
+
+        if site.trash_days() > 0 {
+            actix_rt::spawn(trash::purge_forever(
+                site.clone(),
+                self.s3_client.clone(),
+                self.trash_max_age(),
+            ));
+        }
+
+        actix_rt::spawn(share::expire_forever(site.clone(), self.s3_client.clone()));
+
+        actix_rt::spawn(Sessions::expire_forever(
+            self.sessions.clone(),
+            site.clone(),
+            self.s3_client.clone(),
+        ));
+
+        if let Some(queue_url) = &self.s3_events_queue {
+            actix_rt::spawn(notifications::consume_forever(
+                site.clone(),
+                self.s3_client.clone(),
+                events::Publisher::new(
+                    Client::new(),
+                    self.aws_publisher.clone(),
+                    self.audit_log.clone(),
+                ),
+                self.media_index.clone(),
+                self.region.clone(),
+                queue_url.clone(),
+            ));
+        }
+    }
+}
+
+impl MediaEndpointBuilder {
+    pub fn site_config(mut self, site_config: SiteConfig) -> MediaEndpointBuilder {
+        self.site_config = Some(site_config);
+        self
+    }
+
+    /// The region of the bucket, used when creating it.
+    pub fn region(mut self, region: Region) -> MediaEndpointBuilder {
+        self.region = Some(region);
+        self
+    }
+
+    pub fn s3_client(mut self, s3_client: S3Client) -> MediaEndpointBuilder {
+        self.s3_client = Some(s3_client);
+        self
+    }
+
+    pub fn tenants(mut self, tenants: Vec<Tenant>) -> MediaEndpointBuilder {
+        self.tenants = Some(tenants);
+        self
+    }
+
+    /// The mode to start in.
+    pub fn mode(mut self, mode: Mode) -> MediaEndpointBuilder {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub async fn build(self) -> io::Result<MediaEndpoint> {
+        let site_config = self.site_config.unwrap_or_else(SiteConfig::from_env);
+        let region = self.region.unwrap_or_default();
+
+        let assume_role = std::env::var("S3_ROLE_ARN")
+            .ok()
+            .map(|role_arn| AssumeRole {
+                role_arn,
+                external_id: std::env::var("S3_ROLE_EXTERNAL_ID").ok(),
+                session_name: std::env::var("S3_ROLE_SESSION_NAME")
+                    .unwrap_or_else(|_| "s3-media-endpoint".to_string()),
+                session_duration: std::env::var("S3_ROLE_SESSION_SECONDS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .map(Duration::from_secs),
+            });
+        // A client passed to the builder isn't limited, but requests are
+        // still turned away once the limiter is saturated.
+        let connections = S3Connections {
+            limiter: S3Limiter::new(env_or("S3_MAX_CONCURRENCY", 0), env_or("S3_MAX_QUEUE", 100)),
+            pool_size: std::env::var("S3_POOL_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+        };
+        let s3_client = self.s3_client.unwrap_or_else(|| {
+            credentials::s3_client(region.clone(), assume_role.as_ref(), &connections)
+        });
+
+        let s3_policy = || {
+            S3Policy::new(
+                env_or("S3_RETRIES", 2),
+                Duration::from_millis(env_or("S3_RETRY_BASE_MS", 100)),
+                Duration::from_millis(env_or("S3_TIMEOUT_MS", 10_000)),
+                env_or("S3_BREAKER_THRESHOLD", 5),
+                Duration::from_secs(env_or("S3_BREAKER_COOLDOWN", 30)),
+            )
+        };
+        let read_buckets = ReadBuckets::new(
+            s3_client.clone(),
+            site_config.s3_bucket(),
+            &std::env::var("S3_READ_REPLICAS").unwrap_or_default(),
+            s3_policy,
+            |region| credentials::s3_client(region, assume_role.as_ref(), &connections),
+        );
+
+        let aws_publisher = AwsPublisher::new(
+            std::env::var("EVENT_TOPIC_ARN").ok(),
+            std::env::var("EVENT_QUEUE_URL").ok(),
+            std::env::var("EVENT_BUS_NAME").ok(),
+        );
+
+        let audit_log =
+            web::Data::new(AuditLog::new(std::env::var("AUDIT_LOG").ok().and_then(
+                |v| AuditSink::parse(&v, &s3_client, site_config.s3_bucket()),
+            )));
+
+        let access_log = std::env::var("ACCESS_LOG")
+            .ok()
+            .and_then(|v| AccessLog::parse(&v, &s3_client, site_config.s3_bucket()))
+            .map(Arc::new);
+
+        let cdn =
+            web::Data::new(Cdn::new(std::env::var("CDN").ok().and_then(|v| {
+                CdnBackend::parse(&v, std::env::var("CDN_API_TOKEN").ok())
+            })));
+
+        let media_index = match std::env::var("INDEX_DATABASE_URL") {
+            Ok(url) => Some(web::Data::new(
+                MediaIndex::connect(&url)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+            )),
+            Err(_) => None,
+        };
+
+        let disk_cache = match std::env::var("DISK_CACHE_DIR") {
+            Ok(dir) => Some(web::Data::new(DiskCache::open(
+                dir,
+                env_or("DISK_CACHE_MAX_BYTES", 1024 * 1024 * 1024),
+            )?)),
+            Err(_) => None,
+        };
+
+        let service_mode = web::Data::new(ServiceMode::new(
+            self.mode
+                .or_else(|| {
+                    std::env::var("SERVICE_MODE")
+                        .ok()
+                        .and_then(|v| Mode::parse(&v))
+                })
+                .unwrap_or(Mode::Normal),
+            Duration::from_secs(env_or("MAINTENANCE_RETRY_AFTER", 300)),
+        ));
+
+        let sessions = web::Data::new(Sessions::new(Duration::from_secs(env_or(
+            "SESSION_TTL",
+            24 * 60 * 60,
+        ))));
+
+        let recent_uploads = web::Data::new(RecentUploads::new(Duration::from_secs(env_or(
+            "UNDO_WINDOW",
+            5 * 60,
+        ))));
+
+        let tenants = match self.tenants {
+            Some(tenants) => tenants,
+            None => match std::env::var("TENANTS_FILE") {
+                Ok(path) => tenant::load(&path)?,
+                Err(_) => Vec::new(),
+            },
+        };
+
+        let api_keys = match std::env::var("API_KEYS_FILE") {
+            Ok(path) => api_keys::load(&path)?,
+            Err(_) => Vec::new(),
+        };
+
+        let redirects = match std::env::var("REDIRECT_MAP") {
+            Ok(path) => redirects::load(&path)?,
+            Err(_) => Redirects::default(),
+        };
+
+        let form_signer = web::Data::new(FormSigner::new(region.clone(), assume_role.as_ref()));
+
+        let (error_reporter, report_delivery) = ErrorReporter::new(
+            std::env::var("ERROR_REPORTER")
+                .ok()
+                .and_then(|v| ReportBackend::parse(&v)),
+        );
+
+        Ok(MediaEndpoint {
+            site_config,
+            region,
+            s3_client,
+            read_buckets,
+            s3_limiter: connections.limiter,
+            aws_publisher,
+            audit_log,
+            access_log,
+            cdn,
+            media_index,
+            disk_cache,
+            service_mode,
+            sessions,
+            progress: web::Data::new(Progress::default()),
+            recent_uploads,
+            form_signer,
+            tenants,
+            api_keys: Arc::new(api_keys),
+            redirects,
+            s3_events_queue: std::env::var("S3_EVENTS_QUEUE_URL").ok(),
+            error_reporter,
+            report_delivery: Arc::new(Mutex::new(report_delivery)),
+            // Incomplete multipart uploads are billed until they're aborted.
+            multipart_max_age: Duration::from_secs(env_or("MULTIPART_MAX_AGE", 2 * 24 * 60 * 60)),
+            index_repair: env_or("INDEX_REPAIR", false),
+        })
+    }
+}
@@ -0,0 +1,90 @@
+use actix_web::client::Client;
+use actix_web::web;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::audit::AuditLog;
+use crate::aws_events::AwsPublisher;
+use crate::webhook;
+use crate::websub;
+use crate::SiteConfig;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Create,
+    Delete,
+}
+
+/// Something that happened to a stored object.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaEvent {
+    pub event: EventKind,
+    pub url: String,
+    pub key: String,
+    pub content_type: Option<String>,
+    pub size: Option<u64>,
+    pub author: String,
+    pub client_id: String,
+    /// The API key used instead of an access token, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    pub checksum: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Sends events to webhooks and AWS destinations.
+#[derive(Clone)]
+pub struct Publisher {
+    http_client: Client,
+    aws: AwsPublisher,
+    audit: web::Data<AuditLog>,
+}
+
+impl Publisher {
+    pub fn new(http_client: Client, aws: AwsPublisher, audit: web::Data<AuditLog>) -> Publisher {
+        Publisher {
+            http_client,
+            aws,
+            audit,
+        }
+    }
+
+    /// Send the event to every configured destination.
+    ///
+    /// Delivery happens in the background and never fails the request.
+    pub fn publish(&self, site: &SiteConfig, event: MediaEvent) {
+        if self.audit.is_enabled() {
+            let audit = self.audit.clone();
+            let event = event.clone();
+            actix_rt::spawn(async move { audit.record(&event).await });
+        }
+
+        if site.webhook_urls().next().is_some() {
+            actix_rt::spawn(webhook::deliver(
+                site.clone(),
+                self.http_client.clone(),
+                event.clone(),
+            ));
+        }
+
+        if event.event == EventKind::Create && site.websub_enabled() {
+            actix_rt::spawn(websub::ping(site.clone(), self.http_client.clone()));
+        }
+
+        if self.aws.is_enabled() {
+            actix_rt::spawn(self.aws.clone().deliver(event));
+        }
+    }
+}
+
+/// The hex encoded SHA-256 of `data`.
+pub fn checksum(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,238 @@
+//! Reading and removal of EXIF metadata in stored photos, without
+//! re-encoding them.
+
+use chrono::NaiveDateTime;
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_APP1: u8 = 0xE1;
+const JPEG_SOS: u8 = 0xDA;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const PNG_EXIF: &[u8] = b"eXIf";
+const PNG_IDAT: &[u8] = b"IDAT";
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+/// Remove EXIF (and XMP) metadata from a JPEG or PNG.
+///
+/// Other formats, and images that can't be parsed, are returned unchanged.
+pub fn strip(data: Vec<u8>) -> Vec<u8> {
+    let stripped = if data.starts_with(&JPEG_SOI) {
+        strip_jpeg(&data)
+    } else if data.starts_with(&PNG_SIGNATURE) {
+        strip_png(&data)
+    } else {
+        None
+    };
+
+    stripped.unwrap_or(data)
+}
+
+/// Where the image data begins, after any metadata, if `data` starts with
+/// enough of a JPEG or PNG to tell. Metadata may only come before it, so what
+/// follows can be sent without being read by `strip`.
+pub fn metadata_end(data: &[u8]) -> Option<usize> {
+    if data.starts_with(&JPEG_SOI) {
+        let mut pos = JPEG_SOI.len();
+        loop {
+            if *data.get(pos)? != 0xFF {
+                return None;
+            }
+            let marker = *data.get(pos + 1)?;
+            if marker == JPEG_SOS {
+                return Some(pos);
+            }
+            if marker == 0x01 || (0xD0..=0xD9).contains(&marker) {
+                pos += 2;
+                continue;
+            }
+            let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+            pos += 2 + len;
+        }
+    } else if data.starts_with(&PNG_SIGNATURE) {
+        let mut pos = PNG_SIGNATURE.len();
+        loop {
+            let header = data.get(pos..pos + 8)?;
+            if &header[4..8] == PNG_IDAT {
+                return Some(pos);
+            }
+            let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            pos += 12 + len;
+        }
+    } else {
+        None
+    }
+}
+
+/// Copy every segment except APP1, which holds both EXIF and XMP.
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&JPEG_SOI);
+
+    let mut pos = JPEG_SOI.len();
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+
+        // Markers without a length
+        if marker == 0x01 || (0xD0..=0xD9).contains(&marker) {
+            out.extend_from_slice(&data[pos..pos + 2]);
+            pos += 2;
+            if marker == 0xD9 {
+                return Some(out);
+            }
+            continue;
+        }
+
+        if marker == JPEG_SOS {
+            // Everything from the scan header on is image data.
+            out.extend_from_slice(&data[pos..]);
+            return Some(out);
+        }
+
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        let end = pos + 2 + len;
+        if end > data.len() {
+            return None;
+        }
+
+        if marker != JPEG_APP1 {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+}
+
+/// Copy every chunk except eXIf, which must come before the image data.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&PNG_SIGNATURE);
+
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let header = data.get(pos..pos + 8)?;
+        if &header[4..8] == PNG_IDAT {
+            out.extend_from_slice(&data[pos..]);
+            return Some(out);
+        }
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        // Length, type, data, and CRC
+        let end = pos + 12 + len;
+        if end > data.len() {
+            return None;
+        }
+
+        if &header[4..8] != PNG_EXIF {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+
+    Some(out)
+}
+
+/// The EXIF orientation of a JPEG, from 1 (upright) to 8.
+pub fn orientation(data: &[u8]) -> Option<u16> {
+    let tiff = Tiff::find(data)?;
+    let entry = tiff.entry(tiff.ifd0()?, TAG_ORIENTATION)?;
+    tiff.u16_at(entry + 8)
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// When a JPEG was taken, from DateTimeOriginal, or else the time it was
+/// last changed. EXIF doesn't record the time zone, so it's read as UTC.
+pub fn captured_at(data: &[u8]) -> Option<NaiveDateTime> {
+    let tiff = Tiff::find(data)?;
+    let ifd0 = tiff.ifd0()?;
+    let original = tiff
+        .entry(ifd0, TAG_EXIF_IFD)
+        .and_then(|entry| tiff.u32_at(entry + 8))
+        .and_then(|exif_ifd| tiff.entry(exif_ifd as usize, TAG_DATE_TIME_ORIGINAL));
+    let entry = original.or_else(|| tiff.entry(ifd0, TAG_DATE_TIME))?;
+
+    // Always 20 bytes, e.g. `2019:07:04 18:30:00\0`, so stored elsewhere.
+    let offset = tiff.u32_at(entry + 8)? as usize;
+    let text = std::str::from_utf8(tiff.data.get(offset..offset + 19)?).ok()?;
+    NaiveDateTime::parse_from_str(text, "%Y:%m:%d %H:%M:%S").ok()
+}
+
+/// The TIFF structure inside an EXIF segment: a header, then IFDs of 12 byte
+/// entries of tag, type, count, and value or offset.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    /// Find the EXIF APP1 segment of a JPEG, which comes before the image
+    /// data.
+    fn find(data: &'a [u8]) -> Option<Tiff<'a>> {
+        if !data.starts_with(&JPEG_SOI) {
+            return None;
+        }
+
+        let mut pos = JPEG_SOI.len();
+        let tiff = loop {
+            if *data.get(pos)? != 0xFF {
+                return None;
+            }
+            let marker = *data.get(pos + 1)?;
+            if marker == JPEG_SOS || marker == 0xD9 {
+                return None;
+            }
+            let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+            let segment = data.get(pos + 4..pos + 2 + len)?;
+            if marker == JPEG_APP1 && segment.starts_with(EXIF_HEADER) {
+                break &segment[EXIF_HEADER.len()..];
+            }
+            pos += 2 + len;
+        };
+
+        let big_endian = match tiff.get(..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        };
+        Some(Tiff {
+            data: tiff,
+            big_endian,
+        })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?];
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn ifd0(&self) -> Option<usize> {
+        self.u32_at(4).map(|offset| offset as usize)
+    }
+
+    /// The offset of the entry for `tag` in the IFD at `ifd`.
+    fn entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let entries = self.u16_at(ifd)? as usize;
+        (0..entries)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|entry| self.u16_at(*entry) == Some(tag))
+    }
+}
@@ -0,0 +1,97 @@
+use actix_http::{Error, Request, RequestHead};
+use actix_service::{fn_service, ServiceFactory};
+use actix_web::http::header;
+
+use std::rc::Rc;
+
+use crate::oauth::{VerificationError, VerificationService};
+
+/// A tenant's location, and how its tokens are checked.
+pub struct ExpectSite {
+    pub host: Option<String>,
+    pub path: String,
+    pub verification_service: VerificationService,
+}
+
+struct ExpectCheck {
+    tenants: Vec<ExpectSite>,
+    default: VerificationService,
+}
+
+/// Checks the credentials of uploads sent with `Expect: 100-continue`.
+///
+/// A client that isn't allowed in gets its 401 or 403 before it is told to
+/// send the body. The handlers check the credentials again, along with the
+/// scopes the route needs. Every other request is told to continue, since
+/// not every route takes a token.
+pub fn handler(
+    tenants: Vec<ExpectSite>,
+    default: VerificationService,
+) -> impl ServiceFactory<Config = (), Request = Request, Response = Request, Error = Error, InitError = ()>
+{
+    let check = Rc::new(ExpectCheck { tenants, default });
+    fn_service(move |req: Request| {
+        let check = check.clone();
+        async move {
+            check.check(&req).await?;
+            Ok(req)
+        }
+    })
+}
+
+impl ExpectCheck {
+    async fn check(&self, req: &Request) -> Result<(), Error> {
+        let (service, path) = self.route(req.head());
+        if !is_upload(path) {
+            return Ok(());
+        }
+
+        if let Some(api_key) = req.headers().get("X-Api-Key") {
+            let api_key = api_key
+                .to_str()
+                .map_err(|_| VerificationError::Unauthenticated)?;
+            return service.validate_api_key(api_key).map(|_| ());
+        }
+
+        match req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+        {
+            Some(auth_header) => service.validate(auth_header).await.map(|_| ()),
+            None => Err(VerificationError::Unauthenticated.into()),
+        }
+    }
+
+    /// The tenant's verification service, and the path relative to the
+    /// tenant. Tenants are matched the same way as the tenant scopes are.
+    fn route<'a>(&'a self, head: &'a RequestHead) -> (&'a VerificationService, &'a str) {
+        let host = head
+            .headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| head.uri.host())
+            .and_then(|h| h.split(':').next());
+        let path = head.uri.path();
+
+        self.tenants
+            .iter()
+            .find(|tenant| {
+                let host_matches = match &tenant.host {
+                    Some(tenant_host) => host == Some(tenant_host.as_str()),
+                    None => true,
+                };
+                let path_matches = tenant.path.is_empty()
+                    || path == tenant.path
+                    || path.starts_with(&format!("{}/", tenant.path));
+                host_matches && path_matches
+            })
+            .map(|tenant| (&tenant.verification_service, &path[tenant.path.len()..]))
+            .unwrap_or((&self.default, path))
+    }
+}
+
+/// Whether the path, relative to the tenant, takes uploads with a token.
+fn is_upload(path: &str) -> bool {
+    path == "/micropub/media" || path.starts_with("/micropub/media/session/")
+}
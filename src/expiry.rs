@@ -0,0 +1,131 @@
+//! Uploads which delete themselves, e.g. screenshots shared for a day.
+//!
+//! An upload asks with the `expires-in` form field, naming one of
+//! EXPIRY_DAYS. The object is tagged `expires-in=<days>` and a lifecycle rule
+//! for each of EXPIRY_DAYS deletes objects with that tag.
+
+use chrono::{Duration as ChronoDuration, Utc};
+use log::info;
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    BucketLifecycleConfiguration, DeleteBucketLifecycleRequest,
+    GetBucketLifecycleConfigurationRequest, LifecycleExpiration, LifecycleRule,
+    LifecycleRuleFilter, PutBucketLifecycleConfigurationRequest, S3Client, Tag, S3,
+};
+
+use crate::micropub::MicropubError;
+use crate::SiteConfig;
+
+/// The form field, and the tag the lifecycle rules look for.
+pub const EXPIRY_TAG: &str = "expires-in";
+
+/// Lifecycle rules installed here have IDs starting with this.
+const RULE_PREFIX: &str = "expires-in-";
+
+/// Parse an `expires-in` value, e.g. `7` or `7d`, into days.
+pub fn parse(site: &SiteConfig, value: &str) -> Result<u32, MicropubError> {
+    let days = value
+        .trim()
+        .trim_end_matches('d')
+        .parse()
+        .ok()
+        .filter(|days| site.expiry_days().any(|d| d == *days));
+    days.ok_or_else(|| {
+        let allowed: Vec<String> = site.expiry_days().map(|d| d.to_string()).collect();
+        let description = if allowed.is_empty() {
+            "Uploads can't expire".to_string()
+        } else {
+            format!("{} must be one of {} days", EXPIRY_TAG, allowed.join(", "))
+        };
+        MicropubError::with_description("invalid_request", description)
+    })
+}
+
+/// Add the expiry tag to the tags in `tagging`, a query string.
+pub fn tagging(tagging: Option<String>, days: u32) -> Option<String> {
+    let tag = format!("{}={}", EXPIRY_TAG, days);
+    Some(match tagging {
+        Some(tagging) if !tagging.is_empty() => format!("{}&{}", tagging, tag),
+        _ => tag,
+    })
+}
+
+/// When an object stored now with the given expiry will be deleted, as a
+/// hint for people reading its metadata. S3 may take a day longer.
+pub fn expires_at(days: u32) -> String {
+    (Utc::now() + ChronoDuration::days(i64::from(days))).to_rfc3339()
+}
+
+/// The lifecycle rules for EXPIRY_DAYS.
+pub fn rules(site: &SiteConfig) -> Vec<LifecycleRule> {
+    site.expiry_days()
+        .map(|days| LifecycleRule {
+            id: Some(format!("{}{}", RULE_PREFIX, days)),
+            status: "Enabled".to_string(),
+            filter: Some(LifecycleRuleFilter {
+                tag: Some(Tag {
+                    key: EXPIRY_TAG.to_string(),
+                    value: days.to_string(),
+                }),
+                ..Default::default()
+            }),
+            expiration: Some(LifecycleExpiration {
+                days: Some(i64::from(days)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Replace the bucket's expiry rules with those for EXPIRY_DAYS, keeping
+/// every other lifecycle rule.
+pub async fn install_rules(site: &SiteConfig, s3_client: &S3Client) -> Result<(), String> {
+    let bucket = site.s3_bucket().to_owned();
+    let existing = s3_client
+        .get_bucket_lifecycle_configuration(GetBucketLifecycleConfigurationRequest {
+            bucket: bucket.clone(),
+            ..Default::default()
+        })
+        .await;
+    let mut rules = match existing {
+        Ok(configuration) => configuration.rules.unwrap_or_default(),
+        // There's no configuration yet.
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => Vec::new(),
+        Err(e) => {
+            return Err(format!(
+                "Failed to read lifecycle rules on {}: {}",
+                bucket, e
+            ))
+        }
+    };
+    rules.retain(|rule| {
+        !rule
+            .id
+            .as_deref()
+            .map_or(false, |id| id.starts_with(RULE_PREFIX))
+    });
+    rules.extend(self::rules(site));
+
+    info!("Installing expiry rules on {}", bucket);
+    // S3 won't take an empty configuration.
+    if rules.is_empty() {
+        return s3_client
+            .delete_bucket_lifecycle(DeleteBucketLifecycleRequest {
+                bucket: bucket.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| format!("Failed to remove lifecycle rules on {}: {}", bucket, e));
+    }
+    s3_client
+        .put_bucket_lifecycle_configuration(PutBucketLifecycleConfigurationRequest {
+            bucket: bucket.clone(),
+            lifecycle_configuration: Some(BucketLifecycleConfiguration { rules }),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| format!("Failed to configure lifecycle rules on {}: {}", bucket, e))
+        .map(|_| ())
+}
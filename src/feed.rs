@@ -0,0 +1,142 @@
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpResponse};
+
+use serde::Serialize;
+
+use crate::index::{ListFilter, MediaIndex, MediaRecord};
+use crate::SiteConfig;
+
+/// Number of photos included in the feeds.
+const FEED_LENGTH: i64 = 50;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/media/feed.json").route(web::get().to(json_feed)));
+    cfg.service(web::resource("/media/feed.xml").route(web::get().to(rss_feed)));
+}
+
+#[derive(Serialize)]
+struct JsonFeed<'a> {
+    version: &'static str,
+    title: &'a str,
+    feed_url: String,
+    items: Vec<JsonFeedItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem<'a> {
+    id: &'a str,
+    url: &'a str,
+    image: &'a str,
+    content_html: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<&'a str>,
+    date_published: String,
+}
+
+async fn json_feed(
+    config: web::Data<SiteConfig>,
+    index: Option<web::Data<MediaIndex>>,
+) -> Result<HttpResponse, Error> {
+    let photos = recent_photos(index).await?;
+
+    let feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: config.feed_title(),
+        feed_url: format!("{}/feed.json", config.media_url()),
+        items: photos
+            .iter()
+            .map(|photo| JsonFeedItem {
+                id: &photo.key,
+                url: &photo.url,
+                image: &photo.url,
+                content_html: image_html(photo),
+                summary: photo.caption.as_deref(),
+                date_published: photo.created_at.to_rfc3339(),
+            })
+            .collect(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/feed+json")
+        .header(header::CACHE_CONTROL, "max-age=300")
+        .json(feed))
+}
+
+async fn rss_feed(
+    config: web::Data<SiteConfig>,
+    index: Option<web::Data<MediaIndex>>,
+) -> Result<HttpResponse, Error> {
+    let photos = recent_photos(index).await?;
+
+    let mut rss = String::new();
+    rss.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    rss.push_str("<rss version=\"2.0\">\n<channel>\n");
+    rss.push_str(&format!("<title>{}</title>\n", escape(config.feed_title())));
+    rss.push_str(&format!("<link>{}</link>\n", escape(config.media_url())));
+    rss.push_str(&format!("<description>{}</description>\n", escape(config.feed_title())));
+    for photo in &photos {
+        rss.push_str("<item>\n");
+        if let Some(caption) = &photo.caption {
+            rss.push_str(&format!("<title>{}</title>\n", escape(caption)));
+        }
+        rss.push_str(&format!("<link>{}</link>\n", escape(&photo.url)));
+        rss.push_str(&format!("<guid isPermaLink=\"false\">{}</guid>\n", escape(&photo.key)));
+        rss.push_str(&format!("<pubDate>{}</pubDate>\n", photo.created_at.to_rfc2822()));
+        rss.push_str(&format!("<description>{}</description>\n", escape(&image_html(photo))));
+        if let Some(content_type) = &photo.content_type {
+            rss.push_str(&format!(
+                "<enclosure url=\"{}\" length=\"{}\" type=\"{}\"/>\n",
+                escape(&photo.url),
+                photo.size.unwrap_or(0),
+                escape(content_type)
+            ));
+        }
+        rss.push_str("</item>\n");
+    }
+    rss.push_str("</channel>\n</rss>\n");
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/rss+xml; charset=utf-8")
+        .header(header::CACHE_CONTROL, "max-age=300")
+        .body(rss))
+}
+
+/// The most recent photos in the index.
+async fn recent_photos(index: Option<web::Data<MediaIndex>>) -> Result<Vec<MediaRecord>, Error> {
+    let index = index.ok_or_else(|| ErrorNotFound("Not found"))?;
+    let filter = ListFilter {
+        classification: Some("photo"),
+        limit: FEED_LENGTH,
+        ..Default::default()
+    };
+    index.list(&filter).await.map_err(ErrorInternalServerError)
+}
+
+fn image_html(photo: &MediaRecord) -> String {
+    let mut html = format!(
+        "<img src=\"{}\" alt=\"{}\">",
+        escape(&photo.url),
+        escape(photo.alt.as_deref().unwrap_or(""))
+    );
+    if let Some(caption) = &photo.caption {
+        html.push_str(&format!("<p>{}</p>", escape(caption)));
+    }
+    html
+}
+
+/// Escape text for inclusion in XML or HTML.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,86 @@
+use actix_rt::time::delay_for;
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{error, info};
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, ListMultipartUploadsError, ListMultipartUploadsRequest, S3Client,
+    S3,
+};
+
+use std::time::Duration;
+
+/// How often the background sweep looks for abandoned uploads.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Abort every multipart upload in the bucket started more than `max_age` ago.
+///
+/// Returns the number of uploads aborted.
+pub async fn abort_stale_uploads(
+    s3_client: &S3Client,
+    bucket: &str,
+    max_age: Duration,
+) -> Result<usize, RusotoError<ListMultipartUploadsError>> {
+    let cutoff = Utc::now()
+        - ChronoDuration::from_std(max_age).unwrap_or_else(|_| ChronoDuration::max_value());
+    let mut key_marker = None;
+    let mut upload_id_marker = None;
+    let mut aborted = 0;
+
+    loop {
+        let request = ListMultipartUploadsRequest {
+            bucket: bucket.to_owned(),
+            key_marker: key_marker.take(),
+            upload_id_marker: upload_id_marker.take(),
+            ..Default::default()
+        };
+        let response = s3_client.list_multipart_uploads(request).await?;
+
+        for upload in response.uploads.unwrap_or_default() {
+            let initiated = upload
+                .initiated
+                .as_deref()
+                .and_then(|v| DateTime::parse_from_rfc3339(v).ok());
+            let stale = match initiated {
+                Some(initiated) => initiated < cutoff,
+                None => false,
+            };
+            if !stale {
+                continue;
+            }
+
+            if let (Some(key), Some(upload_id)) = (upload.key, upload.upload_id) {
+                let request = AbortMultipartUploadRequest {
+                    bucket: bucket.to_owned(),
+                    key: key.clone(),
+                    upload_id,
+                    ..Default::default()
+                };
+                match s3_client.abort_multipart_upload(request).await {
+                    Ok(_) => {
+                        info!("Aborted abandoned multipart upload of {}", key);
+                        aborted += 1;
+                    }
+                    Err(e) => error!("Failed to abort multipart upload of {}: {}", key, e),
+                }
+            }
+        }
+
+        if response.is_truncated != Some(true) {
+            return Ok(aborted);
+        }
+        key_marker = response.next_key_marker;
+        upload_id_marker = response.next_upload_id_marker;
+    }
+}
+
+/// Periodically abort multipart uploads older than `max_age`.
+pub async fn sweep_forever(s3_client: S3Client, bucket: String, max_age: Duration) {
+    loop {
+        if let Err(e) = abort_stale_uploads(&s3_client, &bucket, max_age).await {
+            error!("Failed to list multipart uploads: {}", e);
+        }
+        delay_for(SWEEP_INTERVAL).await;
+    }
+}
@@ -0,0 +1,454 @@
+//! GPS traces. GPX and GeoJSON uploads are classified as `map`, checked, and
+//! stored with a simplified GeoJSON copy which previews are drawn from.
+
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+
+use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncReadExt;
+
+use std::f64::consts::PI;
+
+use crate::media;
+use crate::replica::ReadBuckets;
+use crate::SiteConfig;
+
+/// Key prefix for simplified traces, served at `/media/map/simplified/{filename}`.
+pub const SIMPLIFIED_PREFIX: &str = "map/simplified";
+
+/// Points closer than this to the simplified line are dropped, in degrees.
+/// It's about 5m at the equator.
+const TOLERANCE: f64 = 0.00005;
+
+/// Size of a preview unless asked otherwise.
+const DEFAULT_PREVIEW_SIZE: (u32, u32) = (600, 400);
+
+/// Largest width or height of a preview.
+const MAX_PREVIEW_EDGE: u32 = 2000;
+
+/// Pixels left around the trace in a preview.
+const PREVIEW_PADDING: f64 = 20.0;
+
+const BACKGROUND: Rgb<u8> = Rgb([245, 243, 238]);
+const TRACE_COLOR: Rgb<u8> = Rgb([214, 58, 36]);
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/media/map/preview/{filename:.+}").route(web::get().to(serve_preview)),
+    );
+}
+
+/// Whether an upload is a GPX or GeoJSON file.
+pub fn is_trace(content_type: &mime::Mime, filename: Option<&str>) -> bool {
+    let extension = filename
+        .and_then(|f| f.rsplit('.').next().filter(|e| *e != f))
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("gpx") | Some("geojson") => true,
+        _ => {
+            let essence = content_type.essence_str();
+            essence == "application/gpx+xml" || essence == "application/geo+json"
+        }
+    }
+}
+
+/// The lines and points of a trace, as longitude and latitude.
+pub struct Trace {
+    lines: Vec<Vec<(f64, f64)>>,
+}
+
+impl Trace {
+    /// Parse a GPX or GeoJSON upload, checking it has at least one point and
+    /// that every point is on the map.
+    pub fn parse(
+        content_type: &mime::Mime,
+        filename: Option<&str>,
+        data: &[u8],
+    ) -> Result<Trace, String> {
+        let text = std::str::from_utf8(data).map_err(|_| "The trace isn't UTF-8".to_string())?;
+        let gpx = content_type.essence_str() == "application/gpx+xml"
+            || filename.map_or(false, |f| f.to_ascii_lowercase().ends_with(".gpx"));
+        let trace = if gpx {
+            Trace::from_gpx(text)?
+        } else {
+            Trace::from_geojson(text)?
+        };
+
+        if trace.lines.iter().all(Vec::is_empty) {
+            return Err("The trace has no points".to_string());
+        }
+        let on_the_map = |&(lon, lat): &(f64, f64)| {
+            (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat)
+        };
+        if !trace.lines.iter().flatten().all(on_the_map) {
+            return Err("The trace has points off the map".to_string());
+        }
+        Ok(trace)
+    }
+
+    /// Read the track segments, routes, and waypoints of a GPX file.
+    ///
+    /// This only looks at the point tags and their `lat` and `lon`
+    /// attributes, which is all a preview needs.
+    fn from_gpx(text: &str) -> Result<Trace, String> {
+        let mut lines = Vec::new();
+        let mut current = Vec::new();
+        for tag in text.split('<').skip(1) {
+            let tag = tag.splitn(2, '>').next().unwrap_or_default();
+            let name = tag
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default();
+            match name {
+                "trkseg" | "rte" if !current.is_empty() => {
+                    lines.push(std::mem::replace(&mut current, Vec::new()));
+                }
+                "trkpt" | "rtept" => current.push(gpx_point(tag)?),
+                "wpt" => lines.push(vec![gpx_point(tag)?]),
+                _ => (),
+            }
+        }
+        if !current.is_empty() {
+            lines.push(current);
+        }
+        Ok(Trace { lines })
+    }
+
+    fn from_geojson(text: &str) -> Result<Trace, String> {
+        let value: Value =
+            serde_json::from_str(text).map_err(|e| format!("Invalid GeoJSON: {}", e))?;
+        let mut lines = Vec::new();
+        collect_geojson(&value, &mut lines)?;
+        Ok(Trace { lines })
+    }
+
+    /// Drop the points which hardly change the shape of each line.
+    pub fn simplified(&self) -> Trace {
+        Trace {
+            lines: self.lines.iter().map(|line| simplify(line)).collect(),
+        }
+    }
+
+    /// A FeatureCollection of the lines, with lone points as a MultiPoint.
+    pub fn to_geojson(&self) -> Value {
+        // Six decimal places are about 10cm.
+        let round =
+            |&(lon, lat): &(f64, f64)| [(lon * 1e6).round() / 1e6, (lat * 1e6).round() / 1e6];
+        let lines: Vec<Vec<[f64; 2]>> = self
+            .lines
+            .iter()
+            .filter(|line| line.len() > 1)
+            .map(|line| line.iter().map(round).collect())
+            .collect();
+        let points: Vec<[f64; 2]> = self
+            .lines
+            .iter()
+            .filter(|line| line.len() == 1)
+            .map(|line| round(&line[0]))
+            .collect();
+
+        let mut features = Vec::new();
+        if !lines.is_empty() {
+            features.push(json!({
+                "type": "Feature",
+                "properties": {},
+                "geometry": { "type": "MultiLineString", "coordinates": lines },
+            }));
+        }
+        if !points.is_empty() {
+            features.push(json!({
+                "type": "Feature",
+                "properties": {},
+                "geometry": { "type": "MultiPoint", "coordinates": points },
+            }));
+        }
+        json!({ "type": "FeatureCollection", "features": features })
+    }
+
+    /// Draw the trace on a plain background, as a PNG.
+    fn render(&self, width: u32, height: u32) -> Result<Vec<u8>, image::ImageError> {
+        let projected: Vec<Vec<(f64, f64)>> = self
+            .lines
+            .iter()
+            .map(|line| line.iter().map(|&point| mercator(point)).collect())
+            .collect();
+        let all = projected.iter().flatten();
+        let min_x = all.clone().map(|p| p.0).fold(f64::INFINITY, f64::min);
+        let max_x = all.clone().map(|p| p.0).fold(f64::NEG_INFINITY, f64::max);
+        let min_y = all.clone().map(|p| p.1).fold(f64::INFINITY, f64::min);
+        let max_y = all.map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+
+        // Fit the trace within the padding, keeping its shape, and center it.
+        let inner_width = (f64::from(width) - 2.0 * PREVIEW_PADDING).max(1.0);
+        let inner_height = (f64::from(height) - 2.0 * PREVIEW_PADDING).max(1.0);
+        let scale = (inner_width / (max_x - min_x)).min(inner_height / (max_y - min_y));
+        let scale = if scale.is_finite() { scale } else { 0.0 };
+        let offset_x = (f64::from(width) - (max_x - min_x) * scale) / 2.0;
+        let offset_y = (f64::from(height) - (max_y - min_y) * scale) / 2.0;
+        let to_pixel = |(x, y): (f64, f64)| {
+            (
+                (offset_x + (x - min_x) * scale).round() as i64,
+                (offset_y + (y - min_y) * scale).round() as i64,
+            )
+        };
+
+        let mut img = RgbImage::from_pixel(width, height, BACKGROUND);
+        for line in &projected {
+            let pixels: Vec<(i64, i64)> = line.iter().map(|&p| to_pixel(p)).collect();
+            if pixels.len() == 1 {
+                dot(&mut img, pixels[0], 4);
+            }
+            for segment in pixels.windows(2) {
+                draw_line(&mut img, segment[0], segment[1]);
+            }
+        }
+
+        let mut encoded = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut encoded, ImageOutputFormat::Png)?;
+        Ok(encoded)
+    }
+}
+
+/// The position in a `trkpt`, `rtept`, or `wpt` tag.
+fn gpx_point(tag: &str) -> Result<(f64, f64), String> {
+    let attribute = |name: &str| {
+        tag.split_whitespace()
+            .filter_map(|word| word.strip_prefix(name)?.strip_prefix('='))
+            .map(|value| {
+                value
+                    .trim_end_matches('/')
+                    .trim_matches(|c| c == '"' || c == '\'')
+            })
+            .find_map(|value| value.parse().ok())
+    };
+    match (attribute("lon"), attribute("lat")) {
+        (Some(lon), Some(lat)) => Ok((lon, lat)),
+        _ => Err("A GPX point is missing its lat or lon".to_string()),
+    }
+}
+
+/// Add the positions in a GeoJSON object to `lines`. Polygon rings are
+/// treated as lines.
+fn collect_geojson(value: &Value, lines: &mut Vec<Vec<(f64, f64)>>) -> Result<(), String> {
+    let coordinates = &value["coordinates"];
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in array(&value["features"])? {
+                collect_geojson(feature, lines)?;
+            }
+        }
+        Some("Feature") if value["geometry"].is_null() => (),
+        Some("Feature") => collect_geojson(&value["geometry"], lines)?,
+        Some("GeometryCollection") => {
+            for geometry in array(&value["geometries"])? {
+                collect_geojson(geometry, lines)?;
+            }
+        }
+        Some("Point") => lines.push(vec![position(coordinates)?]),
+        Some("MultiPoint") => {
+            for point in array(coordinates)? {
+                lines.push(vec![position(point)?]);
+            }
+        }
+        Some("LineString") => lines.push(positions(coordinates)?),
+        Some("MultiLineString") | Some("Polygon") => {
+            for line in array(coordinates)? {
+                lines.push(positions(line)?);
+            }
+        }
+        Some("MultiPolygon") => {
+            for polygon in array(coordinates)? {
+                for ring in array(polygon)? {
+                    lines.push(positions(ring)?);
+                }
+            }
+        }
+        Some(other) => return Err(format!("Unknown GeoJSON type {}", other)),
+        None => return Err("A GeoJSON object is missing its type".to_string()),
+    }
+    Ok(())
+}
+
+fn array(value: &Value) -> Result<&Vec<Value>, String> {
+    value
+        .as_array()
+        .ok_or_else(|| "Expected a GeoJSON array".to_string())
+}
+
+fn position(value: &Value) -> Result<(f64, f64), String> {
+    match (value[0].as_f64(), value[1].as_f64()) {
+        (Some(lon), Some(lat)) => Ok((lon, lat)),
+        _ => Err("A GeoJSON position must be [lon, lat]".to_string()),
+    }
+}
+
+fn positions(value: &Value) -> Result<Vec<(f64, f64)>, String> {
+    array(value)?.iter().map(position).collect()
+}
+
+/// Ramer-Douglas-Peucker: keep the point furthest from the line between
+/// the ends, if it's further than TOLERANCE, and simplify either side of it.
+fn simplify(line: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    if line.len() < 3 {
+        return line.to_vec();
+    }
+    let (first, last) = (line[0], line[line.len() - 1]);
+    let (furthest, distance) = line[1..line.len() - 1]
+        .iter()
+        .enumerate()
+        .map(|(i, &point)| (i + 1, distance_to_segment(point, first, last)))
+        .fold((0, 0.0), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        });
+    if distance <= TOLERANCE {
+        return vec![first, last];
+    }
+    let mut simplified = simplify(&line[..=furthest]);
+    simplified.pop();
+    simplified.extend(simplify(&line[furthest..]));
+    simplified
+}
+
+fn distance_to_segment(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+    let t = if length == 0.0 {
+        0.0
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length)
+            .max(0.0)
+            .min(1.0)
+    };
+    let (x, y) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - x).powi(2) + (p.1 - y).powi(2)).sqrt()
+}
+
+/// Web Mercator, as on most maps, with y growing southward.
+fn mercator((lon, lat): (f64, f64)) -> (f64, f64) {
+    let lat = lat.max(-85.0).min(85.0).to_radians();
+    let x = (lon + 180.0) / 360.0;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0;
+    (x, y)
+}
+
+/// Draw a three pixel wide line.
+fn draw_line(img: &mut RgbImage, (x0, y0): (i64, i64), (x1, y1): (i64, i64)) {
+    let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+    let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+    let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+    loop {
+        dot(img, (x, y), 1);
+        if x == x1 && y == y1 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+/// Fill the square of the given radius around a pixel.
+fn dot(img: &mut RgbImage, (x, y): (i64, i64), radius: i64) {
+    for py in y - radius..=y + radius {
+        for px in x - radius..=x + radius {
+            if px >= 0 && py >= 0 && px < i64::from(img.width()) && py < i64::from(img.height()) {
+                img.put_pixel(px as u32, py as u32, TRACE_COLOR);
+            }
+        }
+    }
+}
+
+/// The key of the simplified copy of the trace stored as `filename`.
+pub fn simplified_key(filename: &str) -> String {
+    format!("{}/{}", SIMPLIFIED_PREFIX, filename)
+}
+
+/// Store the simplified copy of a trace.
+pub async fn store_simplified(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    filename: &str,
+    trace: &Trace,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(&trace.simplified().to_geojson()).map_err(|e| e.to_string())?;
+    let put_request = PutObjectRequest {
+        body: Some(body.into()),
+        content_type: Some("application/geo+json".to_string()),
+        ..site.put_object_request_for(&simplified_key(filename))
+    };
+    s3_client
+        .put_object(put_request)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to store the simplified trace: {}", e))
+}
+
+/// Query parameters accepted when serving a preview.
+#[derive(Deserialize)]
+struct PreviewOptions {
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+/// Draw a trace from its simplified copy.
+async fn serve_preview(
+    req: HttpRequest,
+    options: web::Query<PreviewOptions>,
+    config: web::Data<SiteConfig>,
+    buckets: web::Data<ReadBuckets>,
+) -> Result<HttpResponse, Error> {
+    let filename = req
+        .match_info()
+        .get("filename")
+        .ok_or(ErrorBadRequest("Bad URI"))?;
+    let width = options.width.unwrap_or(DEFAULT_PREVIEW_SIZE.0);
+    let height = options.height.unwrap_or(DEFAULT_PREVIEW_SIZE.1);
+    if width == 0 || height == 0 || width > MAX_PREVIEW_EDGE || height > MAX_PREVIEW_EDGE {
+        return Err(ErrorBadRequest("Bad size"));
+    }
+
+    let (bucket, key) = config.locate(&simplified_key(filename));
+    let get_request = GetObjectRequest {
+        bucket,
+        key,
+        ..Default::default()
+    };
+    let resp = buckets
+        .get_object(get_request)
+        .await
+        .map_err(media::not_found_or)?;
+    let mut data = Vec::new();
+    resp.body
+        .ok_or(ErrorNotFound("Not found"))?
+        .into_async_read()
+        .read_to_end(&mut data)
+        .await?;
+
+    let png = web::block(move || -> Result<Vec<u8>, String> {
+        let text = String::from_utf8_lossy(&data);
+        let trace = Trace::from_geojson(&text)?;
+        trace.render(width, height).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .header(header::CONTENT_TYPE, "image/png")
+        .body(png))
+}
@@ -0,0 +1,144 @@
+use log::{error, info};
+
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use actix_web::web;
+
+use crate::SiteConfig;
+
+/// Key prefix for HLS renditions. Each video gets a directory below this.
+pub const HLS_PREFIX: &str = "video/hls";
+
+/// The name of the playlist within each rendition directory.
+pub const PLAYLIST: &str = "index.m3u8";
+
+/// Content type for a file produced by the HLS packager.
+pub fn content_type_for(filename: &str) -> Option<&'static str> {
+    match filename.rsplit('.').next() {
+        Some("m3u8") => Some("application/vnd.apple.mpegurl"),
+        Some("ts") => Some("video/mp2t"),
+        _ => None,
+    }
+}
+
+/// The public URL of the playlist for the video with the given id.
+pub fn playlist_url(site: &SiteConfig, id: &str) -> String {
+    format!("{}/{}/{}/{}", site.media_url(), HLS_PREFIX, id, PLAYLIST)
+}
+
+/// Package the video as HLS if it exceeds the configured size or duration.
+///
+/// This shells out to ffmpeg and may take a while, so it is meant to be
+/// spawned after the original upload has been acknowledged.
+pub async fn package(site: SiteConfig, s3_client: S3Client, id: String, data: Vec<u8>) {
+    let workdir = std::env::temp_dir().join(format!("hls-{}", id));
+    let size = data.len() as u64;
+    let dir = workdir.clone();
+    let min_bytes = site.hls_min_bytes();
+    let min_duration = site.hls_min_duration();
+    let segment_seconds = site.hls_segment_seconds();
+
+    let result = web::block(move || -> Result<bool, String> {
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let source = dir.join("source");
+        fs::write(&source, &data).map_err(|e| e.to_string())?;
+
+        let long_enough = match min_duration {
+            Some(min) => probe_duration(&source).map_or(false, |d| d >= min),
+            None => false,
+        };
+        let big_enough = min_bytes.map_or(false, |min| size >= min);
+        if !long_enough && !big_enough {
+            return Ok(false);
+        }
+
+        segment(&source, &dir.join("out"), segment_seconds)?;
+        Ok(true)
+    })
+    .await;
+
+    match result {
+        Ok(true) => match upload(&site, &s3_client, &id, &workdir.join("out")).await {
+            Ok(count) => info!("Packaged {} as HLS with {} files", id, count),
+            Err(e) => error!("Failed to upload HLS rendition for {}: {}", id, e),
+        },
+        Ok(false) => (),
+        Err(e) => error!("Failed to package {} as HLS: {}", id, e),
+    }
+
+    if let Err(e) = fs::remove_dir_all(&workdir) {
+        error!("Failed to clean up {}: {}", workdir.display(), e);
+    }
+}
+
+/// Get the duration of a video, in seconds.
+pub(crate) fn probe_duration(source: &Path) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args(&["-v", "error", "-show_entries", "format=duration"])
+        .args(&["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(source)
+        .output()
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// Split the video into segments and write the playlist to `out`.
+fn segment(source: &Path, out: &Path, segment_seconds: u32) -> Result<(), String> {
+    fs::create_dir_all(out).map_err(|e| e.to_string())?;
+
+    let status = Command::new("ffmpeg")
+        .args(&["-v", "error", "-i"])
+        .arg(source)
+        .args(&["-codec:v", "libx264", "-codec:a", "aac"])
+        .args(&["-hls_time", &segment_seconds.to_string()])
+        .args(&["-hls_playlist_type", "vod", "-hls_segment_filename"])
+        .arg(out.join("segment%04d.ts"))
+        .arg(out.join(PLAYLIST))
+        .status()
+        .map_err(|e| e.to_string())?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("ffmpeg exited with {}", status))
+    }
+}
+
+/// Upload every file in `dir` to the rendition directory for `id`.
+async fn upload(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    id: &str,
+    dir: &Path,
+) -> Result<usize, String> {
+    let entries = fs::read_dir(dir).map_err(|e| e.to_string())?;
+
+    let mut count = 0;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let filename = match path.file_name().and_then(|f| f.to_str()) {
+            Some(f) => f.to_string(),
+            None => continue,
+        };
+        let body = fs::read(&path).map_err(|e| e.to_string())?;
+
+        let put_request = PutObjectRequest {
+            body: Some(body.into()),
+            content_type: content_type_for(&filename).map(|s| s.to_string()),
+            ..site.put_object_request_for(&format!("{}/{}/{}", HLS_PREFIX, id, filename))
+        };
+
+        s3_client
+            .put_object(put_request)
+            .await
+            .map_err(|e| e.to_string())?;
+        count += 1;
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,146 @@
+use chrono::Utc;
+use log::warn;
+use rand::{thread_rng, Rng};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::micropub::random_id;
+use crate::SiteConfig;
+
+/// Crockford's base32, which ULIDs are written in.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// URL-safe characters for nanoids.
+const NANOID_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_-";
+
+const DEFAULT_NANOID_LENGTH: usize = 21;
+const MAX_NANOID_LENGTH: usize = 64;
+
+/// Bits of the counter which orders IDs made in the same millisecond.
+const SEQUENCE_BITS: u32 = 12;
+
+/// The last (millisecond << SEQUENCE_BITS | sequence) handed out.
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// How the ID part of a new key is generated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdScheme {
+    /// Base32 seconds since the epoch and 7 random characters, e.g.
+    /// `AF3QB4-x8Kd2Qz`. This is the original scheme.
+    Base32,
+    /// A 26 character ULID.
+    Ulid,
+    /// A UUID version 7.
+    UuidV7,
+    /// A random, unordered ID of the given length.
+    Nanoid { length: usize },
+}
+
+impl IdScheme {
+    /// Parse the scheme: `base32`, `ulid`, `uuidv7`, or `nanoid[:<length>]`.
+    pub fn parse(value: &str) -> Option<IdScheme> {
+        let mut parts = value.trim().splitn(2, ':');
+        let scheme = match (parts.next()?, parts.next()) {
+            ("base32", None) => IdScheme::Base32,
+            ("ulid", None) => IdScheme::Ulid,
+            ("uuidv7", None) => IdScheme::UuidV7,
+            ("nanoid", None) => IdScheme::Nanoid {
+                length: DEFAULT_NANOID_LENGTH,
+            },
+            ("nanoid", Some(length)) => match length.parse() {
+                Ok(length) if (1..=MAX_NANOID_LENGTH).contains(&length) => {
+                    IdScheme::Nanoid { length }
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(scheme)
+    }
+
+    /// The site's scheme, from ID_SCHEME.
+    pub fn for_site(site: &SiteConfig) -> IdScheme {
+        match site.id_scheme() {
+            Some(value) => IdScheme::parse(value).unwrap_or_else(|| {
+                warn!("Ignoring invalid ID_SCHEME {:?}", value);
+                IdScheme::Base32
+            }),
+            None => IdScheme::Base32,
+        }
+    }
+
+    /// A new ID.
+    ///
+    /// ULIDs and UUIDv7s begin with the time in milliseconds, followed by a
+    /// counter, so IDs made by this process always sort in the order they
+    /// were made, even within the same millisecond or if the clock steps back.
+    pub fn generate(self) -> String {
+        match self {
+            IdScheme::Base32 => random_id(),
+            IdScheme::Ulid => ulid(),
+            IdScheme::UuidV7 => uuid_v7(),
+            IdScheme::Nanoid { length } => nanoid(length),
+        }
+    }
+}
+
+/// A new ID for an upload to the site.
+pub fn new_id(site: &SiteConfig) -> String {
+    IdScheme::for_site(site).generate()
+}
+
+/// The next (millisecond, sequence), always after the last one.
+fn next_tick() -> (u64, u64) {
+    let now = (Utc::now().timestamp_millis().max(0) as u64) << SEQUENCE_BITS;
+    let mut last = LAST_TICK.load(Ordering::Relaxed);
+    loop {
+        // Past the last sequence number, this borrows from the next millisecond.
+        let next = if now > last { now } else { last + 1 };
+        match LAST_TICK.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return (next >> SEQUENCE_BITS, next & ((1_u64 << SEQUENCE_BITS) - 1)),
+            Err(actual) => last = actual,
+        }
+    }
+}
+
+/// 48 bits of milliseconds, 12 of sequence, and 68 random.
+fn ulid() -> String {
+    let (millis, sequence) = next_tick();
+    let random = thread_rng().gen::<u128>() & ((1_u128 << 68) - 1);
+    let value = (u128::from(millis) << 80) | (u128::from(sequence) << 68) | random;
+
+    // 26 characters of 5 bits each; the first holds only the top 3.
+    (0..26)
+        .map(|i| CROCKFORD[((value >> (125 - 5 * i)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// 48 bits of milliseconds, the version, the sequence in `rand_a`, the
+/// variant, and 62 random bits.
+fn uuid_v7() -> String {
+    let (millis, sequence) = next_tick();
+    let random = thread_rng().gen::<u64>() & ((1_u64 << 62) - 1);
+    let value = (u128::from(millis) << 80)
+        | (0x7_u128 << 76)
+        | (u128::from(sequence) << 64)
+        | (0b10_u128 << 62)
+        | u128::from(random);
+
+    let hex = format!("{:032x}", value);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn nanoid(length: usize) -> String {
+    let mut rng = thread_rng();
+    (0..length)
+        .map(|_| NANOID_ALPHABET[rng.gen_range(0, NANOID_ALPHABET.len())] as char)
+        .collect()
+}
@@ -0,0 +1,359 @@
+use actix_web::client::Client;
+use log::{error, info};
+
+use rusoto_s3::{GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+use tokio::io::AsyncReadExt;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::classify;
+use crate::events;
+use crate::geo::{self, Trace};
+use crate::hls;
+use crate::index::{MediaIndex, MediaRecord};
+use crate::keys;
+use crate::media;
+use crate::micropub::{inspect_upload, warms, Placement};
+use crate::multipart;
+use crate::peaks;
+use crate::previews;
+use crate::quarantine;
+use crate::SiteConfig;
+
+/// Where existing media is imported from.
+pub enum Source {
+    /// A directory tree on this machine.
+    Directory(PathBuf),
+    /// The objects below a prefix of another bucket, e.g.
+    /// `s3://old-bucket/uploads/`.
+    Bucket { bucket: String, prefix: String },
+}
+
+impl Source {
+    /// Parse an `s3://bucket/prefix` URL, or else a directory path.
+    pub fn parse(source: &str) -> Source {
+        match source.strip_prefix("s3://") {
+            Some(rest) => {
+                let mut parts = rest.splitn(2, '/');
+                Source::Bucket {
+                    bucket: parts.next().unwrap_or_default().to_string(),
+                    prefix: parts.next().unwrap_or_default().to_string(),
+                }
+            }
+            None => Source::Directory(PathBuf::from(source)),
+        }
+    }
+}
+
+/// What an import did.
+#[derive(Default)]
+pub struct ImportReport {
+    /// Each imported file's path within the source and its new URL.
+    pub imported: Vec<(String, String)>,
+    /// Files which couldn't be imported.
+    pub failed: usize,
+}
+
+/// Store a file as if it was uploaded. It's inspected and moderated like an
+/// upload, and refused, or held for review if QUARANTINE is set, if it fails.
+pub(crate) async fn store(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    data: Vec<u8>,
+    content_type: &mime::Mime,
+    filename: Option<&str>,
+    author: &str,
+) -> Result<Placement, String> {
+    let classification = classify::classify(site, content_type, filename);
+    let size = Some(data.len() as u64);
+    classify::check_limits(site, &classification.name, content_type, size)
+        .map_err(|e| e.to_string())?;
+
+    let checksum = events::checksum(&data);
+    let placement = Placement::unused(
+        site,
+        s3_client,
+        content_type,
+        filename,
+        author,
+        Some(&checksum),
+        keys::date_for(site, Some(&data)),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let object_key = placement.object_key();
+
+    let reason = match inspect_upload(site, http_client, &placement, content_type, &data).await {
+        Ok(None) => None,
+        Ok(Some(reason)) => Some(reason),
+        Err(resp) => {
+            return Err(format!(
+                "Failed to inspect {}: {}",
+                object_key,
+                resp.status()
+            ))
+        }
+    };
+
+    let mut metadata = HashMap::new();
+    metadata.insert("sha256".to_string(), checksum);
+    if let Some(filename) = filename {
+        metadata.insert("filename".to_string(), crate::metadata::encode(filename));
+    }
+    if !author.is_empty() {
+        metadata.insert("author".to_string(), author.to_string());
+    }
+
+    if let Some(reason) = reason {
+        if !site.quarantine() {
+            return Err(format!("{} was rejected: {}", object_key, reason));
+        }
+        let held = quarantine::Held {
+            key: &object_key,
+            author,
+            reason: &reason,
+            body: data,
+            content_type: content_type.to_string(),
+            metadata,
+        };
+        let resp = quarantine::hold(site, s3_client, held).await;
+        if !resp.status().is_success() {
+            return Err(format!("Failed to hold {}: {}", object_key, resp.status()));
+        }
+        return Err(format!("{} was held for review: {}", object_key, reason));
+    }
+
+    let put_request = PutObjectRequest {
+        metadata: Some(metadata),
+        content_type: Some(content_type.to_string()),
+        ..site.put_object_request_for(&object_key)
+    };
+    multipart::put_object(site, s3_client, put_request, data)
+        .await
+        .map_err(|e| format!("Failed to store {}: {}", object_key, e))?;
+
+    Ok(placement)
+}
+
+/// Import every file in `source` into the bucket's key layout, with new IDs,
+/// checksums, index records, and the derivatives an upload would get.
+///
+/// Files are imported one at a time and derivatives are generated before
+/// moving on, since the process exits when the import is done. A file which
+/// can't be imported is logged and skipped.
+pub async fn import(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    index: Option<&MediaIndex>,
+    source: &Source,
+    author: &str,
+) -> Result<ImportReport, String> {
+    let paths = match source {
+        Source::Directory(root) => {
+            let mut paths = Vec::new();
+            walk(root, root, &mut paths)
+                .map_err(|e| format!("Failed to list {}: {}", root.display(), e))?;
+            paths
+        }
+        Source::Bucket { bucket, prefix } => list_bucket(s3_client, bucket, prefix).await?,
+    };
+    info!("Importing {} files", paths.len());
+
+    let mut report = ImportReport::default();
+    for path in paths {
+        let data = match source {
+            Source::Directory(root) => fs::read(root.join(&path)).map_err(|e| e.to_string()),
+            Source::Bucket { bucket, prefix } => {
+                read_object(s3_client, bucket, &format!("{}{}", prefix, path)).await
+            }
+        };
+        let result = match data {
+            Ok(data) => import_file(site, s3_client, http_client, index, &path, data, author).await,
+            Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+        };
+        match result {
+            Ok(url) => {
+                info!("Imported {} as {}", path, url);
+                report.imported.push((path, url));
+            }
+            Err(e) => {
+                error!("{}", e);
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn import_file(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    index: Option<&MediaIndex>,
+    path: &str,
+    data: Vec<u8>,
+    author: &str,
+) -> Result<String, String> {
+    let filename = path.rsplit('/').next();
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    let trace = if geo::is_trace(&content_type, filename) {
+        let trace = Trace::parse(&content_type, filename, &data);
+        Some(trace.map_err(|e| format!("Failed to import {}: {}", path, e))?)
+    } else {
+        None
+    };
+
+    let size = data.len() as u64;
+    let placement = store(
+        site,
+        s3_client,
+        http_client,
+        data.clone(),
+        &content_type,
+        filename,
+        author,
+    )
+    .await
+    .map_err(|e| format!("Failed to import {}: {}", path, e))?;
+    let object_key = placement.object_key();
+    let url = placement.url(site);
+
+    if let Some(index) = index {
+        let record = MediaRecord {
+            key: object_key.clone(),
+            url: url.clone(),
+            classification: placement.classification.clone(),
+            content_type: Some(content_type.to_string()),
+            size: Some(size as i64),
+            author: author.to_string(),
+            client_id: String::new(),
+            checksum: Some(events::checksum(&data)),
+            filename: filename.map(str::to_string),
+            alt: None,
+            caption: None,
+            created_at: placement.date,
+        };
+        if let Err(e) = index.insert(&record).await {
+            error!("Failed to add {} to the index: {}", object_key, e);
+        }
+    }
+
+    if let Some(trace) = trace {
+        if let Err(e) = geo::store_simplified(site, s3_client, &placement.key, &trace).await {
+            error!("{}", e);
+        }
+    }
+    if warms(site, &placement) {
+        media::warm(
+            site.clone(),
+            s3_client.clone(),
+            placement.key.clone(),
+            data.clone(),
+        )
+        .await;
+    }
+    if placement.classification == "video" && site.hls_enabled() {
+        hls::package(
+            site.clone(),
+            s3_client.clone(),
+            placement.id.clone(),
+            data.clone(),
+        )
+        .await;
+    }
+    if placement.classification == "video" && site.video_previews() {
+        previews::generate(site.clone(), s3_client.clone(), object_key, data.clone()).await;
+    }
+    if placement.classification == "audio" && site.audio_peaks() {
+        peaks::generate(site.clone(), s3_client.clone(), placement.key.clone(), data).await;
+    }
+
+    Ok(url)
+}
+
+/// Collect the paths of the files below `dir`, relative to `root`, skipping
+/// hidden files and directories.
+fn walk(root: &Path, dir: &Path, paths: &mut Vec<String>) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            walk(root, &path, paths)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            let parts: Vec<_> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            paths.push(parts.join("/"));
+        }
+    }
+    Ok(())
+}
+
+/// The keys below `prefix` in another bucket, relative to the prefix.
+async fn list_bucket(
+    s3_client: &S3Client,
+    bucket: &str,
+    prefix: &str,
+) -> Result<Vec<String>, String> {
+    let mut paths = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_string(),
+            prefix: Some(prefix.to_string()),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = s3_client
+            .list_objects_v2(request)
+            .await
+            .map_err(|e| format!("Failed to list s3://{}/{}: {}", bucket, prefix, e))?;
+        for object in response.contents.unwrap_or_default() {
+            if let Some(key) = object.key {
+                // Folder placeholders made by the S3 console.
+                if key.ends_with('/') {
+                    continue;
+                }
+                if let Some(path) = key.strip_prefix(prefix) {
+                    paths.push(path.to_string());
+                }
+            }
+        }
+
+        if response.is_truncated != Some(true) {
+            break;
+        }
+        continuation_token = response.next_continuation_token;
+    }
+    Ok(paths)
+}
+
+async fn read_object(s3_client: &S3Client, bucket: &str, key: &str) -> Result<Vec<u8>, String> {
+    let request = GetObjectRequest {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        ..Default::default()
+    };
+    let response = s3_client
+        .get_object(request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let body = response
+        .body
+        .ok_or_else(|| "The object is empty".to_string())?;
+    let mut data = Vec::new();
+    body.into_async_read()
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(data)
+}
@@ -0,0 +1,389 @@
+use actix_rt::time::delay_for;
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::web;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::error;
+use serde::Serialize;
+use sqlx::any::{AnyPool, AnyPoolOptions, AnyRow};
+use sqlx::Row;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::access_log::response_bytes;
+use crate::phash;
+
+/// How often bytes served are written to the database.
+const SERVED_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Creates the index table. The types work for both SQLite and Postgres.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS media (
+    key TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    classification TEXT NOT NULL,
+    content_type TEXT,
+    size BIGINT,
+    author TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    checksum TEXT,
+    filename TEXT,
+    alt TEXT,
+    caption TEXT,
+    created_at BIGINT NOT NULL
+)";
+
+/// Bytes served per object per month, e.g. `2020-06`.
+const SERVED_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS served (
+    month TEXT NOT NULL,
+    key TEXT NOT NULL,
+    bytes BIGINT NOT NULL,
+    PRIMARY KEY (month, key)
+)";
+
+/// Perceptual hashes of photos, kept apart from the media table so existing
+/// databases don't need migrating.
+const PHASH_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS phashes (
+    key TEXT PRIMARY KEY,
+    author TEXT NOT NULL,
+    phash TEXT NOT NULL
+)";
+
+const COLUMNS: &str =
+    "key, url, classification, content_type, size, author, client_id, checksum, filename, alt, caption, created_at";
+
+/// A row in the metadata index.
+#[derive(Serialize, Clone, Debug)]
+pub struct MediaRecord {
+    pub key: String,
+    pub url: String,
+    pub classification: String,
+    pub content_type: Option<String>,
+    pub size: Option<i64>,
+    pub author: String,
+    pub client_id: String,
+    pub checksum: Option<String>,
+    pub filename: Option<String>,
+    pub alt: Option<String>,
+    pub caption: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Filters for listing the index.
+#[derive(Default)]
+pub struct ListFilter<'a> {
+    pub author: Option<&'a str>,
+    pub classification: Option<&'a str>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+/// Usage of one media type, or all of them.
+#[derive(Serialize, Default, Clone, Copy, Debug)]
+pub struct Usage {
+    pub objects: i64,
+    pub bytes_stored: i64,
+    pub bytes_served: i64,
+}
+
+/// The stored object a request served, kept in the request extensions by the
+/// media handlers so the bytes can be counted.
+pub struct ServedKey(pub String);
+
+/// An optional database of everything that has been uploaded.
+pub struct MediaIndex {
+    pool: AnyPool,
+    /// Bytes served since the last flush, by month and key.
+    served: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl MediaIndex {
+    /// Connect to a `sqlite://` or `postgres://` URL and create the table if needed.
+    pub async fn connect(url: &str) -> Result<MediaIndex, sqlx::Error> {
+        let pool = AnyPoolOptions::new().max_connections(5).connect(url).await?;
+        sqlx::query(SCHEMA).execute(&pool).await?;
+        sqlx::query(SERVED_SCHEMA).execute(&pool).await?;
+        sqlx::query(PHASH_SCHEMA).execute(&pool).await?;
+        Ok(MediaIndex {
+            pool,
+            served: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Count the bytes sent for the object the response served, if any.
+    /// They're written out periodically.
+    pub fn count_response<B: MessageBody>(&self, res: &ServiceResponse<B>) {
+        if !res.status().is_success() {
+            return;
+        }
+        let key = match res.request().extensions().get::<ServedKey>() {
+            Some(ServedKey(key)) => key.clone(),
+            None => return,
+        };
+        if let Some(bytes) = response_bytes(res) {
+            let month = Utc::now().format("%Y-%m").to_string();
+            *self.served.lock().unwrap().entry((month, key)).or_insert(0) += bytes;
+        }
+    }
+
+    async fn flush_served(&self) -> Result<(), sqlx::Error> {
+        let served = std::mem::replace(&mut *self.served.lock().unwrap(), HashMap::new());
+        for ((month, key), bytes) in served {
+            sqlx::query(
+                "INSERT INTO served (month, key, bytes) VALUES ($1, $2, $3)
+                 ON CONFLICT (month, key) DO UPDATE SET bytes = served.bytes + excluded.bytes",
+            )
+            .bind(&month)
+            .bind(&key)
+            .bind(bytes as i64)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Periodically write the bytes served to the database.
+    pub async fn flush_served_forever(index: web::Data<MediaIndex>) {
+        loop {
+            delay_for(SERVED_FLUSH_INTERVAL).await;
+            if let Err(e) = index.flush_served().await {
+                error!("Failed to record bytes served: {}", e);
+            }
+        }
+    }
+
+    /// The author's usage by media type, with bytes served in `month`.
+    pub async fn usage(
+        &self,
+        author: &str,
+        month: &str,
+    ) -> Result<BTreeMap<String, Usage>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT m.classification AS classification,
+                    COUNT(*) AS objects,
+                    CAST(COALESCE(SUM(m.size), 0) AS BIGINT) AS bytes_stored,
+                    CAST(COALESCE(SUM(s.bytes), 0) AS BIGINT) AS bytes_served
+             FROM media m
+             LEFT JOIN served s ON s.key = m.key AND s.month = $2
+             WHERE m.author = $1
+             GROUP BY m.classification",
+        )
+        .bind(author)
+        .bind(month)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.try_get("classification")?,
+                    Usage {
+                        objects: row.try_get("objects")?,
+                        bytes_stored: row.try_get("bytes_stored")?,
+                        bytes_served: row.try_get("bytes_served")?,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    pub async fn insert(&self, record: &MediaRecord) -> Result<(), sqlx::Error> {
+        let sql = format!(
+            "INSERT INTO media ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            COLUMNS
+        );
+        sqlx::query(&sql)
+            .bind(&record.key)
+            .bind(&record.url)
+            .bind(&record.classification)
+            .bind(&record.content_type)
+            .bind(record.size)
+            .bind(&record.author)
+            .bind(&record.client_id)
+            .bind(&record.checksum)
+            .bind(&record.filename)
+            .bind(&record.alt)
+            .bind(&record.caption)
+            .bind(record.created_at.timestamp())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM media WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM phashes WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Record a photo's perceptual hash.
+    pub async fn insert_phash(
+        &self,
+        key: &str,
+        author: &str,
+        hash: u64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO phashes (key, author, phash) VALUES ($1, $2, $3)
+             ON CONFLICT (key) DO UPDATE SET phash = excluded.phash",
+        )
+        .bind(key)
+        .bind(author)
+        .bind(phash::encode(hash))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The URL of the author's photo whose hash is nearest to `hash`, if it's
+    /// within `max_distance` bits.
+    pub async fn similar(
+        &self,
+        author: &str,
+        hash: u64,
+        max_distance: u32,
+    ) -> Result<Option<String>, sqlx::Error> {
+        // Neither database can count bits, so every hash is compared here.
+        let rows = sqlx::query(
+            "SELECT m.url AS url, p.phash AS phash
+             FROM phashes p
+             JOIN media m ON m.key = p.key
+             WHERE p.author = $1",
+        )
+        .bind(author)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut nearest = None;
+        for row in &rows {
+            let stored: String = row.try_get("phash")?;
+            let distance = match phash::decode(&stored) {
+                Some(stored) => phash::distance(stored, hash),
+                None => continue,
+            };
+            if distance <= max_distance && nearest.as_ref().map_or(true, |(d, _)| distance < *d) {
+                nearest = Some((distance, row.try_get("url")?));
+            }
+        }
+        Ok(nearest.map(|(_, url)| url))
+    }
+
+    /// The key of every record.
+    pub async fn keys(&self) -> Result<HashSet<String>, sqlx::Error> {
+        let rows = sqlx::query("SELECT key FROM media")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|row| row.try_get("key")).collect()
+    }
+
+    /// Move a record to a new key and URL.
+    pub async fn rename(&self, key: &str, new_key: &str, new_url: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE media SET key = $1, url = $2 WHERE key = $3")
+            .bind(new_key)
+            .bind(new_url)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("UPDATE phashes SET key = $1 WHERE key = $2")
+            .bind(new_key)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// List records matching the filter, newest first.
+    pub async fn list(&self, filter: &ListFilter<'_>) -> Result<Vec<MediaRecord>, sqlx::Error> {
+        // Unused filters compare NULL, which keeps the placeholders fixed.
+        let sql = format!(
+            "SELECT {} FROM media
+             WHERE ($1 IS NULL OR author = $1)
+               AND ($2 IS NULL OR classification = $2)
+               AND ($3 IS NULL OR created_at >= $3)
+               AND ($4 IS NULL OR created_at < $4)
+             ORDER BY created_at DESC, key DESC
+             LIMIT $5 OFFSET $6",
+            COLUMNS
+        );
+        let rows = sqlx::query(&sql)
+            .bind(filter.author)
+            .bind(filter.classification)
+            .bind(filter.since.map(|t| t.timestamp()))
+            .bind(filter.until.map(|t| t.timestamp()))
+            .bind(filter.limit)
+            .bind(filter.offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter().map(record_from_row).collect()
+    }
+
+    /// Find the author's records whose filename, alt text, or caption contains the query.
+    pub async fn search(
+        &self,
+        author: &str,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<MediaRecord>, sqlx::Error> {
+        let sql = format!(
+            "SELECT {} FROM media
+             WHERE author = $1
+               AND (LOWER(filename) LIKE $2 ESCAPE '\\'
+                 OR LOWER(alt) LIKE $2 ESCAPE '\\'
+                 OR LOWER(caption) LIKE $2 ESCAPE '\\')
+             ORDER BY created_at DESC, key DESC
+             LIMIT $3 OFFSET $4",
+            COLUMNS
+        );
+        let rows = sqlx::query(&sql)
+            .bind(author)
+            .bind(like_pattern(query))
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.iter().map(record_from_row).collect()
+    }
+}
+
+/// A case-insensitive LIKE pattern matching `query` anywhere.
+fn like_pattern(query: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in query.to_lowercase().chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+fn record_from_row(row: &AnyRow) -> Result<MediaRecord, sqlx::Error> {
+    let created_at: i64 = row.try_get("created_at")?;
+    Ok(MediaRecord {
+        key: row.try_get("key")?,
+        url: row.try_get("url")?,
+        classification: row.try_get("classification")?,
+        content_type: row.try_get("content_type")?,
+        size: row.try_get("size")?,
+        author: row.try_get("author")?,
+        client_id: row.try_get("client_id")?,
+        checksum: row.try_get("checksum")?,
+        filename: row.try_get("filename")?,
+        alt: row.try_get("alt")?,
+        caption: row.try_get("caption")?,
+        created_at: DateTime::from_utc(NaiveDateTime::from_timestamp(created_at, 0), Utc),
+    })
+}
@@ -0,0 +1,173 @@
+use actix_web::client::Client;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{error, warn};
+use serde::Deserialize;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::oauth::AccessToken;
+use crate::SiteConfig;
+
+/// Don't refetch the key set more often than this when a token names an
+/// unknown key, so junk tokens can't make us hammer the auth server.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Validates signed JWT access tokens locally against a JSON Web Key Set.
+///
+/// Only RSA keys are supported.
+pub struct JwtValidator {
+    jwks_url: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+    keys: Mutex<KeyCache>,
+}
+
+#[derive(Default)]
+struct KeyCache {
+    /// RSA modulus and exponent, by key id.
+    keys: HashMap<String, (String, String)>,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+/// The claims we care about. IndieAuth servers put the user in `me`, others
+/// in `sub`.
+#[derive(Deserialize)]
+struct Claims {
+    me: Option<String>,
+    sub: Option<String>,
+    client_id: Option<String>,
+    azp: Option<String>,
+    #[serde(default)]
+    scope: String,
+}
+
+impl JwtValidator {
+    /// A validator for the site, if JWKS_URL is configured.
+    pub fn for_site(site: &SiteConfig) -> Option<JwtValidator> {
+        site.jwks_url().map(|jwks_url| JwtValidator {
+            jwks_url: jwks_url.to_string(),
+            issuer: site.jwt_issuer().map(str::to_string),
+            audience: site.jwt_audience().map(str::to_string),
+            keys: Mutex::new(KeyCache::default()),
+        })
+    }
+
+    /// Check the signature, expiry, issuer, and audience of the bearer token
+    /// in the Authorization header.
+    ///
+    /// Returns None if the token is not acceptable.
+    pub async fn validate(&self, client: &Client, auth_token: &str) -> Option<AccessToken> {
+        let token = auth_token
+            .strip_prefix("Bearer ")
+            .or_else(|| auth_token.strip_prefix("bearer "))?
+            .trim();
+
+        let header = decode_header(token).ok()?;
+        match header.alg {
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 => (),
+            alg => {
+                warn!("Rejecting JWT signed with {:?}", alg);
+                return None;
+            }
+        }
+        let kid = header.kid.unwrap_or_default();
+
+        let (n, e) = match self.key(&kid) {
+            Some(key) => key,
+            None => {
+                self.refresh(client).await;
+                self.key(&kid)?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.iss = self.issuer.clone();
+        validation.aud = self.audience.as_ref().map(|aud| {
+            let mut set = HashSet::new();
+            set.insert(aud.clone());
+            set
+        });
+
+        let claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_rsa_components(&n, &e),
+            &validation,
+        )
+        .map_err(|err| warn!("Rejecting JWT: {}", err))
+        .ok()?
+        .claims;
+
+        Some(AccessToken::new(
+            claims.me.or(claims.sub)?,
+            claims.client_id.or(claims.azp).unwrap_or_default(),
+            claims.scope,
+        ))
+    }
+
+    /// The named key, or the only key if the token didn't name one.
+    fn key(&self, kid: &str) -> Option<(String, String)> {
+        let cache = self.keys.lock().unwrap();
+        match cache.keys.get(kid) {
+            Some(key) => Some(key.clone()),
+            None if kid.is_empty() && cache.keys.len() == 1 => cache.keys.values().next().cloned(),
+            None => None,
+        }
+    }
+
+    async fn refresh(&self, client: &Client) {
+        {
+            let mut cache = self.keys.lock().unwrap();
+            if let Some(fetched_at) = cache.fetched_at {
+                if fetched_at.elapsed() < MIN_REFRESH_INTERVAL {
+                    return;
+                }
+            }
+            cache.fetched_at = Some(Instant::now());
+        }
+
+        let jwks: Jwks = match client.get(&self.jwks_url).send().await {
+            Ok(mut resp) if resp.status().is_success() => match resp.json().await {
+                Ok(jwks) => jwks,
+                Err(e) => {
+                    error!("Failed to parse JWKS from {}: {}", self.jwks_url, e);
+                    return;
+                }
+            },
+            Ok(resp) => {
+                error!(
+                    "Failed to fetch JWKS from {}: {}",
+                    self.jwks_url,
+                    resp.status()
+                );
+                return;
+            }
+            Err(e) => {
+                error!("Failed to fetch JWKS from {}: {}", self.jwks_url, e);
+                return;
+            }
+        };
+
+        let keys = jwks
+            .keys
+            .into_iter()
+            .filter(|k| k.kty == "RSA")
+            .filter_map(|k| Some((k.kid.unwrap_or_default(), (k.n?, k.e?))))
+            .collect();
+        self.keys.lock().unwrap().keys = keys;
+    }
+}
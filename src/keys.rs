@@ -0,0 +1,201 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+
+use crate::classify::UrlStyle;
+use crate::exif;
+use crate::SiteConfig;
+
+/// Every key starts with its classification's prefix, which routes it to
+/// storage and the serving routes.
+const REQUIRED_START: &str = "{classification}/";
+
+/// What a key template may refer to.
+pub struct KeyVars<'a> {
+    /// The classification's key prefix.
+    pub prefix: &'a str,
+    pub id: &'a str,
+    pub filename: Option<&'a str>,
+    /// The uploader's profile URL.
+    pub author: &'a str,
+    /// The SHA-256 of the content, if it's known when the key is chosen.
+    pub checksum: Option<&'a str>,
+    pub date: DateTime<Utc>,
+}
+
+/// The date a new key is filed under: now, or with KEY_DATE=exif, when the
+/// photo was taken, so backfilled photos sort among their peers.
+pub fn date_for(site: &SiteConfig, data: Option<&[u8]>) -> DateTime<Utc> {
+    if site.key_date_exif() {
+        if let Some(taken) = data.and_then(exif::captured_at) {
+            return DateTime::from_utc(taken, Utc);
+        }
+    }
+    Utc::now()
+}
+
+/// The template for a new key.
+///
+/// KEY_TEMPLATE, e.g. `{classification}/{yyyy}/{mm}/{id}.{ext}`, may use
+/// `{classification}`, `{yyyy}`, `{mm}`, `{dd}`, `{id}`, `{ext}`,
+/// `{filename}`, `{slug}` (the filename, simplified), `{user}` (the
+/// uploader's domain), and `{checksum}`. Without it, keys are laid out as
+/// they always have been.
+pub fn template(site: &SiteConfig, style: UrlStyle) -> &str {
+    match site.key_template() {
+        Some(template) if template.starts_with(REQUIRED_START) => template,
+        Some(template) => {
+            warn!(
+                "Ignoring KEY_TEMPLATE {:?}, which must start with {}",
+                template, REQUIRED_START
+            );
+            default_template(style)
+        }
+        None => default_template(style),
+    }
+}
+
+fn default_template(style: UrlStyle) -> &'static str {
+    match style {
+        UrlStyle::Extension | UrlStyle::Resized => "{classification}/{id}.{ext}",
+        UrlStyle::Filename => "{classification}/{id}/{filename}",
+    }
+}
+
+/// Fill in the template's variables.
+///
+/// A missing extension drops the `.` before it, and a missing filename or
+/// checksum is replaced with the id. Unknown variables are left as written.
+pub fn render(template: &str, vars: &KeyVars) -> String {
+    let mut key = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        key.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 1..end];
+        match value(name, vars) {
+            Some(value) => key.push_str(&value),
+            None if name == "ext" => {
+                if key.ends_with('.') {
+                    key.pop();
+                }
+            }
+            None => key.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    key.push_str(rest);
+    key
+}
+
+fn value(name: &str, vars: &KeyVars) -> Option<String> {
+    let value = match name {
+        "classification" => vars.prefix.to_string(),
+        "yyyy" => vars.date.format("%Y").to_string(),
+        "mm" => vars.date.format("%m").to_string(),
+        "dd" => vars.date.format("%d").to_string(),
+        "id" => vars.id.to_string(),
+        "ext" => extension(vars.filename?)?.to_string(),
+        "filename" => vars.filename.unwrap_or(vars.id).to_string(),
+        "slug" => vars
+            .filename
+            .map(|f| slug(f.rsplitn(2, '.').last().unwrap_or(f)))
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| vars.id.to_string()),
+        "user" => slug(domain(vars.author)),
+        "checksum" => vars.checksum.unwrap_or(vars.id).to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The extension, the same way uploads have always been named: everything
+/// after the last `.`, or the whole name if there isn't one.
+fn extension(filename: &str) -> Option<&str> {
+    filename.rsplit('.').next()
+}
+
+/// The host part of a profile URL.
+fn domain(url: &str) -> &str {
+    let rest = url.splitn(2, "://").nth(1).unwrap_or(url);
+    rest.split(|c| c == '/' || c == '?' || c == '#')
+        .next()
+        .unwrap_or(rest)
+}
+
+/// Lowercase letters and digits, with anything else collapsed to `-`.
+fn slug(value: &str) -> String {
+    let mut slug = String::new();
+    for c in value.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::{render, slug, KeyVars};
+
+    fn vars(filename: Option<&str>) -> KeyVars {
+        KeyVars {
+            prefix: "photo",
+            id: "abc123",
+            filename,
+            author: "https://me.example/",
+            checksum: None,
+            date: "2021-03-04T05:06:07Z".parse::<DateTime<Utc>>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn variables_are_filled_in() {
+        let vars = vars(Some("Cat Pic.JPG"));
+        assert_eq!(
+            render("{classification}/{yyyy}/{mm}/{dd}/{id}.{ext}", &vars),
+            "photo/2021/03/04/abc123.JPG"
+        );
+        assert_eq!(
+            render("{classification}/{user}/{slug}-{id}", &vars),
+            "photo/me-example/cat-pic-abc123"
+        );
+    }
+
+    #[test]
+    fn missing_values_fall_back_to_the_id() {
+        assert_eq!(
+            render("{classification}/{id}.{ext}", &vars(None)),
+            "photo/abc123"
+        );
+        assert_eq!(
+            render("{classification}/{filename}/{checksum}", &vars(None)),
+            "photo/abc123/abc123"
+        );
+        assert_eq!(
+            render("{classification}/{slug}", &vars(Some("???.png"))),
+            "photo/abc123"
+        );
+    }
+
+    #[test]
+    fn unknown_variables_are_left_as_written() {
+        assert_eq!(
+            render("{classification}/{nope}/{id", &vars(None)),
+            "photo/{nope}/{id"
+        );
+    }
+
+    #[test]
+    fn slugs_are_lowercase_words_joined_by_dashes() {
+        assert_eq!(slug("My Holiday -- Day 1!"), "my-holiday-day-1");
+        assert_eq!(slug("  leading"), "leading");
+        assert_eq!(slug("Ünïcode"), "n-code");
+    }
+}
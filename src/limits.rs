@@ -0,0 +1,183 @@
+use bytes::Bytes;
+use futures::channel::oneshot;
+use futures::Stream;
+
+use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::ByteStream;
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// The dispatch error returned when S3 is saturated.
+const SATURATED: &str = "Too many concurrent S3 requests";
+
+/// Caps the number of S3 requests in flight, with a bounded queue of callers
+/// waiting for a turn. Shared by every S3 client, so a burst of traffic is
+/// turned away instead of exhausting sockets.
+#[derive(Clone, Default)]
+pub struct S3Limiter(Option<Arc<Limits>>);
+
+struct Limits {
+    max_in_flight: usize,
+    max_queued: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// A turn to talk to S3, given back when dropped.
+pub struct Permit(Option<Arc<Limits>>);
+
+impl S3Limiter {
+    /// Allow `max_in_flight` requests at once, and `max_queued` more to wait.
+    /// A `max_in_flight` of 0 doesn't limit anything.
+    pub fn new(max_in_flight: usize, max_queued: usize) -> S3Limiter {
+        if max_in_flight == 0 {
+            return S3Limiter(None);
+        }
+        S3Limiter(Some(Arc::new(Limits {
+            max_in_flight,
+            max_queued,
+            state: Mutex::new(State::default()),
+        })))
+    }
+
+    /// Whether another request would be turned away.
+    pub fn is_saturated(&self) -> bool {
+        match &self.0 {
+            Some(limits) => {
+                let state = limits.state.lock().unwrap();
+                state.in_flight >= limits.max_in_flight && state.waiting.len() >= limits.max_queued
+            }
+            None => false,
+        }
+    }
+
+    /// Wait for a turn, or fail right away if the queue is full.
+    pub async fn acquire(&self) -> Option<Permit> {
+        let limits = match &self.0 {
+            Some(limits) => limits,
+            None => return Some(Permit(None)),
+        };
+
+        let turn = {
+            let mut state = limits.state.lock().unwrap();
+            if state.in_flight < limits.max_in_flight {
+                state.in_flight += 1;
+                return Some(Permit(Some(limits.clone())));
+            }
+
+            // Forget callers who stopped waiting.
+            state.waiting.retain(|tx| !tx.is_canceled());
+            if state.waiting.len() >= limits.max_queued {
+                return None;
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            rx
+        };
+        turn.await.ok()
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let limits = match self.0.take() {
+            Some(limits) => limits,
+            None => return,
+        };
+
+        loop {
+            let next = {
+                let mut state = limits.state.lock().unwrap();
+                match state.waiting.pop_front() {
+                    Some(next) => next,
+                    None => {
+                        state.in_flight -= 1;
+                        return;
+                    }
+                }
+            };
+
+            // Hand the turn straight to the next caller in line.
+            match next.send(Permit(Some(limits.clone()))) {
+                Ok(()) => return,
+                // They gave up waiting, so this isn't really a second turn.
+                Err(mut permit) => permit.0 = None,
+            }
+        }
+    }
+}
+
+/// Whether a request failed because S3 was saturated.
+pub fn is_saturated(err: &HttpDispatchError) -> bool {
+    err.to_string() == SATURATED
+}
+
+/// Dispatches S3 requests within the limiter's bounds.
+///
+/// A turn is held until the response body has been read, since the
+/// connection is busy until then.
+pub struct LimitedDispatcher<D> {
+    inner: Arc<D>,
+    limiter: S3Limiter,
+}
+
+impl<D> LimitedDispatcher<D> {
+    pub fn new(inner: D, limiter: S3Limiter) -> LimitedDispatcher<D> {
+        LimitedDispatcher {
+            inner: Arc::new(inner),
+            limiter,
+        }
+    }
+}
+
+impl<D> DispatchSignedRequest for LimitedDispatcher<D>
+where
+    D: DispatchSignedRequest + Send + Sync + 'static,
+{
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let inner = self.inner.clone();
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let permit = match limiter.acquire().await {
+                Some(permit) => permit,
+                None => return Err(HttpDispatchError::new(SATURATED.to_string())),
+            };
+            let mut response = inner.dispatch(request, timeout).await?;
+            let body = std::mem::replace(&mut response.body, ByteStream::from(Vec::new()));
+            response.body = ByteStream::new(HeldBody {
+                body,
+                _permit: permit,
+            });
+            Ok(response)
+        })
+    }
+}
+
+/// A response body that keeps its turn until it's read or dropped.
+struct HeldBody {
+    body: ByteStream,
+    _permit: Permit,
+}
+
+impl Stream for HeldBody {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
@@ -0,0 +1,97 @@
+use actix_web::HttpResponse;
+
+use rusoto_s3::{ListObjectsV2Request, S3Client, S3};
+
+use serde::Serialize;
+
+use crate::media;
+use crate::metadata;
+use crate::micropub::MicropubError;
+use crate::SiteConfig;
+
+#[derive(Serialize)]
+struct BucketListing {
+    items: Vec<ListedObject>,
+    /// Pass back as `continuation` for the next page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ListedObject {
+    key: String,
+    url: String,
+    size: Option<i64>,
+    last_modified: Option<String>,
+}
+
+/// List the objects under `prefix` straight from the bucket, a page at a
+/// time, for endpoints without a metadata index.
+///
+/// The prefix starts with a classification, e.g. `photo/`, so routed
+/// classifications are listed from their own bucket. Without one, only
+/// S3_BUCKET is listed. `after` is a key to start after, and `continuation`
+/// the `next` of the previous page. Unlike the index, the listing isn't
+/// limited to the author's own uploads.
+pub(crate) async fn list_bucket(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    prefix: Option<&str>,
+    after: Option<&str>,
+    continuation: Option<String>,
+    limit: i64,
+) -> HttpResponse {
+    let prefix = prefix.unwrap_or_default().trim_start_matches('/');
+    if is_hidden(site, prefix.split('/').next().unwrap_or_default()) {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "Unknown prefix",
+        ));
+    }
+
+    // The part of the object keys which isn't in the logical keys.
+    let (bucket, located) = site.locate(prefix);
+    let route_prefix = located[..located.len() - prefix.len()].to_string();
+
+    let request = ListObjectsV2Request {
+        bucket,
+        prefix: Some(located),
+        start_after: after.map(|after| format!("{}{}", route_prefix, after)),
+        continuation_token: continuation,
+        max_keys: Some(limit),
+        ..Default::default()
+    };
+    let response = match s3_client.list_objects_v2(request).await {
+        Ok(response) => response,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    let items = response
+        .contents
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|object| {
+            let key = object.key?.strip_prefix(&route_prefix)?.to_string();
+            if is_hidden(site, key.split('/').next().unwrap_or_default()) {
+                return None;
+            }
+            Some(ListedObject {
+                url: format!("{}/{}", site.media_url(), key),
+                key,
+                size: object.size,
+                last_modified: object.last_modified,
+            })
+        })
+        .collect();
+    let next = match response.is_truncated {
+        Some(true) => response.next_continuation_token,
+        _ => None,
+    };
+
+    HttpResponse::Ok().json(BucketListing { items, next })
+}
+
+/// Whether objects under the prefix are never listed.
+fn is_hidden(site: &SiteConfig, prefix: &str) -> bool {
+    media::is_hidden(site, prefix) || prefix == metadata::SIDECAR_PREFIX
+}
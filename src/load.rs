@@ -0,0 +1,82 @@
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageOutputFormat};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::SiteConfig;
+
+/// Resizes waiting for or running on the blocking pool.
+static RESIZES: AtomicUsize = AtomicUsize::new(0);
+
+/// JPEG quality used when the load is normal. It's what the image crate
+/// picks when given just the format.
+const FULL_QUALITY: u8 = 75;
+
+/// Counts as one resize in the queue until it's dropped.
+pub struct Resizing(());
+
+impl Resizing {
+    pub fn start() -> Resizing {
+        RESIZES.fetch_add(1, Ordering::Relaxed);
+        Resizing(())
+    }
+}
+
+impl Drop for Resizing {
+    fn drop(&mut self) {
+        RESIZES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How many resizes are queued or running.
+pub fn queue_depth() -> usize {
+    RESIZES.load(Ordering::Relaxed)
+}
+
+/// How much work goes into making a derivative.
+#[derive(Clone, Copy)]
+pub struct Effort {
+    quality: u8,
+    filter: FilterType,
+    reduced: bool,
+}
+
+impl Effort {
+    pub fn full() -> Effort {
+        Effort {
+            quality: FULL_QUALITY,
+            filter: FilterType::CatmullRom,
+            reduced: false,
+        }
+    }
+
+    /// Full effort, unless more than ADAPTIVE_QUALITY_DEPTH resizes are queued.
+    /// Then JPEGs are encoded at ADAPTIVE_QUALITY with a cheaper filter.
+    pub fn for_site(site: &SiteConfig) -> Effort {
+        match site.adaptive_quality_depth() {
+            Some(depth) if queue_depth() > depth => Effort {
+                quality: site.adaptive_quality(),
+                filter: FilterType::Triangle,
+                reduced: true,
+            },
+            _ => Effort::full(),
+        }
+    }
+
+    /// Whether fidelity was traded away. These derivatives aren't cached.
+    pub fn is_reduced(&self) -> bool {
+        self.reduced
+    }
+
+    pub fn filter(&self) -> FilterType {
+        self.filter
+    }
+
+    /// The encoding for an image in `fmt`.
+    pub fn output(&self, fmt: ImageFormat) -> ImageOutputFormat {
+        match fmt {
+            ImageFormat::Jpeg => ImageOutputFormat::Jpeg(self.quality),
+            fmt => fmt.into(),
+        }
+    }
+}
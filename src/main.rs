@@ -1,88 +1,290 @@
-use actix_web::client::Client;
-use actix_web::{middleware, web, App, HttpServer};
+use actix_http::HttpService;
+use actix_server::Server;
+use actix_service::map_config;
+use actix_web::dev::AppConfig;
+use actix_web::{middleware, App};
+use clap::{App as Cli, AppSettings, Arg, SubCommand};
 
-use rusoto_core::Region;
-use rusoto_s3::S3Client;
+use s3_media_endpoint_rs::{MediaEndpoint, SiteConfig};
 
-use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
 
-mod media;
-mod micropub;
-mod oauth;
-
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(rename_all = "PascalCase")]
-pub struct SiteConfig {
-    bind: String,
-
-    media_url: String,
-    token_endpoint: String,
-    s3_bucket: String,
-
-    default_width: u32,
-    default_height: u32,
-}
-
-impl SiteConfig {
-    pub fn bind(&self) -> &str {
-        &self.bind
-    }
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    let matches = Cli::new("s3-media-endpoint-rs")
+        .about("A Micropub media endpoint backed by S3")
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(SubCommand::with_name("serve").about("Run the server (the default)"))
+        .subcommand(
+            SubCommand::with_name("upload")
+                .about("Store a file and print its URL")
+                .arg(Arg::with_name("file").required(true))
+                .arg(
+                    Arg::with_name("content-type")
+                        .long("content-type")
+                        .takes_value(true)
+                        .help("Defaults to a guess from the file extension"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List stored media")
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .takes_value(true)
+                        .help("A key prefix, e.g. photo, audio, video, or file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("purge")
+                .about("Delete the cached derivatives of a photo")
+                .arg(
+                    Arg::with_name("key")
+                        .required(true)
+                        .help("e.g. photo/abc.jpg"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .about("Cache the default size and WARM_WIDTHS of every photo missing them"),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Abort abandoned uploads and remove stale derivatives, trash, and shares"),
+        )
+        .subcommand(
+            SubCommand::with_name("reconcile")
+                .about("Find differences between the index and the bucket")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Add missing objects to the index and remove deleted ones"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Import a directory or another bucket and print each old and new URL")
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .help("A directory, or s3://bucket/prefix"),
+                )
+                .arg(
+                    Arg::with_name("author")
+                        .long("author")
+                        .takes_value(true)
+                        .help("The me URL the files are imported for"),
+                )
+                .arg(
+                    Arg::with_name("base-url")
+                        .long("base-url")
+                        .takes_value(true)
+                        .help("The old URL of the source, prepended to each path in the mapping"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the mapping to a file rather than standard output"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Copy every original and a manifest of their metadata")
+                .arg(
+                    Arg::with_name("destination")
+                        .required(true)
+                        .help("A tarball to create, or s3://bucket/prefix"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Put back everything in a backup")
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .help("A tarball, or s3://bucket/prefix"),
+                ),
+        )
+        .get_matches();
 
-    /// Base URL for serving files
-    pub fn media_url(&self) -> &str {
-        &self.media_url
-    }
+    std::env::set_var("RUST_LOG", "actix_web=info,s3_media_endpoint_rs=info");
+    env_logger::init();
 
-    /// The URI to use to validate an access token.
-    pub fn token_endpoint(&self) -> &str {
-        &self.token_endpoint
-    }
+    let site_config = SiteConfig::from_env();
+    let bind = site_config.bind().to_string();
+    let endpoint = MediaEndpoint::builder()
+        .site_config(site_config)
+        .build()
+        .await?;
 
-    /// S3 output bucket
-    pub fn s3_bucket(&self) -> &str {
-        &self.s3_bucket
+    if std::env::var("S3_CREATE_BUCKET")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+    {
+        endpoint.create_bucket().await?;
     }
 
-    pub fn default_width(&self) -> u32 {
-        self.default_width
+    if std::env::var("S3_EXPIRY_RULES")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+    {
+        endpoint.install_expiry_rules().await?;
     }
 
-    pub fn default_height(&self) -> u32 {
-        self.default_height
+    match matches.subcommand() {
+        ("upload", Some(args)) => {
+            let path = args.value_of("file").unwrap();
+            let content_type = match args.value_of("content-type") {
+                Some(v) => v
+                    .parse()
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Bad content type"))?,
+                None => mime_guess::from_path(path).first_or_octet_stream(),
+            };
+            let filename = std::path::Path::new(path)
+                .file_name()
+                .and_then(|f| f.to_str());
+            let data = std::fs::read(path)?;
+            let url = endpoint
+                .upload(data, content_type, filename)
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            println!("{}", url);
+            return Ok(());
+        }
+        ("list", Some(args)) => {
+            let objects = endpoint
+                .list(args.value_of("type"))
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            for object in objects {
+                println!(
+                    "{}\t{}\t{}",
+                    object.last_modified.unwrap_or_default(),
+                    object.size.unwrap_or_default(),
+                    object.key.unwrap_or_default()
+                );
+            }
+            return Ok(());
+        }
+        ("purge", Some(args)) => {
+            let purged = endpoint
+                .purge(args.value_of("key").unwrap())
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            println!("Purged {} derivatives", purged);
+            return Ok(());
+        }
+        ("backfill", Some(_)) => {
+            let report = endpoint
+                .backfill()
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            println!(
+                "Generated {} derivatives of {} photos ({} failed)",
+                report.generated, report.photos, report.failed
+            );
+            return Ok(());
+        }
+        ("gc", Some(_)) => {
+            return endpoint
+                .collect_garbage()
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e));
+        }
+        ("reconcile", Some(args)) => {
+            let report = endpoint
+                .reconcile(args.is_present("repair"))
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            for key in &report.unindexed {
+                println!("unindexed\t{}", key);
+            }
+            for key in &report.orphaned {
+                println!("orphaned\t{}", key);
+            }
+            println!(
+                "Found {} objects missing from the index and {} deleted objects in it; repaired {}",
+                report.unindexed.len(),
+                report.orphaned.len(),
+                report.repaired
+            );
+            return Ok(());
+        }
+        ("import", Some(args)) => {
+            let report = endpoint
+                .import(
+                    args.value_of("source").unwrap(),
+                    args.value_of("author").unwrap_or_default(),
+                )
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            let base_url = args
+                .value_of("base-url")
+                .map(|url| url.trim_end_matches('/'));
+            let mut mapping = String::new();
+            for (path, url) in &report.imported {
+                let old_url = match base_url {
+                    Some(base_url) => format!("{}/{}", base_url, path),
+                    None => path.clone(),
+                };
+                mapping.push_str(&format!("{}\t{}\n", old_url, url));
+            }
+            match args.value_of("output") {
+                Some(output) => std::fs::write(output, mapping)?,
+                None => print!("{}", mapping),
+            }
+            eprintln!(
+                "Imported {} files ({} failed)",
+                report.imported.len(),
+                report.failed
+            );
+            return Ok(());
+        }
+        ("backup", Some(args)) => {
+            let copied = endpoint
+                .backup(args.value_of("destination").unwrap())
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            println!("Backed up {} objects", copied);
+            return Ok(());
+        }
+        ("restore", Some(args)) => {
+            let restored = endpoint
+                .restore(args.value_of("source").unwrap())
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            println!("Restored {} objects", restored);
+            return Ok(());
+        }
+        _ => (),
     }
-}
 
-#[actix_rt::main]
-async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
+    endpoint.log_configuration();
+    endpoint.report_panics();
+    endpoint.spawn_tasks();
 
-    let site_config = SiteConfig {
-        bind: std::env::var("BIND").unwrap_or_else(|_| "127.0.0.1:8180".to_string()),
-        s3_bucket: std::env::var("S3_BUCKET").expect("Expected S3_BUCKET env var"),
-        media_url: std::env::var("MEDIA_URL").expect("Expected MEDIA_URL env var"),
-        token_endpoint: std::env::var("TOKEN_ENDPOINT").expect("Expected TOKEN_ENDPOINT env var"),
-        default_width: std::env::var("DEFAULT_WIDTH").ok().and_then(|v| v.parse().ok()).unwrap_or(1000),
-        default_height: std::env::var("DEFAULT_HEIGHT").ok().and_then(|v| v.parse().ok()).unwrap_or(0),
-    };
+    // HttpServer can't take an expect handler, so the service is assembled
+    // by hand.
+    Server::build()
+        .bind("s3-media-endpoint", bind, move || {
+            let mut app = App::new()
+                .wrap(middleware::Compress::default())
+                .wrap(middleware::Logger::default());
 
-    let bind = site_config.bind().to_string();
-    let s3_client = S3Client::new(Region::default());
-    let token_endpoint = site_config.token_endpoint().to_string();
+            // Tenants are matched before the default site.
+            for scope in endpoint.tenant_scopes() {
+                app = app.service(scope);
+            }
 
-    HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::Logger::default())
-            .data(Client::new())
-            .data(site_config.clone())
-            .data(s3_client.clone())
-            .data(oauth::VerificationService::new(token_endpoint.clone()))
-            .service(
-                web::resource("/micropub/media").route(web::post().to(micropub::handle_upload)),
-            )
-            .configure(media::configure)
-    })
-    .bind(bind)?
-    .run()
-    .await
+            HttpService::build()
+                .expect(endpoint.expect_handler())
+                .finish(map_config(app.service(endpoint.scope("")), |_| {
+                    AppConfig::default()
+                }))
+                .tcp()
+        })?
+        .run()
+        .await
 }
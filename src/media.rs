@@ -1,21 +1,86 @@
 use actix_web::error::{ErrorBadRequest, ErrorNotFound, ErrorInternalServerError};
-use actix_web::http::header;
+use actix_web::body::Body;
+use actix_web::dev::{BodyEncoding, HttpResponseBuilder};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::{header, ContentEncoding, StatusCode};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 
 use image::imageops::FilterType;
-use image::GenericImageView;
-use image::ImageFormat;
+use image::{DynamicImage, GenericImageView, ImageFormat};
 
-use futures::TryFutureExt;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{future, stream, SinkExt, StreamExt};
+use log::error;
 use tokio::io::AsyncReadExt;
 
-use rusoto_s3::{HeadObjectRequest, GetObjectRequest, S3Client, S3};
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    GetObjectError, GetObjectOutput, GetObjectRequest, GetObjectTaggingRequest, HeadObjectOutput,
+    HeadObjectRequest, PutObjectRequest, S3Client, S3,
+};
 
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Write};
+use std::iter;
+use std::time::Duration;
+
+use crate::access_log::{CacheSource, CacheStatus};
+use crate::cancel::CancelToken;
+use crate::captions::{self, CaptionTrack};
+use crate::derivatives;
+use crate::disk_cache::{Cached, DiskCache};
+use crate::exif;
+use crate::hls;
+use crate::index::ServedKey;
+use crate::load::{Effort, Resizing};
+use crate::metadata;
+use crate::metrics;
+use crate::micropub::authorize;
+use crate::oauth;
+use crate::originals;
+use crate::policy::Permission;
+use crate::previews;
+use crate::quarantine;
+use crate::replica::ReadBuckets;
+use crate::response_headers;
+use crate::restore;
+use crate::retry::RetryError;
+use crate::security_headers;
+use crate::share;
+use crate::staging;
+use crate::trash;
 use crate::SiteConfig;
 
+/// Key prefix for cached resized photos.
+pub const DERIVATIVE_PREFIX: &str = "derivatives/photo";
+
+/// Seconds a derivative made with reduced effort may be cached.
+const REDUCED_MAX_AGE: u32 = 60;
+
+/// Size of the chunks sent to the client while encoding an image.
+const ENCODE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of encoded chunks that may be waiting for the client.
+const ENCODE_CHANNEL_DEPTH: usize = 4;
+
+/// The most of a photo read looking for the end of its metadata when
+/// stripping EXIF, before giving up and reading all of it.
+const STRIP_EXIF_MAX_HEADER: usize = 1024 * 1024;
+
 /// Build an HttpResponse for an AWS response
+///
+/// Default headers may be given as (name, value) pairs. The object's own
+/// headers take precedence over them.
 macro_rules! response_for {
     ($resp:expr) => {
+        response_for!($resp, iter::empty::<(HeaderName, HeaderValue)>())
+    };
+    ($resp:expr, $defaults:expr) => {
         {
             let mut client_resp = HttpResponse::Ok();
 
@@ -23,13 +88,24 @@ macro_rules! response_for {
             client_resp.set(header::CacheControl(vec![header::CacheDirective::MaxAge(
                 31557600u32,
             )]));
+            for (name, value) in $defaults {
+                client_resp.set_header(name, value);
+            }
 
             // Copy all of the relevant S3 headers.
             $resp.cache_control.map(|v| client_resp.set_header(header::CACHE_CONTROL, v));
             $resp.content_disposition.map(|v| client_resp.set_header(header::CONTENT_DISPOSITION, v));
             $resp.content_encoding.map(|v| client_resp.set_header(header::CONTENT_ENCODING, v));
             $resp.content_language.map(|v| client_resp.set_header(header::CONTENT_LANGUAGE, v));
-            $resp.content_type.map(|v| client_resp.set_header(header::CONTENT_TYPE, v));
+            match $resp.content_type {
+                Some(v) => {
+                    negotiate_encoding(&mut client_resp, &v);
+                    client_resp.set_header(header::CONTENT_TYPE, v);
+                }
+                None => {
+                    client_resp.encoding(ContentEncoding::Identity);
+                }
+            }
             $resp.e_tag.map(|v| client_resp.set_header(header::ETAG, v));
             $resp.last_modified.map(|v| client_resp.set_header(header::LAST_MODIFIED, v));
 
@@ -40,8 +116,15 @@ macro_rules! response_for {
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
-        web::resource("/media/photo/{width:\\d+}x{height:\\d+}/{filename}")
-            .route(web::get().to(serve_photo)),
+        web::resource("/media/photo/{width:\\d+}x{height:\\d+}/{filename:.+}")
+            .route(web::get().to(serve_photo))
+            .route(web::head().to(head_photo)),
+    );
+    cfg.service(
+        web::resource("/media/info/{type}/{filename:.+}").route(web::get().to(serve_info)),
+    );
+    cfg.service(
+        web::resource("/media/photo/original/{filename:.+}").route(web::get().to(serve_original)),
     );
     cfg.service(
         web::resource("/media/{type}/{filename:.+}")
@@ -50,10 +133,20 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
     );
 }
 
+/// Whether objects under the prefix are kept from the public.
+pub(crate) fn is_hidden(site: &SiteConfig, prefix: &str) -> bool {
+    prefix == trash::TRASH_PREFIX
+        || prefix == quarantine::QUARANTINE_PREFIX
+        || prefix == originals::ORIGINALS_PREFIX
+        || prefix == share::SHARE_PREFIX
+        || prefix == staging::STAGING_PREFIX
+        || site.log_prefixes().any(|log_prefix| log_prefix == prefix)
+}
+
 async fn head_file(
     req: HttpRequest,
     config: web::Data<SiteConfig>,
-    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
 ) -> Result<HttpResponse, Error> {
 
     // Get the path paramaters
@@ -65,27 +158,108 @@ async fn head_file(
         .match_info()
         .get("filename")
         .ok_or(ErrorBadRequest("Bad URI"))?;
+    if is_hidden(&config, media_type) {
+        return Err(ErrorNotFound("Not found"));
+    }
 
     // Construct an S3 key
-    let key = format!("{}/{}", media_type, filename);
-    let resp = s3_client.head_object(HeadObjectRequest {
-        bucket: config.s3_bucket().to_owned(),
+    let object_key = format!("{}/{}", media_type, filename);
+    let (bucket, key) = config.locate(&object_key);
+    let head_request = HeadObjectRequest {
+        bucket,
         key,
         ..Default::default()
-    })
-    .map_err(|e| ErrorInternalServerError(e))
-    .await?;
+    };
+    let mut resp = buckets.head_object(head_request).await?;
+    if let Some(location) = &resp.website_redirect_location {
+        return Ok(moved(location, req.query_string()));
+    }
 
-    let mut client_resp = response_for!(resp);
+    // Photos are sent stripped of their metadata, as `original_response`
+    // sends them, so the stored validators don't describe what's sent.
+    if media_type == "photo" && config.strip_exif() {
+        resp.e_tag = resp
+            .e_tag
+            .map(|e_tag| derivative_etag(&e_tag, "strip-exif"));
+        resp.last_modified = None;
+    }
+
+    let download_name = download_name(resp.metadata.as_ref(), filename);
+    let stored_type = resp.content_type.clone();
+    let defaults = response_headers::defaults_for(&config, &object_key);
+    let mut client_resp = response_for!(resp, defaults);
+    if security_headers::must_download(&config, stored_type.as_deref(), filename) {
+        force_download(&mut client_resp, &download_name);
+    }
     // TODO: trick actix into returning the content-length.
     Ok(client_resp.finish())
 }
 
 
+/// Query parameters accepted when serving a file.
+#[derive(Deserialize)]
+struct FileOptions {
+    /// If set to 1 or true, serve the file as an attachment.
+    download: Option<String>,
+    /// Serve this version of the object instead of the latest.
+    #[serde(rename = "versionId")]
+    version_id: Option<String>,
+}
+
+impl FileOptions {
+    fn download(&self) -> bool {
+        matches!(self.download.as_deref(), Some("1") | Some("true"))
+    }
+}
+
+/// Serve a file. Old versions are only served to tokens which may read
+/// private media.
 async fn serve_file(
     req: HttpRequest,
+    options: web::Query<FileOptions>,
+    config: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> Result<HttpResponse, Error> {
+    let version_id = options.version_id.as_deref();
+    let versioned = authorize_version(&req, &config, &verification_service, version_id).await?;
+    let resp = file_response(req, options, config, s3_client, buckets).await?;
+    Ok(keep_private(resp, versioned))
+}
+
+/// Old versions aren't public, so asking for one takes a token which may
+/// read private media. Returns whether a version was asked for.
+async fn authorize_version(
+    req: &HttpRequest,
+    config: &SiteConfig,
+    verification_service: &oauth::VerificationService,
+    version_id: Option<&str>,
+) -> Result<bool, Error> {
+    if version_id.is_none() {
+        return Ok(false);
+    }
+    authorize(req, verification_service, config, Permission::Read)
+        .await
+        .map_err(Error::from)?;
+    Ok(true)
+}
+
+/// Keep a response for an old version out of shared caches.
+fn keep_private(mut resp: HttpResponse, private: bool) -> HttpResponse {
+    if private {
+        resp.headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("private"));
+    }
+    resp
+}
+
+async fn file_response(
+    req: HttpRequest,
+    options: web::Query<FileOptions>,
     config: web::Data<SiteConfig>,
     s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
 ) -> Result<HttpResponse, Error> {
 
     // Get the path paramaters
@@ -97,29 +271,390 @@ async fn serve_file(
         .match_info()
         .get("filename")
         .ok_or(ErrorBadRequest("Bad URI"))?;
+    if is_hidden(&config, media_type) {
+        return Err(ErrorNotFound("Not found"));
+    }
+    // The stored photo still has the metadata STRIP_EXIF keeps private.
+    if media_type == "photo" && config.strip_exif() {
+        return original_response(req, options, config, s3_client, buckets).await;
+    }
 
     // Construct an S3 key
-    let key = format!("{}/{}", media_type, filename);
-    let resp = s3_client.get_object(GetObjectRequest {
-        bucket: config.s3_bucket().to_owned(),
-        key,
-        ..Default::default()
-    })
-    .map_err(|e| ErrorInternalServerError(e))
-    .await?;
+    let object_key = format!("{}/{}", media_type, filename);
+    req.extensions_mut().insert(ServedKey(object_key.clone()));
+    let (bucket, key) = config.locate(&object_key);
+    let mut get_request = conditional_get(&req, &bucket, key);
+    get_request.version_id = options.version_id.clone();
+    let resp = match get_conditional(&buckets, get_request).await {
+        Ok(resp) => resp,
+        Err(e) if restore::is_archived(&e) => {
+            let version_id = options.version_id.as_deref();
+            return Ok(restore::restoring(&config, &s3_client, &object_key, version_id).await);
+        }
+        Err(e) => return e.s3_error().and_then(conditional_response).ok_or_else(|| e.into()),
+    };
+    if let Some(location) = &resp.website_redirect_location {
+        return Ok(moved(location, req.query_string()));
+    }
 
     // If there is no payload, return a 404.
     let data = resp.body.ok_or(ErrorNotFound("Not found"))?;
 
-    let mut client_resp = response_for!(resp);
+    let download_name = download_name(resp.metadata.as_ref(), filename);
+    let stored_type = resp.content_type.clone();
+
+    let defaults = response_headers::defaults_for(&config, &object_key);
+    let mut client_resp = response_for!(resp, defaults);
+    client_resp.header(header::ACCEPT_RANGES, "bytes");
+    if options.download() {
+        client_resp.set_header(header::CONTENT_DISPOSITION, attachment(&download_name));
+    }
+    if let Some(range) = resp.content_range {
+        partial_content(&mut client_resp, range);
+    }
+
+    // Players are picky about the playlist and segment types.
+    if media_type == "video" && filename.starts_with("hls/") {
+        if let Some(mime) = hls::content_type_for(filename) {
+            client_resp.set_header(header::CONTENT_TYPE, mime);
+        }
+    }
+    if security_headers::must_download(&config, stored_type.as_deref(), filename) {
+        force_download(&mut client_resp, &download_name);
+    }
+
     Ok(client_resp.streaming(data))
 }
 
+/// Query parameters accepted when serving a resized photo.
+#[derive(Deserialize)]
+struct PhotoOptions {
+    /// 1 or true to allow enlarging the image, 0 or false to forbid it.
+    upscale: Option<String>,
+    /// Resize this version of the photo instead of the latest.
+    #[serde(rename = "versionId")]
+    version_id: Option<String>,
+}
+
+impl PhotoOptions {
+    /// The largest factor the image may be scaled by.
+    fn max_scale(&self, config: &SiteConfig) -> f64 {
+        let upscale = match self.upscale.as_deref() {
+            Some("1") | Some("true") => true,
+            Some("0") | Some("false") => false,
+            _ => config.upscale(),
+        };
+        max_scale(config, upscale)
+    }
+}
+
+fn max_scale(config: &SiteConfig, upscale: bool) -> f64 {
+    if upscale {
+        config.max_upscale().max(1.0)
+    } else {
+        1.0
+    }
+}
+
+/// The size a photo is served at by its default URL.
+///
+/// Only the image's header is read.
+pub(crate) fn default_dimensions(config: &SiteConfig, data: &[u8]) -> Option<(u32, u32)> {
+    let (width, height) = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    Some(target_dimensions(
+        width,
+        height,
+        config.default_width(),
+        config.default_height(),
+        max_scale(config, config.upscale()),
+    ))
+}
+
+/// Serve a resized photo, saying which cache, if any, it came from.
 async fn serve_photo(
     req: HttpRequest,
+    options: web::Query<PhotoOptions>,
     config: web::Data<SiteConfig>,
     s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+    disk_cache: Option<web::Data<DiskCache>>,
+    verification_service: web::Data<oauth::VerificationService>,
 ) -> Result<HttpResponse, Error> {
+    let version_id = options.version_id.as_deref();
+    let versioned = authorize_version(&req, &config, &verification_service, version_id).await?;
+    let resp = resized_photo(req.clone(), options, config, s3_client, buckets, disk_cache).await?;
+    let mut resp = keep_private(resp, versioned);
+
+    let status = req.extensions().get::<CacheStatus>().copied();
+    if let Some(status) = status {
+        let source = req.extensions().get::<CacheSource>().copied();
+        metrics::count_cache(status, source);
+
+        let headers = resp.headers_mut();
+        headers.insert(
+            HeaderName::from_static("x-cache"),
+            HeaderValue::from_static(status.as_header()),
+        );
+        if let Some(source) = source {
+            headers.insert(
+                HeaderName::from_static("x-cache-source"),
+                HeaderValue::from_static(source.as_header()),
+            );
+        }
+    }
+    Ok(resp)
+}
+
+async fn resized_photo(
+    req: HttpRequest,
+    options: web::Query<PhotoOptions>,
+    config: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+    disk_cache: Option<web::Data<DiskCache>>,
+) -> Result<HttpResponse, Error> {
+    let (width, height, filename) = photo_path(&req)?;
+
+    req.extensions_mut().insert(ServedKey(format!("photo/{}", filename)));
+
+    let max_scale = options.max_scale(&config);
+
+    // Serve the cached derivative if we already made one. The key has to
+    // cover everything that shapes the image; request headers don't, so
+    // every client can share it.
+    let size = derivative_size(width, height, max_scale);
+    let derivative_key = format!("{}/{}/{}", DERIVATIVE_PREFIX, size, filename);
+    // Only the latest version is cached.
+    let use_cache = config.derivative_cache() && options.version_id.is_none();
+    let disk_cache = disk_cache.filter(|_| options.version_id.is_none());
+    let disk_key = format!("{}/{}", config.s3_bucket(), derivative_key);
+    if let Some(disk_cache) = &disk_cache {
+        if let Some((cached, data)) = disk_cache.get(&disk_key).await {
+            req.extensions_mut().insert(CacheStatus::Hit);
+            req.extensions_mut().insert(CacheSource::Disk);
+            if let Some(etag) = &cached.etag {
+                if etag_matches(&req, etag) {
+                    return Ok(not_modified(etag, cached.last_modified.as_ref()));
+                }
+            }
+            let length = data.len() as u64;
+            let range = requested_range(&req, cached.etag.as_deref(), length);
+            let mut client_resp = from_disk(cached);
+            client_resp.header(header::ACCEPT_RANGES, "bytes");
+            return Ok(match range {
+                Some(Ok((start, end))) => {
+                    let range = format!("bytes {}-{}/{}", start, end, length);
+                    partial_content(&mut client_resp, range);
+                    client_resp.body(data[start as usize..=end as usize].to_vec())
+                }
+                Some(Err(())) => HttpResponse::RangeNotSatisfiable()
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", length))
+                    .finish(),
+                None => client_resp.body(data),
+            });
+        }
+    }
+
+    if use_cache {
+        let cached_request = GetObjectRequest {
+            bucket: config.s3_bucket().to_owned(),
+            key: derivative_key.clone(),
+            range: derivative_range(&req),
+            ..Default::default()
+        };
+        let cached = match buckets.get_object(cached_request).await {
+            Err(e) if is_unsatisfiable(&e) => {
+                req.extensions_mut().insert(CacheStatus::Hit);
+                req.extensions_mut().insert(CacheSource::S3);
+                return e
+                    .s3_error()
+                    .and_then(conditional_response)
+                    .ok_or_else(|| e.into());
+            }
+            cached => cached,
+        };
+
+        if let Ok(mut resp) = cached {
+            if let Some(data) = resp.body.take() {
+                req.extensions_mut().insert(CacheStatus::Hit);
+                req.extensions_mut().insert(CacheSource::S3);
+                // The source's validators were stored with the derivative.
+                let metadata = resp.metadata.take().unwrap_or_default();
+                if let Some(etag) = metadata.get("etag") {
+                    if etag_matches(&req, etag) {
+                        return Ok(not_modified(etag, metadata.get("last-modified")));
+                    }
+                }
+
+                if let Some(max_idle) = config.derivative_max_idle() {
+                    if is_older_than(resp.last_modified.as_deref(), max_idle / 2) {
+                        let site = config.get_ref().clone();
+                        let s3 = s3_client.get_ref().clone();
+                        let content_type = resp.content_type.clone();
+                        let metadata = metadata.clone();
+                        let key = derivative_key.clone();
+                        actix_rt::spawn(async move {
+                            derivatives::touch(&site, &s3, key, content_type, metadata)
+                                .await
+                        });
+                    }
+                }
+
+                let mut client_resp = response_for!(resp);
+                client_resp.header(header::ACCEPT_RANGES, "bytes");
+                if let Some(etag) = metadata.get("etag") {
+                    client_resp.set_header(header::ETAG, etag.as_str());
+                }
+                if let Some(last_modified) = metadata.get("last-modified") {
+                    client_resp.set_header(header::LAST_MODIFIED, last_modified.as_str());
+                }
+                if let Some(range) = resp.content_range {
+                    partial_content(&mut client_resp, range);
+                }
+                return Ok(client_resp.streaming(data));
+            }
+        }
+    }
+
+    let (bucket, key) = config.locate(&format!("photo/{}", filename));
+    let get_request = GetObjectRequest {
+        bucket,
+        key,
+        version_id: options.version_id.clone(),
+        ..Default::default()
+    };
+    let resp = buckets.get_object(get_request).await?;
+    if let Some(location) = &resp.website_redirect_location {
+        // Keep the size when following a renamed photo.
+        let photos = format!("{}/photo/", config.media_url());
+        let location = match location.strip_prefix(&photos) {
+            Some(moved_to) => format!("{}{}x{}/{}", photos, width, height, moved_to),
+            None => location.clone(),
+        };
+        return Ok(moved(&location, req.query_string()));
+    }
+
+    let effort = Effort::for_site(&config);
+    let etag = photo_etag(resp.e_tag.as_deref().unwrap_or_default(), &size, &effort);
+    let last_modified = resp.last_modified.clone();
+    let cacheable = (use_cache || disk_cache.is_some()) && !effort.is_reduced();
+    req.extensions_mut().insert(if cacheable {
+        CacheStatus::Miss
+    } else {
+        CacheStatus::Bypass
+    });
+    if etag_matches(&req, &etag) {
+        return Ok(not_modified(&etag, last_modified.as_ref()));
+    }
+
+    // The original has to be decoded in full, so it has to be buffered.
+    let mut data = Vec::new();
+    resp.body
+        .ok_or(ErrorNotFound("Not found"))?
+        .into_async_read()
+        .read_to_end(&mut data)
+        .await?;
+
+    // Resize the image. If the client goes away first, this future is
+    // dropped along with the guard, and the resize stops at its next check.
+    let (cancel, _guard) = CancelToken::new();
+    let resizing = Resizing::start();
+    let filter = effort.filter();
+    let (fmt, scaled) =
+        web::block(move || scale_image(data.as_ref(), width, height, max_scale, filter, &cancel))
+            .await
+            .map_err(|e| ErrorInternalServerError(e))?;
+    let mime = mime_for_image(fmt);
+
+    // Encode on the blocking pool, sending chunks to the client as they're ready.
+    let (tx, rx) = mpsc::channel(ENCODE_CHANNEL_DEPTH);
+    let keep_copy = cacheable;
+    let encoding = web::block(move || -> Result<Option<Vec<u8>>, image::ImageError> {
+        let _resizing = resizing;
+        let mut writer = ChunkWriter::new(tx, keep_copy);
+        scaled.write_to(&mut writer, effort.output(fmt))?;
+        writer.flush()?;
+        Ok(writer.into_copy())
+    });
+
+    // Store the derivative once encoding is complete.
+    let put_defaults = config.put_object_request();
+    let s3 = s3_client.get_ref().clone();
+    let mut metadata = HashMap::new();
+    metadata.insert("etag".to_string(), etag.clone());
+    if let Some(last_modified) = &last_modified {
+        metadata.insert("last-modified".to_string(), last_modified.clone());
+    }
+    let cached = Cached {
+        key: disk_key,
+        content_type: mime.to_string(),
+        etag: Some(etag.clone()),
+        last_modified,
+    };
+    actix_rt::spawn(async move {
+        match encoding.await {
+            Ok(Some(body)) => {
+                if let Some(disk_cache) = disk_cache {
+                    disk_cache.put(cached, body.clone()).await;
+                }
+                if !use_cache {
+                    return;
+                }
+                let put_request = PutObjectRequest {
+                    key: derivative_key,
+                    body: Some(body.into()),
+                    content_type: Some(mime.to_string()),
+                    metadata: Some(metadata),
+                    ..put_defaults
+                };
+                if let Err(e) = s3.put_object(put_request).await {
+                    error!("Failed to cache derivative: {}", e);
+                }
+            }
+            Ok(None) => (),
+            Err(e) => error!("Failed to encode derivative: {}", e),
+        }
+    });
+
+    // Send the new image to the client.
+    let mut client_resp = response_for!(resp);
+    client_resp.set_header(header::CONTENT_TYPE, mime);
+    client_resp.set_header(header::ETAG, etag);
+    negotiate_encoding(&mut client_resp, mime);
+    if effort.is_reduced() {
+        // The same URL will get a better image once the load drops, so
+        // caches shouldn't hold on to this one for long.
+        client_resp.set(header::CacheControl(vec![header::CacheDirective::MaxAge(
+            REDUCED_MAX_AGE,
+        )]));
+    }
+
+    Ok(client_resp.streaming(rx))
+}
+
+/// Build an HttpResponse for a derivative from the disk cache.
+fn from_disk(cached: Cached) -> HttpResponseBuilder {
+    let mut client_resp = HttpResponse::Ok();
+    client_resp.set(header::CacheControl(vec![header::CacheDirective::MaxAge(
+        31557600u32,
+    )]));
+    negotiate_encoding(&mut client_resp, &cached.content_type);
+    client_resp.set_header(header::CONTENT_TYPE, cached.content_type);
+    if let Some(etag) = cached.etag {
+        client_resp.set_header(header::ETAG, etag);
+    }
+    if let Some(last_modified) = cached.last_modified {
+        client_resp.set_header(header::LAST_MODIFIED, last_modified);
+    }
+    client_resp
+}
+
+/// The width, height, and filename of a resized photo's URL.
+fn photo_path(req: &HttpRequest) -> Result<(u32, u32, &str), Error> {
     let width = req
         .match_info()
         .get("width")
@@ -134,66 +669,964 @@ async fn serve_photo(
         .match_info()
         .get("filename")
         .ok_or(ErrorBadRequest("Bad URI"))?;
+    Ok((width, height, filename))
+}
 
-    let key = format!("photo/{}", filename);
-    let resp = s3_client.get_object(GetObjectRequest {
-        bucket: config.s3_bucket().to_owned(),
+/// Describe a resized photo without resizing it.
+///
+/// The Content-Length is only known once the derivative has been cached.
+/// Otherwise the headers are what serve_photo would send.
+async fn head_photo(
+    req: HttpRequest,
+    options: web::Query<PhotoOptions>,
+    config: web::Data<SiteConfig>,
+    buckets: web::Data<ReadBuckets>,
+    disk_cache: Option<web::Data<DiskCache>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> Result<HttpResponse, Error> {
+    let version_id = options.version_id.as_deref();
+    let versioned = authorize_version(&req, &config, &verification_service, version_id).await?;
+    let resp = photo_head(req, options, config, buckets, disk_cache).await?;
+    Ok(keep_private(resp, versioned))
+}
+
+async fn photo_head(
+    req: HttpRequest,
+    options: web::Query<PhotoOptions>,
+    config: web::Data<SiteConfig>,
+    buckets: web::Data<ReadBuckets>,
+    disk_cache: Option<web::Data<DiskCache>>,
+) -> Result<HttpResponse, Error> {
+    let (width, height, filename) = photo_path(&req)?;
+    let max_scale = options.max_scale(&config);
+    let size = derivative_size(width, height, max_scale);
+    let derivative_key = format!("{}/{}/{}", DERIVATIVE_PREFIX, size, filename);
+    // Only the latest version is cached.
+    let latest = options.version_id.is_none();
+
+    if let Some(disk_cache) = disk_cache.filter(|_| latest) {
+        let disk_key = format!("{}/{}", config.s3_bucket(), derivative_key);
+        if let Some((cached, length)) = disk_cache.head(&disk_key).await {
+            if let Some(etag) = &cached.etag {
+                if etag_matches(&req, etag) {
+                    return Ok(not_modified(etag, cached.last_modified.as_ref()));
+                }
+            }
+            let mut client_resp = from_disk(cached);
+            client_resp.header(header::ACCEPT_RANGES, "bytes");
+            client_resp.set_header(header::CONTENT_LENGTH, length);
+            return Ok(client_resp.body(Body::None));
+        }
+    }
+
+    if config.derivative_cache() && latest {
+        let cached_request = HeadObjectRequest {
+            bucket: config.s3_bucket().to_owned(),
+            key: derivative_key,
+            ..Default::default()
+        };
+        if let Ok(mut resp) = buckets.head_object(cached_request).await {
+            // The source's validators were stored with the derivative.
+            let metadata = resp.metadata.take().unwrap_or_default();
+            if let Some(etag) = metadata.get("etag") {
+                if etag_matches(&req, etag) {
+                    return Ok(not_modified(etag, metadata.get("last-modified")));
+                }
+            }
+            let length = resp.content_length;
+            let mut client_resp = response_for!(resp);
+            client_resp.header(header::ACCEPT_RANGES, "bytes");
+            if let Some(etag) = metadata.get("etag") {
+                client_resp.set_header(header::ETAG, etag.as_str());
+            }
+            if let Some(last_modified) = metadata.get("last-modified") {
+                client_resp.set_header(header::LAST_MODIFIED, last_modified.as_str());
+            }
+            if let Some(length) = length {
+                client_resp.set_header(header::CONTENT_LENGTH, length);
+            }
+            return Ok(client_resp.body(Body::None));
+        }
+    }
+
+    // The derivative is encoded in the original's format.
+    let (bucket, key) = config.locate(&format!("photo/{}", filename));
+    let head_request = HeadObjectRequest {
+        bucket,
         key,
+        version_id: options.version_id.clone(),
         ..Default::default()
-    })
-    .map_err(|e| ErrorInternalServerError(e))
-    .await?;
+    };
+    let resp = buckets.head_object(head_request).await?;
+    let effort = Effort::for_site(&config);
+    let etag = photo_etag(resp.e_tag.as_deref().unwrap_or_default(), &size, &effort);
+    if etag_matches(&req, &etag) {
+        return Ok(not_modified(&etag, resp.last_modified.as_ref()));
+    }
+    let mut client_resp = response_for!(resp);
+    client_resp.set_header(header::ETAG, etag);
+    if effort.is_reduced() {
+        client_resp.set(header::CacheControl(vec![header::CacheDirective::MaxAge(
+            REDUCED_MAX_AGE,
+        )]));
+    }
+    Ok(client_resp.body(Body::None))
+}
+
+/// Everything we know about a stored object.
+#[derive(Serialize)]
+struct MediaInfo {
+    url: String,
+    content_type: Option<String>,
+    size: Option<i64>,
+    last_modified: Option<String>,
+    metadata: HashMap<String, String>,
+    tags: HashMap<String, String>,
+    /// WebVTT cues pointing into a sprite of the video's frames.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previews: Option<String>,
+    /// The video's caption tracks, served as WebVTT.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    captions: Vec<CaptionTrack>,
+}
+
+async fn serve_info(
+    req: HttpRequest,
+    config: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+) -> Result<HttpResponse, Error> {
+    let media_type = req
+        .match_info()
+        .get("type")
+        .ok_or(ErrorBadRequest("Bad URI"))?;
+    let filename = req
+        .match_info()
+        .get("filename")
+        .ok_or(ErrorBadRequest("Bad URI"))?;
+    if is_hidden(&config, media_type) {
+        return Err(ErrorNotFound("Not found"));
+    }
+
+    let key = format!("{}/{}", media_type, filename);
+    let (resp, fields) = describe(&config, &buckets, &key).await?;
+
+    let is_video = resp
+        .content_type
+        .as_deref()
+        .map_or(false, |t| t.starts_with("video/"));
+    let (previews, captions) = if is_video {
+        let previews = if has_previews(&config, &buckets, &key).await {
+            Some(previews::cues_url(&config, &key))
+        } else {
+            None
+        };
+        (previews, captions::list(&config, &s3_client, &key).await)
+    } else {
+        (None, Vec::new())
+    };
+
+    let (bucket, key) = config.locate(&key);
+    let tags = match s3_client
+        .get_object_tagging(GetObjectTaggingRequest {
+            bucket,
+            key,
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(tagging) => tagging
+            .tag_set
+            .into_iter()
+            .map(|tag| (tag.key, tag.value))
+            .collect(),
+        Err(e) => {
+            error!("Failed to get tags for {}/{}: {}", media_type, filename, e);
+            HashMap::new()
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(MediaInfo {
+        url: format!("{}/{}/{}", config.media_url(), media_type, filename),
+        content_type: resp.content_type,
+        size: resp.content_length,
+        last_modified: resp.last_modified,
+        metadata: fields,
+        tags,
+        previews,
+        captions,
+    }))
+}
+
+/// Whether scrubbing previews have been generated for the video.
+async fn has_previews(config: &SiteConfig, buckets: &ReadBuckets, key: &str) -> bool {
+    let (bucket, object_key) = config.locate(&previews::preview_key(key, previews::CUES));
+    let head_request = HeadObjectRequest {
+        bucket,
+        key: object_key,
+        ..Default::default()
+    };
+    buckets.head_object(head_request).await.is_ok()
+}
+
+/// Get an object's headers and all of its metadata, including the sidecar.
+pub(crate) async fn describe(
+    config: &SiteConfig,
+    buckets: &ReadBuckets,
+    key: &str,
+) -> Result<(HeadObjectOutput, HashMap<String, String>), Error> {
+    let (bucket, object_key) = config.locate(key);
+    let head_request = HeadObjectRequest {
+        bucket,
+        key: object_key,
+        ..Default::default()
+    };
+    let mut resp = buckets.head_object(head_request).await.map_err(not_found_or)?;
+
+    let mut fields = metadata::decode_all(resp.metadata.take().unwrap_or_default());
+    if config.metadata_sidecar() {
+        fields.extend(read_sidecar(config, buckets, key).await);
+    }
+
+    Ok((resp, fields))
+}
+
+/// Read the extra metadata stored in an object's sidecar, if it has one.
+async fn read_sidecar(
+    config: &SiteConfig,
+    buckets: &ReadBuckets,
+    key: &str,
+) -> HashMap<String, String> {
+    let get_request = GetObjectRequest {
+        bucket: config.s3_bucket().to_owned(),
+        key: metadata::sidecar_key(key),
+        ..Default::default()
+    };
+
+    let body = match buckets.get_object(get_request).await {
+        Ok(resp) => resp.body,
+        Err(RetryError::S3(RusotoError::Service(GetObjectError::NoSuchKey(_)))) => None,
+        Err(e) => {
+            error!("Failed to get sidecar for {}: {}", key, e);
+            None
+        }
+    };
 
     let mut data = Vec::new();
-    resp.body
-        .ok_or(ErrorNotFound("Not found"))?
-        .into_async_read()
-        .read_to_end(&mut data)
-        .await?;
+    if let Some(body) = body {
+        if let Err(e) = body.into_async_read().read_to_end(&mut data).await {
+            error!("Failed to read sidecar for {}: {}", key, e);
+            return HashMap::new();
+        }
+    }
+
+    serde_json::from_slice(&data).unwrap_or_default()
+}
 
-    // Resize the image
-    let (mime, new_data) = web::block(move || scale_image(data.as_ref(), width, height))
+/// Convert an error from S3 into a 404 if the object is missing.
+pub(crate) fn not_found_or<E: std::error::Error + 'static>(err: RetryError<E>) -> Error {
+    match err.s3_error() {
+        Some(RusotoError::Unknown(resp)) if resp.status.as_u16() == 404 => {
+            ErrorNotFound("Not found")
+        }
+        _ => err.into(),
+    }
+}
+
+/// Serve a photo as it was uploaded. Old versions are only served to
+/// tokens which may read private media.
+async fn serve_original(
+    req: HttpRequest,
+    options: web::Query<FileOptions>,
+    config: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> Result<HttpResponse, Error> {
+    let version_id = options.version_id.as_deref();
+    let versioned = authorize_version(&req, &config, &verification_service, version_id).await?;
+    let resp = original_response(req, options, config, s3_client, buckets).await?;
+    Ok(keep_private(resp, versioned))
+}
+
+async fn original_response(
+    req: HttpRequest,
+    options: web::Query<FileOptions>,
+    config: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+) -> Result<HttpResponse, Error> {
+    let filename = req
+        .match_info()
+        .get("filename")
+        .ok_or(ErrorBadRequest("Bad URI"))?;
+
+    let object_key = format!("photo/{}", filename);
+    req.extensions_mut().insert(ServedKey(object_key.clone()));
+    let (bucket, key) = config.locate(&object_key);
+    let mut get_request = conditional_get(&req, &bucket, key);
+    get_request.version_id = options.version_id.clone();
+
+    let strip = config.strip_exif();
+    if strip {
+        unconditional(&mut get_request);
+    }
+
+    let resp = match get_conditional(&buckets, get_request).await {
+        Ok(resp) => resp,
+        Err(e) if restore::is_archived(&e) => {
+            let version_id = options.version_id.as_deref();
+            return Ok(restore::restoring(&config, &s3_client, &object_key, version_id).await);
+        }
+        Err(e) => return e.s3_error().and_then(conditional_response).ok_or_else(|| e.into()),
+    };
+    send_object(&req, &config, resp, &object_key, strip).await
+}
+
+/// Stream a stored object by its logical key, honoring conditional and range
+/// requests. This is for objects reached other than by their own URL.
+///
+/// Photos are stripped of their metadata if STRIP_EXIF is set, as they are at
+/// their own URL.
+pub(crate) async fn serve_object(
+    req: &HttpRequest,
+    config: &SiteConfig,
+    buckets: &ReadBuckets,
+    object_key: &str,
+) -> Result<HttpResponse, Error> {
+    req.extensions_mut()
+        .insert(ServedKey(object_key.to_string()));
+    let (bucket, key) = config.locate(object_key);
+    let mut get_request = conditional_get(req, &bucket, key);
+    let strip = config.strip_exif() && object_key.starts_with("photo/");
+    if strip {
+        unconditional(&mut get_request);
+    }
+    let resp = match get_conditional(buckets, get_request).await {
+        Ok(resp) => resp,
+        Err(e) => match e.s3_error().and_then(conditional_response) {
+            Some(resp) => return Ok(resp),
+            None => return Err(not_found_or(e)),
+        },
+    };
+    send_object(req, config, resp, object_key, strip).await
+}
+
+/// Validators and ranges refer to the stored bytes, which aren't what's sent
+/// once the metadata is stripped.
+fn unconditional(get_request: &mut GetObjectRequest) {
+    get_request.range = None;
+    get_request.if_match = None;
+    get_request.if_none_match = None;
+    get_request.if_modified_since = None;
+}
+
+/// Stream the object S3 returned, less its metadata if `strip` is set. Files
+/// which would run in the browser are sent as downloads.
+async fn send_object(
+    req: &HttpRequest,
+    config: &SiteConfig,
+    mut resp: GetObjectOutput,
+    object_key: &str,
+    strip: bool,
+) -> Result<HttpResponse, Error> {
+    let mut body = resp.body.take().ok_or(ErrorNotFound("Not found"))?;
+    let download_name = download_name(resp.metadata.as_ref(), object_key);
+    let must_download =
+        security_headers::must_download(config, resp.content_type.as_deref(), object_key);
+
+    if !strip {
+        let mut client_resp = response_for!(resp);
+        client_resp.header(header::ACCEPT_RANGES, "bytes");
+        if let Some(range) = resp.content_range {
+            partial_content(&mut client_resp, range);
+        }
+        if must_download {
+            force_download(&mut client_resp, &download_name);
+        }
+        return Ok(client_resp.streaming(body));
+    }
+
+    let etag = derivative_etag(resp.e_tag.as_deref().unwrap_or_default(), "strip-exif");
+    if etag_matches(req, &etag) {
+        return Ok(not_modified(&etag, None));
+    }
+
+    // Metadata only comes before the image data, so only that much is read
+    // before the rest is streamed.
+    let mut head = Vec::new();
+    while exif::metadata_end(&head).is_none() {
+        match body.next().await {
+            Some(chunk) => head.extend_from_slice(&chunk?),
+            None => break,
+        }
+        if head.len() > STRIP_EXIF_MAX_HEADER {
+            // Too odd to stream; strip the whole file instead.
+            while let Some(chunk) = body.next().await {
+                head.extend_from_slice(&chunk?);
+            }
+            break;
+        }
+    }
+    let stripped = web::block(move || -> Result<Vec<u8>, ()> { Ok(exif::strip(head)) })
         .await
         .map_err(|e| ErrorInternalServerError(e))?;
 
-    // Send the new image to the client.
+    // The stored object's Last-Modified would validate ranges of other bytes.
+    resp.e_tag = None;
+    resp.last_modified = None;
     let mut client_resp = response_for!(resp);
-    client_resp.set_header(header::CONTENT_TYPE, mime);
+    client_resp.set_header(header::ETAG, etag);
+    if must_download {
+        force_download(&mut client_resp, &download_name);
+    }
+    let head = stream::once(future::ok::<_, io::Error>(Bytes::from(stripped)));
+    Ok(client_resp.streaming(head.chain(body)))
+}
+
+/// True if an S3 Last-Modified date is more than `age` ago.
+fn is_older_than(last_modified: Option<&str>, age: Duration) -> bool {
+    let age = match chrono::Duration::from_std(age) {
+        Ok(age) => age,
+        Err(_) => return false,
+    };
+    last_modified
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .map_or(false, |t| t < Utc::now() - age)
+}
+
+/// Compute a strong ETag for a derivative of the object with `source_etag`.
+///
+/// `params` must describe every transformation applied to the source. This
+/// uses FNV-1a, which unlike std's DefaultHasher is stable across releases.
+fn derivative_etag(source_etag: &str, params: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in source_etag.bytes().chain(iter::once(0)).chain(params.bytes()) {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("\"{:016x}\"", hash)
+}
+
+/// The ETag of a resized photo. The derivative is a different representation,
+/// so it needs its own ETag. So does one made with less effort, to be
+/// replaced once the load drops.
+fn photo_etag(source_etag: &str, size: &str, effort: &Effort) -> String {
+    if effort.is_reduced() {
+        derivative_etag(source_etag, &format!("{}-reduced", size))
+    } else {
+        derivative_etag(source_etag, size)
+    }
+}
+
+/// Returns true if the request's If-None-Match header matches `etag`.
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.trim() == "*"
+                || v
+                    .split(',')
+                    .any(|tag| tag.trim().trim_start_matches("W/") == etag)
+        })
+        .unwrap_or(false)
+}
+
+/// Build a 304 response carrying the validators.
+fn not_modified(etag: &str, last_modified: Option<&String>) -> HttpResponse {
+    let mut client_resp = HttpResponse::NotModified();
+    client_resp.set_header(header::ETAG, etag);
+    if let Some(last_modified) = last_modified {
+        client_resp.set_header(header::LAST_MODIFIED, last_modified.as_str());
+    }
+    client_resp.finish()
+}
+
+/// A permanent redirect to where a renamed object went, keeping the query.
+fn moved(location: &str, query: &str) -> HttpResponse {
+    let location = if query.is_empty() {
+        location.to_string()
+    } else {
+        format!("{}?{}", location, query)
+    };
+    HttpResponse::MovedPermanently()
+        .header(header::LOCATION, location)
+        .finish()
+}
+
+/// The name a file is saved under: the uploaded name if it was recorded,
+/// or else the last part of its URL.
+fn download_name(stored: Option<&HashMap<String, String>>, filename: &str) -> String {
+    stored
+        .and_then(|m| m.get("filename"))
+        .map(|f| metadata::decode(f))
+        .unwrap_or_else(|| filename.rsplit('/').next().unwrap_or(filename).to_string())
+}
+
+/// Send a file browsers would otherwise run as part of the site as an opaque
+/// download.
+fn force_download(client_resp: &mut HttpResponseBuilder, download_name: &str) {
+    client_resp.set_header(header::CONTENT_TYPE, "application/octet-stream");
+    client_resp.set_header(header::CONTENT_DISPOSITION, attachment(download_name));
+}
+
+/// Build a Content-Disposition header value for downloading a file.
+fn attachment(filename: &str) -> String {
+    let safe: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!("attachment; filename=\"{}\"", safe)
+}
+
+/// Returns true if responses of this type benefit from compression.
+///
+/// Images, audio, and video are already compressed, so they're excluded.
+fn is_compressible(content_type: &str) -> bool {
+    let parsed: mime::Mime = match content_type.parse() {
+        Ok(parsed) => parsed,
+        Err(_) => return false,
+    };
+    let structured = |name| parsed.subtype() == name || parsed.suffix() == Some(name);
+
+    if parsed.type_() == mime::TEXT || structured(mime::JSON) || structured(mime::XML) {
+        true
+    } else if parsed.type_() == mime::IMAGE {
+        parsed.subtype() == mime::SVG
+    } else if parsed.type_() == mime::APPLICATION {
+        parsed.subtype() == mime::JAVASCRIPT || parsed.subtype() == "vnd.apple.mpegurl"
+    } else {
+        false
+    }
+}
 
-    Ok(client_resp.body(new_data))
+/// Mark a response as holding only `range` of the object.
+///
+/// The range counts the object's own bytes, so the Compress middleware
+/// mustn't encode them.
+fn partial_content(client_resp: &mut HttpResponseBuilder, range: String) {
+    client_resp.status(StatusCode::PARTIAL_CONTENT);
+    client_resp.header(header::CONTENT_RANGE, range);
+    client_resp.encoding(ContentEncoding::Identity);
+}
+
+/// Let the Compress middleware encode compressible types, and opt everything else out.
+fn negotiate_encoding(client_resp: &mut HttpResponseBuilder, content_type: &str) {
+    if is_compressible(content_type) {
+        client_resp.set_header(header::VARY, "Accept-Encoding");
+    } else {
+        client_resp.encoding(ContentEncoding::Identity);
+    }
+}
+
+/// Build a GetObjectRequest carrying the client's conditional and range headers.
+///
+/// S3 evaluates these itself, so we don't need any caching logic of our own.
+/// Fetch it with `get_conditional`, which takes care of If-Range.
+fn conditional_get(req: &HttpRequest, bucket: &str, key: String) -> GetObjectRequest {
+    let copy = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+
+    let (range, if_match) = match if_range_etag(req) {
+        Ok(etag) => (copy(header::RANGE), etag),
+        Err(()) => (None, None),
+    };
+    GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key,
+        if_none_match: copy(header::IF_NONE_MATCH),
+        if_modified_since: copy(header::IF_MODIFIED_SINCE),
+        if_match,
+        range,
+        ..Default::default()
+    }
+}
+
+/// The ETag the Range header is conditional on, if any, or Err if the Range
+/// header shouldn't be honored.
+///
+/// If-Range only allows a strong comparison, so a weak ETag never matches. A
+/// date validator can't be checked without a second request, so we send the
+/// whole object instead, which is always a correct response to If-Range.
+fn if_range_etag(req: &HttpRequest) -> Result<Option<String>, ()> {
+    match req.headers().get(header::IF_RANGE) {
+        None => Ok(None),
+        Some(v) => match v.to_str() {
+            Ok(v) if v.starts_with('"') => Ok(Some(v.to_string())),
+            _ => Err(()),
+        },
+    }
+}
+
+/// Fetch a request built by `conditional_get`.
+///
+/// S3 doesn't understand If-Range, so its ETag is sent as If-Match alongside
+/// the Range. If the object has changed since, S3 refuses with 412 and the
+/// whole object is fetched instead, as If-Range requires.
+async fn get_conditional(
+    buckets: &ReadBuckets,
+    request: GetObjectRequest,
+) -> Result<GetObjectOutput, RetryError<GetObjectError>> {
+    let validated = request.range.is_some() && request.if_match.is_some();
+    let whole = GetObjectRequest {
+        range: None,
+        if_match: None,
+        ..request.clone()
+    };
+    match buckets.get_object(request).await {
+        Err(e) if validated && is_precondition_failed(&e) => buckets.get_object(whole).await,
+        result => result,
+    }
+}
+
+/// Whether S3 refused a request because If-Match didn't match.
+fn is_precondition_failed(err: &RetryError<GetObjectError>) -> bool {
+    match err.s3_error() {
+        Some(RusotoError::Unknown(resp)) => resp.status.as_u16() == 412,
+        _ => false,
+    }
+}
+
+/// The Range to request of a cached derivative.
+///
+/// Its ETag lives in the object's metadata, where S3 can't compare it with
+/// If-Range, so a conditional range gets the whole derivative instead.
+fn derivative_range(req: &HttpRequest) -> Option<String> {
+    if req.headers().contains_key(header::IF_RANGE) {
+        return None;
+    }
+    let range = req.headers().get(header::RANGE)?;
+    range.to_str().ok().map(String::from)
+}
+
+/// Whether S3 refused a Range that starts past the end of the object.
+fn is_unsatisfiable(err: &RetryError<GetObjectError>) -> bool {
+    match err.s3_error() {
+        Some(RusotoError::Unknown(resp)) => resp.status.as_u16() == 416,
+        _ => false,
+    }
+}
+
+/// The first and last byte of the range requested of a `length` byte body
+/// whose ETag is `etag`, or Err if the range can't be satisfied.
+///
+/// None means the whole body should be sent: there's no Range, If-Range
+/// names another version, or the Range isn't a single byte range.
+fn requested_range(
+    req: &HttpRequest,
+    etag: Option<&str>,
+    length: u64,
+) -> Option<Result<(u64, u64), ()>> {
+    if let Some(if_range) = req.headers().get(header::IF_RANGE) {
+        // Only strong ETags can match.
+        let if_range = if_range.to_str().ok().filter(|v| v.starts_with('"'));
+        if if_range.is_none() || if_range != etag {
+            return None;
+        }
+    }
+    let range = req.headers().get(header::RANGE)?.to_str().ok()?;
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let mut bounds = spec.splitn(2, '-');
+    let first = bounds.next()?.trim();
+    let last = bounds.next()?.trim();
+
+    let (start, end) = if first.is_empty() {
+        // The final bytes.
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        (length.saturating_sub(suffix), length - 1)
+    } else {
+        let start: u64 = first.parse().ok()?;
+        if start >= length {
+            return Some(Err(()));
+        }
+        let end = match last {
+            "" => length - 1,
+            last => last.parse::<u64>().ok()?.min(length - 1),
+        };
+        if end < start {
+            return None;
+        }
+        (start, end)
+    };
+    Some(Ok((start, end)))
+}
+
+/// Translate S3's conditional responses, which rusoto reports as errors.
+fn conditional_response(err: &RusotoError<GetObjectError>) -> Option<HttpResponse> {
+    match err {
+        RusotoError::Unknown(resp) => {
+            let mut client_resp = match resp.status.as_u16() {
+                304 => HttpResponse::NotModified(),
+                412 => HttpResponse::PreconditionFailed(),
+                416 => HttpResponse::RangeNotSatisfiable(),
+                _ => return None,
+            };
+            for name in &["etag", "last-modified", "cache-control", "content-range"] {
+                if let Some(value) = resp.headers.get(*name) {
+                    client_resp.header(*name, value.as_str());
+                }
+            }
+            Some(client_resp.finish())
+        }
+        _ => None,
+    }
+}
+
+/// Write adapter that forwards encoded image data to a response stream in chunks.
+///
+/// The channel is bounded, so a slow client applies backpressure to the encoder.
+struct ChunkWriter {
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    buf: Vec<u8>,
+    copy: Option<Vec<u8>>,
+}
+
+impl ChunkWriter {
+    fn new(tx: mpsc::Sender<Result<Bytes, io::Error>>, keep_copy: bool) -> ChunkWriter {
+        ChunkWriter {
+            tx,
+            buf: Vec::with_capacity(ENCODE_CHUNK_SIZE),
+            copy: if keep_copy { Some(Vec::new()) } else { None },
+        }
+    }
+
+    /// Everything written so far, if a copy was requested.
+    fn into_copy(self) -> Option<Vec<u8>> {
+        self.copy
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if let Some(copy) = self.copy.as_mut() {
+            copy.extend_from_slice(data);
+        }
+        if self.buf.len() >= ENCODE_CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buf,
+            Vec::with_capacity(ENCODE_CHUNK_SIZE),
+        ));
+        block_on(self.tx.send(Ok(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))
+    }
 }
 
 fn scale_image(
     data: &[u8],
     width: u32,
     height: u32,
-) -> Result<(&'static str, Vec<u8>), image::ImageError> {
+    max_scale: f64,
+    filter: FilterType,
+    cancel: &CancelToken,
+) -> Result<(ImageFormat, DynamicImage), image::ImageError> {
     // Determine the image format
     let fmt = image::guess_format(data)?;
 
     // Parse the image
+    cancel.check()?;
     let img = image::load_from_memory_with_format(data, fmt)?;
 
+    cancel.check()?;
+    Ok((fmt, scale(img, width, height, max_scale, filter)))
+}
+
+fn scale(
+    img: DynamicImage,
+    width: u32,
+    height: u32,
+    max_scale: f64,
+    filter: FilterType,
+) -> DynamicImage {
     let (orig_width, orig_height) = img.dimensions();
 
-    let scaled = if width < orig_width && height < orig_height {
-        // Take the largest size that maintains the aspect ratio
-        let ratio = orig_width as f64 / orig_height as f64;
-        let (new_width, new_height) = if width > height {
-            (width, (width as f64 / ratio) as u32)
-        } else {
-            ((height as f64 * ratio) as u32, height)
-        };
-        img.resize(new_width, new_height, FilterType::CatmullRom)
+    let (new_width, new_height) = target_dimensions(orig_width, orig_height, width, height, max_scale);
+    if (new_width, new_height) != (orig_width, orig_height) {
+        img.resize_exact(new_width, new_height, filter)
     } else {
-        // We're not going to scale up images.
         img
+    }
+}
+
+/// The size part of a derivative's key.
+fn derivative_size(width: u32, height: u32, max_scale: f64) -> String {
+    if max_scale > 1.0 {
+        format!("{}x{}-up{}", width, height, max_scale)
+    } else {
+        format!("{}x{}", width, height)
+    }
+}
+
+/// The sizes generated as soon as a photo is uploaded: the default size,
+/// and WARM_WIDTHS at any height.
+pub(crate) fn warm_sizes(config: &SiteConfig) -> Vec<(u32, u32)> {
+    let mut sizes = vec![(config.default_width(), config.default_height())];
+    for width in config.warm_widths() {
+        if !sizes.contains(&(width, 0)) {
+            sizes.push((width, 0));
+        }
+    }
+    sizes
+}
+
+/// The key the photo `filename` is cached under at its default URL for the size.
+pub(crate) fn derivative_key(
+    config: &SiteConfig,
+    width: u32,
+    height: u32,
+    filename: &str,
+) -> String {
+    let size = derivative_size(width, height, max_scale(config, config.upscale()));
+    format!("{}/{}/{}", DERIVATIVE_PREFIX, size, filename)
+}
+
+/// The original a derivative is made from.
+pub(crate) struct Source<'a> {
+    /// The key within the photo prefix.
+    pub filename: &'a str,
+    pub e_tag: &'a str,
+    pub last_modified: Option<&'a str>,
+}
+
+/// Cache the photo's common sizes, so its first visitors don't wait for
+/// them to be resized.
+pub(crate) async fn warm(config: SiteConfig, s3_client: S3Client, filename: String, data: Vec<u8>) {
+    // The cached copies carry the original's validators.
+    let (bucket, key) = config.locate(&format!("photo/{}", filename));
+    let head_request = HeadObjectRequest {
+        bucket,
+        key,
+        ..Default::default()
     };
+    let head = match s3_client.head_object(head_request).await {
+        Ok(head) => head,
+        Err(e) => {
+            error!("Failed to warm photo/{}: {}", filename, e);
+            return;
+        }
+    };
+
+    let source = Source {
+        filename: &filename,
+        e_tag: head.e_tag.as_deref().unwrap_or_default(),
+        last_modified: head.last_modified.as_deref(),
+    };
+    let sizes = warm_sizes(&config);
+    if let Err(e) = generate(&config, &s3_client, &source, data, sizes).await {
+        error!("Failed to warm photo/{}: {}", filename, e);
+    }
+}
+
+/// Resize the photo to each size and cache the results, returning how many
+/// were stored.
+pub(crate) async fn generate(
+    config: &SiteConfig,
+    s3_client: &S3Client,
+    source: &Source<'_>,
+    data: Vec<u8>,
+    sizes: Vec<(u32, u32)>,
+) -> Result<usize, String> {
+    let max_scale = max_scale(config, config.upscale());
+    // These are cached, so they're always made at full effort.
+    let effort = Effort::full();
+    let resizing = Resizing::start();
+    let (mime, encoded) = web::block(move || -> Result<_, image::ImageError> {
+        let _resizing = resizing;
+        let fmt = image::guess_format(&data)?;
+        let img = image::load_from_memory_with_format(&data, fmt)?;
+        let mut encoded = Vec::new();
+        for (width, height) in sizes {
+            let mut body = Vec::new();
+            scale(img.clone(), width, height, max_scale, effort.filter())
+                .write_to(&mut body, effort.output(fmt))?;
+            encoded.push((derivative_size(width, height, max_scale), body));
+        }
+        Ok((mime_for_image(fmt), encoded))
+    })
+    .await
+    .map_err(|e| e.to_string())?;
 
-    let mut new_data = Vec::new();
-    scaled.write_to(&mut new_data, fmt)?;
+    let mut stored = 0;
+    for (size, body) in encoded {
+        let mut metadata = HashMap::new();
+        metadata.insert("etag".to_string(), photo_etag(source.e_tag, &size, &effort));
+        if let Some(last_modified) = source.last_modified {
+            metadata.insert("last-modified".to_string(), last_modified.to_string());
+        }
+        let put_request = PutObjectRequest {
+            key: format!("{}/{}/{}", DERIVATIVE_PREFIX, size, source.filename),
+            body: Some(body.into()),
+            content_type: Some(mime.to_string()),
+            metadata: Some(metadata),
+            ..config.put_object_request()
+        };
+        match s3_client.put_object(put_request).await {
+            Ok(_) => stored += 1,
+            Err(e) => error!("Failed to cache derivative: {}", e),
+        }
+    }
+    Ok(stored)
+}
+
+/// Compute the size of a resized image.
+///
+/// The result is the largest size that fits within `width` x `height` and
+/// keeps the original aspect ratio. A 0 for either dimension means that
+/// dimension is unconstrained. Images are never enlarged by more than
+/// `max_scale`, and neither dimension will be rounded down to 0.
+pub(crate) fn target_dimensions(
+    orig_width: u32,
+    orig_height: u32,
+    width: u32,
+    height: u32,
+    max_scale: f64,
+) -> (u32, u32) {
+    if orig_width == 0 || orig_height == 0 {
+        return (orig_width, orig_height);
+    }
+
+    let scale_for = |bound: u32, orig: u32| {
+        if bound == 0 {
+            f64::INFINITY
+        } else {
+            bound as f64 / orig as f64
+        }
+    };
+    let scale = if width == 0 && height == 0 {
+        1.0
+    } else {
+        scale_for(width, orig_width)
+            .min(scale_for(height, orig_height))
+            .min(max_scale)
+    };
 
-    Ok((mime_for_image(fmt), new_data))
+    let scaled = |orig: u32| ((orig as f64 * scale).round() as u32).max(1);
+    (scaled(orig_width), scaled(orig_height))
 }
 
 fn mime_for_image(fmt: ImageFormat) -> &'static str {
@@ -213,3 +1646,77 @@ fn mime_for_image(fmt: ImageFormat) -> &'static str {
         _ => "",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+
+    use super::{requested_range, target_dimensions};
+
+    #[test]
+    fn landscape_photos_fit_the_width() {
+        assert_eq!(target_dimensions(4000, 3000, 1000, 0, 1.0), (1000, 750));
+        assert_eq!(target_dimensions(4000, 3000, 0, 600, 1.0), (800, 600));
+        assert_eq!(target_dimensions(4000, 3000, 1000, 1000, 1.0), (1000, 750));
+    }
+
+    #[test]
+    fn portrait_photos_fit_the_height() {
+        assert_eq!(target_dimensions(3000, 4000, 1000, 0, 1.0), (1000, 1333));
+        assert_eq!(target_dimensions(3000, 4000, 0, 1000, 1.0), (750, 1000));
+        assert_eq!(target_dimensions(3000, 4000, 1000, 1000, 1.0), (750, 1000));
+    }
+
+    #[test]
+    fn tiny_photos_are_only_enlarged_up_to_max_scale() {
+        assert_eq!(target_dimensions(10, 5, 1000, 0, 1.0), (10, 5));
+        assert_eq!(target_dimensions(10, 5, 1000, 0, 2.0), (20, 10));
+        assert_eq!(target_dimensions(10, 5, 0, 0, 2.0), (10, 5));
+    }
+
+    #[test]
+    fn dimensions_are_never_rounded_to_zero() {
+        assert_eq!(target_dimensions(1000, 1, 10, 0, 1.0), (10, 1));
+        assert_eq!(target_dimensions(0, 0, 1000, 0, 1.0), (0, 0));
+    }
+
+    fn range(value: &str, if_range: Option<&str>) -> Option<Result<(u64, u64), ()>> {
+        let mut req = TestRequest::default().header(header::RANGE, value);
+        if let Some(if_range) = if_range {
+            req = req.header(header::IF_RANGE, if_range);
+        }
+        requested_range(&req.to_http_request(), Some("\"abc\""), 100)
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_length() {
+        assert_eq!(range("bytes=0-9", None), Some(Ok((0, 9))));
+        assert_eq!(range("bytes=90-", None), Some(Ok((90, 99))));
+        assert_eq!(range("bytes=-10", None), Some(Ok((90, 99))));
+        assert_eq!(range("bytes=95-200", None), Some(Ok((95, 99))));
+        assert_eq!(range("bytes=-200", None), Some(Ok((0, 99))));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(range("bytes=100-", None), Some(Err(())));
+        assert_eq!(range("bytes=-0", None), Some(Err(())));
+    }
+
+    #[test]
+    fn unsupported_ranges_get_the_whole_object() {
+        assert_eq!(range("bytes=0-1,5-6", None), None);
+        assert_eq!(range("items=0-9", None), None);
+        assert_eq!(range("bytes=9-0", None), None);
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(requested_range(&req, None, 100), None);
+    }
+
+    #[test]
+    fn if_range_must_be_the_strong_etag() {
+        assert_eq!(range("bytes=0-9", Some("\"abc\"")), Some(Ok((0, 9))));
+        assert_eq!(range("bytes=0-9", Some("\"def\"")), None);
+        assert_eq!(range("bytes=0-9", Some("W/\"abc\"")), None);
+    }
+}
@@ -0,0 +1,70 @@
+//! Encoding of user supplied values stored as S3 object metadata.
+//!
+//! Metadata travels as HTTP headers, so anything outside of printable ASCII
+//! is percent-encoded on the way in and decoded on the way out.
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use std::collections::HashMap;
+
+/// Key prefix for sidecar JSON documents holding extra metadata.
+pub const SIDECAR_PREFIX: &str = "meta";
+
+/// Characters that must be escaped in a metadata value.
+const ESCAPED: &AsciiSet = &CONTROLS.add(b'%');
+
+/// Characters that must be escaped in a tag key or value.
+const TAG_ESCAPED: &AsciiSet = &CONTROLS
+    .add(b'%')
+    .add(b'&')
+    .add(b'=')
+    .add(b'+')
+    .add(b' ')
+    .add(b'#');
+
+pub fn encode(value: &str) -> String {
+    utf8_percent_encode(value, ESCAPED).to_string()
+}
+
+pub fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+/// The key of the sidecar document for the object with the given key.
+pub fn sidecar_key(key: &str) -> String {
+    format!("{}/{}.json", SIDECAR_PREFIX, key)
+}
+
+/// Decode every value in an object's metadata.
+pub fn decode_all(metadata: HashMap<String, String>) -> HashMap<String, String> {
+    metadata
+        .into_iter()
+        .map(|(k, v)| {
+            let v = decode(&v);
+            (k, v)
+        })
+        .collect()
+}
+
+/// Convert comma separated `key=value` pairs into the query string S3 expects for tags.
+pub fn tagging(tags: &str) -> String {
+    tags.split(',')
+        .filter_map(|tag| {
+            let tag = tag.trim();
+            let (key, value) = match tag.find('=') {
+                Some(i) => (&tag[..i], &tag[i + 1..]),
+                None => (tag, ""),
+            };
+            if key.is_empty() {
+                None
+            } else {
+                Some(format!(
+                    "{}={}",
+                    utf8_percent_encode(key, TAG_ESCAPED),
+                    utf8_percent_encode(value, TAG_ESCAPED)
+                ))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
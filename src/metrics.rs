@@ -0,0 +1,76 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::access_log::{CacheSource, CacheStatus};
+use crate::load;
+use crate::micropub::authorize;
+use crate::oauth;
+use crate::policy::Permission;
+use crate::SiteConfig;
+
+/// Photo cache lookups since the process started.
+static DISK_HITS: AtomicU64 = AtomicU64::new(0);
+static S3_HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static BYPASSES: AtomicU64 = AtomicU64::new(0);
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/admin/metrics").route(web::get().to(handle_metrics)));
+}
+
+/// Count how a photo request was served.
+pub fn count_cache(status: CacheStatus, source: Option<CacheSource>) {
+    let counter = match (status, source) {
+        (CacheStatus::Hit, Some(CacheSource::Disk)) => &DISK_HITS,
+        (CacheStatus::Hit, _) => &S3_HITS,
+        (CacheStatus::Miss, _) => &MISSES,
+        (CacheStatus::Bypass, _) => &BYPASSES,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Serialize)]
+struct MetricsResponse {
+    cache: CacheMetrics,
+    resize_queue_depth: usize,
+}
+
+#[derive(Serialize)]
+struct CacheMetrics {
+    disk_hits: u64,
+    s3_hits: u64,
+    misses: u64,
+    bypasses: u64,
+    /// Hits over hits and misses. Bypasses couldn't have been hits.
+    hit_rate: Option<f64>,
+}
+
+/// Report this process's counters.
+async fn handle_metrics(
+    req: HttpRequest,
+    site: web::Data<SiteConfig>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+
+    let disk_hits = DISK_HITS.load(Ordering::Relaxed);
+    let s3_hits = S3_HITS.load(Ordering::Relaxed);
+    let misses = MISSES.load(Ordering::Relaxed);
+    let hits = disk_hits + s3_hits;
+    HttpResponse::Ok().json(MetricsResponse {
+        cache: CacheMetrics {
+            disk_hits,
+            s3_hits,
+            misses,
+            bypasses: BYPASSES.load(Ordering::Relaxed),
+            hit_rate: Some(hits + misses)
+                .filter(|lookups| *lookups > 0)
+                .map(|lookups| hits as f64 / lookups as f64),
+        },
+        resize_queue_depth: load::queue_depth(),
+    })
+}
@@ -1,30 +1,87 @@
-use actix_multipart::Multipart;
+use actix_multipart::{Field, Multipart};
+use actix_web::body::{Body, ResponseBody};
+use actix_web::client::Client;
+use actix_web::dev::RequestHead;
+use actix_web::error::BlockingError;
 use actix_web::http::header;
 use actix_web::{web, HttpRequest, HttpResponse};
 
-use chrono::Utc;
+use chrono::{DateTime, NaiveDate, Utc};
 
-use futures::{StreamExt, TryStreamExt};
+use futures::TryStreamExt;
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
-use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use derive_more::Display;
+use log::{error, warn};
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    HeadObjectError, HeadObjectOutput, HeadObjectRequest, PutObjectRequest, S3Client, S3,
+};
 
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 use std::iter;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
 
+use crate::archive::{self, Unpacker};
+use crate::audit::AuditLog;
+use crate::captions;
+use crate::cdn::Cdn;
+use crate::classify::{self, UrlStyle};
+use crate::delete;
+use crate::derivatives;
+use crate::disk_cache::DiskCache;
+use crate::events::{self, EventKind, MediaEvent};
+use crate::expiry;
+use crate::geo::{self, Trace};
+use crate::hls;
+use crate::ids;
+use crate::index::{ListFilter, MediaIndex, MediaRecord, Usage};
+use crate::keys;
+use crate::listing;
+use crate::media;
+use crate::metadata;
+use crate::moderation::{self, Moderation, ModerationBackend};
+use crate::multipart;
 use crate::oauth;
+use crate::originals;
+use crate::peaks;
+use crate::phash::{self, DuplicateMode};
+use crate::policy::{self, Permission};
+use crate::previews;
+use crate::progress::Progress;
+use crate::quarantine;
+use crate::reencode;
+use crate::reporting::ReportUser;
+use crate::scan::{ScanBackend, ScanError, Verdict};
+use crate::staging;
+use crate::transfer::{Transfer, TransferError};
+use crate::undo::RecentUploads;
 use crate::SiteConfig;
 
 // To make the timepart shorter, we'll offset it with a custom epoch.
 const EPOCH: i64 = 631152000;
 
+/// The admin gallery page.
+const GALLERY_HTML: &str = include_str!("gallery.html");
+
+/// The form asking for a token before the gallery is served.
+const GALLERY_LOGIN_HTML: &str = include_str!("gallery_login.html");
+
+/// The longest value accepted for a metadata form field.
+const MAX_METADATA_LENGTH: usize = 2048;
+
+/// How many IDs to try before giving up on finding an unused key.
+const MAX_KEY_ATTEMPTS: usize = 5;
+
 #[derive(Serialize, Deserialize)]
-struct MicropubError {
+pub(crate) struct MicropubError {
     error: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     error_description: Option<String>,
@@ -53,7 +110,756 @@ impl MicropubError {
     }
 }
 
-fn random_id() -> String {
+/// The file part of a multipart upload.
+struct Upload {
+    content_type: mime::Mime,
+    filename: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Read a multipart field into memory.
+async fn read_field(mut field: Field, transfer: &mut Transfer) -> Result<Vec<u8>, TransferError> {
+    let mut body = Vec::new();
+    while let Some(chunk) = transfer.next_chunk(&mut field).await? {
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Where a new object will be stored.
+pub(crate) struct Placement {
+    /// The media type, e.g. `photo`.
+    pub classification: String,
+    /// The first part of the key.
+    pub prefix: String,
+    style: UrlStyle,
+    pub id: String,
+    /// The key within the prefix.
+    pub key: String,
+    /// The date the key is filed under.
+    pub date: DateTime<Utc>,
+}
+
+impl Placement {
+    /// Choose a new key for a file with the given type and name, laid out
+    /// by the site's key template.
+    pub fn new(
+        site: &SiteConfig,
+        content_type: &mime::Mime,
+        filename: Option<&str>,
+        author: &str,
+        checksum: Option<&str>,
+        date: DateTime<Utc>,
+    ) -> Placement {
+        let classification = classify::classify(site, content_type, filename);
+
+        // This will be the key in S3.
+        let id = ids::new_id(site);
+        let vars = keys::KeyVars {
+            prefix: &classification.prefix,
+            id: &id,
+            filename,
+            author,
+            checksum,
+            date,
+        };
+        let object_key = keys::render(keys::template(site, classification.style), &vars);
+        let key = object_key[classification.prefix.len() + 1..].to_string();
+
+        Placement {
+            classification: classification.name,
+            prefix: classification.prefix,
+            style: classification.style,
+            id,
+            key,
+            date,
+        }
+    }
+
+    /// Choose a key which isn't already in use, trying a new ID each time
+    /// one is taken.
+    ///
+    /// There's still a short window between the check and the upload, since
+    /// rusoto can't make the put conditional.
+    ///
+    /// Without `s3:ListBucket`, S3 answers 403 rather than 404 for missing
+    /// keys, so a 403 is taken to mean the key is free too.
+    pub async fn unused(
+        site: &SiteConfig,
+        s3_client: &S3Client,
+        content_type: &mime::Mime,
+        filename: Option<&str>,
+        author: &str,
+        checksum: Option<&str>,
+        date: DateTime<Utc>,
+    ) -> Result<Placement, PlacementError> {
+        let mut attempts = 0;
+        loop {
+            let placement = Placement::new(site, content_type, filename, author, checksum, date);
+            let object_key = placement.object_key();
+            let (bucket, key) = site.locate(&object_key);
+            let request = HeadObjectRequest {
+                bucket,
+                key,
+                ..Default::default()
+            };
+            match s3_client.head_object(request).await {
+                Err(RusotoError::Unknown(ref resp))
+                    if matches!(resp.status.as_u16(), 403 | 404) =>
+                {
+                    return Ok(placement)
+                }
+                Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => return Ok(placement),
+                Err(e) => return Err(PlacementError::Failed(object_key, e.to_string())),
+                Ok(_) => {
+                    attempts += 1;
+                    warn!("{} is already in use", object_key);
+                    if attempts == MAX_KEY_ATTEMPTS {
+                        return Err(PlacementError::Taken(object_key));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Where an object which is already stored was placed, e.g. one sent
+    /// straight to the bucket. The id is taken to be the first part of the
+    /// key, without its extension, as the default layouts have it.
+    pub fn existing(
+        site: &SiteConfig,
+        object_key: &str,
+        content_type: &mime::Mime,
+        filename: Option<&str>,
+    ) -> Option<Placement> {
+        let classification = classify::classify(site, content_type, filename);
+        let key = object_key
+            .strip_prefix(&classification.prefix)?
+            .strip_prefix('/')?
+            .to_string();
+        let id = key.split('/').next()?.split('.').next()?.to_string();
+        Some(Placement {
+            classification: classification.name,
+            prefix: classification.prefix,
+            style: classification.style,
+            id,
+            key,
+            date: Utc::now(),
+        })
+    }
+
+    /// The full S3 key.
+    pub fn object_key(&self) -> String {
+        format!("{}/{}", self.prefix, self.key)
+    }
+
+    /// This will be the publicly accessible URL for the file.
+    pub fn url(&self, site: &SiteConfig) -> String {
+        if self.style == UrlStyle::Resized {
+            format!(
+                "{}/{}/{}x{}/{}",
+                site.media_url(),
+                self.prefix,
+                site.default_width(),
+                site.default_height(),
+                self.key
+            )
+        } else {
+            format!("{}/{}/{}", site.media_url(), self.prefix, self.key)
+        }
+    }
+}
+
+/// Why no key could be chosen for an upload.
+#[derive(Display, Debug)]
+pub(crate) enum PlacementError {
+    /// Every key tried was in use, e.g. because the key template doesn't
+    /// include the ID.
+    #[display(fmt = "{} and the other keys tried are already in use", _0)]
+    Taken(String),
+    #[display(fmt = "Failed to check whether {} is in use: {}", _0, _1)]
+    Failed(String, String),
+}
+
+impl PlacementError {
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            PlacementError::Taken(_) => {
+                HttpResponse::Conflict().json(MicropubError::with_description("conflict", self))
+            }
+            PlacementError::Failed(..) => {
+                error!("{}", self);
+                HttpResponse::InternalServerError().body(self.to_string())
+            }
+        }
+    }
+}
+
+/// A newly stored object.
+pub(crate) struct NewObject {
+    pub placement: Placement,
+    pub url: String,
+    pub content_type: mime::Mime,
+    pub filename: Option<String>,
+    pub size: u64,
+    pub checksum: Option<String>,
+    pub extra_metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct CreatedResponse<'a> {
+    url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    /// An earlier upload which looks the same.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<&'a str>,
+}
+
+/// The size of a photo at its URL, if it's served resized.
+pub(crate) fn default_dimensions(
+    site: &SiteConfig,
+    placement: &Placement,
+    data: &[u8],
+) -> Option<(u32, u32)> {
+    if placement.style != UrlStyle::Resized {
+        return None;
+    }
+    media::default_dimensions(site, data)
+}
+
+/// Whether the photo's common sizes are cached as soon as it's uploaded.
+pub(crate) fn warms(site: &SiteConfig, placement: &Placement) -> bool {
+    placement.style == UrlStyle::Resized && site.derivative_cache()
+}
+
+/// The response for a stored upload.
+///
+/// Photos include the size they'll be served at, so clients can set the
+/// `width` and `height` of the image.
+pub(crate) fn created(url: &str, dimensions: Option<(u32, u32)>) -> HttpResponse {
+    created_like(url, dimensions, None)
+}
+
+fn created_like(
+    url: &str,
+    dimensions: Option<(u32, u32)>,
+    duplicate_of: Option<&str>,
+) -> HttpResponse {
+    let mut resp = HttpResponse::Created();
+    resp.header(header::LOCATION, url);
+    if let Some((width, height)) = dimensions {
+        resp.header("X-Image-Width", width.to_string());
+        resp.header("X-Image-Height", height.to_string());
+    }
+    resp.json(CreatedResponse {
+        url,
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        duplicate_of,
+    })
+}
+
+/// Point at an earlier upload instead of storing one which looks the same.
+fn duplicate(existing: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .header(header::LOCATION, existing)
+        .json(CreatedResponse {
+            url: existing,
+            width: None,
+            height: None,
+            duplicate_of: Some(existing),
+        })
+}
+
+/// Build the metadata for a new object.
+///
+/// Extra fields go in a sidecar if configured, otherwise in the object metadata.
+pub(crate) async fn object_metadata(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    object_key: &str,
+    extra_metadata: &HashMap<String, String>,
+    access_token: &oauth::AccessToken,
+    filename: Option<&str>,
+) -> Result<HashMap<String, String>, HttpResponse> {
+    let mut metadata: HashMap<String, String> = if site.metadata_sidecar() {
+        if !extra_metadata.is_empty() {
+            let sidecar_request = PutObjectRequest {
+                key: metadata::sidecar_key(object_key),
+                body: Some(serde_json::to_vec(extra_metadata).unwrap().into()),
+                content_type: Some("application/json".to_string()),
+                ..site.put_object_request()
+            };
+            if let Err(e) = s3_client.put_object(sidecar_request).await {
+                return Err(HttpResponse::InternalServerError().body(format!("{}", e)));
+            }
+        }
+        HashMap::new()
+    } else {
+        extra_metadata
+            .iter()
+            .map(|(k, v)| (k.clone(), metadata::encode(v)))
+            .collect()
+    };
+
+    metadata.insert(
+        "client-id".to_string(),
+        access_token.client_id().to_string(),
+    );
+    metadata.insert("author".to_string(), access_token.me().to_string());
+    if let Some(api_key) = access_token.api_key() {
+        metadata.insert("api-key".to_string(), api_key.to_string());
+    }
+    if let Some(f) = filename {
+        metadata.insert("filename".to_string(), metadata::encode(f));
+    }
+
+    Ok(metadata)
+}
+
+/// Add a stored object to the index and tell everyone about it.
+pub(crate) async fn record_upload(
+    site: &SiteConfig,
+    publisher: &events::Publisher,
+    index: Option<&MediaIndex>,
+    access_token: &oauth::AccessToken,
+    object: NewObject,
+) {
+    let object_key = object.placement.object_key();
+
+    if let Some(index) = index {
+        let record = MediaRecord {
+            key: object_key.clone(),
+            url: object.url.clone(),
+            classification: object.placement.classification.clone(),
+            content_type: Some(object.content_type.to_string()),
+            size: Some(object.size as i64),
+            author: access_token.me().to_string(),
+            client_id: access_token.client_id().to_string(),
+            checksum: object.checksum.clone(),
+            filename: object.filename.clone(),
+            alt: object.extra_metadata.get("alt").cloned(),
+            caption: object.extra_metadata.get("caption").cloned(),
+            created_at: object.placement.date,
+        };
+        if let Err(e) = index.insert(&record).await {
+            error!("Failed to add {} to the index: {}", object_key, e);
+        }
+    }
+
+    publisher.publish(
+        site,
+        MediaEvent {
+            event: EventKind::Create,
+            url: object.url,
+            key: object_key,
+            content_type: Some(object.content_type.to_string()),
+            size: Some(object.size),
+            author: access_token.me().to_string(),
+            client_id: access_token.client_id().to_string(),
+            api_key: access_token.api_key().map(str::to_string),
+            checksum: object.checksum,
+            timestamp: Utc::now(),
+        },
+    );
+}
+
+/// The trace in an upload classified as a map, which must parse for it to be
+/// stored. Other uploads have none.
+pub(crate) fn parse_trace(
+    classification: &str,
+    content_type: &mime::Mime,
+    filename: Option<&str>,
+    data: &[u8],
+) -> Result<Option<Trace>, HttpResponse> {
+    if classification != "map" {
+        return Ok(None);
+    }
+    Trace::parse(content_type, filename, data)
+        .map(Some)
+        .map_err(|e| {
+            HttpResponse::BadRequest().json(MicropubError::with_description("invalid_request", e))
+        })
+}
+
+/// Whether uploads like this one are inspected before they are published.
+pub(crate) fn inspects(site: &SiteConfig, placement: &Placement) -> bool {
+    placement.classification == "photo"
+        || ScanBackend::for_upload(site, &placement.classification).is_some()
+        || ModerationBackend::for_upload(site, &placement.classification).is_some()
+}
+
+/// Why the upload shouldn't be published, if there's a reason.
+///
+/// On top of [`check_upload`], photos are moderated if MODERATION is set.
+/// Only uploads sent straight to the media endpoint can be moderated after
+/// they're published, so this moderates first even with MODERATION_ASYNC.
+pub(crate) async fn inspect_upload(
+    site: &SiteConfig,
+    http_client: &Client,
+    placement: &Placement,
+    content_type: &mime::Mime,
+    data: &[u8],
+) -> Result<Option<String>, HttpResponse> {
+    if let Some(reason) = check_upload(site, placement, content_type, data).await? {
+        return Ok(Some(reason));
+    }
+    moderate_upload(site, http_client, placement, content_type, data).await
+}
+
+/// Why the upload shouldn't be published, if there's a reason.
+///
+/// Photos must be what they claim to be and within MAX_IMAGE_PIXELS. Files
+/// are scanned if a scanner is configured.
+async fn check_upload(
+    site: &SiteConfig,
+    placement: &Placement,
+    content_type: &mime::Mime,
+    data: &[u8],
+) -> Result<Option<String>, HttpResponse> {
+    if placement.classification == "photo" {
+        return Ok(quarantine::check_image(site, content_type, data));
+    }
+
+    let scanner = match ScanBackend::for_upload(site, &placement.classification) {
+        Some(scanner) => scanner,
+        None => return Ok(None),
+    };
+    match scanner.scan(data.to_vec()).await {
+        Ok(Verdict::Clean) => Ok(None),
+        Ok(Verdict::Infected(signature)) => Ok(Some(format!("The file contains {}", signature))),
+        Err(e) => {
+            error!("Failed to scan {}: {}", placement.object_key(), e);
+            Err(HttpResponse::InternalServerError().body(format!("{}", e)))
+        }
+    }
+}
+
+/// Why the moderator flagged the upload, if it's moderated and it did.
+async fn moderate_upload(
+    site: &SiteConfig,
+    http_client: &Client,
+    placement: &Placement,
+    content_type: &mime::Mime,
+    data: &[u8],
+) -> Result<Option<String>, HttpResponse> {
+    let moderator = match ModerationBackend::for_upload(site, &placement.classification) {
+        Some(moderator) => moderator,
+        None => return Ok(None),
+    };
+    match moderator
+        .moderate(http_client, content_type, data.to_vec())
+        .await
+    {
+        Ok(Moderation::Approved) => Ok(None),
+        Ok(Moderation::Flagged(flagged)) => Ok(Some(moderation::reason(&flagged))),
+        Err(e) => {
+            error!("Failed to moderate {}: {}", placement.object_key(), e);
+            Err(HttpResponse::InternalServerError().body(format!("{}", e)))
+        }
+    }
+}
+
+/// What inspecting an upload completed in the bucket found.
+pub(crate) struct Inspected {
+    /// Why it shouldn't be published, if there's a reason.
+    pub rejection: Option<String>,
+    /// The whole upload, if it was read to inspect it.
+    pub data: Option<Vec<u8>>,
+}
+
+/// Inspect a `size` byte upload completed in the bucket under the logical
+/// key `key`, like [`inspect_upload`], without holding it all in memory
+/// unless it has to be.
+///
+/// Files are scanned a range at a time. Photos are read whole, and refused if
+/// they're bigger than INSPECT_MAX_BYTES.
+pub(crate) async fn inspect_stored(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    placement: &Placement,
+    content_type: &mime::Mime,
+    key: &str,
+    size: u64,
+) -> Result<Inspected, HttpResponse> {
+    if placement.classification == "photo" {
+        let data = match staging::read_small(site, s3_client, key, size).await {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                return Ok(Inspected {
+                    rejection: Some("The photo is too large to inspect".to_string()),
+                    data: None,
+                })
+            }
+            Err(e) => {
+                error!("{}", e);
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        };
+        let rejection = inspect_upload(site, http_client, placement, content_type, &data).await?;
+        return Ok(Inspected {
+            rejection,
+            data: Some(data),
+        });
+    }
+
+    let rejection = match ScanBackend::for_upload(site, &placement.classification) {
+        Some(scanner) => match scan_stored(site, s3_client, &scanner, key, size).await {
+            Ok(Verdict::Clean) => None,
+            Ok(Verdict::Infected(signature)) => Some(format!("The file contains {}", signature)),
+            Err(e) => {
+                error!("Failed to scan {}: {}", key, e);
+                return Err(HttpResponse::InternalServerError().body(format!("{}", e)));
+            }
+        },
+        None => None,
+    };
+    Ok(Inspected {
+        rejection,
+        data: None,
+    })
+}
+
+/// Send a stored upload to the scanner a range at a time.
+async fn scan_stored(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    scanner: &ScanBackend,
+    key: &str,
+    size: u64,
+) -> Result<Verdict, ScanError> {
+    let mut scan = scanner.start().await?;
+    for (start, len) in staging::ranges(size) {
+        let data = staging::read_range(site, s3_client, key, start, len)
+            .await
+            .map_err(ScanError::Failed)?;
+        scan = scan.write(data).await?;
+    }
+    scan.finish().await
+}
+
+/// How many redirects a sideloaded URL may go through.
+const MAX_SIDELOAD_REDIRECTS: usize = 5;
+
+/// Fetch a remote file to store as if it had been uploaded.
+///
+/// Only hosts with public addresses are fetched, and each redirect is checked
+/// the same way before it's followed, so the endpoint can't be used to reach
+/// its own network.
+async fn sideload(url: &str, max_bytes: usize) -> Result<Upload, MicropubError> {
+    // Redirects are followed here so each hop can be checked.
+    let client = Client::build().disable_redirects().finish();
+    let mut url = url.to_string();
+    let mut redirects = 0;
+    let mut resp = loop {
+        check_public(&url).await?;
+        let resp = client
+            .get(&url)
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await
+            .map_err(|e| MicropubError::with_description("invalid_request", e))?;
+        if !resp.status().is_redirection() {
+            break resp;
+        }
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        match location {
+            Some(location) if redirects < MAX_SIDELOAD_REDIRECTS => {
+                url = resolve_location(&url, &location)
+            }
+            _ => break resp,
+        }
+        redirects += 1;
+    };
+    let url = url.as_str();
+
+    if !resp.status().is_success() {
+        return Err(MicropubError::with_description(
+            "invalid_request",
+            format!("Fetching {} returned {}", url, resp.status()),
+        ));
+    }
+
+    let content_type: mime::Mime = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    match content_type.type_() {
+        mime::IMAGE | mime::AUDIO | mime::VIDEO => (),
+        _ => {
+            return Err(MicropubError::with_description(
+                "invalid_request",
+                format!("{} is not a photo, audio, or video file", url),
+            ))
+        }
+    }
+
+    let body = resp
+        .body()
+        .limit(max_bytes)
+        .await
+        .map_err(|e| MicropubError::with_description("invalid_request", e))?;
+
+    // Use the last path segment as the filename.
+    let path = url.split(|c| c == '?' || c == '#').next().unwrap_or(url);
+    let filename = path
+        .splitn(2, "://")
+        .nth(1)
+        .and_then(|rest| rest.find('/').map(|i| &rest[i + 1..]))
+        .and_then(|p| p.rsplit('/').next())
+        .filter(|f| !f.is_empty())
+        .map(|f| f.to_string());
+
+    Ok(Upload {
+        content_type,
+        filename,
+        body: body.to_vec(),
+    })
+}
+
+/// Refuse a URL which isn't http(s) or whose host resolves to a loopback,
+/// private, or link-local address.
+async fn check_public(url: &str) -> Result<(), MicropubError> {
+    let (scheme, rest) = match url.find("://") {
+        Some(i) => (&url[..i], &url[i + 3..]),
+        None => ("", url),
+    };
+    let default_port = match scheme.to_ascii_lowercase().as_str() {
+        "https" => 443,
+        "http" => 80,
+        _ => {
+            return Err(MicropubError::with_description(
+                "invalid_request",
+                "Only http and https URLs may be imported",
+            ))
+        }
+    };
+
+    let authority = rest
+        .split(|c| c == '/' || c == '?' || c == '#')
+        .next()
+        .unwrap_or("");
+    let host_port = authority.rsplit('@').next().unwrap_or(authority);
+    let (host, port) = if host_port.starts_with('[') {
+        // An IPv6 literal, e.g. [::1]:8080
+        match host_port.find(']') {
+            Some(end) => (&host_port[1..end], host_port[end + 1..].strip_prefix(':')),
+            None => (host_port, None),
+        }
+    } else {
+        match host_port.rfind(':') {
+            Some(i) => (&host_port[..i], Some(&host_port[i + 1..])),
+            None => (host_port, None),
+        }
+    };
+    let port = match port.filter(|p| !p.is_empty()) {
+        Some(port) => port.parse().map_err(|_| {
+            MicropubError::with_description("invalid_request", format!("Bad port in {}", url))
+        })?,
+        None => default_port,
+    };
+    if host.is_empty() {
+        return Err(MicropubError::with_description(
+            "invalid_request",
+            format!("{} has no host", url),
+        ));
+    }
+
+    let lookup = (host.to_string(), port);
+    let addrs: Vec<SocketAddr> =
+        match web::block(move || lookup.to_socket_addrs().map(|addrs| addrs.collect())).await {
+            Ok(addrs) => addrs,
+            Err(BlockingError::Error(e)) => {
+                return Err(MicropubError::with_description(
+                    "invalid_request",
+                    format!("Can't resolve {}: {}", host, e),
+                ))
+            }
+            Err(BlockingError::Canceled) => {
+                return Err(MicropubError::new("server_error"));
+            }
+        };
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(MicropubError::with_description(
+            "invalid_request",
+            format!("{} isn't a public host", host),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether an address is reachable on the public internet.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4() {
+                // IPv4-mapped and -compatible addresses, but not ::1
+                if !ip.is_loopback() {
+                    return is_public(IpAddr::V4(v4));
+                }
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local
+                || (first & 0xfe00) == 0xfc00
+                // Link-local
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolve a redirect's Location against the URL which returned it.
+fn resolve_location(base: &str, location: &str) -> String {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return location.to_string();
+    }
+    let scheme_end = base.find("://").map(|i| i + 3).unwrap_or(0);
+    if location.starts_with("//") {
+        return format!("{}{}", &base[..scheme_end - 1], &location[1..]);
+    }
+    let path_start = base[scheme_end..]
+        .find(|c| c == '/' || c == '?' || c == '#')
+        .map(|i| scheme_end + i)
+        .unwrap_or_else(|| base.len());
+    if location.starts_with('/') {
+        return format!("{}{}", &base[..path_start], location);
+    }
+    let path = base[path_start..]
+        .split(|c| c == '?' || c == '#')
+        .next()
+        .unwrap_or("");
+    let dir = match path.rfind('/') {
+        Some(i) => &path[..=i],
+        None => "/",
+    };
+    format!("{}{}{}", &base[..path_start], dir, location)
+}
+
+pub(crate) fn random_id() -> String {
     let now = Utc::now();
 
     // Generate the time part
@@ -74,94 +880,1261 @@ fn random_id() -> String {
     format!("{}-{}", time_part, random_part)
 }
 
+/// Validate the request's access token, or its API key.
+pub(crate) async fn authenticate(
+    req: &HttpRequest,
+    verification_service: &oauth::VerificationService,
+) -> Result<oauth::AccessToken, HttpResponse> {
+    let validated = match req.headers().get("X-Api-Key") {
+        Some(api_key) => api_key
+            .to_str()
+            .map_err(|_| actix_web::Error::from(oauth::VerificationError::Unauthenticated))
+            .and_then(|key| verification_service.validate_api_key(key)),
+        None => match req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|s| s.to_str().ok())
+        {
+            Some(auth_header) => verification_service.validate(auth_header).await,
+            None => {
+                return Err(HttpResponse::Unauthorized().json(MicropubError::new("unauthorized")))
+            }
+        },
+    };
+
+    let access_token = validated.map_err(|e| {
+        HttpResponse::Unauthorized().json(MicropubError::with_description("unauthorized", e))
+    })?;
+    req.extensions_mut()
+        .insert(ReportUser(access_token.me().to_string()));
+    Ok(access_token)
+}
+
+/// Validate the request's credentials and make sure they grant `permission`.
+pub(crate) async fn authorize(
+    req: &HttpRequest,
+    verification_service: &oauth::VerificationService,
+    site: &SiteConfig,
+    permission: Permission,
+) -> Result<oauth::AccessToken, HttpResponse> {
+    let access_token = authenticate(req, verification_service).await?;
+    if !policy::allows(site, &access_token, permission) {
+        return Err(insufficient_scope(permission));
+    }
+    Ok(access_token)
+}
+
+/// The response for a token without a scope that grants `permission`.
+///
+/// Tokens that can't use the media endpoint at all are unauthorized, while
+/// ones missing `delete` or `admin` are forbidden.
+pub(crate) fn insufficient_scope(permission: Permission) -> HttpResponse {
+    match permission {
+        Permission::Create | Permission::Read | Permission::List => {
+            HttpResponse::Unauthorized().json(MicropubError::new("unauthorized"))
+        }
+        Permission::Delete | Permission::Admin => {
+            HttpResponse::Forbidden().json(MicropubError::new("insufficient_scope"))
+        }
+    }
+}
+
+/// Query parameters accepted by the media endpoint.
+#[derive(Deserialize)]
+pub struct MediaQuery {
+    action: Option<String>,
+    url: Option<String>,
+    /// Track the upload's progress under this ID.
+    upload_id: Option<String>,
+}
+
 pub async fn handle_upload(
     req: HttpRequest,
+    query: web::Query<MediaQuery>,
     mut payload: Multipart,
     site: web::Data<SiteConfig>,
     s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    (publisher, index): (web::Data<events::Publisher>, Option<web::Data<MediaIndex>>),
+    (cdn, disk_cache): (web::Data<Cdn>, Option<web::Data<DiskCache>>),
+    (recent_uploads, progress): (web::Data<RecentUploads>, web::Data<Progress>),
     verification_service: web::Data<oauth::VerificationService>,
 ) -> HttpResponse {
-    let auth_header = match req
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|s| s.to_str().ok())
-    {
-        Some(auth_header) => auth_header,
-        None => return HttpResponse::Unauthorized().json(MicropubError::new("unauthorized")),
+    let access_token = match authenticate(&req, &verification_service).await {
+        Ok(token) => token,
+        Err(resp) => return resp,
     };
 
-    let access_token = match verification_service.validate(auth_header).await {
-        Ok(token) => token,
-        Err(e) => {
-            return HttpResponse::Unauthorized()
-                .json(MicropubError::with_description("unauthorized", e))
+    match query.action.as_deref() {
+        None => (),
+        Some("delete") => {
+            if !policy::allows(&site, &access_token, Permission::Delete) {
+                return insufficient_scope(Permission::Delete);
+            }
+            return match &query.url {
+                Some(url) => {
+                    delete::delete_url(
+                        &site,
+                        &s3_client,
+                        &http_client,
+                        &cdn,
+                        disk_cache.as_deref(),
+                        &publisher,
+                        index.as_deref(),
+                        &access_token,
+                        url,
+                    )
+                    .await
+                }
+                None => HttpResponse::BadRequest()
+                    .json(MicropubError::with_description("invalid_request", "Missing url")),
+            };
+        }
+        Some("undo") => {
+            if !policy::allows(&site, &access_token, Permission::Delete) {
+                return insufficient_scope(Permission::Delete);
+            }
+            let url = match recent_uploads.last(access_token.me()) {
+                Some(url) => url,
+                None => {
+                    return HttpResponse::NotFound().json(MicropubError::with_description(
+                        "not_found",
+                        "There's no recent upload to undo",
+                    ))
+                }
+            };
+            let resp = delete::delete_url(
+                &site,
+                &s3_client,
+                &http_client,
+                &cdn,
+                disk_cache.as_deref(),
+                &publisher,
+                index.as_deref(),
+                &access_token,
+                &url,
+            )
+            .await;
+            if resp.status().is_success() {
+                recent_uploads.forget(access_token.me(), &url);
+            }
+            return resp;
+        }
+        Some(action) => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                format!("Unknown action {}", action),
+            ))
+        }
+    }
+
+    if !policy::allows(&site, &access_token, Permission::Create) {
+        return insufficient_scope(Permission::Create);
+    }
+
+    // Collect the file and any extra metadata fields from the multipart stream.
+    let mut upload: Option<Upload> = None;
+    let mut extra_metadata: HashMap<String, String> = HashMap::new();
+    let mut sideload_url: Option<String> = None;
+    let mut expires_in: Option<u32> = None;
+    let mut caption_for: Option<String> = None;
+    let mut caption_lang: Option<String> = None;
+    let mut expand = false;
+    let mut transfer = Transfer::new(&site);
+    let _tracking = match &query.upload_id {
+        Some(id) => {
+            let total = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            match Progress::start(&progress, access_token.me(), id, total) {
+                Ok(tracking) => {
+                    transfer.report_to(tracking.counter());
+                    Some(tracking)
+                }
+                Err(resp) => return resp,
+            }
         }
+        _ => None,
     };
+    loop {
+        let field = match transfer.wait(payload.try_next()).await {
+            Ok(Ok(Some(field))) => field,
+            Ok(Ok(None)) => break,
+            // A truncated or malformed body mustn't be stored as if it was whole.
+            Ok(Err(e)) => {
+                return HttpResponse::BadRequest().json(MicropubError::with_description(
+                    "invalid_request",
+                    format!("Malformed multipart body: {}", e),
+                ))
+            }
+            Err(e) => return e.response(),
+        };
+        let content_disp = match field.content_disposition() {
+            Some(content_disp) => content_disp,
+            None => continue,
+        };
+
+        // Some clients leave out the filename, so the `file` field is always
+        // the upload.
+        let filename = content_disp.get_filename().map(str::to_string);
+        if filename.is_some() || content_disp.get_name() == Some("file") {
+            if upload.is_some() {
+                return HttpResponse::BadRequest().json(MicropubError::with_description(
+                    "invalid_request",
+                    "Only one file may be uploaded",
+                ));
+            }
+
+            let content_type = field.content_type().clone();
+            let body = match read_field(field, &mut transfer).await {
+                Ok(body) => body,
+                Err(e) => return e.response(),
+            };
+            upload = Some(Upload {
+                content_type,
+                filename,
+                body,
+            });
+        } else if let Some(name) = content_disp.get_name() {
+            let name = name.to_string();
+            let value = match read_field(field, &mut transfer).await {
+                Ok(value) => value,
+                Err(e) => return e.response(),
+            };
+            if name == "url" {
+                sideload_url = Some(String::from_utf8_lossy(&value).trim().to_string());
+            } else if name == "expand" {
+                expand = matches!(String::from_utf8_lossy(&value).trim(), "1" | "true");
+            } else if name == "for" {
+                caption_for = Some(String::from_utf8_lossy(&value).trim().to_string());
+            } else if name == "lang" {
+                caption_lang = Some(String::from_utf8_lossy(&value).trim().to_string());
+            } else if name == expiry::EXPIRY_TAG {
+                match expiry::parse(&site, &String::from_utf8_lossy(&value)) {
+                    Ok(days) => expires_in = Some(days),
+                    Err(e) => return HttpResponse::BadRequest().json(e),
+                }
+            } else if site.metadata_fields().any(|f| f == name) {
+                let value = String::from_utf8_lossy(&value);
+                if value.len() > MAX_METADATA_LENGTH {
+                    return HttpResponse::BadRequest().json(MicropubError::with_description(
+                        "invalid_request",
+                        format!("{} is too long", name),
+                    ));
+                }
+                extra_metadata.insert(name, value.into_owned());
+            }
+        }
+    }
 
-    if !access_token.scopes().any(|s| s == "media") {
-        return HttpResponse::Unauthorized().json(MicropubError::new("unauthorized"));
+    // Without a file, fetch the one at the given URL.
+    if upload.is_none() {
+        if let Some(url) = &sideload_url {
+            match sideload(url, site.sideload_max_bytes()).await {
+                Ok(fetched) => upload = Some(fetched),
+                Err(e) => return HttpResponse::BadRequest().json(e),
+            }
+        }
+    }
+
+    if let Some(upload) = upload {
+        // Captions are stored with the video they're for.
+        if let Some(video_url) = &caption_for {
+            return captions::attach(
+                &site,
+                &s3_client,
+                &access_token,
+                video_url,
+                caption_lang.as_deref(),
+                upload.filename.as_deref(),
+                upload.body,
+            )
+            .await;
+        }
+
+        if expand {
+            if !archive::is_archive(&upload.content_type, upload.filename.as_deref()) {
+                return HttpResponse::BadRequest().json(MicropubError::with_description(
+                    "invalid_request",
+                    "Only zip archives can be expanded",
+                ));
+            }
+            return expand_archive(
+                &site,
+                &s3_client,
+                &http_client,
+                &publisher,
+                &index,
+                &cdn,
+                &recent_uploads,
+                &disk_cache,
+                &access_token,
+                upload.body,
+                extra_metadata,
+                expires_in,
+            )
+            .await;
+        }
+
+        return store_upload(
+            &site,
+            &s3_client,
+            &http_client,
+            &publisher,
+            &index,
+            &cdn,
+            &recent_uploads,
+            &disk_cache,
+            &access_token,
+            upload,
+            extra_metadata,
+            expires_in,
+        )
+        .await;
+    }
+
+    HttpResponse::BadRequest().finish()
+}
+
+/// Whether a request has a form-encoded body rather than a multipart one.
+pub fn is_form(head: &RequestHead) -> bool {
+    head.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| {
+            v.starts_with("application/x-www-form-urlencoded")
+        })
+}
+
+/// The form-encoded body of a request to import a file by URL.
+#[derive(Deserialize)]
+pub struct SideloadForm {
+    url: String,
+}
+
+/// Import the file at a URL posted as a form rather than in a multipart body.
+pub async fn handle_sideload(
+    req: HttpRequest,
+    form: web::Form<SideloadForm>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    (cdn, disk_cache): (web::Data<Cdn>, Option<web::Data<DiskCache>>),
+    recent_uploads: web::Data<RecentUploads>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authenticate(&req, &verification_service).await {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+    if !policy::allows(&site, &access_token, Permission::Create) {
+        return insufficient_scope(Permission::Create);
     }
 
-    // iterate over multipart stream
-    if let Ok(Some(field)) = payload.try_next().await {
-        let content_disp = field.content_disposition().unwrap();
-        let content_type = field.content_type().clone();
-        let filename = content_disp.get_filename();
-        let ext = filename.and_then(|f| f.rsplit('.').next());
-        let (classification, sep, suffix) = match content_type.type_() {
-            mime::IMAGE => ("photo", '.', ext),
-            mime::AUDIO => ("audio", '.', ext),
-            mime::VIDEO => ("video", '.', ext),
-            _ => ("file", '/', filename),
+    let upload = match sideload(form.url.trim(), site.sideload_max_bytes()).await {
+        Ok(upload) => upload,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    store_upload(
+        &site,
+        &s3_client,
+        &http_client,
+        &publisher,
+        &index,
+        &cdn,
+        &recent_uploads,
+        &disk_cache,
+        &access_token,
+        upload,
+        HashMap::new(),
+        None,
+    )
+    .await
+}
+
+/// Where one file from an expanded archive went.
+#[derive(Serialize)]
+struct ExpandedEntry {
+    name: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExpandedArchive {
+    entries: Vec<ExpandedEntry>,
+}
+
+impl ExpandedEntry {
+    /// Summarize the response to storing the entry.
+    fn new(name: String, resp: &HttpResponse) -> ExpandedEntry {
+        let url = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let error = if resp.status().is_success() {
+            None
+        } else {
+            let described = match resp.body() {
+                ResponseBody::Body(Body::Bytes(bytes)) => {
+                    serde_json::from_slice::<MicropubError>(bytes)
+                        .ok()
+                        .map(|e| e.error_description.unwrap_or(e.error))
+                }
+                _ => None,
+            };
+            described.or_else(|| resp.status().canonical_reason().map(str::to_string))
         };
+        ExpandedEntry {
+            name,
+            status: resp.status().as_u16(),
+            url,
+            error,
+        }
+    }
+}
 
-        // This will be the key in S3.
-        let key = match suffix {
-            Some(ext) => format!("{}{}{}", random_id(), sep, ext),
-            None => format!("{}", random_id()),
+/// Store each file in a zip archive as if it was uploaded on its own, and
+/// list where each one went. A file which can't be stored doesn't stop the
+/// rest.
+async fn expand_archive(
+    site: &web::Data<SiteConfig>,
+    s3_client: &web::Data<S3Client>,
+    http_client: &web::Data<Client>,
+    publisher: &web::Data<events::Publisher>,
+    index: &Option<web::Data<MediaIndex>>,
+    cdn: &web::Data<Cdn>,
+    recent_uploads: &RecentUploads,
+    disk_cache: &Option<web::Data<DiskCache>>,
+    access_token: &oauth::AccessToken,
+    data: Vec<u8>,
+    extra_metadata: HashMap<String, String>,
+    expires_in: Option<u32>,
+) -> HttpResponse {
+    let unpack_site = site.get_ref().clone();
+    let mut unpacker = match web::block(move || Unpacker::new(&unpack_site, data)).await {
+        Ok(unpacker) => unpacker,
+        Err(BlockingError::Error(e)) => return e.response(),
+        Err(BlockingError::Canceled) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // Each file is stored before the next is unpacked. One which can't be
+    // unpacked ends the listing, since the rest of the archive can't be
+    // trusted either.
+    let mut results = Vec::new();
+    while let Some(name) = unpacker.next_name().map(str::to_string) {
+        let unpacked = web::block(move || {
+            let entry = unpacker.next_entry();
+            entry.map(|entry| (unpacker, entry))
+        })
+        .await;
+        let entry = match unpacked {
+            Ok((rest, Some(entry))) => {
+                unpacker = rest;
+                entry
+            }
+            Ok((_, None)) => break,
+            Err(BlockingError::Error(e)) => {
+                results.push(ExpandedEntry::new(name, &e.response()));
+                break;
+            }
+            Err(BlockingError::Canceled) => {
+                let resp = HttpResponse::InternalServerError().finish();
+                results.push(ExpandedEntry::new(name, &resp));
+                break;
+            }
         };
+        let upload = Upload {
+            content_type: entry.content_type,
+            filename: Some(entry.name.clone()),
+            body: entry.data,
+        };
+        let resp = store_upload(
+            site,
+            s3_client,
+            http_client,
+            publisher,
+            index,
+            cdn,
+            recent_uploads,
+            disk_cache,
+            access_token,
+            upload,
+            extra_metadata.clone(),
+            expires_in,
+        )
+        .await;
+        results.push(ExpandedEntry::new(entry.name, &resp));
+    }
 
-        // This will be the publicly accessible URL for the file.
-        let url = if classification == "photo" {
-            format!("{}/photo/{}x{}/{}", site.media_url(), site.default_width(), site.default_height(), key)
-        } else {
-            format!("{}/{}/{}", site.media_url(), classification, key)
+    HttpResponse::Ok().json(ExpandedArchive { entries: results })
+}
+
+/// Classify, check, and store an uploaded file.
+async fn store_upload(
+    site: &web::Data<SiteConfig>,
+    s3_client: &web::Data<S3Client>,
+    http_client: &web::Data<Client>,
+    publisher: &web::Data<events::Publisher>,
+    index: &Option<web::Data<MediaIndex>>,
+    cdn: &web::Data<Cdn>,
+    recent_uploads: &RecentUploads,
+    disk_cache: &Option<web::Data<DiskCache>>,
+    access_token: &oauth::AccessToken,
+    upload: Upload,
+    extra_metadata: HashMap<String, String>,
+    expires_in: Option<u32>,
+) -> HttpResponse {
+    let classification = classify::classify(site, &upload.content_type, upload.filename.as_deref());
+    let size = Some(upload.body.len() as u64);
+    if let Err(e) = classify::check_limits(site, &classification.name, &upload.content_type, size) {
+        return e.response();
+    }
+
+    // Traces are checked before they're stored, and simplified for previews.
+    let trace = match parse_trace(
+        &classification.name,
+        &upload.content_type,
+        upload.filename.as_deref(),
+        &upload.body,
+    ) {
+        Ok(trace) => trace,
+        Err(resp) => return resp,
+    };
+
+    let mut checksum = events::checksum(&upload.body);
+    let placement = match Placement::unused(
+        site,
+        s3_client,
+        &upload.content_type,
+        upload.filename.as_deref(),
+        access_token.me(),
+        Some(&checksum),
+        keys::date_for(site, Some(&upload.body)),
+    )
+    .await
+    {
+        Ok(placement) => placement,
+        Err(e) => return e.response(),
+    };
+    let url = placement.url(site);
+    let object_key = placement.object_key();
+
+    let mut rejection =
+        match check_upload(site, &placement, &upload.content_type, &upload.body).await {
+            Ok(rejection) => rejection,
+            Err(resp) => return resp,
         };
+    if let Some(reason) = &rejection {
+        if !site.quarantine() {
+            return quarantine::rejected(&object_key, access_token.me(), reason);
+        }
+    }
+
+    // Photos are compared with the uploader's others by how they look.
+    let duplicate_mode = site.duplicate_photos().and_then(DuplicateMode::parse);
+    let photo_hash = match duplicate_mode {
+        Some(_) if rejection.is_none() && placement.classification == "photo" => {
+            phash::hash(upload.body.clone()).await
+        }
+        _ => None,
+    };
+    let duplicate_of = match (photo_hash, index.as_deref()) {
+        (Some(hash), Some(index)) => {
+            let similar = index
+                .similar(access_token.me(), hash, site.duplicate_distance())
+                .await;
+            similar.unwrap_or_else(|e| {
+                error!("Failed to look for duplicates of {}: {}", object_key, e);
+                None
+            })
+        }
+        _ => None,
+    };
+    if let (Some(DuplicateMode::Skip), Some(existing)) = (duplicate_mode, &duplicate_of) {
+        return duplicate(existing);
+    }
 
-        let mut metadata: HashMap<String, String> = HashMap::new();
-        metadata.insert(
-            "client-id".to_string(),
-            access_token.client_id().to_string(),
+    // Flagged photos are always held for review, rather than rejected.
+    let moderator = ModerationBackend::for_upload(site, &placement.classification)
+        .filter(|_| rejection.is_none());
+    if moderator.is_some() && !site.moderation_async() {
+        let moderated = moderate_upload(
+            site,
+            http_client,
+            &placement,
+            &upload.content_type,
+            &upload.body,
         );
-        metadata.insert("author".to_string(), access_token.me().to_string());
-        if let Some(f) = filename {
-            metadata.insert("filename".to_string(), f.to_string());
+        match moderated.await {
+            Ok(flagged) => rejection = flagged,
+            Err(resp) => return resp,
         }
+    }
 
-        let body = field
-            .map(|b| b.map(|b| b.to_vec()))
-            .try_concat()
-            .await
-            .unwrap();
-
-        let put_request = PutObjectRequest {
-            bucket: site.s3_bucket().to_owned(),
-            key: format!("{}/{}", classification, key),
-            body: Some(body.into()),
-            metadata: Some(metadata),
-            content_type: Some(content_type.to_string()),
-            ..Default::default()
-        };
+    let mut metadata = match object_metadata(
+        site,
+        s3_client,
+        &object_key,
+        &extra_metadata,
+        access_token,
+        upload.filename.as_deref(),
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(resp) => return resp,
+    };
 
-        match s3_client.put_object(put_request).await {
-            Ok(_) => {
-                return HttpResponse::Created()
-                    .header(header::LOCATION, url)
-                    .finish();
+    let mut body = upload.body;
+    if rejection.is_none() && placement.classification == "photo" {
+        // Straight-off-the-camera photos are shrunk before they're stored.
+        if let Some(shrunk) = reencode::shrink(site, &body).await {
+            let original = std::mem::replace(&mut body, shrunk);
+            checksum = events::checksum(&body);
+            if site.keep_originals() {
+                let content_type = upload.content_type.to_string();
+                let kept = originals::keep(
+                    site,
+                    s3_client,
+                    &object_key,
+                    original,
+                    content_type,
+                    metadata.clone(),
+                )
+                .await;
+                if let Err(e) = kept {
+                    return HttpResponse::InternalServerError().body(e);
+                }
             }
-            Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+        }
+    }
+    metadata.insert("sha256".to_string(), checksum.clone());
+    if let Some(hash) = photo_hash {
+        metadata.insert(phash::PHASH_FIELD.to_string(), phash::encode(hash));
+    }
+    if let Some(days) = expires_in {
+        metadata.insert("expires-at".to_string(), expiry::expires_at(days));
+    }
+    let size = body.len() as u64;
+
+    if let Some(reason) = &rejection {
+        let held = quarantine::Held {
+            key: &object_key,
+            author: access_token.me(),
+            reason,
+            body,
+            content_type: upload.content_type.to_string(),
+            metadata,
         };
+        return quarantine::hold(site, s3_client, held).await;
     }
 
-    HttpResponse::BadRequest().finish()
+    let dimensions = default_dimensions(site, &placement, &body);
+    let warm_source = if warms(site, &placement) {
+        Some(body.clone())
+    } else {
+        None
+    };
+
+    let moderation_source = match &moderator {
+        Some(_) if site.moderation_async() => Some(body.clone()),
+        _ => None,
+    };
+
+    // Long videos are also packaged for adaptive streaming.
+    let hls_source = if placement.classification == "video" && site.hls_enabled() {
+        Some(body.clone())
+    } else {
+        None
+    };
+
+    // Players show frames from a sprite while scrubbing.
+    let preview_source = if placement.classification == "video" && site.video_previews() {
+        Some(body.clone())
+    } else {
+        None
+    };
+
+    // Players draw a waveform from the peaks.
+    let peaks_source = if placement.classification == "audio" && site.audio_peaks() {
+        Some(body.clone())
+    } else {
+        None
+    };
+
+    let mut put_request = PutObjectRequest {
+        metadata: Some(metadata),
+        content_type: Some(upload.content_type.to_string()),
+        ..site.put_object_request_for(&object_key)
+    };
+    if let Some(days) = expires_in {
+        put_request.tagging = expiry::tagging(put_request.tagging.take(), days);
+    }
+
+    if let Err(e) = multipart::put_object(site, s3_client, put_request, body).await {
+        return HttpResponse::InternalServerError().body(format!("{}", e));
+    }
+
+    if let Some(data) = hls_source {
+        actix_rt::spawn(hls::package(
+            site.get_ref().clone(),
+            s3_client.get_ref().clone(),
+            placement.id.clone(),
+            data,
+        ));
+    }
+    if let Some(data) = preview_source {
+        actix_rt::spawn(previews::generate(
+            site.get_ref().clone(),
+            s3_client.get_ref().clone(),
+            object_key.clone(),
+            data,
+        ));
+    }
+    if let Some(trace) = trace {
+        let stored = geo::store_simplified(site, s3_client, &placement.key, &trace);
+        if let Err(e) = stored.await {
+            error!("{}", e);
+        }
+    }
+    if let Some(data) = peaks_source {
+        actix_rt::spawn(peaks::generate(
+            site.get_ref().clone(),
+            s3_client.get_ref().clone(),
+            placement.key.clone(),
+            data,
+        ));
+    }
+    if let Some(data) = warm_source {
+        actix_rt::spawn(media::warm(
+            site.get_ref().clone(),
+            s3_client.get_ref().clone(),
+            placement.key.clone(),
+            data,
+        ));
+    }
+
+    let upload_type = upload.content_type.clone();
+    let object = NewObject {
+        placement,
+        url: url.clone(),
+        content_type: upload.content_type,
+        filename: upload.filename,
+        size,
+        checksum: Some(checksum),
+        extra_metadata,
+    };
+    record_upload(site, publisher, index.as_deref(), access_token, object).await;
+    if let (Some(hash), Some(index)) = (photo_hash, index.as_deref()) {
+        let author = access_token.me();
+        if let Err(e) = index.insert_phash(&object_key, author, hash).await {
+            error!("Failed to index the hash of {}: {}", object_key, e);
+        }
+    }
+    recent_uploads.record(access_token.me(), &url);
+
+    let mut resp = created_like(&url, dimensions, duplicate_of.as_deref());
+    if let (Some(backend), Some(data)) = (moderator, moderation_source) {
+        actix_rt::spawn(moderation::moderate_later(moderation::Pending {
+            backend,
+            site: site.get_ref().clone(),
+            s3_client: s3_client.get_ref().clone(),
+            http_client: http_client.clone(),
+            cdn: cdn.clone(),
+            disk_cache: disk_cache.clone(),
+            publisher: publisher.clone(),
+            index: index.clone(),
+            key: object_key,
+            url: url.clone(),
+            content_type: upload_type,
+            body: data,
+        }));
+        resp.headers_mut().insert(
+            header::HeaderName::from_static("x-moderation"),
+            header::HeaderValue::from_static("pending"),
+        );
+    }
+    resp
+}
+
+/// Clean up after the object at `url` was deleted: drop cached copies,
+/// remove it from the index, and tell everyone it's gone.
+pub(crate) async fn forget_deleted(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    cdn: &Cdn,
+    disk_cache: Option<&DiskCache>,
+    publisher: &events::Publisher,
+    index: Option<&MediaIndex>,
+    access_token: &oauth::AccessToken,
+    url: &str,
+    key: String,
+    head: HeadObjectOutput,
+) {
+    invalidate(site, s3_client, http_client, cdn, disk_cache, &key, url).await;
+
+    if let Some(index) = index {
+        if let Err(e) = index.delete(&key).await {
+            error!("Failed to remove {} from the index: {}", key, e);
+        }
+    }
+
+    let checksum = head
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get("sha256"))
+        .cloned();
+    publisher.publish(
+        site,
+        MediaEvent {
+            event: EventKind::Delete,
+            url: url.to_string(),
+            key,
+            content_type: head.content_type,
+            size: head.content_length.map(|l| l as u64),
+            author: access_token.me().to_string(),
+            client_id: access_token.client_id().to_string(),
+            api_key: access_token.api_key().map(str::to_string),
+            checksum,
+            timestamp: Utc::now(),
+        },
+    );
+}
+
+/// Drop cached copies of the object at `key` after it was deleted or replaced.
+///
+/// Photo derivatives are deleted, from S3 and the disk cache, and every URL
+/// the object may be cached under is invalidated on the CDN.
+pub(crate) async fn invalidate(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    cdn: &Cdn,
+    disk_cache: Option<&DiskCache>,
+    key: &str,
+    url: &str,
+) {
+    let mut stale_urls = vec![url.to_string()];
+    if let Some(filename) = key.strip_prefix("photo/") {
+        stale_urls.push(format!("{}/photo/original/{}", site.media_url(), filename));
+        if site.derivative_cache() {
+            match derivatives::purge(s3_client, site.s3_bucket(), filename).await {
+                Ok(purged) => stale_urls.extend(
+                    purged.iter().filter_map(|key| derivatives::url_for(site, key)),
+                ),
+                Err(e) => error!("Failed to purge derivatives of {}: {}", key, e),
+            }
+        }
+        if let Some(disk_cache) = disk_cache {
+            let bucket = format!("{}/", site.s3_bucket());
+            let purged = disk_cache.purge(site.s3_bucket(), filename);
+            stale_urls.extend(
+                purged
+                    .iter()
+                    .filter_map(|key| derivatives::url_for(site, key.strip_prefix(&bucket)?)),
+            );
+        }
+    }
+    // The same derivative is often purged from both caches.
+    stale_urls.sort_unstable();
+    stale_urls.dedup();
+    cdn.invalidate(http_client, stale_urls).await;
+}
+
+/// Find the S3 key for one of our public URLs.
+pub(crate) fn key_for_url(site: &SiteConfig, url: &str) -> Option<String> {
+    let path = url.strip_prefix(site.media_url())?.trim_start_matches('/');
+    let (media_type, rest) = split_first(path)?;
+
+    if media_type == "photo" {
+        // Photos are usually linked by a sized or original URL.
+        if let Some((size, filename)) = split_first(rest) {
+            let mut dims = size.splitn(2, 'x');
+            let sized = dims.next().map_or(false, |w| w.parse::<u32>().is_ok())
+                && dims.next().map_or(false, |h| h.parse::<u32>().is_ok());
+            if size == "original" || sized {
+                return Some(format!("photo/{}", filename));
+            }
+        }
+    }
+
+    if rest.is_empty() {
+        None
+    } else {
+        Some(format!("{}/{}", media_type, rest))
+    }
+}
+
+/// Split a path into its first segment and the remainder.
+fn split_first(path: &str) -> Option<(&str, &str)> {
+    let i = path.find('/')?;
+    Some((&path[..i], &path[i + 1..]))
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    limit: Option<usize>,
+}
+
+/// Return the authenticated user's most recent audit log entries.
+pub async fn handle_audit(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    site: web::Data<SiteConfig>,
+    audit: web::Data<AuditLog>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::List).await {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    if !audit.is_enabled() {
+        return HttpResponse::NotFound().json(MicropubError::with_description(
+            "not_found",
+            "The audit log is not enabled",
+        ));
+    }
+
+    let limit = query.limit.unwrap_or(50).min(1000);
+    match audit.recent(access_token.me(), limit).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    #[serde(rename = "type")]
+    classification: Option<String>,
+    /// Only include uploads on or after this date (YYYY-MM-DD).
+    since: Option<String>,
+    /// Only include uploads before this date (YYYY-MM-DD).
+    until: Option<String>,
+    offset: Option<i64>,
+    limit: Option<i64>,
+    /// List the bucket under this key prefix, e.g. `photo/`, instead of the
+    /// index.
+    prefix: Option<String>,
+    /// With a prefix, the key to list after.
+    after: Option<String>,
+    /// With a prefix, where the previous page left off.
+    continuation: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    items: Vec<MediaRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_offset: Option<i64>,
+}
+
+/// List the authenticated user's uploads from the metadata index, or the
+/// bucket's objects when there's no index or a prefix is given.
+pub async fn handle_list(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::List).await {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let index = match index {
+        Some(index) if query.prefix.is_none() => index,
+        _ => {
+            return listing::list_bucket(
+                &site,
+                &s3_client,
+                query.prefix.as_deref(),
+                query.after.as_deref(),
+                query.continuation.clone(),
+                query.limit.unwrap_or(50).max(1).min(1000),
+            )
+            .await
+        }
+    };
+
+    let parse_date = |date: &Option<String>| -> Result<Option<DateTime<Utc>>, HttpResponse> {
+        match date {
+            None => Ok(None),
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map(|d| Some(DateTime::from_utc(d.and_hms(0, 0, 0), Utc)))
+                .map_err(|e| {
+                    HttpResponse::BadRequest()
+                        .json(MicropubError::with_description("invalid_request", e))
+                }),
+        }
+    };
+    let (since, until) = match (parse_date(&query.since), parse_date(&query.until)) {
+        (Ok(since), Ok(until)) => (since, until),
+        (Err(resp), _) | (_, Err(resp)) => return resp,
+    };
+
+    let limit = query.limit.unwrap_or(50).max(1).min(500);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let filter = ListFilter {
+        author: Some(access_token.me()),
+        classification: query.classification.as_deref(),
+        since,
+        until,
+        offset,
+        limit,
+    };
+
+    match index.list(&filter).await {
+        Ok(items) => {
+            let next_offset = if items.len() as i64 == limit {
+                Some(offset + limit)
+            } else {
+                None
+            };
+            HttpResponse::Ok().json(ListResponse { items, next_offset })
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    offset: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Search the authenticated user's uploads by filename, alt text, and caption.
+pub async fn handle_search(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    site: web::Data<SiteConfig>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::List).await {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let index = match index {
+        Some(index) => index,
+        None => {
+            return HttpResponse::NotFound().json(MicropubError::with_description(
+                "not_found",
+                "The metadata index is not enabled",
+            ))
+        }
+    };
+
+    let limit = query.limit.unwrap_or(50).max(1).min(500);
+    let offset = query.offset.unwrap_or(0).max(0);
+    match index
+        .search(access_token.me(), query.q.trim(), offset, limit)
+        .await
+    {
+        Ok(items) => {
+            let next_offset = if items.len() as i64 == limit {
+                Some(offset + limit)
+            } else {
+                None
+            };
+            HttpResponse::Ok().json(ListResponse { items, next_offset })
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    /// The month bytes served are counted for, e.g. `2020-06`.
+    month: String,
+    total: Usage,
+    types: BTreeMap<String, Usage>,
+}
+
+/// Summarize the authenticated user's storage and this month's traffic.
+pub async fn handle_stats(
+    req: HttpRequest,
+    site: web::Data<SiteConfig>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::List).await {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let index = match index {
+        Some(index) => index,
+        None => {
+            return HttpResponse::NotFound().json(MicropubError::with_description(
+                "not_found",
+                "The metadata index is not enabled",
+            ))
+        }
+    };
+
+    let month = Utc::now().format("%Y-%m").to_string();
+    match index.usage(access_token.me(), &month).await {
+        Ok(types) => {
+            let total = types.values().fold(Usage::default(), |total, usage| Usage {
+                objects: total.objects + usage.objects,
+                bytes_stored: total.bytes_stored + usage.bytes_stored,
+                bytes_served: total.bytes_served + usage.bytes_served,
+            });
+            HttpResponse::Ok().json(StatsResponse {
+                month,
+                total,
+                types,
+            })
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+/// Serve the gallery page to requests with a token that may list media.
+///
+/// Browsers can't send the token with a page load, so requests without
+/// credentials get a form which posts it to [`handle_gallery_login`].
+pub async fn handle_gallery(
+    req: HttpRequest,
+    site: web::Data<SiteConfig>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let credentials = ["X-Api-Key", "Authorization"]
+        .iter()
+        .find_map(|name| Some((*name, req.headers().get(*name)?.to_str().ok()?)));
+    let (name, value) = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            return HttpResponse::Unauthorized()
+                .content_type("text/html; charset=utf-8")
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(GALLERY_LOGIN_HTML)
+        }
+    };
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::List).await {
+        return resp;
+    }
+    gallery(&site, name, value)
+}
+
+#[derive(Deserialize)]
+pub struct GalleryLogin {
+    access_token: String,
+}
+
+/// Serve the gallery page for the token posted from the login form.
+pub async fn handle_gallery_login(
+    form: web::Form<GalleryLogin>,
+    site: web::Data<SiteConfig>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let auth_header = format!("Bearer {}", form.access_token);
+    let access_token = match verification_service.validate(&auth_header).await {
+        Ok(access_token) => access_token,
+        Err(e) => {
+            return HttpResponse::Unauthorized()
+                .json(MicropubError::with_description("unauthorized", e))
+        }
+    };
+    if !policy::allows(&site, &access_token, Permission::List) {
+        return insufficient_scope(Permission::List);
+    }
+    gallery(&site, "Authorization", &auth_header)
+}
+
+/// The gallery page, which sends the credentials header `name` with its
+/// requests. The credentials only live in the page, never in storage.
+fn gallery(site: &SiteConfig, name: &str, value: &str) -> HttpResponse {
+    // Nothing in the values may close the script they're embedded in.
+    let script_value = |v: serde_json::Value| v.to_string().replace("</", "<\\/");
+    let media_url = script_value(serde_json::json!(site.media_url()));
+    let credentials = script_value(serde_json::json!({ name: value }));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::REFERRER_POLICY, "no-referrer")
+        .body(
+            GALLERY_HTML
+                .replace("{{MEDIA_URL}}", &media_url)
+                .replace("{{CREDENTIALS}}", &credentials),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{is_public, resolve_location};
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in &[
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.1.2.3",
+            "100.64.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn internet_addresses_are_public() {
+        for ip in &[
+            "93.184.216.34",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn locations_are_resolved_against_the_request_url() {
+        let base = "https://a.example/x/y?q=1";
+        assert_eq!(
+            resolve_location(base, "https://b.example/z"),
+            "https://b.example/z"
+        );
+        assert_eq!(
+            resolve_location(base, "//c.example/z"),
+            "https://c.example/z"
+        );
+        assert_eq!(resolve_location(base, "/z"), "https://a.example/z");
+        assert_eq!(resolve_location(base, "z"), "https://a.example/x/z");
+        assert_eq!(
+            resolve_location("https://a.example", "z"),
+            "https://a.example/z"
+        );
+    }
 }
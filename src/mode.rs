@@ -0,0 +1,136 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{header, Method};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+
+use serde::{Deserialize, Serialize};
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use crate::micropub::{authorize, MicropubError};
+use crate::oauth;
+use crate::policy::Permission;
+use crate::SiteConfig;
+
+/// Requests to this path are allowed in every mode so the mode can be changed back.
+const MODE_PATH: &str = "/admin/mode";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Everything works.
+    Normal,
+    /// Media is served, but nothing can be changed.
+    ReadOnly,
+    /// Every request fails.
+    Maintenance,
+}
+
+/// The service's current mode, which can be changed while running.
+pub struct ServiceMode {
+    mode: AtomicU8,
+    retry_after: Duration,
+}
+
+impl Mode {
+    /// Parse `normal`, `read-only`, or `maintenance`.
+    pub fn parse(value: &str) -> Option<Mode> {
+        match value {
+            "normal" => Some(Mode::Normal),
+            "read-only" => Some(Mode::ReadOnly),
+            "maintenance" => Some(Mode::Maintenance),
+            _ => None,
+        }
+    }
+}
+
+impl ServiceMode {
+    pub fn new(mode: Mode, retry_after: Duration) -> ServiceMode {
+        ServiceMode {
+            mode: AtomicU8::new(mode as u8),
+            retry_after,
+        }
+    }
+
+    pub fn get(&self) -> Mode {
+        match self.mode.load(Ordering::Relaxed) {
+            0 => Mode::Normal,
+            1 => Mode::ReadOnly,
+            _ => Mode::Maintenance,
+        }
+    }
+
+    pub fn set(&self, mode: Mode) {
+        self.mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    /// The response for a request the current mode doesn't permit.
+    ///
+    /// The path is matched relative to the scope, so each tenant's mode path
+    /// is allowed too.
+    pub fn check(&self, req: &ServiceRequest) -> Option<HttpResponse> {
+        if req.match_info().unprocessed() == MODE_PATH {
+            return None;
+        }
+        let allowed = match self.get() {
+            Mode::Normal => true,
+            Mode::ReadOnly => matches!(*req.method(), Method::GET | Method::HEAD),
+            Mode::Maintenance => false,
+        };
+        if allowed {
+            return None;
+        }
+
+        Some(
+            HttpResponse::ServiceUnavailable()
+                .header(header::RETRY_AFTER, self.retry_after.as_secs().to_string())
+                .json(MicropubError::with_description(
+                    "temporarily_unavailable",
+                    format!("The service is in {:?} mode", self.get()),
+                )),
+        )
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource(MODE_PATH)
+            .route(web::get().to(get_mode))
+            .route(web::put().to(set_mode)),
+    );
+}
+
+#[derive(Serialize, Deserialize)]
+struct ModeBody {
+    mode: Mode,
+}
+
+async fn get_mode(mode: web::Data<ServiceMode>) -> HttpResponse {
+    HttpResponse::Ok().json(ModeBody { mode: mode.get() })
+}
+
+/// Change the mode, e.g. `{"mode": "read-only"}`.
+///
+/// The body is only read once the request is authorized.
+async fn set_mode(
+    req: HttpRequest,
+    payload: web::Payload,
+    mode: web::Data<ServiceMode>,
+    site: web::Data<SiteConfig>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+
+    let body = match web::Json::<ModeBody>::from_request(&req, &mut payload.into_inner()).await {
+        Ok(body) => body,
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .json(MicropubError::with_description("invalid_request", e))
+        }
+    };
+
+    mode.set(body.mode);
+    HttpResponse::Ok().json(ModeBody { mode: mode.get() })
+}
@@ -0,0 +1,290 @@
+use actix_web::client::Client;
+use actix_web::error::BlockingError;
+use actix_web::http::header;
+use actix_web::web;
+use derive_more::Display;
+use log::{error, warn};
+use serde::Deserialize;
+
+use rusoto_s3::S3Client;
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::alias;
+use crate::cdn::Cdn;
+use crate::disk_cache::DiskCache;
+use crate::events;
+use crate::index::MediaIndex;
+use crate::metadata;
+use crate::micropub::forget_deleted;
+use crate::quarantine;
+use crate::trash;
+use crate::SiteConfig;
+
+/// How long to wait on a moderation API.
+const MODERATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How photos are checked before they're published.
+#[derive(Clone, Debug)]
+pub enum ModerationBackend {
+    /// An HTTP API which is POSTed the photo and answers with JSON like
+    /// `{"flagged": true, "reason": "Explicit Nudity"}`, e.g. a function in
+    /// front of Rekognition.
+    Http { url: String },
+    /// A command which reads the photo on stdin, e.g. a local model. It exits
+    /// with 1 if the photo is flagged, giving the reason on stdout.
+    Command { program: String, args: Vec<String> },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Moderation {
+    Approved,
+    /// Why the photo was flagged.
+    Flagged(String),
+}
+
+#[derive(Display, Debug)]
+pub enum ModerationError {
+    #[display(fmt = "Failed to reach the moderator: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "Moderation failed: {}", _0)]
+    Failed(String),
+}
+
+impl From<io::Error> for ModerationError {
+    fn from(e: io::Error) -> Self {
+        ModerationError::Io(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    flagged: bool,
+    reason: Option<String>,
+}
+
+impl ModerationBackend {
+    /// Parse the moderation configuration: an `http://` or `https://` URL, or
+    /// `command:<program> [args...]`.
+    pub fn parse(value: &str) -> Option<ModerationBackend> {
+        if value.starts_with("https://") || value.starts_with("http://") {
+            Some(ModerationBackend::Http {
+                url: value.to_string(),
+            })
+        } else if let Some(command) = value.strip_prefix("command:") {
+            let mut words = command.split_whitespace().map(str::to_string);
+            Some(ModerationBackend::Command {
+                program: words.next()?,
+                args: words.collect(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The moderator for uploads of the given classification, if they're
+    /// moderated. Only photos are.
+    pub fn for_upload(site: &SiteConfig, classification: &str) -> Option<ModerationBackend> {
+        if classification != "photo" {
+            return None;
+        }
+        site.moderation().and_then(ModerationBackend::parse)
+    }
+
+    /// Ask whether the photo may be published.
+    pub async fn moderate(
+        &self,
+        http_client: &Client,
+        content_type: &mime::Mime,
+        data: Vec<u8>,
+    ) -> Result<Moderation, ModerationError> {
+        match self {
+            ModerationBackend::Http { url } => {
+                moderate_http(http_client, url, content_type, data).await
+            }
+            ModerationBackend::Command { program, args } => {
+                let (program, args) = (program.clone(), args.clone());
+                web::block(move || moderate_command(&program, &args, &data))
+                    .await
+                    .map_err(|e| match e {
+                        BlockingError::Error(e) => e,
+                        BlockingError::Canceled => {
+                            ModerationError::Failed("Moderation was canceled".to_string())
+                        }
+                    })
+            }
+        }
+    }
+}
+
+async fn moderate_http(
+    http_client: &Client,
+    url: &str,
+    content_type: &mime::Mime,
+    data: Vec<u8>,
+) -> Result<Moderation, ModerationError> {
+    let mut resp = http_client
+        .post(url)
+        .timeout(MODERATION_TIMEOUT)
+        .header(header::CONTENT_TYPE, content_type.to_string())
+        .send_body(data)
+        .await
+        .map_err(|e| ModerationError::Failed(e.to_string()))?;
+    if !resp.status().is_success() {
+        return Err(ModerationError::Failed(format!(
+            "{} returned {}",
+            url,
+            resp.status()
+        )));
+    }
+    let answer: ModerationResponse = resp
+        .json()
+        .await
+        .map_err(|e| ModerationError::Failed(e.to_string()))?;
+    Ok(if answer.flagged {
+        Moderation::Flagged(answer.reason.unwrap_or_else(|| "unknown".to_string()))
+    } else {
+        Moderation::Approved
+    })
+}
+
+/// Pipe the photo to the command, which exits with 1 if it's flagged.
+fn moderate_command(
+    program: &str,
+    args: &[String],
+    data: &[u8],
+) -> Result<Moderation, ModerationError> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(data) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let output = child.wait_with_output()?;
+    match output.status.code() {
+        Some(0) => Ok(Moderation::Approved),
+        Some(1) => {
+            let reason = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Ok(Moderation::Flagged(if reason.is_empty() {
+                "unknown".to_string()
+            } else {
+                reason
+            }))
+        }
+        _ => Err(ModerationError::Failed(format!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+/// The reason given for holding a flagged photo.
+pub fn reason(flagged: &str) -> String {
+    format!("Flagged by moderation: {}", flagged)
+}
+
+/// A published photo to be moderated in the background.
+pub struct Pending {
+    pub backend: ModerationBackend,
+    pub site: SiteConfig,
+    pub s3_client: S3Client,
+    pub http_client: web::Data<Client>,
+    pub cdn: web::Data<Cdn>,
+    pub disk_cache: Option<web::Data<DiskCache>>,
+    pub publisher: web::Data<events::Publisher>,
+    pub index: Option<web::Data<MediaIndex>>,
+    pub key: String,
+    pub url: String,
+    pub content_type: mime::Mime,
+    pub body: Vec<u8>,
+}
+
+/// Moderate a photo after it was published, moving it to quarantine if it's
+/// flagged. It stays published if the moderator can't be reached.
+pub async fn moderate_later(pending: Pending) {
+    let Pending {
+        backend,
+        site,
+        s3_client,
+        http_client,
+        cdn,
+        disk_cache,
+        publisher,
+        index,
+        key,
+        url,
+        content_type,
+        body,
+    } = pending;
+
+    let flagged = match backend.moderate(&http_client, &content_type, body).await {
+        Ok(Moderation::Approved) => return,
+        Ok(Moderation::Flagged(flagged)) => flagged,
+        Err(e) => {
+            error!("Failed to moderate {}: {}", key, e);
+            return;
+        }
+    };
+
+    let head = match alias::find(&site, &s3_client, &key).await {
+        Ok(Some(head)) => head,
+        // It's already gone.
+        Ok(None) => return,
+        Err(e) => {
+            error!("Failed to read {} to quarantine it: {}", key, e);
+            return;
+        }
+    };
+    let reason = reason(&flagged);
+    warn!("Quarantined {} after publishing: {}", key, reason);
+    let mut held_metadata = head.metadata.clone().unwrap_or_default();
+    held_metadata.insert("quarantine-reason".to_string(), metadata::encode(&reason));
+    let held = quarantine::quarantine_key(&key);
+    let copied = trash::copy(
+        &site,
+        &s3_client,
+        &key,
+        &held,
+        head.content_type.clone(),
+        held_metadata,
+    )
+    .await;
+    if let Err(e) = copied {
+        error!("{}", e);
+        return;
+    }
+    let (bucket, object_key) = site.locate(&key);
+    if let Err(e) = trash::delete(&s3_client, &bucket, &object_key).await {
+        error!("{}", e);
+        return;
+    }
+
+    let uploader = quarantine::uploader(&head.metadata.clone().unwrap_or_default());
+    forget_deleted(
+        &site,
+        &s3_client,
+        &http_client,
+        &cdn,
+        disk_cache.as_deref(),
+        &publisher,
+        index.as_deref(),
+        &uploader,
+        &url,
+        key,
+        head,
+    )
+    .await;
+}
@@ -0,0 +1,269 @@
+use bytes::Bytes;
+use futures::future;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::error;
+
+use rusoto_core::ByteStream;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, PutObjectRequest, S3Client,
+    UploadPartCopyRequest, UploadPartRequest, S3,
+};
+
+use std::io;
+
+use crate::SiteConfig;
+
+/// S3 allows at most 10,000 parts.
+const MAX_PARTS: usize = 10_000;
+
+/// The biggest object S3 copies in a single request.
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// The size of the parts bigger objects are copied in.
+const COPY_PART_SIZE: u64 = 512 * 1024 * 1024;
+
+/// The size of the parts to send a `length` byte upload in.
+///
+/// Parts are made large enough to keep every concurrent request busy, within
+/// UPLOAD_PART_SIZE and UPLOAD_MAX_PART_SIZE, but never so small that S3
+/// would need more than 10,000 of them.
+pub fn part_size(site: &SiteConfig, length: usize) -> usize {
+    let parallelism = site.upload_parallelism();
+    let per_request = (length + parallelism - 1) / parallelism;
+    let fewest = (length + MAX_PARTS - 1) / MAX_PARTS;
+    per_request
+        .min(site.upload_max_part_size())
+        .max(site.upload_part_size())
+        .max(fewest)
+}
+
+/// Store `body` under the key and settings in `request`, whose own body is
+/// ignored.
+///
+/// Anything bigger than a single part is sent as a multipart upload, with up
+/// to UPLOAD_PARALLELISM parts in flight at once.
+pub async fn put_object(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    request: PutObjectRequest,
+    body: Vec<u8>,
+) -> Result<(), String> {
+    let part_size = part_size(site, body.len());
+    if body.len() <= part_size {
+        let request = PutObjectRequest {
+            body: Some(body.into()),
+            ..request
+        };
+        return s3_client
+            .put_object(request)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+    }
+
+    let bucket = request.bucket.clone();
+    let key = request.key.clone();
+    let create_request = CreateMultipartUploadRequest {
+        bucket: request.bucket,
+        key: request.key,
+        acl: request.acl,
+        cache_control: request.cache_control,
+        content_disposition: request.content_disposition,
+        content_encoding: request.content_encoding,
+        content_language: request.content_language,
+        content_type: request.content_type,
+        metadata: request.metadata,
+        server_side_encryption: request.server_side_encryption,
+        ssekms_key_id: request.ssekms_key_id,
+        storage_class: request.storage_class,
+        tagging: request.tagging,
+        ..Default::default()
+    };
+    let upload_id = s3_client
+        .create_multipart_upload(create_request)
+        .await
+        .map_err(|e| e.to_string())?
+        .upload_id
+        .ok_or_else(|| "Missing upload ID".to_string())?;
+
+    let body = Bytes::from(body);
+    let parts = (0..body.len())
+        .step_by(part_size)
+        .enumerate()
+        .map(|(i, start)| {
+            let part = body.slice(start..(start + part_size).min(body.len()));
+            (i as i64 + 1, part)
+        });
+    let uploaded = stream::iter(parts)
+        .map(|(number, part)| {
+            let size = part.len();
+            let request = UploadPartRequest {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                upload_id: upload_id.clone(),
+                part_number: number,
+                content_length: Some(size as i64),
+                body: Some(ByteStream::new_with_size(
+                    stream::once(future::ok::<_, io::Error>(part)),
+                    size,
+                )),
+                ..Default::default()
+            };
+            async move {
+                let resp = s3_client
+                    .upload_part(request)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok::<_, String>(CompletedPart {
+                    e_tag: resp.e_tag,
+                    part_number: Some(number),
+                })
+            }
+        })
+        .buffer_unordered(site.upload_parallelism())
+        .try_collect::<Vec<CompletedPart>>()
+        .await;
+
+    let completed = match uploaded {
+        Ok(mut parts) => {
+            parts.sort_by_key(|part| part.part_number);
+            let complete_request = CompleteMultipartUploadRequest {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                upload_id: upload_id.clone(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                ..Default::default()
+            };
+            s3_client
+                .complete_multipart_upload(complete_request)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e),
+    };
+
+    if completed.is_err() {
+        let abort_request = AbortMultipartUploadRequest {
+            bucket,
+            key: key.clone(),
+            upload_id,
+            ..Default::default()
+        };
+        if let Err(e) = s3_client.abort_multipart_upload(abort_request).await {
+            error!("Failed to abort upload of {}: {}", key, e);
+        }
+    }
+    completed
+}
+
+/// Copy a `size` byte object as `request` says.
+///
+/// S3 only copies objects up to 5 GB in one request, so bigger ones are
+/// copied a part at a time, with up to UPLOAD_PARALLELISM parts in flight.
+pub async fn copy_object(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    request: CopyObjectRequest,
+    size: u64,
+) -> Result<(), String> {
+    if size <= MAX_COPY_SIZE {
+        return s3_client
+            .copy_object(request)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+    }
+
+    let bucket = request.bucket.clone();
+    let key = request.key.clone();
+    let copy_source = request.copy_source.clone();
+    let create_request = CreateMultipartUploadRequest {
+        bucket: request.bucket,
+        key: request.key,
+        acl: request.acl,
+        cache_control: request.cache_control,
+        content_disposition: request.content_disposition,
+        content_encoding: request.content_encoding,
+        content_language: request.content_language,
+        content_type: request.content_type,
+        metadata: request.metadata,
+        server_side_encryption: request.server_side_encryption,
+        ssekms_key_id: request.ssekms_key_id,
+        storage_class: request.storage_class,
+        tagging: request.tagging,
+        ..Default::default()
+    };
+    let upload_id = s3_client
+        .create_multipart_upload(create_request)
+        .await
+        .map_err(|e| e.to_string())?
+        .upload_id
+        .ok_or_else(|| "Missing upload ID".to_string())?;
+
+    let parts = (0..size)
+        .step_by(COPY_PART_SIZE as usize)
+        .enumerate()
+        .map(|(i, start)| {
+            let end = (start + COPY_PART_SIZE).min(size) - 1;
+            (i as i64 + 1, format!("bytes={}-{}", start, end))
+        });
+    let copied = stream::iter(parts)
+        .map(|(number, range)| {
+            let request = UploadPartCopyRequest {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                upload_id: upload_id.clone(),
+                part_number: number,
+                copy_source: copy_source.clone(),
+                copy_source_range: Some(range),
+                ..Default::default()
+            };
+            async move {
+                let resp = s3_client
+                    .upload_part_copy(request)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok::<_, String>(CompletedPart {
+                    e_tag: resp.copy_part_result.and_then(|result| result.e_tag),
+                    part_number: Some(number),
+                })
+            }
+        })
+        .buffer_unordered(site.upload_parallelism())
+        .try_collect::<Vec<CompletedPart>>()
+        .await;
+
+    let completed = match copied {
+        Ok(mut parts) => {
+            parts.sort_by_key(|part| part.part_number);
+            let complete_request = CompleteMultipartUploadRequest {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                upload_id: upload_id.clone(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                ..Default::default()
+            };
+            s3_client
+                .complete_multipart_upload(complete_request)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e),
+    };
+
+    if completed.is_err() {
+        let abort_request = AbortMultipartUploadRequest {
+            bucket,
+            key: key.clone(),
+            upload_id,
+            ..Default::default()
+        };
+        if let Err(e) = s3_client.abort_multipart_upload(abort_request).await {
+            error!("Failed to abort copy to {}: {}", key, e);
+        }
+    }
+    completed
+}
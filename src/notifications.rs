@@ -0,0 +1,400 @@
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use log::{error, info, warn};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
+use rusoto_core::Region;
+use rusoto_s3::{CopyObjectRequest, S3Client, S3};
+use rusoto_sqs::{DeleteMessageRequest, ReceiveMessageRequest, Sqs, SqsClient};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::captions::CAPTION_PREFIX;
+use crate::classify;
+use crate::events;
+use crate::geo::SIMPLIFIED_PREFIX;
+use crate::hls::HLS_PREFIX;
+use crate::index::MediaIndex;
+use crate::metadata;
+use crate::micropub::{authorize, MicropubError, Placement};
+use crate::oauth::{self, AccessToken};
+use crate::peaks::PEAKS_PREFIX;
+use crate::policy::Permission;
+use crate::previews::PREVIEW_PREFIX;
+use crate::trash;
+use crate::upload_form;
+use crate::SiteConfig;
+
+/// The client ID recorded for objects registered from notifications.
+const CLIENT_ID: &str = "s3-events";
+
+/// How long to wait before polling the queue again after it fails.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Derivatives the endpoint stores beside uploads, which aren't uploads
+/// themselves.
+const GENERATED_PREFIXES: &[&str] = &[
+    HLS_PREFIX,
+    PREVIEW_PREFIX,
+    PEAKS_PREFIX,
+    CAPTION_PREFIX,
+    SIMPLIFIED_PREFIX,
+];
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/micropub/media/notifications").route(web::post().to(handle_notification)),
+    );
+}
+
+/// An S3 event notification, as S3 sends it to SQS, SNS, or Lambda.
+#[derive(Deserialize)]
+struct S3Notification {
+    #[serde(rename = "Records", default)]
+    records: Vec<S3Record>,
+}
+
+#[derive(Deserialize)]
+struct S3Record {
+    #[serde(rename = "eventName")]
+    event_name: String,
+    s3: S3Entity,
+}
+
+#[derive(Deserialize)]
+struct S3Entity {
+    bucket: S3Bucket,
+    object: S3Object,
+}
+
+#[derive(Deserialize)]
+struct S3Bucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct S3Object {
+    key: String,
+}
+
+/// A notification forwarded by SNS, with the S3 notification as its message.
+#[derive(Deserialize)]
+struct SnsEnvelope {
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+/// The created objects in a notification, as bucket and key.
+///
+/// Notifications may be sent as they are, or wrapped by SNS. Anything else,
+/// like the test event S3 sends when notifications are configured, has no
+/// objects.
+fn created_objects(body: &[u8]) -> Vec<(String, String)> {
+    let notification = match serde_json::from_slice::<SnsEnvelope>(body) {
+        Ok(envelope) => serde_json::from_str(&envelope.message),
+        Err(_) => serde_json::from_slice::<S3Notification>(body),
+    };
+    notification
+        .map(|notification| notification.records)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|record| record.event_name.starts_with("ObjectCreated:"))
+        .map(|record| {
+            // Keys are URL-encoded, with spaces as `+`.
+            let key = record.s3.object.key.replace('+', " ");
+            let key = percent_decode_str(&key).decode_utf8_lossy().into_owned();
+            (record.s3.bucket.name, key)
+        })
+        .collect()
+}
+
+/// The logical key of an object in `bucket`, if it's stored where uploads
+/// are.
+fn logical_key(site: &SiteConfig, bucket: &str, key: &str) -> Option<String> {
+    let object_key = classify::prefixes(site).into_iter().find_map(|prefix| {
+        let logical_prefix = format!("{}/", prefix);
+        let (prefix_bucket, object_prefix) = site.locate(&logical_prefix);
+        if prefix_bucket != bucket {
+            return None;
+        }
+        let rest = key.strip_prefix(&object_prefix)?;
+        Some(format!("{}{}", logical_prefix, rest))
+    })?;
+    let generated = GENERATED_PREFIXES
+        .iter()
+        .any(|generated| object_key.starts_with(&format!("{}/", generated)));
+    if generated || object_key.ends_with('/') {
+        return None;
+    }
+    Some(object_key)
+}
+
+/// Why an object in a notification wasn't registered.
+enum Failure {
+    /// Registration refused the object, e.g. it failed inspection. Trying
+    /// again won't help.
+    Rejected(String),
+    /// Something went wrong which may not happen again.
+    Transient(String),
+}
+
+/// Register an object which was stored without going through the endpoint,
+/// so it's served and listed like any other upload. Returns its URL, or
+/// `None` if it doesn't need registering.
+///
+/// Objects the endpoint stored itself are skipped: they have a checksum, or
+/// the client which uploaded them. Registered objects are given a checksum
+/// once they're registered, so the notification for rewriting their metadata
+/// is skipped too.
+async fn register_object(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    publisher: &events::Publisher,
+    index: Option<&MediaIndex>,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<String>, Failure> {
+    let object_key = match logical_key(site, bucket, key) {
+        Some(object_key) => object_key,
+        None => return Ok(None),
+    };
+    let stored = match upload_form::read_stored(site, s3_client, &object_key).await {
+        Ok(Some(stored)) => stored,
+        // Deleted again before the notification was handled.
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(Failure::Transient(e)),
+    };
+    if stored.metadata.contains_key("sha256") || stored.metadata.contains_key("client-id") {
+        return Ok(None);
+    }
+    let filename = stored.metadata.get("filename").map(|f| metadata::decode(f));
+    if Placement::existing(site, &object_key, &stored.content_type, filename.as_deref()).is_none() {
+        return Ok(None);
+    }
+
+    let author = stored
+        .metadata
+        .get("author")
+        .cloned()
+        .unwrap_or_else(|| site.s3_events_author().to_string());
+    let mut marked = stored.metadata.clone();
+    marked.insert("client-id".to_string(), CLIENT_ID.to_string());
+    if !author.is_empty() {
+        marked.insert("author".to_string(), author.clone());
+    }
+    let content_type = stored.content_type.to_string();
+
+    let access_token = AccessToken::new(author, CLIENT_ID.to_string(), "create".to_string());
+    let registered = upload_form::register(
+        site,
+        s3_client,
+        http_client,
+        publisher,
+        index,
+        &access_token,
+        &object_key,
+        stored,
+        false,
+    )
+    .await;
+    let published = match registered {
+        Ok(published) => published,
+        Err(resp) => {
+            let e = format!("{} wasn't registered: {}", object_key, resp.status());
+            return Err(if resp.status().is_server_error() {
+                Failure::Transient(e)
+            } else {
+                Failure::Rejected(e)
+            });
+        }
+    };
+
+    // It's registered either way, so a failure here is only logged. Left
+    // unmarked, it would be registered again by another notification.
+    marked.insert("sha256".to_string(), published.checksum);
+    if let Err(e) = mark_registered(site, s3_client, &object_key, content_type, marked).await {
+        error!("{}", e);
+    }
+    Ok(Some(published.url))
+}
+
+/// Replace the object's metadata with `metadata`, which has its checksum and
+/// author, by copying it onto itself.
+async fn mark_registered(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    object_key: &str,
+    content_type: String,
+    metadata: HashMap<String, String>,
+) -> Result<(), String> {
+    let defaults = site.put_object_request_for(object_key);
+    let request = CopyObjectRequest {
+        copy_source: trash::copy_source(&defaults.bucket, &defaults.key),
+        bucket: defaults.bucket,
+        key: defaults.key,
+        content_type: Some(content_type),
+        metadata: Some(metadata),
+        metadata_directive: Some("REPLACE".to_string()),
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        acl: defaults.acl,
+        ..Default::default()
+    };
+    s3_client
+        .copy_object(request)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to update {}: {}", object_key, e))
+}
+
+/// Register every object created in a notification, logging failures.
+async fn register_all(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    publisher: &events::Publisher,
+    index: Option<&MediaIndex>,
+    body: &[u8],
+) -> Registered {
+    let mut registered = Registered::default();
+    for (bucket, key) in created_objects(body) {
+        let outcome = register_object(
+            site,
+            s3_client,
+            http_client,
+            publisher,
+            index,
+            &bucket,
+            &key,
+        );
+        match outcome.await {
+            Ok(Some(url)) => {
+                info!("Registered {} as {}", key, url);
+                registered.registered.push(url);
+            }
+            Ok(None) => registered.skipped += 1,
+            Err(Failure::Rejected(e)) => {
+                warn!("{}", e);
+                registered.rejected += 1;
+            }
+            Err(Failure::Transient(e)) => {
+                error!("{}", e);
+                registered.failed += 1;
+            }
+        }
+    }
+    registered
+}
+
+#[derive(Default, Serialize)]
+struct Registered {
+    /// The URLs of the objects which were registered.
+    registered: Vec<String>,
+    /// Objects which didn't need registering.
+    skipped: usize,
+    /// Objects which registration refused, e.g. because they failed
+    /// inspection.
+    rejected: usize,
+    /// Objects which may be registered if the notification is tried again.
+    failed: usize,
+}
+
+/// Register the objects in an S3 event notification, e.g. one forwarded by a
+/// Lambda function or an SNS subscription.
+async fn handle_notification(
+    req: HttpRequest,
+    body: web::Bytes,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+    if serde_json::from_slice::<serde_json::Value>(&body).is_err() {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "The notification must be JSON",
+        ));
+    }
+
+    let registered = register_all(
+        &site,
+        &s3_client,
+        &http_client,
+        &publisher,
+        index.as_deref(),
+        &body,
+    )
+    .await;
+    HttpResponse::Ok().json(registered)
+}
+
+/// Register the objects in the notifications sent to S3_EVENTS_QUEUE_URL,
+/// deleting each message once it's handled.
+///
+/// Messages with an object which failed to register for a reason that may
+/// pass, like an S3 error, are left on the queue. SQS delivers them again
+/// once their visibility timeout is up, or moves them to the queue's
+/// dead-letter queue if it has one.
+pub async fn consume_forever(
+    site: SiteConfig,
+    s3_client: S3Client,
+    publisher: events::Publisher,
+    index: Option<web::Data<MediaIndex>>,
+    region: Region,
+    queue_url: String,
+) {
+    let sqs_client = SqsClient::new(region);
+    let http_client = Client::new();
+    loop {
+        let request = ReceiveMessageRequest {
+            queue_url: queue_url.clone(),
+            max_number_of_messages: Some(10),
+            wait_time_seconds: Some(20),
+            ..Default::default()
+        };
+        let messages = match sqs_client.receive_message(request).await {
+            Ok(response) => response.messages.unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to receive S3 notifications: {}", e);
+                delay_for(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+
+        for message in messages {
+            let body = message.body.unwrap_or_default();
+            let registered = register_all(
+                &site,
+                &s3_client,
+                &http_client,
+                &publisher,
+                index.as_deref(),
+                body.as_bytes(),
+            )
+            .await;
+            if registered.failed > 0 {
+                continue;
+            }
+            if let Some(receipt_handle) = message.receipt_handle {
+                let request = DeleteMessageRequest {
+                    queue_url: queue_url.clone(),
+                    receipt_handle,
+                };
+                if let Err(e) = sqs_client.delete_message(request).await {
+                    error!("Failed to delete an S3 notification: {}", e);
+                }
+            }
+        }
+    }
+}
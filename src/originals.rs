@@ -0,0 +1,135 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use log::error;
+use serde::Deserialize;
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectError, GetObjectRequest, PutObjectRequest, S3Client, S3,
+};
+
+use std::collections::HashMap;
+
+use crate::micropub::{authorize, key_for_url, MicropubError};
+use crate::oauth;
+use crate::policy::Permission;
+use crate::SiteConfig;
+
+/// Key prefix for uploads as they were received, before they were processed.
+/// Nothing under it is served publicly.
+pub const ORIGINALS_PREFIX: &str = "originals";
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/original").route(web::get().to(handle_original)));
+}
+
+/// The key the untouched upload of `key` is kept under.
+pub fn original_key(key: &str) -> String {
+    format!("{}/{}", ORIGINALS_PREFIX, key)
+}
+
+/// Keep the upload as it was received, since what's stored under `key` was
+/// changed. It's private, and stored in ORIGINALS_STORAGE_CLASS if set.
+pub async fn keep(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    body: Vec<u8>,
+    content_type: String,
+    metadata: HashMap<String, String>,
+) -> Result<(), String> {
+    let mut put_request = PutObjectRequest {
+        body: Some(body.into()),
+        content_type: Some(content_type),
+        metadata: Some(metadata),
+        acl: None,
+        ..site.put_object_request_for(&original_key(key))
+    };
+    if let Some(storage_class) = site.originals_storage_class() {
+        put_request.storage_class = Some(storage_class.to_string());
+    }
+    s3_client
+        .put_object(put_request)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to keep the original of {}: {}", key, e))
+}
+
+/// Delete the original of `key`, if originals are kept.
+pub async fn delete(site: &SiteConfig, s3_client: &S3Client, key: &str) {
+    if !site.keep_originals() {
+        return;
+    }
+    let (bucket, original) = site.locate(&original_key(key));
+    let request = DeleteObjectRequest {
+        bucket,
+        key: original,
+        ..Default::default()
+    };
+    if let Err(e) = s3_client.delete_object(request).await {
+        error!("Failed to delete the original of {}: {}", key, e);
+    }
+}
+
+#[derive(Deserialize)]
+struct OriginalQuery {
+    url: String,
+}
+
+/// Download the upload behind `url` as it was received.
+async fn handle_original(
+    req: HttpRequest,
+    query: web::Query<OriginalQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Read).await {
+        Ok(access_token) => access_token,
+        Err(resp) => return resp,
+    };
+    let key = match key_for_url(&site, &query.url) {
+        Some(key) => key,
+        None => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "Unknown URL",
+            ))
+        }
+    };
+
+    let (bucket, original) = site.locate(&original_key(&key));
+    let request = GetObjectRequest {
+        bucket,
+        key: original,
+        ..Default::default()
+    };
+    let resp = match s3_client.get_object(request).await {
+        Ok(resp) => resp,
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+            return HttpResponse::NotFound().json(MicropubError::new("not_found"))
+        }
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => {
+            return HttpResponse::NotFound().json(MicropubError::new("not_found"))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    // Don't reveal anything about other people's uploads.
+    if let Some(author) = resp.metadata.as_ref().and_then(|m| m.get("author")) {
+        if author != access_token.me() {
+            return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+        }
+    }
+
+    let body = match resp.body {
+        Some(body) => body,
+        None => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+    };
+    let mut client_resp = HttpResponse::Ok();
+    if let Some(content_type) = resp.content_type {
+        client_resp.header(header::CONTENT_TYPE, content_type);
+    }
+    client_resp.streaming(body)
+}
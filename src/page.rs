@@ -0,0 +1,159 @@
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use chrono::DateTime;
+
+use crate::feed::escape;
+use crate::media;
+use crate::replica::ReadBuckets;
+use crate::SiteConfig;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/media/page/{type}/{filename:.+}").route(web::get().to(serve_page)));
+}
+
+/// Render a small HTML page embedding the media, for sharing.
+async fn serve_page(
+    req: HttpRequest,
+    config: web::Data<SiteConfig>,
+    buckets: web::Data<ReadBuckets>,
+) -> Result<HttpResponse, Error> {
+    let media_type = req
+        .match_info()
+        .get("type")
+        .ok_or(ErrorBadRequest("Bad URI"))?;
+    let filename = req
+        .match_info()
+        .get("filename")
+        .ok_or(ErrorBadRequest("Bad URI"))?;
+    if media::is_hidden(&config, media_type) {
+        return Err(ErrorNotFound("Not found"));
+    }
+
+    let key = format!("{}/{}", media_type, filename);
+    let (head, fields) = media::describe(&config, &buckets, &key).await?;
+
+    let title = fields
+        .get("caption")
+        .or_else(|| fields.get("filename"))
+        .map(|t| t.as_str())
+        .unwrap_or(filename);
+    let alt = fields.get("alt").map(|a| a.as_str()).unwrap_or("");
+    let page_url = format!("{}/page/{}", config.media_url(), key);
+    let media_url = if media_type == "photo" {
+        format!(
+            "{}/photo/{}x{}/{}",
+            config.media_url(),
+            config.default_width(),
+            config.default_height(),
+            filename
+        )
+    } else {
+        format!("{}/{}", config.media_url(), key)
+    };
+    let content_type = head.content_type.unwrap_or_default();
+
+    let (og_type, embed) = match media_type {
+        "photo" => (
+            "og:image",
+            format!(
+                "<img class=\"u-photo\" src=\"{}\" alt=\"{}\">",
+                escape(&media_url),
+                escape(alt)
+            ),
+        ),
+        "video" => (
+            "og:video",
+            format!(
+                "<video class=\"u-video\" src=\"{}\" controls></video>",
+                escape(&media_url)
+            ),
+        ),
+        "audio" => (
+            "og:audio",
+            format!(
+                "<audio class=\"u-audio\" src=\"{}\" controls></audio>",
+                escape(&media_url)
+            ),
+        ),
+        _ => (
+            "og:url",
+            format!("<a class=\"u-url\" href=\"{}\">{}</a>", escape(&media_url), escape(title)),
+        ),
+    };
+
+    let mut meta = vec![
+        ("og:title".to_string(), title.to_string()),
+        ("og:url".to_string(), page_url.clone()),
+        ("og:type".to_string(), "website".to_string()),
+        ("twitter:title".to_string(), title.to_string()),
+    ];
+    if og_type != "og:url" {
+        meta.push((og_type.to_string(), media_url.clone()));
+        if !content_type.is_empty() {
+            meta.push((format!("{}:type", og_type), content_type));
+        }
+    }
+    if media_type == "photo" {
+        meta.push(("twitter:card".to_string(), "summary_large_image".to_string()));
+        meta.push(("twitter:image".to_string(), media_url.clone()));
+        if !alt.is_empty() {
+            meta.push(("og:image:alt".to_string(), alt.to_string()));
+            meta.push(("twitter:image:alt".to_string(), alt.to_string()));
+        }
+    } else {
+        meta.push(("twitter:card".to_string(), "summary".to_string()));
+    }
+    if let Some(caption) = fields.get("caption") {
+        meta.push(("og:description".to_string(), caption.clone()));
+    }
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n");
+    html.push_str(&format!("<title>{}</title>\n", escape(title)));
+    for (property, content) in &meta {
+        html.push_str(&format!(
+            "<meta property=\"{}\" content=\"{}\">\n",
+            property,
+            escape(content)
+        ));
+    }
+    html.push_str("<style>body{margin:0;text-align:center;font-family:sans-serif}");
+    html.push_str("img,video{max-width:100%;max-height:90vh}</style>\n");
+    html.push_str("</head>\n<body>\n<article class=\"h-entry\">\n");
+    html.push_str(&embed);
+    html.push('\n');
+    if let Some(caption) = fields.get("caption") {
+        html.push_str(&format!("<p class=\"p-name e-content\">{}</p>\n", escape(caption)));
+    }
+    if let Some(author) = fields.get("author") {
+        html.push_str(&format!(
+            "<a class=\"p-author h-card\" href=\"{}\">{}</a>\n",
+            escape(author),
+            escape(author)
+        ));
+    }
+    let published = head
+        .last_modified
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc2822(t).ok());
+    if let Some(published) = published {
+        html.push_str(&format!(
+            "<time class=\"dt-published\" datetime=\"{}\">{}</time>\n",
+            published.to_rfc3339(),
+            published.format("%B %-d, %Y")
+        ));
+    }
+    html.push_str(&format!(
+        "<a class=\"u-url\" href=\"{}\" hidden></a>\n",
+        escape(&page_url)
+    ));
+    html.push_str("</article>\n</body>\n</html>\n");
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "max-age=3600")
+        .body(html))
+}
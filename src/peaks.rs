@@ -0,0 +1,120 @@
+use log::{error, info};
+
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+
+use serde::Serialize;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use actix_web::web;
+
+use crate::SiteConfig;
+
+/// Key prefix for waveform peaks, served at `/media/audio/peaks/{filename}`.
+pub const PEAKS_PREFIX: &str = "audio/peaks";
+
+/// How many peaks are computed, however long the recording.
+const PEAK_COUNT: usize = 1000;
+
+/// Audio is decoded to mono at this rate, which is plenty for an envelope.
+const SAMPLE_RATE: u32 = 8000;
+
+/// The amplitude envelope of a recording, e.g. for a waveform player.
+#[derive(Serialize)]
+struct Peaks {
+    /// Seconds.
+    duration: f64,
+    /// The loudest sample in each slice of the recording, from 0 to 1.
+    peaks: Vec<f32>,
+}
+
+/// The key of the peaks of the audio stored as `filename`.
+pub fn peaks_key(filename: &str) -> String {
+    format!("{}/{}", PEAKS_PREFIX, filename)
+}
+
+/// Compute the waveform peaks of an audio upload and store them as JSON.
+///
+/// Like HLS packaging, this shells out to ffmpeg, so it's meant to be
+/// spawned after the upload has been acknowledged.
+pub async fn generate(site: SiteConfig, s3_client: S3Client, filename: String, data: Vec<u8>) {
+    let workdir = std::env::temp_dir().join(format!("peaks-{}", filename.replace('/', "-")));
+    let dir = workdir.clone();
+
+    let result = web::block(move || -> Result<Peaks, String> {
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let source = dir.join("source");
+        fs::write(&source, &data).map_err(|e| e.to_string())?;
+        decode(&source).map(|samples| peaks(&samples))
+    })
+    .await;
+
+    match result {
+        Ok(peaks) => match upload(&site, &s3_client, &filename, &peaks).await {
+            Ok(()) => info!("Generated waveform peaks for {}", filename),
+            Err(e) => error!("Failed to upload waveform peaks for {}: {}", filename, e),
+        },
+        Err(e) => error!("Failed to generate waveform peaks for {}: {}", filename, e),
+    }
+
+    if let Err(e) = fs::remove_dir_all(&workdir) {
+        error!("Failed to clean up {}: {}", workdir.display(), e);
+    }
+}
+
+/// Decode the recording to mono 16-bit samples.
+fn decode(source: &Path) -> Result<Vec<i16>, String> {
+    let output = Command::new("ffmpeg")
+        .args(&["-v", "error", "-i"])
+        .arg(source)
+        .args(&["-ac", "1", "-ar", &SAMPLE_RATE.to_string()])
+        .args(&["-f", "s16le", "pipe:1"])
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(format!("ffmpeg exited with {}", output.status));
+    }
+    Ok(output
+        .stdout
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect())
+}
+
+fn peaks(samples: &[i16]) -> Peaks {
+    let slice = ((samples.len() + PEAK_COUNT - 1) / PEAK_COUNT).max(1);
+    let peaks = samples
+        .chunks(slice)
+        .map(|chunk| {
+            let loudest = chunk.iter().map(|s| i32::from(*s).abs()).max().unwrap_or(0);
+            // Three decimal places are enough to draw and keep the JSON small.
+            (loudest as f32 / 32768.0 * 1000.0).round() / 1000.0
+        })
+        .collect();
+    Peaks {
+        duration: samples.len() as f64 / f64::from(SAMPLE_RATE),
+        peaks,
+    }
+}
+
+async fn upload(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    filename: &str,
+    peaks: &Peaks,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(peaks).map_err(|e| e.to_string())?;
+    let put_request = PutObjectRequest {
+        body: Some(body.into()),
+        content_type: Some("application/json".to_string()),
+        ..site.put_object_request_for(&peaks_key(filename))
+    };
+    s3_client
+        .put_object(put_request)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,105 @@
+//! Perceptual hashes of photos, to notice when one is uploaded again.
+//!
+//! Unlike the sha256 checksum, the hash survives re-encoding, resizing and
+//! small edits: photos which look the same have hashes a few bits apart.
+
+use actix_web::web;
+use image::imageops::FilterType;
+use image::GenericImageView;
+
+use std::f64::consts::PI;
+
+/// The metadata field holding a photo's hash.
+pub const PHASH_FIELD: &str = "phash";
+
+/// Photos are shrunk to this many pixels square before they're hashed.
+const SAMPLE_SIZE: u32 = 32;
+
+/// The lowest frequencies, this many square, make up the hash.
+const HASH_SIZE: usize = 8;
+
+/// What to do with a photo which looks like one already uploaded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateMode {
+    /// Store it anyway, and name the existing URL in the response.
+    Warn,
+    /// Don't store it, and respond with the existing URL.
+    Skip,
+}
+
+impl DuplicateMode {
+    /// Parse `warn` or `skip`.
+    pub fn parse(value: &str) -> Option<DuplicateMode> {
+        match value {
+            "warn" => Some(DuplicateMode::Warn),
+            "skip" => Some(DuplicateMode::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// Hash a photo, or None if it can't be decoded.
+pub async fn hash(data: Vec<u8>) -> Option<u64> {
+    web::block(move || compute(&data).ok_or(())).await.ok()
+}
+
+/// The DCT hash: each bit says whether one of the 64 lowest frequencies of
+/// the grayscale photo is above their median.
+fn compute(data: &[u8]) -> Option<u64> {
+    let img = image::load_from_memory(data)
+        .ok()?
+        .grayscale()
+        .resize_exact(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle);
+
+    let n = SAMPLE_SIZE as usize;
+    let mut pixels = vec![0f64; n * n];
+    for (x, y, pixel) in img.pixels() {
+        pixels[y as usize * n + x as usize] = f64::from(pixel[0]);
+    }
+
+    let cosines: Vec<f64> = (0..HASH_SIZE * n)
+        .map(|i| {
+            let (u, x) = (i / n, i % n);
+            ((2 * x + 1) as f64 * u as f64 * PI / (2 * n) as f64).cos()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(HASH_SIZE * HASH_SIZE);
+    for v in 0..HASH_SIZE {
+        for u in 0..HASH_SIZE {
+            let mut sum = 0.0;
+            for y in 0..n {
+                for x in 0..n {
+                    sum += pixels[y * n + x] * cosines[u * n + x] * cosines[v * n + y];
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+
+    // The first coefficient is the average brightness, which would skew the
+    // median.
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+    Some(
+        coefficients
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > median)
+            .fold(0u64, |hash, (i, _)| hash | 1 << i),
+    )
+}
+
+/// How many bits differ between two hashes.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// The hash as it's stored, in hex.
+pub fn encode(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn decode(value: &str) -> Option<u64> {
+    u64::from_str_radix(value, 16).ok()
+}
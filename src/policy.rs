@@ -0,0 +1,98 @@
+use crate::oauth::AccessToken;
+use crate::SiteConfig;
+
+/// Something a request may be allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    /// Upload new media.
+    Create,
+    /// Access media which isn't public, like old versions.
+    Read,
+    /// List and search the user's media and audit log.
+    List,
+    /// Delete or restore media.
+    Delete,
+    /// Administer the endpoint, e.g. purge caches or approve quarantined
+    /// uploads.
+    Admin,
+}
+
+impl Permission {
+    fn name(self) -> &'static str {
+        match self {
+            Permission::Create => "create",
+            Permission::Read => "read",
+            Permission::List => "list",
+            Permission::Delete => "delete",
+            Permission::Admin => "admin",
+        }
+    }
+
+    /// Scopes that grant the permission unless SCOPE_POLICY says otherwise.
+    fn default_scopes(self) -> &'static str {
+        match self {
+            Permission::Create => "media",
+            Permission::Read => "media:read",
+            Permission::List => "media",
+            Permission::Delete => "delete",
+            Permission::Admin => "admin",
+        }
+    }
+}
+
+/// Does the token have a scope that grants the permission?
+///
+/// SCOPE_POLICY maps permissions to the scopes that grant them, e.g.
+/// `create=media create,delete=delete`. Permissions it leaves out keep their
+/// default scopes.
+pub fn allows(site: &SiteConfig, token: &AccessToken, permission: Permission) -> bool {
+    let granting = granting_scopes(site.scope_policy(), permission);
+    token
+        .scopes()
+        .any(|s| granting.split_ascii_whitespace().any(|g| g == s))
+}
+
+/// The space-separated scopes `policy` says grant the permission.
+fn granting_scopes(policy: &str, permission: Permission) -> &str {
+    policy
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.trim().splitn(2, '=');
+            Some((parts.next()?.trim(), parts.next()?))
+        })
+        .find(|(name, _)| *name == permission.name())
+        .map(|(_, scopes)| scopes)
+        .unwrap_or_else(|| permission.default_scopes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{granting_scopes, Permission};
+
+    fn scopes(policy: &str, permission: Permission) -> Vec<&str> {
+        granting_scopes(policy, permission)
+            .split_ascii_whitespace()
+            .collect()
+    }
+
+    #[test]
+    fn permissions_have_default_scopes() {
+        assert_eq!(scopes("", Permission::Create), vec!["media"]);
+        assert_eq!(scopes("", Permission::Read), vec!["media:read"]);
+        assert_eq!(scopes("", Permission::Delete), vec!["delete"]);
+        assert_eq!(scopes("", Permission::Admin), vec!["admin"]);
+    }
+
+    #[test]
+    fn the_policy_replaces_the_defaults_it_names() {
+        let policy = "create=media create, delete = delete admin";
+        assert_eq!(scopes(policy, Permission::Create), vec!["media", "create"]);
+        assert_eq!(scopes(policy, Permission::Delete), vec!["delete", "admin"]);
+        assert_eq!(scopes(policy, Permission::List), vec!["media"]);
+    }
+
+    #[test]
+    fn malformed_entries_are_ignored() {
+        assert_eq!(scopes("create,=admin", Permission::Create), vec!["media"]);
+    }
+}
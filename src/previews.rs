@@ -0,0 +1,209 @@
+use log::{error, info};
+
+use rusoto_s3::{PutObjectRequest, S3Client, S3};
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use actix_web::web;
+
+use crate::hls;
+use crate::SiteConfig;
+
+/// Key prefix for scrubbing previews. Each video gets a directory below this
+/// named after its key.
+pub const PREVIEW_PREFIX: &str = "video/previews";
+
+/// The name of the contact sheet within each preview directory.
+pub const SPRITE: &str = "sprite.jpg";
+
+/// The name of the WebVTT file pointing into the sprite.
+pub const CUES: &str = "thumbnails.vtt";
+
+/// Width of each frame in the sprite.
+const FRAME_WIDTH: u32 = 160;
+
+/// Frames per row of the sprite.
+const COLUMNS: u32 = 10;
+
+/// Longer videos have frames further apart, rather than a larger sprite.
+const MAX_FRAMES: u32 = 100;
+
+/// The key of one of the previews of the video at `key`.
+pub fn preview_key(key: &str, name: &str) -> String {
+    format!("{}/{}/{}", PREVIEW_PREFIX, key, name)
+}
+
+/// The public URL of the cue file for the video at `key`.
+pub fn cues_url(site: &SiteConfig, key: &str) -> String {
+    format!("{}/{}", site.media_url(), preview_key(key, CUES))
+}
+
+/// How a video is cut into frames.
+struct Layout {
+    duration: f64,
+    interval: f64,
+    frames: u32,
+    columns: u32,
+    rows: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Layout {
+    fn new(duration: f64, interval: f64, (width, height): (u32, u32)) -> Layout {
+        let interval = interval.max(duration / f64::from(MAX_FRAMES));
+        let frames = ((duration / interval).ceil() as u32).max(1);
+        let columns = frames.min(COLUMNS);
+        // Keep the frame's aspect ratio, with an even height for the encoder.
+        let scaled = f64::from(FRAME_WIDTH) * f64::from(height) / f64::from(width.max(1));
+        let height = (scaled / 2.0).round() as u32 * 2;
+        Layout {
+            duration,
+            interval,
+            frames,
+            columns,
+            rows: (frames + columns - 1) / columns,
+            width: FRAME_WIDTH,
+            height: height.max(2),
+        }
+    }
+
+    /// A cue for each frame, naming its region of the sprite.
+    fn cues(&self) -> String {
+        let mut vtt = String::from("WEBVTT\n");
+        for frame in 0..self.frames {
+            let start = f64::from(frame) * self.interval;
+            let end = (start + self.interval).min(self.duration);
+            let x = frame % self.columns * self.width;
+            let y = frame / self.columns * self.height;
+            let _ = write!(
+                vtt,
+                "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                timestamp(start),
+                timestamp(end),
+                SPRITE,
+                x,
+                y,
+                self.width,
+                self.height
+            );
+        }
+        vtt
+    }
+}
+
+/// Format seconds as a WebVTT timestamp, e.g. `00:01:02.500`.
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Build a sprite of frames every PREVIEW_INTERVAL seconds and the WebVTT
+/// cues a player needs to show them while scrubbing.
+///
+/// Like HLS packaging, this shells out to ffmpeg, so it's meant to be
+/// spawned after the upload has been acknowledged.
+pub async fn generate(site: SiteConfig, s3_client: S3Client, key: String, data: Vec<u8>) {
+    let workdir = std::env::temp_dir().join(format!("previews-{}", key.replace('/', "-")));
+    let dir = workdir.clone();
+    let interval = site.preview_interval();
+
+    let result = web::block(move || -> Result<String, String> {
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let source = dir.join("source");
+        fs::write(&source, &data).map_err(|e| e.to_string())?;
+
+        let duration =
+            hls::probe_duration(&source).ok_or_else(|| "Couldn't find the duration".to_string())?;
+        let dimensions =
+            probe_dimensions(&source).ok_or_else(|| "Couldn't find the size".to_string())?;
+        let layout = Layout::new(duration, interval, dimensions);
+        tile(&source, &dir.join(SPRITE), &layout)?;
+        Ok(layout.cues())
+    })
+    .await;
+
+    match result {
+        Ok(cues) => match upload(&site, &s3_client, &key, &workdir.join(SPRITE), cues).await {
+            Ok(()) => info!("Generated scrubbing previews for {}", key),
+            Err(e) => error!("Failed to upload previews for {}: {}", key, e),
+        },
+        Err(e) => error!("Failed to generate previews for {}: {}", key, e),
+    }
+
+    if let Err(e) = fs::remove_dir_all(&workdir) {
+        error!("Failed to clean up {}: {}", workdir.display(), e);
+    }
+}
+
+/// Get the width and height of a video's first video stream.
+fn probe_dimensions(source: &Path) -> Option<(u32, u32)> {
+    let output = Command::new("ffprobe")
+        .args(&["-v", "error", "-select_streams", "v:0"])
+        .args(&["-show_entries", "stream=width,height", "-of", "csv=p=0:s=x"])
+        .arg(source)
+        .output()
+        .ok()?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut parts = output.trim().splitn(2, 'x');
+    let width = parts.next()?.parse().ok()?;
+    let height = parts.next()?.parse().ok()?;
+    Some((width, height))
+}
+
+/// Write the frames, tiled in a grid, to `out`.
+fn tile(source: &Path, out: &Path, layout: &Layout) -> Result<(), String> {
+    let filter = format!(
+        "fps=1/{},scale={}:{},tile={}x{}",
+        layout.interval, layout.width, layout.height, layout.columns, layout.rows
+    );
+    let status = Command::new("ffmpeg")
+        .args(&["-v", "error", "-i"])
+        .arg(source)
+        .args(&["-vf", &filter, "-frames:v", "1", "-q:v", "4", "-y"])
+        .arg(out)
+        .status()
+        .map_err(|e| e.to_string())?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("ffmpeg exited with {}", status))
+    }
+}
+
+async fn upload(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    sprite: &Path,
+    cues: String,
+) -> Result<(), String> {
+    let sprite = fs::read(sprite).map_err(|e| e.to_string())?;
+    let files = vec![
+        (SPRITE, "image/jpeg", sprite),
+        (CUES, "text/vtt", cues.into_bytes()),
+    ];
+    for (name, content_type, body) in files {
+        let put_request = PutObjectRequest {
+            body: Some(body.into()),
+            content_type: Some(content_type.to_string()),
+            ..site.put_object_request_for(&preview_key(key, name))
+        };
+        s3_client
+            .put_object(put_request)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
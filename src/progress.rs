@@ -0,0 +1,133 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::micropub::{authorize, MicropubError};
+use crate::oauth;
+use crate::policy::Permission;
+use crate::SiteConfig;
+
+/// The longest upload ID a client may choose.
+const MAX_ID_LENGTH: usize = 128;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/micropub/media/progress/{id}").route(web::get().to(handle_progress)),
+    );
+}
+
+/// Bytes received by uploads in flight, so clients can show a progress bar.
+///
+/// Uploads are tracked under an ID chosen by the client, scoped to its
+/// author, until the request finishes.
+#[derive(Default)]
+pub struct Progress {
+    uploads: Mutex<HashMap<(String, String), Tracked>>,
+}
+
+struct Tracked {
+    received: Arc<AtomicU64>,
+    /// The request's Content-Length, if it sent one.
+    total: Option<u64>,
+}
+
+/// Counts an upload's bytes until it's dropped.
+pub struct Tracking {
+    progress: web::Data<Progress>,
+    key: (String, String),
+    received: Arc<AtomicU64>,
+}
+
+#[derive(Serialize)]
+struct ProgressResponse {
+    received: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+}
+
+impl Progress {
+    /// Start tracking the upload `id` by `author`.
+    ///
+    /// Fails if the ID is unusable or the author already has an upload in
+    /// flight with it.
+    pub fn start(
+        progress: &web::Data<Progress>,
+        author: &str,
+        id: &str,
+        total: Option<u64>,
+    ) -> Result<Tracking, HttpResponse> {
+        if id.is_empty() || id.len() > MAX_ID_LENGTH {
+            return Err(
+                HttpResponse::BadRequest().json(MicropubError::with_description(
+                    "invalid_request",
+                    format!("Upload IDs must be 1 to {} bytes", MAX_ID_LENGTH),
+                )),
+            );
+        }
+
+        let key = (author.to_string(), id.to_string());
+        let received = Arc::new(AtomicU64::new(0));
+        let mut uploads = progress.uploads.lock().unwrap();
+        if uploads.contains_key(&key) {
+            return Err(
+                HttpResponse::Conflict().json(MicropubError::with_description(
+                    "conflict",
+                    format!("Upload {} is already in progress", id),
+                )),
+            );
+        }
+        uploads.insert(
+            key.clone(),
+            Tracked {
+                received: received.clone(),
+                total,
+            },
+        );
+
+        Ok(Tracking {
+            progress: progress.clone(),
+            key,
+            received,
+        })
+    }
+}
+
+impl Tracking {
+    /// The counter to add received bytes to.
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.received.clone()
+    }
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        self.progress.uploads.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Report how much of an upload has arrived. Finished uploads are gone.
+async fn handle_progress(
+    req: HttpRequest,
+    id: web::Path<String>,
+    site: web::Data<SiteConfig>,
+    progress: web::Data<Progress>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let key = (access_token.me().to_string(), id.into_inner());
+    match progress.uploads.lock().unwrap().get(&key) {
+        Some(tracked) => HttpResponse::Ok().json(ProgressResponse {
+            received: tracked.received.load(Ordering::Relaxed),
+            total: tracked.total,
+        }),
+        None => HttpResponse::NotFound().json(MicropubError::new("not_found")),
+    }
+}
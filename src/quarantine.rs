@@ -0,0 +1,412 @@
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use image::ImageFormat;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    CopyObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3,
+};
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::cdn::Cdn;
+use crate::events;
+use crate::index::MediaIndex;
+use crate::metadata;
+use crate::micropub::{authorize, invalidate, MicropubError};
+use crate::multipart;
+use crate::oauth::{self, AccessToken};
+use crate::policy::Permission;
+use crate::replica::ReadBuckets;
+use crate::trash;
+use crate::SiteConfig;
+
+/// Key prefix for uploads held for review. Nothing under it is served.
+pub const QUARANTINE_PREFIX: &str = "quarantine";
+
+/// Image formats we can decode, which an upload claiming to be one must be.
+const DECODABLE_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+    "image/tiff",
+];
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/admin/quarantine").route(web::get().to(handle_list)));
+    cfg.service(web::resource("/admin/quarantine/approve").route(web::post().to(handle_approve)));
+    cfg.service(web::resource("/admin/quarantine/purge").route(web::post().to(handle_purge)));
+}
+
+/// The key a held upload is kept under.
+pub fn quarantine_key(key: &str) -> String {
+    format!("{}/{}", QUARANTINE_PREFIX, key)
+}
+
+/// Why a photo isn't what it claims to be, or is too large to process.
+pub fn check_image(site: &SiteConfig, content_type: &mime::Mime, data: &[u8]) -> Option<String> {
+    let declared = match content_type.essence_str() {
+        "image/jpg" | "image/pjpeg" => "image/jpeg",
+        "image/x-icon" => "image/vnd.microsoft.icon",
+        essence => essence,
+    };
+
+    let format = match image::guess_format(data) {
+        Ok(format) => format,
+        Err(_) if DECODABLE_TYPES.contains(&declared) => {
+            return Some(format!("The content is not {}", declared))
+        }
+        // Something we can't decode, like SVG.
+        Err(_) => return None,
+    };
+    let actual = mime_for_format(format);
+    if actual != declared {
+        return Some(format!("The content is {}, not {}", actual, declared));
+    }
+
+    let max_pixels = site.max_image_pixels()?;
+    let reader = image::io::Reader::with_format(Cursor::new(data), format);
+    match reader.into_dimensions() {
+        Ok((width, height)) if u64::from(width) * u64::from(height) > max_pixels => Some(format!(
+            "The image is {}x{}, more than {} pixels",
+            width, height, max_pixels
+        )),
+        Ok(_) => None,
+        Err(e) => Some(format!("The image could not be read: {}", e)),
+    }
+}
+
+fn mime_for_format(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Bmp => "image/bmp",
+        ImageFormat::Tiff => "image/tiff",
+        ImageFormat::Ico => "image/vnd.microsoft.icon",
+        _ => "an unsupported image format",
+    }
+}
+
+/// The response for an upload which failed inspection, when QUARANTINE is off.
+pub fn rejected(key: &str, author: &str, reason: &str) -> HttpResponse {
+    warn!("Rejected {} from {}: {}", key, author, reason);
+    HttpResponse::UnprocessableEntity()
+        .json(MicropubError::with_description("invalid_request", reason))
+}
+
+/// An upload which failed inspection, to be kept for review.
+pub struct Held<'a> {
+    pub key: &'a str,
+    pub author: &'a str,
+    pub reason: &'a str,
+    pub body: Vec<u8>,
+    pub content_type: String,
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct HeldResponse<'a> {
+    status: &'static str,
+    reason: &'a str,
+}
+
+/// Store the upload under the quarantine prefix, where it isn't served.
+pub async fn hold(site: &SiteConfig, s3_client: &S3Client, upload: Held<'_>) -> HttpResponse {
+    warn!(
+        "Quarantined {} from {}: {}",
+        upload.key, upload.author, upload.reason
+    );
+    let mut object_metadata = upload.metadata;
+    object_metadata.insert(
+        "quarantine-reason".to_string(),
+        metadata::encode(upload.reason),
+    );
+    let put_request = PutObjectRequest {
+        body: Some(upload.body.into()),
+        content_type: Some(upload.content_type),
+        metadata: Some(object_metadata),
+        // Held uploads shouldn't be public.
+        acl: None,
+        ..site.put_object_request_for(&quarantine_key(upload.key))
+    };
+    match s3_client.put_object(put_request).await {
+        Ok(_) => HttpResponse::Accepted().json(HeldResponse {
+            status: "quarantined",
+            reason: upload.reason,
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+/// An upload which failed inspection after it was completed in the bucket,
+/// to be kept for review.
+pub struct HeldCopy<'a> {
+    /// The logical key the upload is stored under now.
+    pub from: &'a str,
+    pub size: u64,
+    pub key: &'a str,
+    pub author: &'a str,
+    pub reason: &'a str,
+    pub content_type: String,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Copy the upload under the quarantine prefix, like [`hold`], without
+/// reading it.
+pub async fn hold_copy(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    upload: HeldCopy<'_>,
+) -> HttpResponse {
+    warn!(
+        "Quarantined {} from {}: {}",
+        upload.key, upload.author, upload.reason
+    );
+    let mut object_metadata = upload.metadata;
+    object_metadata.insert(
+        "quarantine-reason".to_string(),
+        metadata::encode(upload.reason),
+    );
+    let (from_bucket, from_key) = site.locate(upload.from);
+    let defaults = site.put_object_request_for(&quarantine_key(upload.key));
+    let request = CopyObjectRequest {
+        bucket: defaults.bucket,
+        copy_source: trash::copy_source(&from_bucket, &from_key),
+        key: defaults.key,
+        content_type: Some(upload.content_type),
+        metadata: Some(object_metadata),
+        metadata_directive: Some("REPLACE".to_string()),
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        // Held uploads shouldn't be public.
+        acl: None,
+        ..Default::default()
+    };
+    match multipart::copy_object(site, s3_client, request, upload.size).await {
+        Ok(()) => HttpResponse::Accepted().json(HeldResponse {
+            status: "quarantined",
+            reason: upload.reason,
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+#[derive(Serialize)]
+struct HeldUpload {
+    key: String,
+    size: Option<i64>,
+    last_modified: Option<String>,
+    author: Option<String>,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    items: Vec<HeldUpload>,
+}
+
+/// List the uploads waiting for review.
+async fn handle_list(
+    req: HttpRequest,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+
+    let (bucket, prefix) = site.locate(&format!("{}/", QUARANTINE_PREFIX));
+    let mut items = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.clone(),
+            prefix: Some(prefix.clone()),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = match s3_client.list_objects_v2(request).await {
+            Ok(response) => response,
+            Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+        };
+
+        for object in response.contents.unwrap_or_default() {
+            let held = match object.key {
+                Some(key) => key,
+                None => continue,
+            };
+            let head = s3_client
+                .head_object(HeadObjectRequest {
+                    bucket: bucket.clone(),
+                    key: held.clone(),
+                    ..Default::default()
+                })
+                .await;
+            let fields = match head {
+                Ok(head) => head.metadata.unwrap_or_default(),
+                Err(e) => {
+                    error!("Failed to read {}: {}", held, e);
+                    HashMap::new()
+                }
+            };
+            items.push(HeldUpload {
+                key: held[prefix.len()..].to_string(),
+                size: object.size,
+                last_modified: object.last_modified,
+                author: fields.get("author").cloned(),
+                reason: fields.get("quarantine-reason").map(|r| metadata::decode(r)),
+            });
+        }
+
+        if response.is_truncated != Some(true) {
+            return HttpResponse::Ok().json(ListResponse { items });
+        }
+        continuation_token = response.next_continuation_token;
+    }
+}
+
+#[derive(Deserialize)]
+struct ReviewQuery {
+    /// The key the upload would have had, e.g. `file/abc/report.pdf`.
+    key: String,
+}
+
+/// Publish a held upload at the URL it would have had.
+async fn handle_approve(
+    req: HttpRequest,
+    query: web::Query<ReviewQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+    http_client: web::Data<Client>,
+    cdn: web::Data<Cdn>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+
+    let key = &query.key;
+    let held = quarantine_key(key);
+    let (bucket, held_key) = site.locate(&held);
+    let head = match s3_client
+        .head_object(HeadObjectRequest {
+            bucket: bucket.clone(),
+            key: held_key.clone(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(head) => head,
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => {
+            return HttpResponse::NotFound().json(MicropubError::new("not_found"))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    let mut metadata = head.metadata.unwrap_or_default();
+    metadata.remove("quarantine-reason");
+    let uploader = uploader(&metadata);
+
+    if let Err(e) = trash::copy(&site, &s3_client, &held, key, head.content_type, metadata).await {
+        return HttpResponse::InternalServerError().body(e);
+    }
+    if let Err(e) = trash::delete(&s3_client, &bucket, &held_key).await {
+        error!("{}", e);
+    }
+
+    // The 404 may have been cached.
+    let url = url_for(&site, key);
+    invalidate(
+        &site,
+        &s3_client,
+        &http_client,
+        &cdn,
+        // Nothing was cached while it was hidden.
+        None,
+        key,
+        &url,
+    )
+    .await;
+
+    trash::republish(
+        &site,
+        &buckets,
+        &publisher,
+        index.as_deref(),
+        key,
+        &url,
+        &uploader,
+    )
+    .await;
+
+    HttpResponse::Created()
+        .header(header::LOCATION, url)
+        .finish()
+}
+
+/// Permanently delete a held upload.
+async fn handle_purge(
+    req: HttpRequest,
+    query: web::Query<ReviewQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &verification_service, &site, Permission::Admin).await {
+        return resp;
+    }
+
+    let (bucket, held_key) = site.locate(&quarantine_key(&query.key));
+    if let Err(e) = trash::delete(&s3_client, &bucket, &held_key).await {
+        return HttpResponse::InternalServerError().body(e);
+    }
+    if site.metadata_sidecar() {
+        let sidecar = metadata::sidecar_key(&query.key);
+        if let Err(e) = trash::delete(&s3_client, site.s3_bucket(), &sidecar).await {
+            error!("{}", e);
+        }
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+/// The token the upload was made with, as far as it can be told.
+pub(crate) fn uploader(metadata: &HashMap<String, String>) -> AccessToken {
+    let author = metadata.get("author").cloned().unwrap_or_default();
+    match metadata.get("api-key") {
+        Some(name) => AccessToken::for_api_key(name, author, String::new()),
+        None => AccessToken::new(
+            author,
+            metadata.get("client-id").cloned().unwrap_or_default(),
+            String::new(),
+        ),
+    }
+}
+
+/// The public URL for the object with the logical key `key`.
+fn url_for(site: &SiteConfig, key: &str) -> String {
+    match key.strip_prefix("photo/") {
+        Some(filename) => format!(
+            "{}/photo/{}x{}/{}",
+            site.media_url(),
+            site.default_width(),
+            site.default_height(),
+            filename
+        ),
+        None => format!("{}/{}", site.media_url(), key),
+    }
+}
@@ -0,0 +1,185 @@
+use actix_rt::time::delay_for;
+use actix_web::web;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+
+use rusoto_s3::{ListObjectsV2Request, S3Client, S3};
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::alias;
+use crate::classify;
+use crate::index::{MediaIndex, MediaRecord};
+use crate::metadata;
+use crate::phash;
+use crate::SiteConfig;
+
+/// How often the index is checked against the bucket.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The differences found between the index and the bucket.
+#[derive(Default)]
+pub struct ReconcileReport {
+    /// Stored objects with no index record.
+    pub unindexed: Vec<String>,
+    /// Index records with no stored object.
+    pub orphaned: Vec<String>,
+    /// How many of them were fixed.
+    pub repaired: usize,
+}
+
+/// Compare the index with the objects in the bucket, logging each difference.
+///
+/// Objects can be changed out-of-band, e.g. deleted in the S3 console, and an
+/// index write can fail after an upload. With `repair`, objects missing from
+/// the index are added from their metadata and records of deleted objects are
+/// removed. Each difference is checked again before it's reported, so
+/// uploads and deletes racing the check aren't mistaken for one.
+pub async fn reconcile(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    index: &MediaIndex,
+    repair: bool,
+) -> Result<ReconcileReport, String> {
+    let indexed = index
+        .keys()
+        .await
+        .map_err(|e| format!("Failed to read the index: {}", e))?;
+    let stored = stored_keys(site, s3_client).await?;
+
+    let mut report = ReconcileReport::default();
+    for key in stored.difference(&indexed) {
+        let head = match alias::find(site, s3_client, key).await {
+            Ok(Some(head)) => head,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to read {}: {}", key, e);
+                continue;
+            }
+        };
+        // Renamed objects leave a redirect which isn't indexed.
+        if head.website_redirect_location.is_some() {
+            continue;
+        }
+        warn!("{} is missing from the index", key);
+        report.unindexed.push(key.clone());
+        if !repair {
+            continue;
+        }
+
+        let fields = metadata::decode_all(head.metadata.unwrap_or_default());
+        let record = MediaRecord {
+            key: key.clone(),
+            url: format!("{}/{}", site.media_url(), key),
+            // Only the prefix is known, which names the classification
+            // unless CLASSIFICATION_RULES says otherwise.
+            classification: key.split('/').next().unwrap_or_default().to_string(),
+            content_type: head.content_type,
+            size: head.content_length,
+            author: fields.get("author").cloned().unwrap_or_default(),
+            client_id: fields.get("client-id").cloned().unwrap_or_default(),
+            checksum: fields.get("sha256").cloned(),
+            filename: fields.get("filename").cloned(),
+            alt: fields.get("alt").cloned(),
+            caption: fields.get("caption").cloned(),
+            created_at: head
+                .last_modified
+                .as_deref()
+                .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+                .map_or_else(Utc::now, |t| t.with_timezone(&Utc)),
+        };
+        match index.insert(&record).await {
+            Ok(()) => report.repaired += 1,
+            Err(e) => error!("Failed to add {} to the index: {}", key, e),
+        }
+        if let Some(hash) = fields
+            .get(phash::PHASH_FIELD)
+            .and_then(|v| phash::decode(v))
+        {
+            if let Err(e) = index.insert_phash(key, &record.author, hash).await {
+                error!("Failed to add the hash of {} to the index: {}", key, e);
+            }
+        }
+    }
+
+    for key in indexed.difference(&stored) {
+        match alias::find(site, s3_client, key).await {
+            Ok(None) => (),
+            Ok(Some(_)) => continue,
+            Err(e) => {
+                error!("Failed to read {}: {}", key, e);
+                continue;
+            }
+        }
+        warn!("{} is in the index but not the bucket", key);
+        report.orphaned.push(key.clone());
+        if !repair {
+            continue;
+        }
+        match index.delete(key).await {
+            Ok(()) => report.repaired += 1,
+            Err(e) => error!("Failed to remove {} from the index: {}", key, e),
+        }
+    }
+
+    Ok(report)
+}
+
+/// The logical key of every upload in the bucket.
+async fn stored_keys(site: &SiteConfig, s3_client: &S3Client) -> Result<HashSet<String>, String> {
+    let mut keys = HashSet::new();
+    for classification in classify::prefixes(site) {
+        let logical_prefix = format!("{}/", classification);
+        let (bucket, prefix) = site.locate(&logical_prefix);
+        let route_prefix = prefix[..prefix.len() - logical_prefix.len()].to_string();
+        let mut continuation_token = None;
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: bucket.clone(),
+                prefix: Some(prefix.clone()),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+            let response = s3_client
+                .list_objects_v2(request)
+                .await
+                .map_err(|e| format!("Failed to list {}: {}", prefix, e))?;
+            for object in response.contents.unwrap_or_default() {
+                if let Some(key) = object.key {
+                    if let Some(key) = key.strip_prefix(&route_prefix) {
+                        keys.insert(key.to_string());
+                    }
+                }
+            }
+
+            if response.is_truncated != Some(true) {
+                break;
+            }
+            continuation_token = response.next_continuation_token;
+        }
+    }
+    Ok(keys)
+}
+
+/// Periodically check the index against the bucket.
+pub async fn reconcile_forever(
+    site: SiteConfig,
+    s3_client: S3Client,
+    index: web::Data<MediaIndex>,
+    repair: bool,
+) {
+    loop {
+        delay_for(RECONCILE_INTERVAL).await;
+        match reconcile(&site, &s3_client, &index, repair).await {
+            Ok(report) => info!(
+                "Found {} objects missing from the index and {} deleted objects in it; repaired {}",
+                report.unindexed.len(),
+                report.orphaned.len(),
+                report.repaired
+            ),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+
+use crate::SiteConfig;
+
+/// Old paths, e.g. from a previous media host, and where they've moved.
+#[derive(Clone, Default)]
+pub struct Redirects {
+    targets: Arc<HashMap<String, String>>,
+}
+
+/// Read the redirects from a JSON object of old paths to new keys, or a CSV
+/// file with an old path and a new key on each line.
+///
+/// Old paths may be whole URLs, like the mapping printed by `import`, and
+/// only their path is matched. New keys may be whole URLs too, for media
+/// which isn't in the bucket.
+pub fn load(path: &str) -> io::Result<Redirects> {
+    let contents = fs::read_to_string(path)?;
+    let pairs: Vec<(String, String)> = if path.ends_with(".json") {
+        serde_json::from_str::<HashMap<String, String>>(&contents)?
+            .into_iter()
+            .collect()
+    } else {
+        parse_csv(&contents)?
+    };
+
+    let targets = pairs
+        .into_iter()
+        .map(|(old, new)| (old_path(&old), new))
+        .collect();
+    Ok(Redirects {
+        targets: Arc::new(targets),
+    })
+}
+
+/// Read `old,new` pairs, one per line, with tabs also accepted. Blank lines
+/// and lines starting with `#` are skipped.
+fn parse_csv(contents: &str) -> io::Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(2, |c| c == ',' || c == '\t');
+        match (fields.next(), fields.next()) {
+            (Some(old), Some(new)) if !old.trim().is_empty() && !new.trim().is_empty() => {
+                pairs.push((old.trim().to_string(), new.trim().to_string()))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Line {} of the redirect map needs an old path and a new key",
+                        i + 1
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+/// The path of an old URL, or the old path with a leading slash.
+fn old_path(old: &str) -> String {
+    let path = match old.find("://") {
+        Some(scheme_end) => {
+            let rest = &old[scheme_end + 3..];
+            rest.find('/').map_or("/", |host_end| &rest[host_end..])
+        }
+        None => old,
+    };
+    format!("/{}", path.trim_start_matches('/'))
+}
+
+impl Redirects {
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Where the request for `path` has moved, if anywhere.
+    pub fn target(&self, site: &SiteConfig, path: &str) -> Option<String> {
+        let new = self.targets.get(path)?;
+        if new.contains("://") {
+            Some(new.clone())
+        } else {
+            Some(format!(
+                "{}/{}",
+                site.media_url(),
+                new.trim_start_matches('/')
+            ))
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use actix_web::web;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+use log::{info, warn};
+
+use std::io::Cursor;
+
+use crate::exif;
+use crate::media::target_dimensions;
+use crate::SiteConfig;
+
+/// Whether the site re-encodes large photos at all.
+pub fn enabled(site: &SiteConfig) -> bool {
+    site.reencode_max_bytes().is_some() || site.reencode_max_pixels().is_some()
+}
+
+/// Shrink a JPEG or PNG over REENCODE_MAX_BYTES or REENCODE_MAX_PIXELS to fit
+/// within REENCODE_LONG_EDGE, in the same format.
+///
+/// Returns the new image, or None if the photo is left as it was: because it's
+/// under the limits, isn't a JPEG or PNG, or wouldn't get any smaller.
+/// Metadata isn't carried over, though the EXIF orientation is applied.
+pub async fn shrink(site: &SiteConfig, data: &[u8]) -> Option<Vec<u8>> {
+    if !enabled(site) {
+        return None;
+    }
+
+    let reader = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?;
+    let format = reader.format()?;
+    if format != ImageFormat::Jpeg && format != ImageFormat::Png {
+        return None;
+    }
+    let (width, height) = reader.into_dimensions().ok()?;
+
+    let too_many_bytes = site
+        .reencode_max_bytes()
+        .map_or(false, |max| data.len() as u64 > max);
+    let too_many_pixels = site
+        .reencode_max_pixels()
+        .map_or(false, |max| u64::from(width) * u64::from(height) > max);
+    if !too_many_bytes && !too_many_pixels {
+        return None;
+    }
+
+    let long_edge = site.reencode_long_edge();
+    let quality = site.reencode_quality();
+    let original = data.to_vec();
+    let encoded = web::block(move || -> Result<Vec<u8>, image::ImageError> {
+        let img = image::load_from_memory_with_format(&original, format)?;
+        let img = orient(img, exif::orientation(&original));
+        let (orig_width, orig_height) = img.dimensions();
+        let (new_width, new_height) =
+            target_dimensions(orig_width, orig_height, long_edge, long_edge, 1.0);
+        let img = if (new_width, new_height) != (orig_width, orig_height) {
+            img.resize_exact(new_width, new_height, image::imageops::FilterType::Lanczos3)
+        } else {
+            img
+        };
+
+        let output = match format {
+            ImageFormat::Jpeg => ImageOutputFormat::Jpeg(quality),
+            _ => ImageOutputFormat::Png,
+        };
+        let mut encoded = Vec::new();
+        img.write_to(&mut encoded, output)?;
+        Ok(encoded)
+    })
+    .await;
+
+    match encoded {
+        Ok(encoded) if encoded.len() < data.len() => {
+            info!(
+                "Re-encoded a {}x{} photo from {} to {} bytes",
+                width,
+                height,
+                data.len(),
+                encoded.len()
+            );
+            Some(encoded)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Failed to re-encode a photo: {}", e);
+            None
+        }
+    }
+}
+
+/// Turn the pixels upright, since the orientation tag is lost.
+fn orient(img: DynamicImage, orientation: Option<u16>) -> DynamicImage {
+    match orientation {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    }
+}
@@ -0,0 +1,140 @@
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{
+    GetObjectError, GetObjectOutput, GetObjectRequest, HeadObjectError, HeadObjectOutput,
+    HeadObjectRequest, S3Client, S3,
+};
+
+use log::warn;
+
+use crate::retry::{RetryError, S3Policy};
+
+/// An ordered list of buckets holding the same objects.
+///
+/// Reads go to the first bucket and fail over to the replicas when a region
+/// is erroring or timing out. Each region has its own circuit breaker.
+#[derive(Clone)]
+pub struct ReadBuckets {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Clone)]
+struct Bucket {
+    client: S3Client,
+    name: String,
+    policy: S3Policy,
+}
+
+impl ReadBuckets {
+    /// Create the list from the primary bucket and a replica specification.
+    ///
+    /// Replicas are given as comma separated `region:bucket` pairs, in the order they
+    /// should be tried. `policy` is called to create the policy for each bucket, and
+    /// `connect` to create the client for each replica's region.
+    pub fn new<F, C>(
+        client: S3Client,
+        bucket: &str,
+        replicas: &str,
+        policy: F,
+        connect: C,
+    ) -> ReadBuckets
+    where
+        F: Fn() -> S3Policy,
+        C: Fn(Region) -> S3Client,
+    {
+        let mut buckets = vec![Bucket {
+            client,
+            name: bucket.to_string(),
+            policy: policy(),
+        }];
+
+        for replica in replicas.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (region, name) = match replica.find(':') {
+                Some(i) => (&replica[..i], &replica[i + 1..]),
+                None => panic!("Expected region:bucket, found {}", replica),
+            };
+            let region: Region = region.parse().expect("Invalid replica region");
+            buckets.push(Bucket {
+                client: connect(region),
+                name: name.to_string(),
+                policy: policy(),
+            });
+        }
+
+        ReadBuckets { buckets }
+    }
+
+    pub async fn get_object(
+        &self,
+        request: GetObjectRequest,
+    ) -> Result<GetObjectOutput, RetryError<GetObjectError>> {
+        let mut result = None;
+        for bucket in self.candidates(&request.bucket) {
+            let request = GetObjectRequest {
+                bucket: bucket.name.clone(),
+                ..request.clone()
+            };
+            let attempt = bucket
+                .policy
+                .call(|| bucket.client.get_object(request.clone()))
+                .await;
+            match attempt {
+                Err(e) if should_fail_over(&e) => {
+                    warn!("Failing over from bucket {}: {}", bucket.name, e);
+                    result = Some(Err(e));
+                }
+                attempt => return attempt,
+            }
+        }
+        result.expect("At least one bucket")
+    }
+
+    pub async fn head_object(
+        &self,
+        request: HeadObjectRequest,
+    ) -> Result<HeadObjectOutput, RetryError<HeadObjectError>> {
+        let mut result = None;
+        for bucket in self.candidates(&request.bucket) {
+            let request = HeadObjectRequest {
+                bucket: bucket.name.clone(),
+                ..request.clone()
+            };
+            let attempt = bucket
+                .policy
+                .call(|| bucket.client.head_object(request.clone()))
+                .await;
+            match attempt {
+                Err(e) if should_fail_over(&e) => {
+                    warn!("Failing over from bucket {}: {}", bucket.name, e);
+                    result = Some(Err(e));
+                }
+                attempt => return attempt,
+            }
+        }
+        result.expect("At least one bucket")
+    }
+
+    /// The buckets to try for a request to `name`.
+    ///
+    /// Only the primary bucket has replicas. Other buckets are read through
+    /// the primary's client and policy.
+    fn candidates(&self, name: &str) -> Vec<Bucket> {
+        if name == self.buckets[0].name {
+            self.buckets.clone()
+        } else {
+            vec![Bucket {
+                name: name.to_string(),
+                ..self.buckets[0].clone()
+            }]
+        }
+    }
+}
+
+/// Returns true if the error says more about the region than the object.
+fn should_fail_over<E: std::error::Error + 'static>(err: &RetryError<E>) -> bool {
+    match err {
+        RetryError::Unavailable(_) | RetryError::TimedOut => true,
+        RetryError::S3(RusotoError::HttpDispatch(_)) => true,
+        RetryError::S3(RusotoError::Unknown(resp)) => resp.status.is_server_error(),
+        RetryError::S3(_) => false,
+    }
+}
@@ -0,0 +1,259 @@
+use actix_web::client::Client;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::Method;
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::{error, warn};
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use serde_json::json;
+
+use std::collections::HashMap;
+use std::panic;
+use std::sync::{Arc, Mutex};
+
+/// Reports waiting to be delivered. More are dropped.
+const QUEUE_SIZE: usize = 100;
+
+/// Where errors are reported.
+pub enum ReportBackend {
+    /// Send events to Sentry's store API.
+    Sentry {
+        store_url: String,
+        public_key: String,
+    },
+    /// POST each report as JSON.
+    Http { url: String },
+}
+
+/// An error, with whatever is known about the request that caused it.
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    pub message: String,
+    /// `error` for 5xx responses, `fatal` for panics.
+    pub level: &'static str,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub route: Option<String>,
+    pub key: Option<String>,
+    pub user: Option<String>,
+    pub status: Option<u16>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// The user a request was authenticated as, kept in the request extensions
+/// so it can be included in reports.
+pub struct ReportUser(pub String);
+
+/// Queues reports for delivery in the background.
+#[derive(Clone)]
+pub struct ErrorReporter {
+    sender: Option<Arc<Mutex<mpsc::Sender<Report>>>>,
+}
+
+/// The receiving end of the report queue.
+pub struct ReportDelivery {
+    backend: ReportBackend,
+    receiver: mpsc::Receiver<Report>,
+}
+
+impl ReportBackend {
+    /// Parse the reporter configuration: `sentry:<DSN>` or an http(s) URL.
+    pub fn parse(value: &str) -> Option<ReportBackend> {
+        if let Some(dsn) = value.strip_prefix("sentry:") {
+            // https://<public key>@<host>/<project id>
+            let (scheme, rest) = dsn.split_at(dsn.find("://")? + 3);
+            let at = rest.find('@')?;
+            let public_key = rest[..at].split(':').next()?.to_string();
+            let host_and_path = &rest[at + 1..];
+            let slash = host_and_path.rfind('/')?;
+            Some(ReportBackend::Sentry {
+                store_url: format!(
+                    "{}{}/api/{}/store/",
+                    scheme,
+                    &host_and_path[..slash],
+                    &host_and_path[slash + 1..]
+                ),
+                public_key,
+            })
+        } else if value.starts_with("http://") || value.starts_with("https://") {
+            Some(ReportBackend::Http {
+                url: value.to_string(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl ErrorReporter {
+    /// A reporter, and the delivery to spawn, if a backend is configured.
+    pub fn new(backend: Option<ReportBackend>) -> (ErrorReporter, Option<ReportDelivery>) {
+        match backend {
+            Some(backend) => {
+                let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+                (
+                    ErrorReporter {
+                        sender: Some(Arc::new(Mutex::new(sender))),
+                    },
+                    Some(ReportDelivery { backend, receiver }),
+                )
+            }
+            None => (ErrorReporter { sender: None }, None),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Queue the report. This never blocks, so it is safe in a panic hook.
+    pub fn report(&self, report: Report) {
+        if let Some(sender) = &self.sender {
+            let sent = match sender.lock() {
+                Ok(mut sender) => sender.try_send(report).is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                warn!("Dropping error report");
+            }
+        }
+    }
+
+    /// Report the response if it is a server error.
+    pub fn report_response<B>(&self, res: &ServiceResponse<B>) {
+        let status = res.status();
+        if !status.is_server_error() {
+            return;
+        }
+
+        let req = res.request();
+        let message = match res.response().error() {
+            Some(e) => e.to_string(),
+            None => format!("{} {} returned {}", req.method(), req.path(), status),
+        };
+        let user = req.extensions().get::<ReportUser>().map(|u| u.0.clone());
+
+        self.report(Report {
+            message,
+            level: "error",
+            method: Some(req.method().to_string()),
+            path: Some(req.path().to_string()),
+            route: req.match_pattern(),
+            key: key_of(req.method(), req.path(), req.query_string()),
+            user,
+            status: Some(status.as_u16()),
+            timestamp: Utc::now(),
+        });
+    }
+
+    /// Report panics, after the existing hook has run.
+    pub fn report_panics(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let reporter = self.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            reporter.report(Report {
+                message: info.to_string(),
+                level: "fatal",
+                method: None,
+                path: None,
+                route: None,
+                key: None,
+                user: None,
+                status: None,
+                timestamp: Utc::now(),
+            });
+        }));
+    }
+}
+
+/// The object a request was about, if it can be told from the request.
+fn key_of(method: &Method, path: &str, query: &str) -> Option<String> {
+    let params = web::Query::<HashMap<String, String>>::from_query(query)
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    if let Some(key) = params.get("key").or_else(|| params.get("url")) {
+        return Some(key.clone());
+    }
+
+    // Everything else outside the API is a media path.
+    let path = path.trim_start_matches('/');
+    if (*method == Method::GET || *method == Method::HEAD)
+        && !path.starts_with("micropub/")
+        && !path.starts_with("admin/")
+    {
+        Some(path.to_string())
+    } else {
+        None
+    }
+}
+
+impl ReportDelivery {
+    /// Deliver queued reports until every reporter is gone.
+    pub async fn deliver_forever(mut self) {
+        let client = Client::new();
+        while let Some(report) = self.receiver.next().await {
+            let result = match &self.backend {
+                ReportBackend::Sentry {
+                    store_url,
+                    public_key,
+                } => {
+                    client
+                        .post(store_url)
+                        .header(
+                            "X-Sentry-Auth",
+                            format!(
+                                "Sentry sentry_version=7, sentry_client={}/{}, sentry_key={}",
+                                env!("CARGO_PKG_NAME"),
+                                env!("CARGO_PKG_VERSION"),
+                                public_key
+                            ),
+                        )
+                        .send_json(&sentry_event(&report))
+                        .await
+                }
+                ReportBackend::Http { url } => client.post(url).send_json(&report).await,
+            };
+
+            match result {
+                Ok(resp) if resp.status().is_success() => (),
+                Ok(resp) => error!("Error report was rejected with {}", resp.status()),
+                Err(e) => error!("Failed to send error report: {}", e),
+            }
+        }
+    }
+}
+
+/// The report as a Sentry event.
+fn sentry_event(report: &Report) -> serde_json::Value {
+    let event_id: String = (0..32)
+        .map(|_| std::char::from_digit(thread_rng().gen_range(0, 16), 16).unwrap())
+        .collect();
+
+    json!({
+        "event_id": event_id,
+        "timestamp": report.timestamp.to_rfc3339(),
+        "level": report.level,
+        "platform": "other",
+        "logger": env!("CARGO_PKG_NAME"),
+        "message": { "formatted": report.message },
+        "transaction": report.route,
+        "tags": {
+            "status": report.status,
+            "route": report.route,
+        },
+        "user": report.user.as_ref().map(|user| json!({ "id": user })),
+        "request": {
+            "method": report.method,
+            "url": report.path,
+        },
+        "extra": { "key": report.key },
+    })
+}
@@ -0,0 +1,86 @@
+use actix_web::http::header::{HeaderName, HeaderValue};
+use log::warn;
+
+use crate::SiteConfig;
+
+enum Pattern {
+    /// A filename extension, e.g. `.zip`.
+    Extension(String),
+    /// A classification's key prefix, e.g. `file`.
+    Prefix(String),
+}
+
+struct Rule {
+    pattern: Pattern,
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl Pattern {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            Pattern::Extension(ext) => key
+                .rsplit('/')
+                .next()
+                .and_then(|name| name.rsplit('.').next().filter(|e| *e != name))
+                .map_or(false, |e| e.eq_ignore_ascii_case(ext)),
+            Pattern::Prefix(prefix) => key.split('/').next() == Some(prefix.as_str()),
+        }
+    }
+}
+
+/// Parse RESPONSE_HEADERS, e.g.
+/// `.zip=Content-Disposition: attachment | file=X-Robots-Tag: noindex`.
+///
+/// Each rule matches an extension or a classification and names one header
+/// to send with it. Rules are separated by `|`, since header values are full
+/// of commas. Invalid rules are logged and skipped.
+fn rules(site: &SiteConfig) -> Vec<Rule> {
+    site.response_headers()
+        .split('|')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let rule = parse_rule(entry);
+            if rule.is_none() {
+                warn!("Ignoring invalid response header rule {:?}", entry);
+            }
+            rule
+        })
+        .collect()
+}
+
+fn parse_rule(entry: &str) -> Option<Rule> {
+    let mut parts = entry.splitn(2, '=');
+    let pattern = parts.next()?.trim();
+    let mut header = parts.next()?.splitn(2, ':');
+    let name = HeaderName::from_bytes(header.next()?.trim().as_bytes()).ok()?;
+    let value = HeaderValue::from_str(header.next()?.trim()).ok()?;
+
+    let pattern = match pattern.strip_prefix('.') {
+        Some(ext) if !ext.is_empty() => Pattern::Extension(ext.to_string()),
+        Some(_) => return None,
+        None if !pattern.is_empty() && !pattern.contains('/') => {
+            Pattern::Prefix(pattern.to_string())
+        }
+        None => return None,
+    };
+
+    Some(Rule {
+        pattern,
+        name,
+        value,
+    })
+}
+
+/// The configured headers for the object with the logical key `key`, e.g.
+/// `file/abc123/notes.zip`. The first rule for each header wins.
+pub fn defaults_for(site: &SiteConfig, key: &str) -> Vec<(HeaderName, HeaderValue)> {
+    let mut headers: Vec<(HeaderName, HeaderValue)> = Vec::new();
+    for rule in rules(site) {
+        if rule.pattern.matches(key) && !headers.iter().any(|(name, _)| *name == rule.name) {
+            headers.push((rule.name, rule.value));
+        }
+    }
+    headers
+}
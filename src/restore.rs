@@ -0,0 +1,113 @@
+use actix_web::http::header;
+use actix_web::HttpResponse;
+
+use log::{error, info};
+use serde::Serialize;
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    GetObjectError, GlacierJobParameters, HeadObjectRequest, RestoreObjectRequest, RestoreRequest,
+    S3Client, S3,
+};
+
+use crate::retry::RetryError;
+use crate::SiteConfig;
+
+#[derive(Serialize)]
+struct RestoringResponse {
+    status: &'static str,
+}
+
+/// Whether the GET failed because the object is archived and must be
+/// restored before it can be read.
+pub fn is_archived(err: &RetryError<GetObjectError>) -> bool {
+    match err.s3_error() {
+        Some(RusotoError::Service(GetObjectError::InvalidObjectState(_))) => true,
+        Some(RusotoError::Unknown(resp)) => {
+            resp.status.as_u16() == 403
+                && String::from_utf8_lossy(&resp.body).contains("InvalidObjectState")
+        }
+        _ => false,
+    }
+}
+
+/// Start restoring the archived object with the logical key `key`, unless
+/// that's already underway, and tell the client when to try again.
+pub async fn restoring(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    version_id: Option<&str>,
+) -> HttpResponse {
+    let (bucket, object_key) = site.locate(key);
+    let head_request = HeadObjectRequest {
+        bucket: bucket.clone(),
+        key: object_key.clone(),
+        version_id: version_id.map(str::to_string),
+        ..Default::default()
+    };
+    let head = match s3_client.head_object(head_request).await {
+        Ok(head) => head,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    // Deep Archive can't be restored at the Expedited tier.
+    let storage_class = head.storage_class.unwrap_or_default();
+    let tier = match site.restore_tier() {
+        "Expedited" if storage_class == "DEEP_ARCHIVE" => "Standard",
+        tier => tier,
+    };
+
+    // x-amz-restore is absent until a restore is requested.
+    let ongoing = head
+        .restore
+        .as_deref()
+        .map_or(false, |r| r.contains("ongoing-request=\"true\""));
+    if !ongoing {
+        let request = RestoreObjectRequest {
+            bucket,
+            key: object_key,
+            version_id: version_id.map(str::to_string),
+            restore_request: Some(RestoreRequest {
+                days: Some(site.restore_days()),
+                glacier_job_parameters: Some(GlacierJobParameters {
+                    tier: tier.to_string(),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        match s3_client.restore_object(request).await {
+            Ok(_) => info!("Restoring {} from {}", key, storage_class),
+            // Someone else asked first.
+            Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 409 => (),
+            Err(e) => {
+                error!("Failed to restore {}: {}", key, e);
+                return HttpResponse::InternalServerError().body(format!("{}", e));
+            }
+        }
+    }
+
+    HttpResponse::Accepted()
+        .header(
+            header::RETRY_AFTER,
+            retry_after(&storage_class, tier).to_string(),
+        )
+        .header(header::CACHE_CONTROL, "no-store")
+        .json(RestoringResponse {
+            status: "restoring",
+        })
+}
+
+/// Roughly how many seconds a restore takes.
+fn retry_after(storage_class: &str, tier: &str) -> u64 {
+    const MINUTE: u64 = 60;
+    const HOUR: u64 = 60 * MINUTE;
+    match (storage_class, tier) {
+        ("DEEP_ARCHIVE", "Bulk") => 48 * HOUR,
+        ("DEEP_ARCHIVE", _) => 12 * HOUR,
+        (_, "Expedited") => 5 * MINUTE,
+        (_, "Bulk") => 12 * HOUR,
+        _ => 5 * HOUR,
+    }
+}
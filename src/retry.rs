@@ -0,0 +1,164 @@
+use actix_rt::time::{delay_for, timeout};
+use actix_web::http::header;
+use actix_web::{Error, HttpResponse};
+
+use derive_more::Display;
+use log::warn;
+use rand::{thread_rng, Rng};
+use rusoto_core::RusotoError;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::limits;
+
+/// Retry, timeout, and circuit breaking policy for idempotent S3 calls.
+#[derive(Clone)]
+pub struct S3Policy {
+    retries: u32,
+    base_delay: Duration,
+    timeout: Duration,
+    breaker: Arc<CircuitBreaker>,
+}
+
+/// Stops calling S3 for a while after too many consecutive failures.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+}
+
+#[derive(Display, Debug)]
+pub enum RetryError<E: std::error::Error + 'static> {
+    /// The circuit breaker is open; try again after the duration.
+    #[display(fmt = "S3 is unavailable")]
+    Unavailable(Duration),
+    #[display(fmt = "S3 timed out")]
+    TimedOut,
+    #[display(fmt = "{}", _0)]
+    S3(RusotoError<E>),
+}
+
+impl S3Policy {
+    pub fn new(
+        retries: u32,
+        base_delay: Duration,
+        timeout: Duration,
+        breaker_threshold: u32,
+        breaker_cooldown: Duration,
+    ) -> S3Policy {
+        S3Policy {
+            retries,
+            base_delay,
+            timeout,
+            breaker: Arc::new(CircuitBreaker {
+                threshold: breaker_threshold,
+                cooldown: breaker_cooldown,
+                failures: AtomicU32::new(0),
+                open_until: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Call `f` until it succeeds, fails permanently, or runs out of retries.
+    ///
+    /// Only use this for idempotent requests.
+    pub async fn call<T, E, F, Fut>(&self, mut f: F) -> Result<T, RetryError<E>>
+    where
+        E: std::error::Error + 'static,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RusotoError<E>>>,
+    {
+        if let Some(wait) = self.breaker.open_for() {
+            return Err(RetryError::Unavailable(wait));
+        }
+
+        let mut attempt = 0;
+        loop {
+            let result = match timeout(self.timeout, f()).await {
+                Ok(Ok(value)) => {
+                    self.breaker.success();
+                    return Ok(value);
+                }
+                Ok(Err(e)) if !is_transient(&e) => return Err(RetryError::S3(e)),
+                Ok(Err(e)) => RetryError::S3(e),
+                Err(_) => RetryError::TimedOut,
+            };
+
+            self.breaker.failure();
+            if attempt >= self.retries {
+                return Err(result);
+            }
+
+            // Exponential backoff with full jitter
+            let cap = self.base_delay.as_millis() as u64 * (1 << attempt.min(16));
+            let delay = Duration::from_millis(thread_rng().gen_range(0, cap + 1));
+            warn!("Retrying S3 call in {:?} after: {}", delay, result);
+            delay_for(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// If the breaker is open, returns how much longer it will stay open.
+    fn open_for(&self) -> Option<Duration> {
+        let open_until = self.open_until.lock().unwrap();
+        open_until.and_then(|until| until.checked_duration_since(Instant::now()))
+    }
+
+    fn success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.threshold > 0 && failures >= self.threshold {
+            warn!("Opening the S3 circuit breaker after {} failures", failures);
+            *self.open_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+            self.failures.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> RetryError<E> {
+    /// The underlying S3 error, if S3 responded.
+    pub fn s3_error(&self) -> Option<&RusotoError<E>> {
+        match self {
+            RetryError::S3(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> From<RetryError<E>> for Error {
+    fn from(err: RetryError<E>) -> Error {
+        match err {
+            RetryError::Unavailable(wait) => HttpResponse::ServiceUnavailable()
+                .header(header::RETRY_AFTER, (wait.as_secs() + 1).to_string())
+                .finish()
+                .into(),
+            RetryError::TimedOut => HttpResponse::GatewayTimeout().finish().into(),
+            RetryError::S3(RusotoError::HttpDispatch(ref e)) if limits::is_saturated(e) => {
+                HttpResponse::ServiceUnavailable()
+                    .header(header::RETRY_AFTER, "1")
+                    .finish()
+                    .into()
+            }
+            RetryError::S3(e) => actix_web::error::ErrorInternalServerError(e),
+        }
+    }
+}
+
+/// Returns true if the request might succeed if tried again.
+fn is_transient<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        // Retrying would only add to the queue.
+        RusotoError::HttpDispatch(e) => !limits::is_saturated(e),
+        RusotoError::Unknown(resp) => resp.status.is_server_error() || resp.status.as_u16() == 429,
+        _ => false,
+    }
+}
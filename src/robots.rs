@@ -0,0 +1,71 @@
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::SiteConfig;
+
+const ROBOTS_PATH: &str = "robots.txt";
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/robots.txt").route(web::get().to(handle_robots)));
+}
+
+/// Keeps crawlers from indexing the classifications in
+/// NOINDEX_CLASSIFICATIONS.
+#[derive(Clone)]
+pub struct Robots {
+    noindex: Vec<String>,
+}
+
+impl Robots {
+    pub fn for_site(site: &SiteConfig) -> Robots {
+        Robots {
+            noindex: site.noindex_classifications().map(String::from).collect(),
+        }
+    }
+
+    /// Whether the media at `path`, relative to the site, is kept out of
+    /// search results, e.g. `/media/file/abc.zip` or
+    /// `/media/photo/1000x0/abc.jpg`.
+    pub fn is_noindex(&self, path: &str) -> bool {
+        let classification = path
+            .strip_prefix("/media/")
+            .and_then(|rest| rest.split('/').next());
+        match classification {
+            Some(classification) => self.noindex.iter().any(|c| c == classification),
+            None => false,
+        }
+    }
+
+    /// Tell crawlers not to index the response, unless RESPONSE_HEADERS
+    /// already said otherwise.
+    pub fn apply<B>(&self, res: &mut ServiceResponse<B>) {
+        let name = HeaderName::from_static("x-robots-tag");
+        if !res.headers().contains_key(&name) {
+            res.headers_mut()
+                .insert(name, HeaderValue::from_static("noindex"));
+        }
+    }
+}
+
+/// Ask crawlers to stay out of the Micropub API and ROBOTS_DISALLOW.
+async fn handle_robots(req: HttpRequest, site: web::Data<SiteConfig>) -> HttpResponse {
+    // The endpoint may be mounted under a prefix.
+    let base = req
+        .path()
+        .trim_end_matches(ROBOTS_PATH)
+        .trim_end_matches('/');
+    let mut body = String::from("User-agent: *\n");
+    body.push_str(&format!("Disallow: {}/micropub\n", base));
+    for path in site.robots_disallow() {
+        body.push_str(&format!(
+            "Disallow: {}/{}\n",
+            base,
+            path.trim_start_matches('/')
+        ));
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(body)
+}
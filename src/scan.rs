@@ -0,0 +1,235 @@
+use actix_web::error::BlockingError;
+use actix_web::web;
+use derive_more::Display;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use crate::SiteConfig;
+
+/// How long to wait on clamd before giving up.
+const CLAMD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Size of the chunks streamed to clamd.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How uploads are scanned.
+#[derive(Clone, Debug)]
+pub enum ScanBackend {
+    /// A clamd TCP socket, e.g. `clamd://localhost:3310`.
+    Clamd { addr: String },
+    /// A command which reads the upload on stdin, e.g.
+    /// `command:clamdscan --no-summary -`. It exits with 1 if the upload is
+    /// infected, naming the signature as `<name> FOUND` in its output.
+    Command { program: String, args: Vec<String> },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Clean,
+    /// The name of the signature which matched.
+    Infected(String),
+}
+
+#[derive(Display, Debug)]
+pub enum ScanError {
+    #[display(fmt = "Failed to reach the scanner: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "Scanner failed: {}", _0)]
+    Failed(String),
+}
+
+impl From<io::Error> for ScanError {
+    fn from(e: io::Error) -> Self {
+        ScanError::Io(e)
+    }
+}
+
+impl ScanBackend {
+    /// Parse the scanner configuration: `clamd://<host>:<port>` or
+    /// `command:<program> [args...]`.
+    pub fn parse(value: &str) -> Option<ScanBackend> {
+        if let Some(addr) = value.strip_prefix("clamd://") {
+            Some(ScanBackend::Clamd {
+                addr: addr.trim_end_matches('/').to_string(),
+            })
+        } else if let Some(command) = value.strip_prefix("command:") {
+            let mut words = command.split_whitespace().map(str::to_string);
+            Some(ScanBackend::Command {
+                program: words.next()?,
+                args: words.collect(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The scanner for uploads of the given classification, if they are
+    /// scanned. Only `file` uploads are, since nothing else is served as-is.
+    pub fn for_upload(site: &SiteConfig, classification: &str) -> Option<ScanBackend> {
+        if classification != "file" {
+            return None;
+        }
+        site.virus_scanner().and_then(ScanBackend::parse)
+    }
+
+    /// Scan the upload. This blocks, so it runs on the thread pool.
+    pub async fn scan(&self, data: Vec<u8>) -> Result<Verdict, ScanError> {
+        self.start().await?.write(data).await?.finish().await
+    }
+
+    /// Start a scan which is sent the upload a chunk at a time, so it needn't
+    /// all be in memory.
+    pub async fn start(&self) -> Result<Scan, ScanError> {
+        let backend = self.clone();
+        blocking(move || match backend {
+            ScanBackend::Clamd { addr } => start_clamd(&addr),
+            ScanBackend::Command { program, args } => start_command(program, &args),
+        })
+        .await
+    }
+}
+
+/// A scan which is being sent an upload.
+pub struct Scan {
+    scanner: Scanner,
+}
+
+enum Scanner {
+    Clamd(TcpStream),
+    Command {
+        program: String,
+        child: Child,
+        /// Unset once the command stops reading.
+        reading: bool,
+    },
+}
+
+impl Scan {
+    /// Send the next chunk of the upload.
+    pub async fn write(mut self, data: Vec<u8>) -> Result<Scan, ScanError> {
+        blocking(move || {
+            match &mut self.scanner {
+                Scanner::Clamd(stream) => {
+                    for chunk in data.chunks(CHUNK_SIZE) {
+                        stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+                        stream.write_all(chunk)?;
+                    }
+                }
+                // A scanner may exit before reading everything once it finds
+                // something.
+                Scanner::Command { child, reading, .. } => {
+                    if let (true, Some(stdin)) = (*reading, child.stdin.as_mut()) {
+                        match stdin.write_all(&data) {
+                            Ok(()) => (),
+                            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => *reading = false,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+            }
+            Ok(self)
+        })
+        .await
+    }
+
+    /// Wait for the verdict on everything sent.
+    pub async fn finish(self) -> Result<Verdict, ScanError> {
+        blocking(move || match self.scanner {
+            Scanner::Clamd(stream) => finish_clamd(stream),
+            Scanner::Command { program, child, .. } => finish_command(&program, child),
+        })
+        .await
+    }
+}
+
+/// Run `f` on the thread pool.
+async fn blocking<F, T>(f: F) -> Result<T, ScanError>
+where
+    F: FnOnce() -> Result<T, ScanError> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => ScanError::Failed("Scan was canceled".to_string()),
+    })
+}
+
+/// Start sending an upload with clamd's INSTREAM command.
+fn start_clamd(addr: &str) -> Result<Scan, ScanError> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| ScanError::Failed(format!("{} did not resolve", addr)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CLAMD_TIMEOUT)?;
+    stream.set_read_timeout(Some(CLAMD_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLAMD_TIMEOUT))?;
+
+    stream.write_all(b"zINSTREAM\0")?;
+    Ok(Scan {
+        scanner: Scanner::Clamd(stream),
+    })
+}
+
+fn finish_clamd(mut stream: TcpStream) -> Result<Verdict, ScanError> {
+    stream.write_all(&[0; 4])?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    // stream: OK, stream: <signature> FOUND, or <message> ERROR
+    let reply = reply.trim_end_matches('\0').trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.trim().to_string()))
+    } else {
+        Err(ScanError::Failed(reply.to_string()))
+    }
+}
+
+/// Start the command, which is piped the upload and exits with 1 if it's
+/// infected.
+fn start_command(program: String, args: &[String]) -> Result<Scan, ScanError> {
+    let child = Command::new(&program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    Ok(Scan {
+        scanner: Scanner::Command {
+            program,
+            child,
+            reading: true,
+        },
+    })
+}
+
+fn finish_command(program: &str, mut child: Child) -> Result<Verdict, ScanError> {
+    // Closing stdin tells the command the upload is over.
+    drop(child.stdin.take());
+    let output = child.wait_with_output()?;
+    match output.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let signature = stdout
+                .lines()
+                .filter_map(|line| line.trim().strip_suffix(" FOUND"))
+                .map(|found| found.rsplit(": ").next().unwrap_or(found).to_string())
+                .next()
+                .unwrap_or_else(|| "unknown".to_string());
+            Ok(Verdict::Infected(signature))
+        }
+        _ => Err(ScanError::Failed(format!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
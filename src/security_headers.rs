@@ -0,0 +1,153 @@
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+
+use crate::SiteConfig;
+
+/// Types a browser would render as a document, running any scripts inside.
+const ACTIVE_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+];
+
+/// Types a browser would run as a script if the site included them.
+const SCRIPT_TYPES: &[&str] = &[
+    "application/javascript",
+    "application/ecmascript",
+    "text/javascript",
+    "text/ecmascript",
+];
+
+/// Extensions of active content, which browsers may act on whatever the
+/// stored type says.
+const ACTIVE_EXTENSIONS: &[&str] = &["htm", "html", "xhtml", "js", "mjs", "svg", "svgz", "xml"];
+
+/// Headers that keep browsers from treating uploads as part of the site.
+///
+/// Headers a handler has already set are left alone.
+#[derive(Clone, Default)]
+pub struct SecurityHeaders {
+    content_security_policy: Option<HeaderValue>,
+    cross_origin_resource_policy: Option<HeaderValue>,
+    strict_transport_security: Option<HeaderValue>,
+    enabled: bool,
+}
+
+impl SecurityHeaders {
+    pub fn for_site(site: &SiteConfig) -> SecurityHeaders {
+        if !site.security_headers() {
+            return SecurityHeaders::default();
+        }
+
+        let value = |v: Option<&str>| v.and_then(|v| HeaderValue::from_str(v).ok());
+        SecurityHeaders {
+            content_security_policy: value(site.content_security_policy()),
+            cross_origin_resource_policy: value(site.cross_origin_resource_policy()),
+            strict_transport_security: site.hsts_max_age().and_then(|max_age| {
+                HeaderValue::from_str(&format!("max-age={}", max_age.as_secs())).ok()
+            }),
+            enabled: true,
+        }
+    }
+
+    /// Add the headers to the response.
+    ///
+    /// The content security policy is only sent with stored objects, see
+    /// [`serves_objects`]; the endpoint's own pages need their scripts.
+    pub fn apply<B>(&self, res: &mut ServiceResponse<B>, objects: bool) {
+        if !self.enabled {
+            return;
+        }
+
+        // HSTS is ignored over plain HTTP, so only send it when the client
+        // (or the proxy in front of us) used TLS.
+        let https = res.request().connection_info().scheme() == "https";
+        let active = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(is_active_type)
+            .unwrap_or(false);
+
+        let headers = res.headers_mut();
+        let mut set_default = |name: HeaderName, value: Option<&HeaderValue>| {
+            if let Some(value) = value {
+                if !headers.contains_key(&name) {
+                    headers.insert(name, value.clone());
+                }
+            }
+        };
+
+        set_default(
+            header::X_CONTENT_TYPE_OPTIONS,
+            Some(&HeaderValue::from_static("nosniff")),
+        );
+        set_default(
+            HeaderName::from_static("cross-origin-resource-policy"),
+            self.cross_origin_resource_policy.as_ref(),
+        );
+        if active && objects {
+            set_default(
+                header::CONTENT_SECURITY_POLICY,
+                self.content_security_policy.as_ref(),
+            );
+        }
+        if https {
+            set_default(
+                header::STRICT_TRANSPORT_SECURITY,
+                self.strict_transport_security.as_ref(),
+            );
+        }
+    }
+}
+
+/// Whether the path, relative to the site, serves stored objects rather
+/// than the endpoint's own pages.
+pub fn serves_objects(path: &str) -> bool {
+    (path.starts_with("/media/") && !path.starts_with("/media/page/"))
+        || path.starts_with("/share/")
+}
+
+/// Whether a file would run in the browser if served as stored, and isn't
+/// allowed to by ACTIVE_CONTENT_ALLOWLIST. Such files are sent as downloads.
+///
+/// SVGs count, since nothing sanitizes them on upload.
+pub fn must_download(site: &SiteConfig, content_type: Option<&str>, filename: &str) -> bool {
+    let mime = content_type.map(essence).unwrap_or_default();
+    let extension = filename
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.rsplit('.').next().filter(|e| *e != name))
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    let active_type =
+        ACTIVE_TYPES.contains(&mime.as_str()) || SCRIPT_TYPES.contains(&mime.as_str());
+    let active_extension = ACTIVE_EXTENSIONS.contains(&extension.as_str());
+    if !active_type && !active_extension {
+        return false;
+    }
+
+    !site
+        .active_content_allowlist()
+        .any(|allowed| match allowed.strip_prefix('.') {
+            Some(ext) => ext.eq_ignore_ascii_case(&extension),
+            None => allowed.eq_ignore_ascii_case(&mime),
+        })
+}
+
+fn is_active_type(content_type: &str) -> bool {
+    ACTIVE_TYPES.contains(&essence(content_type).as_str())
+}
+
+/// The type without its parameters, in lower case.
+fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
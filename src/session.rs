@@ -0,0 +1,808 @@
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use log::{error, info};
+use serde::Serialize;
+
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, PutObjectRequest, S3Client, UploadPartRequest, S3,
+};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::classify::{self, Restriction};
+use crate::events;
+use crate::expiry;
+use crate::geo;
+use crate::index::MediaIndex;
+use crate::keys;
+use crate::micropub::{
+    authorize, created, default_dimensions, inspect_stored, inspects, object_metadata, parse_trace,
+    random_id, record_upload, MicropubError, NewObject, Placement,
+};
+use crate::oauth;
+use crate::originals;
+use crate::policy::Permission;
+use crate::quarantine;
+use crate::reencode;
+use crate::staging;
+use crate::transfer::Transfer;
+use crate::undo::RecentUploads;
+use crate::upload_form;
+use crate::SiteConfig;
+
+/// How often abandoned sessions are cleaned up.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// S3 allows part numbers from 1 to 10,000.
+const MAX_PART_NUMBER: i64 = 10_000;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/session").route(web::post().to(open_session)));
+    cfg.service(
+        web::resource("/micropub/media/session/{id}/part/{number}")
+            .route(web::put().to(upload_part)),
+    );
+    cfg.service(
+        web::resource("/micropub/media/session/{id}/complete")
+            .route(web::post().to(complete_session)),
+    );
+}
+
+/// An upload that arrives in parts, backed by an S3 multipart upload.
+///
+/// The multipart upload isn't started until a second part arrives or the
+/// first is too big to hold. An upload sent as a single small part is stored
+/// with one PutObject instead, saving the requests a multipart upload takes.
+struct Session {
+    /// Set once the multipart upload has been started.
+    upload_id: Option<String>,
+    /// Starts the multipart upload. The PutObject taking its place copies it.
+    create_request: CreateMultipartUploadRequest,
+    placement: Placement,
+    /// Whether it's assembled in staging, to be published once inspected.
+    staged: bool,
+    /// Days until it's deleted, if it expires.
+    expires_in: Option<u32>,
+    content_type: mime::Mime,
+    filename: Option<String>,
+    extra_metadata: HashMap<String, String>,
+    author: String,
+    created: Instant,
+    /// ETag and size of each part sent to S3 so far.
+    parts: BTreeMap<i64, (String, u64)>,
+    /// A small first part, held back in case it's the whole upload.
+    held: Option<(i64, Vec<u8>)>,
+}
+
+impl Session {
+    /// The logical key the upload is assembled under.
+    fn upload_key(&self) -> String {
+        let key = self.placement.object_key();
+        if self.staged {
+            staging::staging_key(&key)
+        } else {
+            key
+        }
+    }
+}
+
+/// All open upload sessions.
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    ttl: Duration,
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    id: String,
+    expires_in: u64,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Sessions {
+        Sessions {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Periodically abort sessions which haven't been completed in time.
+    pub async fn expire_forever(sessions: web::Data<Sessions>, site: SiteConfig, s3_client: S3Client) {
+        loop {
+            delay_for(EXPIRY_INTERVAL).await;
+
+            let expired: Vec<Session> = {
+                let mut open = sessions.sessions.lock().unwrap();
+                let ids: Vec<String> = open
+                    .iter()
+                    .filter(|(_, s)| s.created.elapsed() > sessions.ttl)
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                ids.iter().filter_map(|id| open.remove(id)).collect()
+            };
+
+            for session in expired {
+                info!(
+                    "Aborting abandoned upload session for {}",
+                    session.placement.object_key()
+                );
+                abort(&site, &s3_client, &session).await;
+            }
+        }
+    }
+}
+
+async fn abort(site: &SiteConfig, s3_client: &S3Client, session: &Session) {
+    if let Some(upload_id) = &session.upload_id {
+        let (bucket, key) = site.locate(&session.upload_key());
+        abort_upload(s3_client, bucket, key, upload_id.clone()).await;
+    }
+}
+
+async fn abort_upload(s3_client: &S3Client, bucket: String, key: String, upload_id: String) {
+    let request = AbortMultipartUploadRequest {
+        bucket,
+        key: key.clone(),
+        upload_id,
+        ..Default::default()
+    };
+    if let Err(e) = s3_client.abort_multipart_upload(request).await {
+        error!("Failed to abort upload of {}: {}", key, e);
+    }
+}
+
+/// Start a session. The query may include `filename`, `content_type`,
+/// `expires-in`, and metadata fields.
+async fn open_session(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    sessions: web::Data<Sessions>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let filename = query.get("filename").cloned();
+    let content_type: mime::Mime = query
+        .get("content_type")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let extra_metadata: HashMap<String, String> = site
+        .metadata_fields()
+        .filter_map(|f| query.get(f).map(|v| (f.to_string(), v.clone())))
+        .collect();
+    let expires_in = match query.get(expiry::EXPIRY_TAG) {
+        Some(value) => match expiry::parse(&site, value) {
+            Ok(days) => Some(days),
+            Err(e) => return HttpResponse::BadRequest().json(e),
+        },
+        None => None,
+    };
+
+    // The size isn't known until the parts arrive.
+    let classification = classify::classify(&site, &content_type, filename.as_deref());
+    if let Err(e) = classify::check_limits(&site, &classification.name, &content_type, None) {
+        return e.response();
+    }
+
+    // The content hasn't been sent yet, so {checksum} falls back to the id.
+    let placement = match Placement::unused(
+        &site,
+        &s3_client,
+        &content_type,
+        filename.as_deref(),
+        access_token.me(),
+        None,
+        keys::date_for(&site, None),
+    )
+    .await
+    {
+        Ok(placement) => placement,
+        Err(e) => return e.response(),
+    };
+    let object_key = placement.object_key();
+    let mut metadata = match object_metadata(
+        &site,
+        &s3_client,
+        &object_key,
+        &extra_metadata,
+        &access_token,
+        filename.as_deref(),
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(resp) => return resp,
+    };
+    if let Some(days) = expires_in {
+        metadata.insert("expires-at".to_string(), expiry::expires_at(days));
+    }
+
+    // Uploads which are inspected, and traces which must parse, are assembled
+    // in staging, where they stay private until they pass.
+    let staged = inspects(&site, &placement) || placement.classification == "map";
+    let upload_key = if staged {
+        staging::staging_key(&object_key)
+    } else {
+        object_key
+    };
+    let mut defaults = site.put_object_request_for(&upload_key);
+    if let Some(days) = expires_in {
+        defaults.tagging = expiry::tagging(defaults.tagging.take(), days);
+    }
+    let create_request = CreateMultipartUploadRequest {
+        bucket: defaults.bucket,
+        key: defaults.key,
+        content_type: Some(content_type.to_string()),
+        metadata: Some(metadata),
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        acl: defaults.acl.filter(|_| !staged),
+        tagging: defaults.tagging,
+        ..Default::default()
+    };
+
+    let id = random_id();
+    sessions.sessions.lock().unwrap().insert(
+        id.clone(),
+        Session {
+            upload_id: None,
+            create_request,
+            placement,
+            staged,
+            expires_in,
+            content_type,
+            filename,
+            extra_metadata,
+            author: access_token.me().to_string(),
+            created: Instant::now(),
+            parts: BTreeMap::new(),
+            held: None,
+        },
+    );
+
+    HttpResponse::Created()
+        .header(
+            header::LOCATION,
+            format!("/micropub/media/session/{}", id),
+        )
+        .json(SessionResponse {
+            id,
+            expires_in: sessions.ttl.as_secs(),
+        })
+}
+
+/// Receive one part. Every part but the last must be at least 5 MB.
+async fn upload_part(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    mut payload: web::Payload,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    sessions: web::Data<Sessions>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let (id, number) = path.into_inner();
+    if number < 1 || number > MAX_PART_NUMBER {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "Part numbers must be between 1 and 10000",
+        ));
+    }
+
+    let key = match session_for(&sessions, &id, &access_token) {
+        Ok(key) => key,
+        Err(resp) => return resp,
+    };
+
+    let mut body = Vec::new();
+    let mut transfer = Transfer::new(&site);
+    loop {
+        match transfer.next_chunk(&mut payload).await {
+            Ok(Some(chunk)) if body.len() + chunk.len() <= site.session_part_max_bytes() => {
+                body.extend_from_slice(&chunk)
+            }
+            Ok(Some(_)) => {
+                return HttpResponse::PayloadTooLarge().json(MicropubError::new("too_large"))
+            }
+            Ok(None) => break,
+            Err(e) => {
+                // A client this slow won't finish, so don't leave the parts
+                // it already sent lying around.
+                if e.is_timeout() {
+                    let session = sessions.sessions.lock().unwrap().remove(&id);
+                    if let Some(session) = session {
+                        abort(&site, &s3_client, &session).await;
+                    }
+                }
+                return e.response();
+            }
+        }
+    }
+    let size = body.len() as u64;
+
+    // A part sent again replaces the earlier one.
+    let limited = {
+        let open = sessions.sessions.lock().unwrap();
+        open.get(&id).and_then(|session| {
+            let classification = &session.placement.classification;
+            let max_bytes = classify::max_bytes(&site, classification)?;
+            let sent: u64 = session
+                .parts
+                .iter()
+                .filter(|(n, _)| **n != number)
+                .map(|(_, (_, size))| size)
+                .sum();
+            let held: u64 = session
+                .held
+                .iter()
+                .filter(|(n, _)| *n != number)
+                .map(|(_, body)| body.len() as u64)
+                .sum();
+            let total = sent + held;
+            Some((classification.clone(), max_bytes)).filter(|_| total + size > max_bytes)
+        })
+    };
+    if let Some((classification, max_bytes)) = limited {
+        return Restriction::TooLarge(classification, max_bytes).response();
+    }
+
+    // Hold on to a small first part until it's clear whether more follow.
+    let body = {
+        let mut open = sessions.sessions.lock().unwrap();
+        match open.get_mut(&id) {
+            Some(session)
+                if session.upload_id.is_none()
+                    && session.held.as_ref().map_or(true, |(n, _)| *n == number)
+                    && body.len() < site.upload_part_size() =>
+            {
+                session.held = Some((number, body));
+                return HttpResponse::NoContent().finish();
+            }
+            Some(_) => body,
+            None => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+        }
+    };
+
+    let upload_id = match multipart_upload(&s3_client, &sessions, &id).await {
+        Ok(upload_id) => upload_id,
+        Err(resp) => return resp,
+    };
+    let held = match sessions.sessions.lock().unwrap().get_mut(&id) {
+        Some(session) => session.held.take(),
+        None => None,
+    };
+    let mut parts = vec![(number, body)];
+    if let Some((held_number, held_body)) = held {
+        // A part sent again replaces the one held.
+        if held_number != number {
+            parts.push((held_number, held_body));
+        }
+    }
+
+    for (number, body) in parts {
+        let size = body.len() as u64;
+        let e_tag = match send_part(&site, &s3_client, &key, &upload_id, number, body).await {
+            Ok(e_tag) => e_tag,
+            Err(e) => return HttpResponse::InternalServerError().body(e),
+        };
+        if let Some(session) = sessions.sessions.lock().unwrap().get_mut(&id) {
+            session.parts.insert(number, (e_tag, size));
+        }
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+/// The session's multipart upload, started now if it hasn't been yet.
+async fn multipart_upload(
+    s3_client: &S3Client,
+    sessions: &Sessions,
+    id: &str,
+) -> Result<String, HttpResponse> {
+    let request = match sessions.sessions.lock().unwrap().get(id) {
+        Some(session) => match &session.upload_id {
+            Some(upload_id) => return Ok(upload_id.clone()),
+            None => session.create_request.clone(),
+        },
+        None => return Err(HttpResponse::NotFound().json(MicropubError::new("not_found"))),
+    };
+
+    let (bucket, key) = (request.bucket.clone(), request.key.clone());
+    let upload_id = match s3_client.create_multipart_upload(request).await {
+        Ok(resp) => match resp.upload_id {
+            Some(upload_id) => upload_id,
+            None => return Err(HttpResponse::InternalServerError().body("Missing upload ID")),
+        },
+        Err(e) => return Err(HttpResponse::InternalServerError().body(format!("{}", e))),
+    };
+
+    // Another part may have started one in the meantime.
+    let started = match sessions.sessions.lock().unwrap().get_mut(id) {
+        Some(session) => Some(
+            session
+                .upload_id
+                .get_or_insert_with(|| upload_id.clone())
+                .clone(),
+        ),
+        None => None,
+    };
+    if started.as_ref() != Some(&upload_id) {
+        abort_upload(s3_client, bucket, key, upload_id).await;
+    }
+    started.ok_or_else(|| HttpResponse::NotFound().json(MicropubError::new("not_found")))
+}
+
+/// Send one part of the multipart upload, returning its ETag.
+async fn send_part(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    upload_id: &str,
+    number: i64,
+    body: Vec<u8>,
+) -> Result<String, String> {
+    let (bucket, key) = site.locate(key);
+    let request = UploadPartRequest {
+        bucket,
+        key,
+        upload_id: upload_id.to_string(),
+        part_number: number,
+        content_length: Some(body.len() as i64),
+        body: Some(body.into()),
+        ..Default::default()
+    };
+    s3_client
+        .upload_part(request)
+        .await
+        .map(|resp| resp.e_tag.unwrap_or_default())
+        .map_err(|e| e.to_string())
+}
+
+/// Assemble the parts into the final object.
+async fn complete_session(
+    req: HttpRequest,
+    path: web::Path<String>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    sessions: web::Data<Sessions>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    recent_uploads: web::Data<RecentUploads>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let id = path.into_inner();
+    if let Err(resp) = session_for(&sessions, &id, &access_token) {
+        return resp;
+    }
+    let mut session = match sessions.sessions.lock().unwrap().remove(&id) {
+        Some(session) => session,
+        None => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+    };
+
+    if session.parts.is_empty() && session.held.is_none() {
+        abort(&site, &s3_client, &session).await;
+        return HttpResponse::BadRequest()
+            .json(MicropubError::with_description("invalid_request", "No parts were uploaded"));
+    }
+
+    let held = session.held.as_ref().map_or(0, |(_, body)| body.len() as u64);
+    let received = session.parts.values().map(|(_, size)| size).sum::<u64>() + held;
+    let completed = match (&session.upload_id, session.held.take()) {
+        // The only part was small enough to store in one go.
+        (None, Some((_, body))) => {
+            let request = put_request(session.create_request.clone(), body);
+            s3_client
+                .put_object(request)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        _ => {
+            let parts = session
+                .parts
+                .iter()
+                .map(|(number, (e_tag, _))| CompletedPart {
+                    e_tag: Some(e_tag.clone()),
+                    part_number: Some(*number),
+                })
+                .collect();
+            let (bucket, key) = site.locate(&session.upload_key());
+            let request = CompleteMultipartUploadRequest {
+                bucket,
+                key,
+                upload_id: session.upload_id.clone().unwrap_or_default(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                ..Default::default()
+            };
+            s3_client
+                .complete_multipart_upload(request)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+    };
+    if let Err(e) = completed {
+        abort(&site, &s3_client, &session).await;
+        return HttpResponse::InternalServerError().body(e);
+    }
+
+    // Parts can't be inspected on their own, so the assembled upload is,
+    // while it's still private.
+    let key = session.placement.object_key();
+    let upload_key = session.upload_key();
+    let mut data = None;
+    let mut trace = None;
+    let mut shrunk_size = None;
+    if session.staged {
+        let metadata = session.create_request.metadata.clone().unwrap_or_default();
+        let inspected = inspect_stored(
+            &site,
+            &s3_client,
+            &http_client,
+            &session.placement,
+            &session.content_type,
+            &upload_key,
+            received,
+        )
+        .await;
+        let inspected = match inspected {
+            Ok(inspected) => inspected,
+            Err(resp) => {
+                staging::discard(&site, &s3_client, &key).await;
+                return resp;
+            }
+        };
+        if let Some(reason) = inspected.rejection {
+            let response = if site.quarantine() {
+                let held = quarantine::HeldCopy {
+                    from: &upload_key,
+                    size: received,
+                    key: &key,
+                    author: access_token.me(),
+                    reason: &reason,
+                    content_type: session.content_type.to_string(),
+                    metadata,
+                };
+                quarantine::hold_copy(&site, &s3_client, held).await
+            } else {
+                quarantine::rejected(&key, access_token.me(), &reason)
+            };
+            staging::discard(&site, &s3_client, &key).await;
+            return response;
+        }
+        data = inspected.data;
+
+        // Traces are checked before they're published, and simplified for
+        // previews.
+        if session.placement.classification == "map" {
+            if data.is_none() {
+                data = match staging::read_small(&site, &s3_client, &upload_key, received).await {
+                    Ok(Some(data)) => Some(data),
+                    Ok(None) => {
+                        staging::discard(&site, &s3_client, &key).await;
+                        return HttpResponse::PayloadTooLarge()
+                            .json(MicropubError::new("too_large"));
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        staging::discard(&site, &s3_client, &key).await;
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                };
+            }
+            let filename = session.filename.as_deref();
+            let body = data.as_deref().unwrap_or_default();
+            trace = match parse_trace("map", &session.content_type, filename, body) {
+                Ok(trace) => trace,
+                Err(resp) => {
+                    staging::discard(&site, &s3_client, &key).await;
+                    return resp;
+                }
+            };
+        }
+
+        if session.placement.classification == "photo" {
+            if let Some(original) = data.take() {
+                data = Some(match reencode::shrink(&site, &original).await {
+                    Some(shrunk) => {
+                        let stored = store_shrunk(
+                            &site,
+                            &s3_client,
+                            &key,
+                            &session.content_type,
+                            &original,
+                            &shrunk,
+                            metadata.clone(),
+                            session.expires_in,
+                        );
+                        match stored.await {
+                            Ok(()) => {
+                                shrunk_size = Some(shrunk.len() as u64);
+                                shrunk
+                            }
+                            Err(e) => {
+                                error!("{}", e);
+                                original
+                            }
+                        }
+                    }
+                    None => original,
+                });
+            }
+        }
+    }
+    let size = shrunk_size.unwrap_or(received);
+
+    let checksum = match &data {
+        Some(data) => events::checksum(data),
+        None => match staging::checksum(&site, &s3_client, &upload_key, size).await {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                error!("{}", e);
+                if session.staged {
+                    staging::discard(&site, &s3_client, &key).await;
+                }
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+    };
+
+    if session.staged {
+        // A re-encoded photo was stored in its place.
+        if shrunk_size.is_some() {
+            staging::discard(&site, &s3_client, &key).await;
+        } else {
+            let published = staging::publish(
+                &site,
+                &s3_client,
+                &key,
+                received,
+                session.content_type.to_string(),
+                session.create_request.metadata.clone().unwrap_or_default(),
+                session.expires_in,
+            );
+            if let Err(e) = published.await {
+                error!("{}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    if let Some(trace) = trace {
+        let stored = geo::store_simplified(&site, &s3_client, &session.placement.key, &trace);
+        if let Err(e) = stored.await {
+            error!("{}", e);
+        }
+    }
+
+    let data = match data {
+        Some(data) => Some(data),
+        None if upload_form::derives(&site, &session.placement) => {
+            match staging::read_small(&site, &s3_client, &key, size).await {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let dimensions = data
+        .as_ref()
+        .and_then(|data| default_dimensions(&site, &session.placement, data));
+    if let Some(data) = data {
+        upload_form::spawn_derivatives(&site, &s3_client, &session.placement, data);
+    }
+
+    let url = session.placement.url(&site);
+    let object = NewObject {
+        placement: session.placement,
+        url: url.clone(),
+        content_type: session.content_type,
+        filename: session.filename,
+        size,
+        checksum: Some(checksum),
+        extra_metadata: session.extra_metadata,
+    };
+    record_upload(&site, &publisher, index.as_deref(), &access_token, object).await;
+    recent_uploads.record(access_token.me(), &url);
+
+    created(&url, dimensions)
+}
+
+/// The PutObjectRequest storing `body` in place of a multipart upload.
+fn put_request(create_request: CreateMultipartUploadRequest, body: Vec<u8>) -> PutObjectRequest {
+    PutObjectRequest {
+        bucket: create_request.bucket,
+        key: create_request.key,
+        body: Some(body.into()),
+        content_type: create_request.content_type,
+        metadata: create_request.metadata,
+        server_side_encryption: create_request.server_side_encryption,
+        ssekms_key_id: create_request.ssekms_key_id,
+        storage_class: create_request.storage_class,
+        acl: create_request.acl,
+        tagging: create_request.tagging,
+        ..Default::default()
+    }
+}
+
+/// Store a re-encoded photo in place of the completed upload, first keeping
+/// the original if KEEP_ORIGINALS is set. Without the original, nothing is
+/// stored. It's tagged to expire if `expires_in` is set.
+async fn store_shrunk(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    content_type: &mime::Mime,
+    original: &[u8],
+    shrunk: &[u8],
+    metadata: HashMap<String, String>,
+    expires_in: Option<u32>,
+) -> Result<(), String> {
+    if site.keep_originals() {
+        originals::keep(
+            site,
+            s3_client,
+            key,
+            original.to_vec(),
+            content_type.to_string(),
+            metadata.clone(),
+        )
+        .await?;
+    }
+    let mut put_request = PutObjectRequest {
+        body: Some(shrunk.to_vec().into()),
+        content_type: Some(content_type.to_string()),
+        metadata: Some(metadata),
+        ..site.put_object_request_for(key)
+    };
+    if let Some(days) = expires_in {
+        put_request.tagging = expiry::tagging(put_request.tagging.take(), days);
+    }
+    s3_client
+        .put_object(put_request)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to store the re-encoded {}: {}", key, e))
+}
+
+/// Find the key a session owned by the token's user is uploaded to.
+fn session_for(
+    sessions: &Sessions,
+    id: &str,
+    access_token: &oauth::AccessToken,
+) -> Result<String, HttpResponse> {
+    let open = sessions.sessions.lock().unwrap();
+    match open.get(id) {
+        Some(session) if session.author == access_token.me() => Ok(session.upload_key()),
+        Some(_) => Err(HttpResponse::Forbidden().json(MicropubError::new("forbidden"))),
+        None => Err(HttpResponse::NotFound().json(MicropubError::new("not_found"))),
+    }
+}
@@ -0,0 +1,265 @@
+use actix_rt::time::delay_for;
+use actix_web::http::{header, HeaderValue};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{error, info};
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use rusoto_s3::{ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::alias;
+use crate::media;
+use crate::micropub::{authorize, key_for_url, MicropubError};
+use crate::oauth;
+use crate::policy::Permission;
+use crate::replica::ReadBuckets;
+use crate::trash;
+use crate::SiteConfig;
+
+/// Key prefix for the markers which map share tokens to objects.
+pub const SHARE_PREFIX: &str = "shares";
+
+/// How long a share link lasts unless asked otherwise.
+const DEFAULT_SHARE_AGE: u64 = 24 * 60 * 60;
+
+/// How often expired share markers are removed.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Length of a share token.
+const TOKEN_LENGTH: usize = 22;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/share").route(web::post().to(handle_share)));
+    cfg.service(web::resource("/share/{token}").route(web::get().to(serve_share)));
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    /// The URL of the object to share.
+    url: String,
+    /// Seconds until the link stops working, at most SHARE_MAX_AGE.
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ShareResponse {
+    url: String,
+    expires_at: String,
+}
+
+fn share_key(token: &str) -> String {
+    format!("{}/{}", SHARE_PREFIX, token)
+}
+
+/// Create a link to an object which stops working after a while.
+///
+/// The token is kept in a small marker object naming the object's key and
+/// when the link expires. Expired markers are removed periodically.
+async fn handle_share(
+    req: HttpRequest,
+    share: web::Json<ShareRequest>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let key = match key_for_url(&site, &share.url) {
+        Some(key) if !media::is_hidden(&site, key.split('/').next().unwrap_or_default()) => key,
+        _ => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "Unknown URL",
+            ))
+        }
+    };
+    let expires_in = share
+        .expires_in
+        .unwrap_or(DEFAULT_SHARE_AGE)
+        .min(site.share_max_age());
+    if expires_in == 0 {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "expires_in must be positive",
+        ));
+    }
+
+    let head = match alias::find(&site, &s3_client, &key).await {
+        Ok(Some(head)) => head,
+        Ok(None) => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+        Err(e) => return HttpResponse::InternalServerError().body(e),
+    };
+    let stored = head.metadata.unwrap_or_default();
+    if let Some(author) = stored.get("author") {
+        if author != access_token.me() {
+            return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+        }
+    }
+    if head.website_redirect_location.is_some() {
+        return HttpResponse::BadRequest().json(MicropubError::with_description(
+            "invalid_request",
+            "The URL has been renamed",
+        ));
+    }
+
+    let token: String = thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .collect();
+    let expires_at = (Utc::now() + ChronoDuration::seconds(expires_in as i64)).to_rfc3339();
+    let mut marker_metadata = HashMap::new();
+    marker_metadata.insert("key".to_string(), key);
+    marker_metadata.insert("expires-at".to_string(), expires_at.clone());
+    marker_metadata.insert("author".to_string(), access_token.me().to_string());
+    let marker = PutObjectRequest {
+        body: Some(Vec::new().into()),
+        metadata: Some(marker_metadata),
+        // Markers are never public.
+        acl: None,
+        ..site.put_object_request_for(&share_key(&token))
+    };
+    if let Err(e) = s3_client.put_object(marker).await {
+        return HttpResponse::InternalServerError().body(format!("{}", e));
+    }
+
+    // The endpoint may be mounted under a prefix.
+    let base = req.path().trim_end_matches("/micropub/media/share");
+    let connection_info = req.connection_info();
+    let url = format!(
+        "{}://{}{}/share/{}",
+        connection_info.scheme(),
+        connection_info.host(),
+        base,
+        token
+    );
+    HttpResponse::Created()
+        .header(header::LOCATION, url.as_str())
+        .json(ShareResponse { url, expires_at })
+}
+
+/// Serve the object behind a share link, until the link expires.
+async fn serve_share(
+    req: HttpRequest,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+) -> Result<HttpResponse, Error> {
+    let token = req.match_info().get("token").unwrap_or_default();
+    if token.len() != TOKEN_LENGTH || !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let marker = match alias::find(&site, &s3_client, &share_key(token)).await {
+        Ok(Some(marker)) => marker.metadata.unwrap_or_default(),
+        Ok(None) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => return Ok(HttpResponse::InternalServerError().body(e)),
+    };
+    let key = match marker.get("key") {
+        Some(key) => key.clone(),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let remaining = match remaining(&marker) {
+        Some(remaining) => remaining,
+        None => {
+            let (bucket, marker_key) = site.locate(&share_key(token));
+            if let Err(e) = trash::delete(&s3_client, &bucket, &marker_key).await {
+                error!("{}", e);
+            }
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let mut resp = media::serve_object(&req, &site, &buckets, &key).await?;
+    // Neither caches nor search engines should outlive the link.
+    let headers = resp.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&format!("private, max-age={}", remaining)) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+    headers.insert(
+        header::HeaderName::from_static("x-robots-tag"),
+        HeaderValue::from_static("noindex"),
+    );
+    Ok(resp)
+}
+
+/// Seconds until the link expires, or None if it already has.
+fn remaining(marker: &HashMap<String, String>) -> Option<i64> {
+    let expires_at = DateTime::parse_from_rfc3339(marker.get("expires-at")?).ok()?;
+    let remaining = (expires_at.with_timezone(&Utc) - Utc::now()).num_seconds();
+    if remaining > 0 {
+        Some(remaining)
+    } else {
+        None
+    }
+}
+
+/// Remove the markers of expired share links.
+pub async fn remove_expired(site: &SiteConfig, s3_client: &S3Client) -> Result<usize, String> {
+    let (bucket, prefix) = site.locate(&format!("{}/", SHARE_PREFIX));
+    let mut continuation_token = None;
+    let mut removed = 0;
+
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.clone(),
+            prefix: Some(prefix.clone()),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = s3_client
+            .list_objects_v2(request)
+            .await
+            .map_err(|e| format!("Failed to list share links: {}", e))?;
+
+        for object in response.contents.unwrap_or_default() {
+            let marker_key = match object.key {
+                Some(key) => key,
+                None => continue,
+            };
+            let token = &marker_key[prefix.len()..];
+            let marker = match alias::find(site, s3_client, &share_key(token)).await {
+                Ok(Some(marker)) => marker.metadata.unwrap_or_default(),
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed to read {}: {}", marker_key, e);
+                    continue;
+                }
+            };
+            if remaining(&marker).is_some() {
+                continue;
+            }
+            match trash::delete(s3_client, &bucket, &marker_key).await {
+                Ok(()) => removed += 1,
+                Err(e) => error!("{}", e),
+            }
+        }
+
+        if response.is_truncated != Some(true) {
+            return Ok(removed);
+        }
+        continuation_token = response.next_continuation_token;
+    }
+}
+
+/// Periodically remove the markers of expired share links.
+pub async fn expire_forever(site: SiteConfig, s3_client: S3Client) {
+    loop {
+        match remove_expired(&site, &s3_client).await {
+            Ok(removed) => info!("Removed {} expired share links", removed),
+            Err(e) => error!("{}", e),
+        }
+        delay_for(EXPIRE_INTERVAL).await;
+    }
+}
@@ -0,0 +1,218 @@
+use actix_rt::time::delay_for;
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{error, info};
+use sha2::{Digest, Sha256};
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    CopyObjectRequest, GetObjectRequest, ListObjectsV2Error, ListObjectsV2Request, S3Client, S3,
+};
+use tokio::io::AsyncReadExt;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::events::hex;
+use crate::expiry;
+use crate::multipart;
+use crate::trash;
+use crate::SiteConfig;
+
+/// Key prefix for uploads which are completed in the bucket, where they're
+/// kept private until they've been inspected.
+pub const STAGING_PREFIX: &str = "staging";
+
+/// How much of a staged upload is read at once.
+const RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// How often abandoned uploads are removed from staging.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The key an upload to `key` is staged under.
+pub fn staging_key(key: &str) -> String {
+    format!("{}/{}", STAGING_PREFIX, key)
+}
+
+/// Read `len` bytes of the object with the logical key `key`, from `start`.
+pub(crate) async fn read_range(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    start: u64,
+    len: u64,
+) -> Result<Vec<u8>, String> {
+    let (bucket, object_key) = site.locate(key);
+    let request = GetObjectRequest {
+        bucket,
+        key: object_key,
+        range: Some(format!("bytes={}-{}", start, start + len - 1)),
+        ..Default::default()
+    };
+    let object = s3_client
+        .get_object(request)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", key, e))?;
+    let mut data = Vec::with_capacity(len as usize);
+    if let Some(body) = object.body {
+        body.into_async_read()
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", key, e))?;
+    }
+    Ok(data)
+}
+
+/// The ranges a `size` byte object is read in, as (start, length).
+pub(crate) fn ranges(size: u64) -> impl Iterator<Item = (u64, u64)> {
+    (0..size)
+        .step_by(RANGE_SIZE as usize)
+        .map(move |start| (start, RANGE_SIZE.min(size - start)))
+}
+
+/// Read each range of a `size` byte object in turn, handing it to `f`.
+async fn for_each_range<F>(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    size: u64,
+    mut f: F,
+) -> Result<(), String>
+where
+    F: FnMut(Vec<u8>),
+{
+    for (start, len) in ranges(size) {
+        f(read_range(site, s3_client, key, start, len).await?);
+    }
+    Ok(())
+}
+
+/// Read a whole `size` byte object, if it's no bigger than INSPECT_MAX_BYTES.
+pub(crate) async fn read_small(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    size: u64,
+) -> Result<Option<Vec<u8>>, String> {
+    if size > site.inspect_max_bytes() {
+        return Ok(None);
+    }
+    let mut data = Vec::with_capacity(size as usize);
+    for_each_range(site, s3_client, key, size, |range| data.extend(range)).await?;
+    Ok(Some(data))
+}
+
+/// The SHA-256 checksum of a `size` byte object, read a range at a time.
+pub(crate) async fn checksum(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    size: u64,
+) -> Result<String, String> {
+    let mut hasher = Sha256::new();
+    for_each_range(site, s3_client, key, size, |range| hasher.update(&range)).await?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Publish a staged upload at `key`, with its type and metadata, and remove
+/// it from staging. It's tagged to expire if `expires_in` is set.
+pub(crate) async fn publish(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    size: u64,
+    content_type: String,
+    metadata: HashMap<String, String>,
+    expires_in: Option<u32>,
+) -> Result<(), String> {
+    let staged = staging_key(key);
+    let (staged_bucket, staged_key) = site.locate(&staged);
+    let mut defaults = site.put_object_request_for(key);
+    if let Some(days) = expires_in {
+        defaults.tagging = expiry::tagging(defaults.tagging.take(), days);
+    }
+    let request = CopyObjectRequest {
+        bucket: defaults.bucket,
+        copy_source: trash::copy_source(&staged_bucket, &staged_key),
+        key: defaults.key,
+        content_type: Some(content_type),
+        metadata: Some(metadata),
+        metadata_directive: Some("REPLACE".to_string()),
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        acl: defaults.acl,
+        tagging: defaults.tagging,
+        tagging_directive: Some("REPLACE".to_string()),
+        ..Default::default()
+    };
+    multipart::copy_object(site, s3_client, request, size)
+        .await
+        .map_err(|e| format!("Failed to publish {}: {}", key, e))?;
+    if let Err(e) = trash::delete(s3_client, &staged_bucket, &staged_key).await {
+        error!("{}", e);
+    }
+    Ok(())
+}
+
+/// Remove a staged upload which won't be published.
+pub(crate) async fn discard(site: &SiteConfig, s3_client: &S3Client, key: &str) {
+    let (bucket, staged_key) = site.locate(&staging_key(key));
+    if let Err(e) = trash::delete(s3_client, &bucket, &staged_key).await {
+        error!("{}", e);
+    }
+}
+
+/// Delete staged uploads older than `max_age`, which were never completed.
+pub async fn purge_expired(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    max_age: Duration,
+) -> Result<usize, RusotoError<ListObjectsV2Error>> {
+    let cutoff = Utc::now()
+        - ChronoDuration::from_std(max_age).unwrap_or_else(|_| ChronoDuration::max_value());
+    let (bucket, prefix) = site.locate(&format!("{}/", STAGING_PREFIX));
+    let mut continuation_token = None;
+    let mut purged = 0;
+
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.clone(),
+            prefix: Some(prefix.clone()),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = s3_client.list_objects_v2(request).await?;
+
+        for object in response.contents.unwrap_or_default() {
+            let expired = object
+                .last_modified
+                .as_deref()
+                .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+                .map_or(false, |t| t < cutoff);
+            match object.key {
+                Some(key) if expired => match trash::delete(s3_client, &bucket, &key).await {
+                    Ok(()) => purged += 1,
+                    Err(e) => error!("{}", e),
+                },
+                _ => (),
+            }
+        }
+
+        if response.is_truncated != Some(true) {
+            return Ok(purged);
+        }
+        continuation_token = response.next_continuation_token;
+    }
+}
+
+/// Periodically delete staged uploads older than `max_age`.
+pub async fn purge_forever(site: SiteConfig, s3_client: S3Client, max_age: Duration) {
+    loop {
+        match purge_expired(&site, &s3_client, max_age).await {
+            Ok(purged) => info!("Deleted {} abandoned uploads from staging", purged),
+            Err(e) => error!("Failed to list staged uploads: {}", e),
+        }
+        delay_for(PURGE_INTERVAL).await;
+    }
+}
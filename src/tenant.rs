@@ -0,0 +1,73 @@
+use serde::Deserialize;
+
+use std::fs;
+use std::io;
+
+use crate::oauth::VerificationService;
+use crate::SiteConfig;
+
+/// A site served by this process alongside the default one.
+///
+/// Requests are matched by Host header, path prefix, or both. Anything not
+/// set falls back to the default configuration.
+#[derive(Deserialize, Clone)]
+pub struct Tenant {
+    pub host: Option<String>,
+    #[serde(default)]
+    pub path: String,
+    s3_bucket: Option<String>,
+    media_url: Option<String>,
+    token_endpoint: Option<String>,
+    /// Replaces the default fallbacks. They are dropped when only
+    /// token_endpoint is set.
+    token_endpoint_fallbacks: Option<Vec<String>>,
+    /// Profile URLs allowed to authenticate. Empty uses the default site's.
+    #[serde(default)]
+    allowed_users: Vec<String>,
+    /// Scopes honored on this tenant's tokens. Unset honors all of them.
+    scopes: Option<Vec<String>>,
+}
+
+/// Read the tenants from a JSON file containing a list of tenants.
+pub fn load(path: &str) -> io::Result<Vec<Tenant>> {
+    let tenants: Vec<Tenant> = serde_json::from_slice(&fs::read(path)?)?;
+    for tenant in &tenants {
+        if tenant.host.is_none() && tenant.path.trim_matches('/').is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Each tenant needs a host or a path",
+            ));
+        }
+    }
+    Ok(tenants)
+}
+
+impl Tenant {
+    /// This tenant's configuration, based on the default one.
+    pub fn site_config(&self, base: &SiteConfig) -> SiteConfig {
+        let mut site = base.clone();
+        if let Some(s3_bucket) = &self.s3_bucket {
+            site.s3_bucket = s3_bucket.clone();
+        }
+        if let Some(media_url) = &self.media_url {
+            site.media_url = media_url.clone();
+        }
+        if let Some(token_endpoint) = &self.token_endpoint {
+            site.token_endpoint = token_endpoint.clone();
+            site.token_endpoint_fallbacks = String::new();
+        }
+        if let Some(fallbacks) = &self.token_endpoint_fallbacks {
+            site.token_endpoint_fallbacks = fallbacks.join(",");
+        }
+        if !self.allowed_users.is_empty() {
+            site.allowed_me = self.allowed_users.join(",");
+        }
+        site
+    }
+
+    /// The verification service for this tenant's tokens.
+    pub fn verification_service(&self, base: &SiteConfig) -> VerificationService {
+        VerificationService::for_site(&self.site_config(base))
+            .allowed_scopes(self.scopes.clone())
+    }
+}
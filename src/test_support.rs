@@ -0,0 +1,444 @@
+//! Stand-ins for S3 and the token endpoint, for testing without AWS.
+//!
+//! Enabled with the `test-support` feature. Both servers listen on a random
+//! local port and must be started from within an actix system.
+
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::{HttpClient, Region};
+use rusoto_s3::S3Client;
+
+use percent_encoding::percent_decode_str;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::events::checksum;
+use crate::{MediaEndpoint, SiteConfig};
+
+/// The bucket used by [`endpoint`].
+pub const BUCKET: &str = "media";
+
+/// The media URL used by [`endpoint`].
+pub const MEDIA_URL: &str = "http://localhost/media";
+
+/// Objects are all given the same modification time.
+const LAST_MODIFIED: &str = "Wed, 01 Jan 2020 00:00:00 GMT";
+
+/// An object held by [`MockS3`].
+#[derive(Clone, Debug)]
+pub struct StoredObject {
+    pub data: Vec<u8>,
+    pub content_type: Option<String>,
+    pub metadata: HashMap<String, String>,
+    pub etag: String,
+    /// The object's website redirect location.
+    pub redirect: Option<String>,
+}
+
+type Store = Arc<Mutex<HashMap<String, StoredObject>>>;
+
+/// An in-memory object store speaking enough of the S3 API for the endpoint.
+///
+/// Supports GET (with Range and If-None-Match), HEAD, PUT (with website
+/// redirects), copies, DELETE of single objects or, with `?delete`, several
+/// at once, and ListObjectsV2.
+pub struct MockS3 {
+    objects: Store,
+    url: String,
+}
+
+impl MockS3 {
+    pub fn start() -> MockS3 {
+        let objects: Store = Arc::new(Mutex::new(HashMap::new()));
+        let state = objects.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .data(state.clone())
+                .default_service(web::route().to(handle_s3))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .expect("Failed to bind mock S3");
+        let url = format!("http://{}", server.addrs()[0]);
+        server.run();
+
+        MockS3 { objects, url }
+    }
+
+    /// A client which talks to this store.
+    pub fn client(&self) -> S3Client {
+        S3Client::new_with(
+            HttpClient::new().expect("Failed to create HTTP client"),
+            StaticProvider::new_minimal("test".to_string(), "test".to_string()),
+            Region::Custom {
+                name: "us-east-1".to_string(),
+                endpoint: self.url.clone(),
+            },
+        )
+    }
+
+    pub fn insert(&self, bucket: &str, key: &str, content_type: &str, data: Vec<u8>) {
+        let object = StoredObject {
+            etag: etag(&data),
+            data,
+            content_type: Some(content_type.to_string()),
+            metadata: HashMap::new(),
+            redirect: None,
+        };
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(format!("{}/{}", bucket, key), object);
+    }
+
+    /// Set a user-defined metadata entry on a stored object.
+    pub fn set_metadata(&self, bucket: &str, key: &str, name: &str, value: &str) {
+        if let Some(object) = self
+            .objects
+            .lock()
+            .unwrap()
+            .get_mut(&format!("{}/{}", bucket, key))
+        {
+            object.metadata.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    pub fn get(&self, bucket: &str, key: &str) -> Option<StoredObject> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(&format!("{}/{}", bucket, key))
+            .cloned()
+    }
+
+    /// Every stored `bucket/key`.
+    pub fn keys(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// A token endpoint which accepts a single bearer token.
+pub struct MockTokenEndpoint {
+    url: String,
+}
+
+impl MockTokenEndpoint {
+    /// Accept `Bearer {token}` as a token for `me` with the given scopes.
+    pub fn start(token: &str, me: &str, scope: &str) -> MockTokenEndpoint {
+        let expected = format!("Bearer {}", token);
+        let response = serde_json::json!({
+            "me": me,
+            "client_id": "https://client.example/",
+            "scope": scope,
+        });
+        let server = HttpServer::new(move || {
+            let expected = expected.clone();
+            let response = response.clone();
+            App::new().default_service(web::route().to(move |req: HttpRequest| {
+                let authorized = req
+                    .headers()
+                    .get(header::AUTHORIZATION)
+                    .map_or(false, |v| v.as_bytes() == expected.as_bytes());
+                async move {
+                    if authorized {
+                        HttpResponse::Ok().json(response)
+                    } else {
+                        HttpResponse::Unauthorized().finish()
+                    }
+                }
+            }))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .expect("Failed to bind mock token endpoint");
+        let url = format!("http://{}/token", server.addrs()[0]);
+        server.run();
+
+        MockTokenEndpoint { url }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+/// Settings are read from the environment, which every test shares, so it's
+/// only changed while this is held.
+static ENV: Mutex<()> = Mutex::new(());
+
+/// An endpoint using [`BUCKET`] in `s3` and the given token endpoint.
+pub async fn endpoint(s3: &MockS3, tokens: &MockTokenEndpoint) -> MediaEndpoint {
+    endpoint_with(s3, tokens, &[]).await
+}
+
+/// Like [`endpoint`], with each of `settings` set as if it was in the
+/// environment, e.g. `("QUARANTINE", "true")`.
+pub async fn endpoint_with(
+    s3: &MockS3,
+    tokens: &MockTokenEndpoint,
+    settings: &[(&str, &str)],
+) -> MediaEndpoint {
+    let site_config = {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, value) in settings {
+            std::env::set_var(name, value);
+        }
+        let site_config = SiteConfig::new(BUCKET, MEDIA_URL, tokens.url());
+        for (name, _) in settings {
+            std::env::remove_var(name);
+        }
+        site_config
+    };
+    MediaEndpoint::builder()
+        .site_config(site_config)
+        .s3_client(s3.client())
+        .tenants(Vec::new())
+        .build()
+        .await
+        .expect("Failed to build the endpoint")
+}
+
+async fn handle_s3(req: HttpRequest, body: web::Bytes, store: web::Data<Store>) -> HttpResponse {
+    let path = percent_decode_str(req.path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .to_string();
+    let mut objects = store.lock().unwrap();
+
+    // Tagging and other sub-resources aren't modeled.
+    if req.query_string().contains("tagging") {
+        return match *req.method() {
+            Method::GET => HttpResponse::Ok()
+                .content_type("application/xml")
+                .body("<Tagging><TagSet></TagSet></Tagging>"),
+            _ => HttpResponse::Ok().finish(),
+        };
+    }
+
+    if req.query_string().starts_with("delete") && *req.method() == Method::POST {
+        let body = String::from_utf8_lossy(&body);
+        for key in body.split("<Key>").skip(1) {
+            let key = key.split("</Key>").next().unwrap_or_default();
+            objects.remove(&format!("{}/{}", path, key));
+        }
+        return HttpResponse::Ok()
+            .content_type("application/xml")
+            .body("<DeleteResult></DeleteResult>");
+    }
+
+    if req.query_string().contains("list-type=2") && *req.method() == Method::GET {
+        return list(&req, &path, &objects);
+    }
+
+    match *req.method() {
+        Method::PUT => {
+            let object = match req.headers().get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = source.to_str().unwrap_or_default();
+                    let source = source.split('?').next().unwrap_or_default();
+                    let source = percent_decode_str(source.trim_start_matches('/'))
+                        .decode_utf8_lossy()
+                        .to_string();
+                    let mut object = match objects.get(&source) {
+                        Some(object) => object.clone(),
+                        None => return not_found(),
+                    };
+                    let replace = req
+                        .headers()
+                        .get("x-amz-metadata-directive")
+                        .map_or(false, |v| v == "REPLACE");
+                    if replace {
+                        object.content_type = req
+                            .headers()
+                            .get(header::CONTENT_TYPE)
+                            .and_then(|v| v.to_str().ok())
+                            .map(|v| v.to_string());
+                        object.metadata = metadata_headers(&req);
+                    }
+                    object
+                }
+                None => StoredObject {
+                    etag: etag(&body),
+                    data: body.to_vec(),
+                    content_type: req
+                        .headers()
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .map(|v| v.to_string()),
+                    metadata: metadata_headers(&req),
+                    redirect: req
+                        .headers()
+                        .get("x-amz-website-redirect-location")
+                        .and_then(|v| v.to_str().ok())
+                        .map(|v| v.to_string()),
+                },
+            };
+            let etag = object.etag.clone();
+            let copied = req.headers().contains_key("x-amz-copy-source");
+            objects.insert(path, object);
+            if copied {
+                HttpResponse::Ok()
+                    .content_type("application/xml")
+                    .body(format!(
+                        "<CopyObjectResult><ETag>{}</ETag></CopyObjectResult>",
+                        etag
+                    ))
+            } else {
+                HttpResponse::Ok().header(header::ETAG, etag).finish()
+            }
+        }
+        Method::DELETE => {
+            objects.remove(&path);
+            HttpResponse::NoContent().finish()
+        }
+        Method::GET | Method::HEAD => {
+            let object = match objects.get(&path) {
+                Some(object) => object,
+                None if *req.method() == Method::HEAD => return HttpResponse::NotFound().finish(),
+                None => return not_found(),
+            };
+
+            let if_none_match = req
+                .headers()
+                .get(header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok());
+            if if_none_match == Some(object.etag.as_str()) {
+                return HttpResponse::NotModified()
+                    .header(header::ETAG, object.etag.as_str())
+                    .finish();
+            }
+
+            let if_match = req
+                .headers()
+                .get(header::IF_MATCH)
+                .and_then(|v| v.to_str().ok());
+            if if_match.map_or(false, |v| v != object.etag) {
+                return HttpResponse::PreconditionFailed().finish();
+            }
+
+            let mut resp = HttpResponse::Ok();
+            resp.header(header::ETAG, object.etag.as_str());
+            resp.header(header::LAST_MODIFIED, LAST_MODIFIED);
+            if let Some(content_type) = &object.content_type {
+                resp.header(header::CONTENT_TYPE, content_type.as_str());
+            }
+            for (name, value) in &object.metadata {
+                resp.header(format!("x-amz-meta-{}", name).as_str(), value.as_str());
+            }
+            if let Some(redirect) = &object.redirect {
+                resp.header("x-amz-website-redirect-location", redirect.as_str());
+            }
+
+            let mut data = &object.data[..];
+            if let Some((start, end)) = range(&req, data.len()) {
+                resp.status(StatusCode::PARTIAL_CONTENT);
+                resp.header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, data.len()),
+                );
+                data = &data[start..=end];
+            }
+
+            if *req.method() == Method::HEAD {
+                resp.header(header::CONTENT_LENGTH, data.len().to_string());
+                resp.finish()
+            } else {
+                resp.body(data.to_vec())
+            }
+        }
+        _ => HttpResponse::NotImplemented().finish(),
+    }
+}
+
+/// List the objects in the bucket at `bucket`, in key order. The
+/// continuation token is the last key of the page.
+fn list(req: &HttpRequest, bucket: &str, objects: &HashMap<String, StoredObject>) -> HttpResponse {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(|query| query.into_inner())
+        .unwrap_or_default();
+    let prefix = format!(
+        "{}/{}",
+        bucket,
+        query.get("prefix").cloned().unwrap_or_default()
+    );
+    let after = query
+        .get("continuation-token")
+        .or_else(|| query.get("start-after"))
+        .map(|after| format!("{}/{}", bucket, after));
+    let max_keys: usize = query
+        .get("max-keys")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+
+    let mut keys: Vec<&String> = objects
+        .keys()
+        .filter(|key| key.starts_with(&prefix))
+        .filter(|key| after.as_ref().map_or(true, |after| *key > after))
+        .collect();
+    keys.sort();
+    let truncated = keys.len() > max_keys;
+    keys.truncate(max_keys);
+
+    let mut body = format!("<ListBucketResult><IsTruncated>{}</IsTruncated>", truncated);
+    for key in &keys {
+        body.push_str(&format!(
+            "<Contents><Key>{}</Key><Size>{}</Size><LastModified>2020-01-01T00:00:00.000Z</LastModified></Contents>",
+            &key[bucket.len() + 1..],
+            objects[*key].data.len()
+        ));
+    }
+    if let (true, Some(last)) = (truncated, keys.last()) {
+        body.push_str(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            &last[bucket.len() + 1..]
+        ));
+    }
+    body.push_str("</ListBucketResult>");
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(body)
+}
+
+/// Parse a single `bytes=start-end` range.
+fn range(req: &HttpRequest, len: usize) -> Option<(usize, usize)> {
+    let spec = req
+        .headers()
+        .get(header::RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes=")?;
+    let mut parts = spec.splitn(2, '-');
+    let start: usize = parts.next()?.parse().ok()?;
+    let end = match parts.next()? {
+        "" => len - 1,
+        end => end.parse::<usize>().ok()?.min(len - 1),
+    };
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound()
+        .content_type("application/xml")
+        .body("<Error><Code>NoSuchKey</Code><Message>Not found</Message></Error>")
+}
+
+/// The `x-amz-meta-` headers of a request, without the prefix.
+fn metadata_headers(req: &HttpRequest) -> HashMap<String, String> {
+    req.headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.as_str().strip_prefix("x-amz-meta-")?;
+            Some((name.to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect()
+}
+
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", &checksum(data)[..32])
+}
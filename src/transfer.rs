@@ -0,0 +1,118 @@
+use actix_rt::time::timeout;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use derive_more::Display;
+use futures::{Stream, StreamExt};
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::micropub::MicropubError;
+use crate::SiteConfig;
+
+/// Why an upload body couldn't be received.
+#[derive(Display, Debug)]
+pub enum TransferError {
+    #[display(fmt = "No data received for too long")]
+    Idle,
+    #[display(fmt = "Upload is slower than the minimum transfer rate")]
+    TooSlow,
+    #[display(fmt = "{}", _0)]
+    Body(String),
+}
+
+impl TransferError {
+    /// Did the client take too long?
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            TransferError::Idle | TransferError::TooSlow => true,
+            TransferError::Body(_) => false,
+        }
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        if self.is_timeout() {
+            HttpResponse::RequestTimeout()
+                .json(MicropubError::with_description("request_timeout", self))
+        } else {
+            HttpResponse::BadRequest()
+                .json(MicropubError::with_description("invalid_request", self))
+        }
+    }
+}
+
+/// Keeps a stalled or trickling client from holding a connection forever.
+///
+/// The client must send something at least every UPLOAD_IDLE_TIMEOUT
+/// seconds, and, once UPLOAD_MIN_RATE_GRACE seconds have passed, average at
+/// least UPLOAD_MIN_RATE bytes per second.
+pub struct Transfer {
+    started: Instant,
+    received: u64,
+    idle_timeout: Option<Duration>,
+    min_rate: Option<u64>,
+    grace: Duration,
+    /// Where a client watching the upload can see what's arrived.
+    progress: Option<Arc<AtomicU64>>,
+}
+
+impl Transfer {
+    /// Start timing a transfer now.
+    pub fn new(site: &SiteConfig) -> Transfer {
+        Transfer {
+            started: Instant::now(),
+            received: 0,
+            idle_timeout: site.upload_idle_timeout(),
+            min_rate: site.upload_min_rate(),
+            grace: site.upload_min_rate_grace(),
+            progress: None,
+        }
+    }
+
+    /// Add every chunk received to `counter` as well.
+    pub fn report_to(&mut self, counter: Arc<AtomicU64>) {
+        self.progress = Some(counter);
+    }
+
+    /// Wait for the client to send something.
+    pub async fn wait<F: Future>(&mut self, future: F) -> Result<F::Output, TransferError> {
+        let rate_deadline = self.min_rate.map(|min_rate| {
+            let allowed = Duration::from_secs_f64(self.received as f64 / min_rate as f64);
+            allowed
+                .max(self.grace)
+                .checked_sub(self.started.elapsed())
+                .unwrap_or_default()
+        });
+
+        let (limit, error) = match (self.idle_timeout, rate_deadline) {
+            (Some(idle), Some(rate)) if rate < idle => (rate, TransferError::TooSlow),
+            (Some(idle), _) => (idle, TransferError::Idle),
+            (None, Some(rate)) => (rate, TransferError::TooSlow),
+            (None, None) => return Ok(future.await),
+        };
+
+        timeout(limit, future).await.map_err(|_| error)
+    }
+
+    /// The next chunk of the body, if there is one.
+    pub async fn next_chunk<S, E>(&mut self, stream: &mut S) -> Result<Option<Bytes>, TransferError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: fmt::Display,
+    {
+        match self.wait(stream.next()).await? {
+            Some(Ok(chunk)) => {
+                self.received += chunk.len() as u64;
+                if let Some(progress) = &self.progress {
+                    progress.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+                Ok(Some(chunk))
+            }
+            Some(Err(e)) => Err(TransferError::Body(e.to_string())),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{error, info};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    CopyObjectRequest, DeleteObjectRequest, HeadObjectOutput, HeadObjectRequest,
+    ListObjectsV2Error, ListObjectsV2Request, S3Client, S3,
+};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::cdn::Cdn;
+use crate::delete;
+use crate::events::{self, EventKind, MediaEvent};
+use crate::index::{MediaIndex, MediaRecord};
+use crate::media;
+use crate::metadata;
+use crate::micropub::{authorize, invalidate, key_for_url, MicropubError};
+use crate::oauth;
+use crate::originals;
+use crate::policy::Permission;
+use crate::replica::ReadBuckets;
+use crate::SiteConfig;
+
+/// Key prefix for deleted objects.
+pub const TRASH_PREFIX: &str = "trash";
+
+/// How often the trash is emptied of expired objects.
+const PURGE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/restore").route(web::post().to(handle_restore)));
+}
+
+/// The key a deleted object is kept under.
+pub fn trash_key(key: &str) -> String {
+    format!("{}/{}", TRASH_PREFIX, key)
+}
+
+/// The key a deleted object's sidecar is kept under.
+fn trash_sidecar_key(key: &str) -> String {
+    trash_key(&metadata::sidecar_key(key))
+}
+
+/// Move an object into the trash, recording when it was deleted.
+///
+/// `key` is the logical key. The trash is always kept in S3_BUCKET.
+pub async fn move_to_trash(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    head: &HeadObjectOutput,
+) -> Result<(), String> {
+    copy_to_trash(site, s3_client, key, head).await?;
+    let (bucket, object_key) = site.locate(key);
+    delete(s3_client, &bucket, &object_key).await?;
+    if site.metadata_sidecar() {
+        let sidecar = metadata::sidecar_key(key);
+        if let Err(e) = delete(s3_client, site.s3_bucket(), &sidecar).await {
+            error!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Put a copy of an object and its sidecar in the trash, leaving them to be
+/// deleted by the caller.
+pub async fn copy_to_trash(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    head: &HeadObjectOutput,
+) -> Result<(), String> {
+    let mut metadata = head.metadata.clone().unwrap_or_default();
+    metadata.insert("deleted-at".to_string(), Utc::now().to_rfc3339());
+    copy(site, s3_client, key, &trash_key(key), head.content_type.clone(), metadata).await?;
+    if site.metadata_sidecar() {
+        let sidecar = metadata::sidecar_key(key);
+        copy_sidecar(site, s3_client, &sidecar, &trash_sidecar_key(key)).await?;
+    }
+    Ok(())
+}
+
+/// Copy a sidecar, if the object has one.
+async fn copy_sidecar(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    from: &str,
+    to: &str,
+) -> Result<bool, String> {
+    let head = match s3_client
+        .head_object(HeadObjectRequest {
+            bucket: site.s3_bucket().to_owned(),
+            key: from.to_owned(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(head) => head,
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => return Ok(false),
+        Err(e) => return Err(format!("Failed to find {}: {}", from, e)),
+    };
+    let metadata = head.metadata.unwrap_or_default();
+    copy(site, s3_client, from, to, head.content_type, metadata).await?;
+    Ok(true)
+}
+
+#[derive(Deserialize)]
+struct RestoreQuery {
+    url: String,
+}
+
+/// Move a deleted object back to its original URL.
+async fn handle_restore(
+    req: HttpRequest,
+    query: web::Query<RestoreQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    buckets: web::Data<ReadBuckets>,
+    http_client: web::Data<Client>,
+    cdn: web::Data<Cdn>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Delete).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let key = match key_for_url(&site, &query.url) {
+        Some(key) => key,
+        None => {
+            return HttpResponse::BadRequest()
+                .json(MicropubError::with_description("invalid_request", "Unknown URL"))
+        }
+    };
+
+    let trashed = trash_key(&key);
+    let head = match s3_client
+        .head_object(HeadObjectRequest {
+            bucket: site.s3_bucket().to_owned(),
+            key: trashed.clone(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(head) => head,
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => {
+            return HttpResponse::NotFound().json(MicropubError::new("not_found"))
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    if !delete::is_author(head.metadata.as_ref(), &access_token) {
+        return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+    }
+    let mut metadata = head.metadata.unwrap_or_default();
+    metadata.remove("deleted-at");
+
+    if let Err(e) = copy(&site, &s3_client, &trashed, &key, head.content_type, metadata).await {
+        return HttpResponse::InternalServerError().body(e);
+    }
+    if let Err(e) = delete(&s3_client, site.s3_bucket(), &trashed).await {
+        error!("{}", e);
+    }
+    if site.metadata_sidecar() {
+        let (sidecar, trashed_sidecar) = (metadata::sidecar_key(&key), trash_sidecar_key(&key));
+        match copy_sidecar(&site, &s3_client, &trashed_sidecar, &sidecar).await {
+            Ok(true) => {
+                if let Err(e) = delete(&s3_client, site.s3_bucket(), &trashed_sidecar).await {
+                    error!("{}", e);
+                }
+            }
+            Ok(false) => (),
+            Err(e) => error!("Failed to restore the sidecar for {}: {}", key, e),
+        }
+    }
+
+    // The 404 may have been cached.
+    invalidate(
+        &site,
+        &s3_client,
+        &http_client,
+        &cdn,
+        // Nothing was cached while it was hidden.
+        None,
+        &key,
+        &query.url,
+    )
+    .await;
+
+    republish(
+        &site,
+        &buckets,
+        &publisher,
+        index.as_deref(),
+        &key,
+        &query.url,
+        &access_token,
+    )
+    .await;
+
+    HttpResponse::NoContent().finish()
+}
+
+/// Add an object which has reappeared at `key` back to the index, and tell
+/// everyone about it.
+pub(crate) async fn republish(
+    site: &SiteConfig,
+    buckets: &ReadBuckets,
+    publisher: &events::Publisher,
+    index: Option<&MediaIndex>,
+    key: &str,
+    url: &str,
+    access_token: &oauth::AccessToken,
+) {
+    let (head, fields) = match media::describe(site, buckets, key).await {
+        Ok(described) => described,
+        Err(e) => {
+            error!("Failed to describe restored object {}: {}", key, e);
+            return;
+        }
+    };
+
+    if let Some(index) = index {
+        let record = MediaRecord {
+            key: key.to_string(),
+            url: url.to_string(),
+            classification: key.split('/').next().unwrap_or_default().to_string(),
+            content_type: head.content_type.clone(),
+            size: head.content_length,
+            author: access_token.me().to_string(),
+            client_id: access_token.client_id().to_string(),
+            checksum: fields.get("sha256").cloned(),
+            filename: fields.get("filename").cloned(),
+            alt: fields.get("alt").cloned(),
+            caption: fields.get("caption").cloned(),
+            created_at: Utc::now(),
+        };
+        if let Err(e) = index.insert(&record).await {
+            error!("Failed to add {} to the index: {}", key, e);
+        }
+    }
+
+    publisher.publish(
+        site,
+        MediaEvent {
+            event: EventKind::Create,
+            url: url.to_string(),
+            key: key.to_string(),
+            content_type: head.content_type,
+            size: head.content_length.map(|l| l as u64),
+            author: access_token.me().to_string(),
+            client_id: access_token.client_id().to_string(),
+            api_key: access_token.api_key().map(str::to_string),
+            checksum: fields.get("sha256").cloned(),
+            timestamp: Utc::now(),
+        },
+    );
+}
+
+/// Permanently delete objects which have been in the trash longer than `max_age`.
+pub async fn purge_expired(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    max_age: Duration,
+) -> Result<usize, RusotoError<ListObjectsV2Error>> {
+    let cutoff = Utc::now()
+        - ChronoDuration::from_std(max_age).unwrap_or_else(|_| ChronoDuration::max_value());
+    let bucket = site.s3_bucket();
+    let mut continuation_token = None;
+    let mut purged = 0;
+
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(format!("{}/", TRASH_PREFIX)),
+            continuation_token: continuation_token.take(),
+            ..Default::default()
+        };
+        let response = s3_client.list_objects_v2(request).await?;
+
+        for object in response.contents.unwrap_or_default() {
+            // Trashed objects are copies, so they were last modified when deleted.
+            let expired = object
+                .last_modified
+                .as_deref()
+                .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+                .map_or(false, |t| t < cutoff);
+            let trashed = match object.key {
+                Some(key) if expired => key,
+                _ => continue,
+            };
+
+            let key = &trashed[TRASH_PREFIX.len() + 1..];
+            // Sidecars are purged with their objects.
+            if key.starts_with(&format!("{}/", metadata::SIDECAR_PREFIX)) {
+                continue;
+            }
+            match delete(s3_client, bucket, &trashed).await {
+                Ok(()) => purged += 1,
+                Err(e) => error!("{}", e),
+            }
+            if site.metadata_sidecar() {
+                if let Err(e) = delete(s3_client, bucket, &trash_sidecar_key(key)).await {
+                    error!("{}", e);
+                }
+            }
+            originals::delete(site, s3_client, key).await;
+        }
+
+        if response.is_truncated != Some(true) {
+            return Ok(purged);
+        }
+        continuation_token = response.next_continuation_token;
+    }
+}
+
+/// Periodically empty expired objects from the trash.
+pub async fn purge_forever(site: SiteConfig, s3_client: S3Client, max_age: Duration) {
+    loop {
+        match purge_expired(&site, &s3_client, max_age).await {
+            Ok(purged) => info!("Permanently deleted {} objects from the trash", purged),
+            Err(e) => error!("Failed to list the trash: {}", e),
+        }
+        delay_for(PURGE_INTERVAL).await;
+    }
+}
+
+/// Characters escaped in a copy source: all but the unreserved ones and `/`.
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// The `x-amz-copy-source` naming `key` in `bucket`. S3 expects it
+/// URL-encoded, and rusoto sends it as given, so keys holding an uploaded
+/// filename would otherwise fail to copy.
+pub(crate) fn copy_source(bucket: &str, key: &str) -> String {
+    format!("{}/{}", bucket, utf8_percent_encode(key, COPY_SOURCE))
+}
+
+pub(crate) async fn copy(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    from: &str,
+    to: &str,
+    content_type: Option<String>,
+    metadata: HashMap<String, String>,
+) -> Result<(), String> {
+    let (from_bucket, from_key) = site.locate(from);
+    let defaults = site.put_object_request_for(to);
+    let request = CopyObjectRequest {
+        bucket: defaults.bucket,
+        copy_source: copy_source(&from_bucket, &from_key),
+        key: defaults.key,
+        content_type,
+        metadata: Some(metadata),
+        metadata_directive: Some("REPLACE".to_string()),
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        // Deleted and held objects shouldn't stay public.
+        acl: if media::is_hidden(site, to.split('/').next().unwrap_or_default()) {
+            None
+        } else {
+            defaults.acl
+        },
+        tagging: defaults.tagging,
+        tagging_directive: Some("REPLACE".to_string()),
+        ..Default::default()
+    };
+    s3_client
+        .copy_object(request)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to copy {} to {}: {}", from, to, e))
+}
+
+pub(crate) async fn delete(s3_client: &S3Client, bucket: &str, key: &str) -> Result<(), String> {
+    let request = DeleteObjectRequest {
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        ..Default::default()
+    };
+    s3_client
+        .delete_object(request)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Failed to delete {}: {}", key, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::copy_source;
+
+    #[test]
+    fn copy_sources_are_url_encoded() {
+        assert_eq!(
+            copy_source("media", "file/abc/My Report+v2.pdf"),
+            "media/file/abc/My%20Report%2Bv2.pdf"
+        );
+    }
+
+    #[test]
+    fn plain_keys_are_unchanged() {
+        assert_eq!(
+            copy_source("media", "photo/a-b_c.d~e.png"),
+            "media/photo/a-b_c.d~e.png"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Each author's most recent upload, so a mistaken one can be undone without
+/// knowing its URL.
+pub struct RecentUploads {
+    uploads: Mutex<HashMap<String, Recent>>,
+    /// How long after an upload it may be undone.
+    window: Duration,
+}
+
+struct Recent {
+    url: String,
+    uploaded: Instant,
+}
+
+impl RecentUploads {
+    pub fn new(window: Duration) -> RecentUploads {
+        RecentUploads {
+            uploads: Mutex::new(HashMap::new()),
+            window,
+        }
+    }
+
+    /// Remember `url` as the latest upload by `author`.
+    pub fn record(&self, author: &str, url: &str) {
+        let mut uploads = self.uploads.lock().unwrap();
+        // Forget anything too old to undo while we're here.
+        let window = self.window;
+        uploads.retain(|_, recent| recent.uploaded.elapsed() < window);
+        uploads.insert(
+            author.to_string(),
+            Recent {
+                url: url.to_string(),
+                uploaded: Instant::now(),
+            },
+        );
+    }
+
+    /// The URL of the latest upload by `author`, if it can still be undone.
+    pub fn last(&self, author: &str) -> Option<String> {
+        let uploads = self.uploads.lock().unwrap();
+        uploads
+            .get(author)
+            .filter(|recent| recent.uploaded.elapsed() < self.window)
+            .map(|recent| recent.url.clone())
+    }
+
+    /// Forget the upload once it's been undone, unless another has replaced it.
+    pub fn forget(&self, author: &str, url: &str) {
+        let mut uploads = self.uploads.lock().unwrap();
+        if uploads
+            .get(author)
+            .map_or(false, |recent| recent.url == url)
+        {
+            uploads.remove(author);
+        }
+    }
+}
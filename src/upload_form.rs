@@ -0,0 +1,613 @@
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use chrono::{Duration as ChronoDuration, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, DefaultCredentialsProvider, ProvideAwsCredentials,
+};
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{HeadObjectError, HeadObjectRequest, S3Client, S3};
+use rusoto_sts::StsAssumeRoleSessionCredentialsProvider;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::classify;
+use crate::credentials::{self, AssumeRole};
+use crate::events::{self, hex};
+use crate::feed::escape;
+use crate::hls;
+use crate::index::MediaIndex;
+use crate::keys;
+use crate::media;
+use crate::metadata;
+use crate::micropub::{
+    authorize, created, default_dimensions, inspect_stored, object_metadata, record_upload, warms,
+    MicropubError, NewObject, Placement,
+};
+use crate::oauth;
+use crate::peaks;
+use crate::policy::Permission;
+use crate::previews;
+use crate::quarantine;
+use crate::staging;
+use crate::trash;
+use crate::undo::RecentUploads;
+use crate::SiteConfig;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/micropub/media/form").route(web::post().to(open_form)));
+    cfg.service(
+        web::resource("/micropub/media/form/complete").route(web::post().to(complete_form)),
+    );
+}
+
+/// Signs browser upload forms with the same credentials the S3 client uses.
+#[derive(Clone)]
+pub struct FormSigner {
+    region: Region,
+    credentials: Credentials,
+}
+
+#[derive(Clone)]
+enum Credentials {
+    Default(Arc<DefaultCredentialsProvider>),
+    Role(Arc<AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>>),
+}
+
+impl FormSigner {
+    pub fn new(region: Region, role: Option<&AssumeRole>) -> FormSigner {
+        let credentials = match role {
+            Some(role) => {
+                Credentials::Role(Arc::new(credentials::role_provider(region.clone(), role)))
+            }
+            None => Credentials::Default(Arc::new(
+                DefaultCredentialsProvider::new().expect("Failed to create credentials provider"),
+            )),
+        };
+        FormSigner {
+            region,
+            credentials,
+        }
+    }
+
+    async fn credentials(&self) -> Result<AwsCredentials, String> {
+        let credentials = match &self.credentials {
+            Credentials::Default(provider) => provider.credentials().await,
+            Credentials::Role(provider) => provider.credentials().await,
+        };
+        credentials.map_err(|e| e.to_string())
+    }
+
+    /// Where the form is posted for `bucket`.
+    fn action(&self, bucket: &str) -> String {
+        match &self.region {
+            Region::Custom { endpoint, .. } => {
+                format!("{}/{}", endpoint.trim_end_matches('/'), bucket)
+            }
+            region => format!("https://{}.s3.{}.amazonaws.com", bucket, region.name()),
+        }
+    }
+}
+
+/// Everything a page needs to post a file straight to the bucket.
+#[derive(Serialize)]
+struct UploadForm {
+    /// Where the form is posted.
+    action: String,
+    /// Form fields to send before the file, in this order.
+    fields: BTreeMap<String, String>,
+    /// The key to complete the upload with.
+    key: String,
+    /// The URL the file will have once the upload is completed.
+    url: String,
+    expires_at: String,
+    /// Post `key` here, with the same token, once the upload has finished.
+    complete: String,
+}
+
+/// Create a pre-signed form for uploading a file straight to the bucket.
+///
+/// The query must include the `content_type`, and may include `filename`
+/// and metadata fields. The form only accepts that type, up to the
+/// classification's limit or UPLOAD_FORM_MAX_BYTES, and expires after
+/// UPLOAD_FORM_TTL. Browsers get a page with the form; everything else gets
+/// JSON.
+///
+/// The file is posted to a private staging key, and only published once the
+/// upload is completed and it's been inspected.
+async fn open_form(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    signer: web::Data<FormSigner>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let filename = query.get("filename").map(String::as_str);
+    let content_type: mime::Mime = match query.get("content_type").map(|v| v.parse()) {
+        Some(Ok(content_type)) => content_type,
+        _ => {
+            return HttpResponse::BadRequest().json(MicropubError::with_description(
+                "invalid_request",
+                "A content_type is required",
+            ))
+        }
+    };
+    let classification = classify::classify(&site, &content_type, filename);
+    if let Err(e) = classify::check_limits(&site, &classification.name, &content_type, None) {
+        return e.response();
+    }
+    let max_bytes = classify::max_bytes(&site, &classification.name)
+        .unwrap_or(u64::MAX)
+        .min(site.upload_form_max_bytes());
+
+    let placement = match Placement::unused(
+        &site,
+        &s3_client,
+        &content_type,
+        filename,
+        access_token.me(),
+        None,
+        keys::date_for(&site, None),
+    )
+    .await
+    {
+        Ok(placement) => placement,
+        Err(e) => return e.response(),
+    };
+    let object_key = placement.object_key();
+    let extra_metadata: HashMap<String, String> = site
+        .metadata_fields()
+        .filter_map(|f| query.get(f).map(|v| (f.to_string(), v.clone())))
+        .collect();
+    let metadata = match object_metadata(
+        &site,
+        &s3_client,
+        &object_key,
+        &extra_metadata,
+        &access_token,
+        filename,
+    )
+    .await
+    {
+        Ok(metadata) => metadata,
+        Err(resp) => return resp,
+    };
+
+    let credentials = match signer.credentials().await {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            error!("Failed to get credentials to sign a form: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Every field but the signature is also a condition of the policy.
+    let put_request = site.put_object_request_for(&staging::staging_key(&object_key));
+    let now = Utc::now();
+    let expires_at = now + ChronoDuration::seconds(i64::from(site.upload_form_ttl()));
+    let scope = format!(
+        "{}/{}/s3/aws4_request",
+        now.format("%Y%m%d"),
+        signer.region.name()
+    );
+    let mut fields = BTreeMap::new();
+    fields.insert("key".to_string(), put_request.key.clone());
+    fields.insert("Content-Type".to_string(), content_type.to_string());
+    fields.insert("success_action_status".to_string(), "201".to_string());
+    fields.insert("x-amz-algorithm".to_string(), ALGORITHM.to_string());
+    fields.insert(
+        "x-amz-credential".to_string(),
+        format!("{}/{}", credentials.aws_access_key_id(), scope),
+    );
+    fields.insert(
+        "x-amz-date".to_string(),
+        now.format("%Y%m%dT%H%M%SZ").to_string(),
+    );
+    if let Some(token) = credentials.token() {
+        fields.insert("x-amz-security-token".to_string(), token.clone());
+    }
+    if let Some(sse) = put_request.server_side_encryption {
+        fields.insert("x-amz-server-side-encryption".to_string(), sse);
+    }
+    if let Some(kms_key_id) = put_request.ssekms_key_id {
+        fields.insert(
+            "x-amz-server-side-encryption-aws-kms-key-id".to_string(),
+            kms_key_id,
+        );
+    }
+    for (name, value) in metadata {
+        fields.insert(format!("x-amz-meta-{}", name), value);
+    }
+
+    let mut conditions: Vec<Value> = vec![json!({ "bucket": put_request.bucket })];
+    conditions.extend(fields.iter().map(|(name, value)| json!({ name: value })));
+    conditions.push(json!(["content-length-range", 1, max_bytes]));
+    let policy = json!({
+        "expiration": expires_at.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        "conditions": conditions,
+    });
+    let policy = base64::encode(policy.to_string());
+    let signature = sign(
+        credentials.aws_secret_access_key(),
+        &now.format("%Y%m%d").to_string(),
+        signer.region.name(),
+        &policy,
+    );
+    fields.insert("policy".to_string(), policy);
+    fields.insert("x-amz-signature".to_string(), signature);
+
+    let base = req
+        .path()
+        .trim_end_matches('/')
+        .trim_end_matches("/form")
+        .to_string();
+    let form = UploadForm {
+        action: signer.action(&put_request.bucket),
+        fields,
+        key: object_key,
+        url: placement.url(&site),
+        expires_at: expires_at.to_rfc3339(),
+        complete: format!(
+            "{}://{}{}/form/complete",
+            req.connection_info().scheme(),
+            req.connection_info().host(),
+            base
+        ),
+    };
+
+    let wants_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.contains("text/html"));
+    if wants_html {
+        HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(form_html(&form))
+    } else {
+        HttpResponse::Ok()
+            .header(header::CACHE_CONTROL, "no-store")
+            .json(form)
+    }
+}
+
+/// Sign the policy with a SigV4 key for S3 in `region` on `date`.
+fn sign(secret: &str, date: &str, region: &str, policy: &str) -> String {
+    let hmac = |key: &[u8], data: &str| {
+        let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key");
+        mac.update(data.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    };
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, "s3");
+    let key = hmac(&key, "aws4_request");
+    hex(&hmac(&key, policy))
+}
+
+fn form_html(form: &UploadForm) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>Upload</title>\n</head>\n<body>\n");
+    html.push_str(&format!(
+        "<form action=\"{}\" method=\"post\" enctype=\"multipart/form-data\">\n",
+        escape(&form.action)
+    ));
+    for (name, value) in &form.fields {
+        html.push_str(&format!(
+            "<input type=\"hidden\" name=\"{}\" value=\"{}\">\n",
+            escape(name),
+            escape(value)
+        ));
+    }
+    html.push_str("<input type=\"file\" name=\"file\">\n");
+    html.push_str("<button type=\"submit\">Upload</button>\n</form>\n</body>\n</html>\n");
+    html
+}
+
+#[derive(Deserialize)]
+struct CompleteQuery {
+    key: String,
+}
+
+/// Register a file uploaded with a form, once it's in the bucket.
+///
+/// The upload is inspected, published, indexed, and announced as if it had
+/// been sent to the endpoint. Rejected uploads are removed from staging.
+async fn complete_form(
+    req: HttpRequest,
+    query: web::Query<CompleteQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    publisher: web::Data<events::Publisher>,
+    index: Option<web::Data<MediaIndex>>,
+    recent_uploads: web::Data<RecentUploads>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let access_token = match authorize(&req, &verification_service, &site, Permission::Create).await
+    {
+        Ok(token) => token,
+        Err(resp) => return resp,
+    };
+
+    let staged = staging::staging_key(&query.key);
+    let stored = match read_stored(&site, &s3_client, &staged).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return HttpResponse::NotFound().json(MicropubError::new("not_found")),
+        Err(e) => {
+            error!("{}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // The form was signed for whoever asked for it.
+    if stored.metadata.get("author").map(String::as_str) != Some(access_token.me()) {
+        return HttpResponse::Forbidden().json(MicropubError::new("forbidden"));
+    }
+
+    let registered = register(
+        &site,
+        &s3_client,
+        &http_client,
+        &publisher,
+        index.as_deref(),
+        &access_token,
+        &query.key,
+        stored,
+        true,
+    )
+    .await;
+    match registered {
+        Ok(published) => {
+            recent_uploads.record(access_token.me(), &published.url);
+            created(&published.url, published.dimensions)
+        }
+        Err(resp) => resp,
+    }
+}
+
+/// An object which was stored without going through the endpoint.
+pub(crate) struct Stored {
+    pub content_type: mime::Mime,
+    pub metadata: HashMap<String, String>,
+    pub size: u64,
+}
+
+/// Look up the object with the logical key `object_key`, if there is one.
+pub(crate) async fn read_stored(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    object_key: &str,
+) -> Result<Option<Stored>, String> {
+    let (bucket, key) = site.locate(object_key);
+    let request = HeadObjectRequest {
+        bucket,
+        key,
+        ..Default::default()
+    };
+    let head = match s3_client.head_object(request).await {
+        Ok(head) => head,
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => return Ok(None),
+        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", object_key, e)),
+    };
+    let content_type = head
+        .content_type
+        .as_deref()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    Ok(Some(Stored {
+        content_type,
+        metadata: head.metadata.unwrap_or_default(),
+        size: head.content_length.unwrap_or_default() as u64,
+    }))
+}
+
+/// An object which was registered.
+pub(crate) struct Published {
+    pub url: String,
+    /// The size it's served at, if it's known.
+    pub dimensions: Option<(u32, u32)>,
+    pub checksum: String,
+}
+
+/// Treat an object which was stored straight in the bucket as an upload
+/// from `access_token`: inspect it, index it, announce it, and generate its
+/// derivatives. Objects which are `staged` are published at `object_key`
+/// once they've been inspected.
+///
+/// Objects which fail inspection are removed, or quarantined if QUARANTINE is
+/// set, and the response saying so is returned instead.
+pub(crate) async fn register(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    http_client: &Client,
+    publisher: &events::Publisher,
+    index: Option<&MediaIndex>,
+    access_token: &oauth::AccessToken,
+    object_key: &str,
+    stored: Stored,
+    staged: bool,
+) -> Result<Published, HttpResponse> {
+    let Stored {
+        content_type,
+        metadata: stored_metadata,
+        size,
+    } = stored;
+    let filename = stored_metadata.get("filename").map(|f| metadata::decode(f));
+    let placement = Placement::existing(site, object_key, &content_type, filename.as_deref())
+        .ok_or_else(|| HttpResponse::NotFound().json(MicropubError::new("not_found")))?;
+    let from = if staged {
+        staging::staging_key(object_key)
+    } else {
+        object_key.to_string()
+    };
+
+    let inspected = inspect_stored(
+        site,
+        s3_client,
+        http_client,
+        &placement,
+        &content_type,
+        &from,
+        size,
+    )
+    .await?;
+    if let Some(reason) = inspected.rejection {
+        let response = if site.quarantine() {
+            let held = quarantine::HeldCopy {
+                from: &from,
+                size,
+                key: object_key,
+                author: access_token.me(),
+                reason: &reason,
+                content_type: content_type.to_string(),
+                metadata: stored_metadata,
+            };
+            quarantine::hold_copy(site, s3_client, held).await
+        } else {
+            quarantine::rejected(object_key, access_token.me(), &reason)
+        };
+        // Keep it if it couldn't be held, so it isn't lost.
+        if !response.status().is_server_error() {
+            let (bucket, key) = site.locate(&from);
+            if let Err(e) = trash::delete(s3_client, &bucket, &key).await {
+                error!("{}", e);
+            }
+        }
+        return Err(response);
+    }
+
+    let checksum = match &inspected.data {
+        Some(data) => events::checksum(data),
+        None => staging::checksum(site, s3_client, &from, size)
+            .await
+            .map_err(|e| HttpResponse::InternalServerError().body(e))?,
+    };
+    if staged {
+        let published = staging::publish(
+            site,
+            s3_client,
+            object_key,
+            size,
+            content_type.to_string(),
+            stored_metadata.clone(),
+            None,
+        );
+        if let Err(e) = published.await {
+            error!("{}", e);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    }
+
+    let data = match inspected.data {
+        Some(data) => Some(data),
+        None if derives(site, &placement) => {
+            match staging::read_small(site, s3_client, object_key, size).await {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let dimensions = data
+        .as_ref()
+        .and_then(|data| default_dimensions(site, &placement, data));
+    if let Some(data) = data {
+        spawn_derivatives(site, s3_client, &placement, data);
+    }
+
+    let url = placement.url(site);
+    let extra_metadata = site
+        .metadata_fields()
+        .filter_map(|f| {
+            let value = stored_metadata.get(f)?;
+            Some((f.to_string(), metadata::decode(value)))
+        })
+        .collect();
+    let object = NewObject {
+        placement,
+        url: url.clone(),
+        content_type,
+        filename,
+        size,
+        checksum: Some(checksum.clone()),
+        extra_metadata,
+    };
+    record_upload(site, publisher, index, access_token, object).await;
+    Ok(Published {
+        url,
+        dimensions,
+        checksum,
+    })
+}
+
+/// Whether [`spawn_derivatives`] would generate anything for the upload.
+/// Uploads bigger than INSPECT_MAX_BYTES get none.
+pub(crate) fn derives(site: &SiteConfig, placement: &Placement) -> bool {
+    warms(site, placement)
+        || (placement.classification == "video" && (site.hls_enabled() || site.video_previews()))
+        || (placement.classification == "audio" && site.audio_peaks())
+}
+
+/// Generate whatever an upload to the endpoint would have had generated.
+pub(crate) fn spawn_derivatives(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    placement: &Placement,
+    data: Vec<u8>,
+) {
+    if warms(site, placement) {
+        actix_rt::spawn(media::warm(
+            site.clone(),
+            s3_client.clone(),
+            placement.key.clone(),
+            data.clone(),
+        ));
+    }
+    if placement.classification == "video" && site.hls_enabled() {
+        actix_rt::spawn(hls::package(
+            site.clone(),
+            s3_client.clone(),
+            placement.id.clone(),
+            data.clone(),
+        ));
+    }
+    if placement.classification == "video" && site.video_previews() {
+        actix_rt::spawn(previews::generate(
+            site.clone(),
+            s3_client.clone(),
+            placement.object_key(),
+            data.clone(),
+        ));
+    }
+    if placement.classification == "audio" && site.audio_peaks() {
+        actix_rt::spawn(peaks::generate(
+            site.clone(),
+            s3_client.clone(),
+            placement.key.clone(),
+            data,
+        ));
+    }
+}
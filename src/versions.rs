@@ -0,0 +1,239 @@
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use rusoto_core::RusotoError;
+use rusoto_s3::{
+    CopyObjectRequest, HeadObjectRequest, ListObjectVersionsRequest, S3Client, S3,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cdn::Cdn;
+use crate::disk_cache::DiskCache;
+use crate::micropub::{authorize, invalidate, key_for_url, MicropubError};
+use crate::oauth;
+use crate::policy::Permission;
+use crate::trash;
+use crate::SiteConfig;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/micropub/media/versions")
+            .route(web::get().to(handle_versions))
+            .route(web::post().to(handle_restore)),
+    );
+}
+
+#[derive(Deserialize)]
+struct VersionQuery {
+    url: String,
+    #[serde(rename = "versionId")]
+    version_id: Option<String>,
+}
+
+/// One stored version of an object.
+#[derive(Serialize)]
+struct Version {
+    version_id: String,
+    /// A URL which always serves this version.
+    url: String,
+    last_modified: Option<String>,
+    size: Option<i64>,
+    is_latest: bool,
+}
+
+#[derive(Serialize)]
+struct VersionsResponse {
+    versions: Vec<Version>,
+}
+
+/// List the versions of the object at `url`, newest first.
+///
+/// Listing them takes the same scope as listing uploads. Serving the old
+/// versions takes one which may read private media.
+async fn handle_versions(
+    req: HttpRequest,
+    query: web::Query<VersionQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let (access_token, key) = match authorize_url(
+        &req,
+        &query.url,
+        &site,
+        &verification_service,
+        Permission::List,
+    )
+    .await
+    {
+        Ok(authorized) => authorized,
+        Err(resp) => return resp,
+    };
+
+    let (bucket, object_key) = site.locate(&key);
+    let request = ListObjectVersionsRequest {
+        bucket,
+        prefix: Some(object_key.clone()),
+        ..Default::default()
+    };
+    let listing = match s3_client.list_object_versions(request).await {
+        Ok(listing) => listing,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    // The prefix also matches longer keys.
+    let versions: Vec<_> = listing
+        .versions
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|v| v.key.as_deref() == Some(object_key.as_str()))
+        .filter_map(|v| {
+            let version_id = v.version_id?;
+            Some(Version {
+                url: format!("{}/{}?versionId={}", site.media_url(), key, version_id),
+                version_id,
+                last_modified: v.last_modified,
+                size: v.size,
+                is_latest: v.is_latest.unwrap_or(false),
+            })
+        })
+        .collect();
+
+    // Don't reveal anything about other people's objects.
+    if let Some(newest) = versions.first() {
+        if let Err(resp) =
+            check_author(&site, &s3_client, &key, &newest.version_id, &access_token).await
+        {
+            return resp;
+        }
+    }
+
+    HttpResponse::Ok().json(VersionsResponse { versions })
+}
+
+/// Make an old version the current one by copying it over the object.
+async fn handle_restore(
+    req: HttpRequest,
+    query: web::Query<VersionQuery>,
+    site: web::Data<SiteConfig>,
+    s3_client: web::Data<S3Client>,
+    http_client: web::Data<Client>,
+    cdn: web::Data<Cdn>,
+    disk_cache: Option<web::Data<DiskCache>>,
+    verification_service: web::Data<oauth::VerificationService>,
+) -> HttpResponse {
+    let (access_token, key) = match authorize_url(
+        &req,
+        &query.url,
+        &site,
+        &verification_service,
+        Permission::Create,
+    )
+    .await
+    {
+        Ok(authorized) => authorized,
+        Err(resp) => return resp,
+    };
+
+    let version_id = match &query.version_id {
+        Some(version_id) => version_id,
+        None => {
+            return HttpResponse::BadRequest()
+                .json(MicropubError::with_description("invalid_request", "Missing versionId"))
+        }
+    };
+
+    if let Err(resp) = check_author(&site, &s3_client, &key, version_id, &access_token).await {
+        return resp;
+    }
+
+    let defaults = site.put_object_request_for(&key);
+    let request = CopyObjectRequest {
+        copy_source: format!(
+            "{}?versionId={}",
+            trash::copy_source(&defaults.bucket, &defaults.key),
+            version_id
+        ),
+        bucket: defaults.bucket,
+        key: defaults.key,
+        server_side_encryption: defaults.server_side_encryption,
+        ssekms_key_id: defaults.ssekms_key_id,
+        storage_class: defaults.storage_class,
+        acl: defaults.acl,
+        ..Default::default()
+    };
+    let new_version = match s3_client.copy_object(request).await {
+        Ok(resp) => resp.version_id,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("{}", e)),
+    };
+
+    invalidate(
+        &site,
+        &s3_client,
+        &http_client,
+        &cdn,
+        disk_cache.as_deref(),
+        &key,
+        &query.url,
+    )
+    .await;
+
+    let mut resp = HttpResponse::Ok();
+    if let Some(new_version) = new_version {
+        resp.header(
+            header::CONTENT_LOCATION,
+            format!("{}/{}?versionId={}", site.media_url(), key, new_version),
+        );
+    }
+    resp.finish()
+}
+
+/// Validate the token and find the key for `url`.
+async fn authorize_url(
+    req: &HttpRequest,
+    url: &str,
+    site: &SiteConfig,
+    verification_service: &oauth::VerificationService,
+    permission: Permission,
+) -> Result<(oauth::AccessToken, String), HttpResponse> {
+    let access_token = authorize(req, verification_service, site, permission).await?;
+
+    match key_for_url(site, url) {
+        Some(key) => Ok((access_token, key)),
+        None => Err(HttpResponse::BadRequest()
+            .json(MicropubError::with_description("invalid_request", "Unknown URL"))),
+    }
+}
+
+/// Make sure a version of `key` was uploaded by the token's owner.
+async fn check_author(
+    site: &SiteConfig,
+    s3_client: &S3Client,
+    key: &str,
+    version_id: &str,
+    access_token: &oauth::AccessToken,
+) -> Result<(), HttpResponse> {
+    let (bucket, key) = site.locate(key);
+    let request = HeadObjectRequest {
+        bucket,
+        key,
+        version_id: Some(version_id.to_owned()),
+        ..Default::default()
+    };
+    let head = match s3_client.head_object(request).await {
+        Ok(head) => head,
+        Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => {
+            return Err(HttpResponse::NotFound().json(MicropubError::new("not_found")))
+        }
+        Err(e) => return Err(HttpResponse::InternalServerError().body(format!("{}", e))),
+    };
+
+    match head.metadata.as_ref().and_then(|m| m.get("author")) {
+        Some(author) if author != access_token.me() => {
+            Err(HttpResponse::Forbidden().json(MicropubError::new("forbidden")))
+        }
+        _ => Ok(()),
+    }
+}
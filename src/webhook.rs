@@ -0,0 +1,62 @@
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+
+use hmac::{Hmac, Mac, NewMac};
+use log::{error, warn};
+use sha2::Sha256;
+
+use std::time::Duration;
+
+use crate::events::{hex, MediaEvent};
+use crate::SiteConfig;
+
+/// The header carrying the HMAC-SHA256 of the request body.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Deliveries are attempted this many times before giving up.
+const ATTEMPTS: u32 = 4;
+
+/// POST the event to every configured webhook, retrying failures with backoff.
+pub async fn deliver(site: SiteConfig, client: Client, event: MediaEvent) {
+    let body = match serde_json::to_vec(&event) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to serialize webhook event: {}", e);
+            return;
+        }
+    };
+    let signature = site.webhook_secret().map(|secret| sign(secret, &body));
+
+    for url in site.webhook_urls() {
+        for attempt in 0..ATTEMPTS {
+            if attempt > 0 {
+                delay_for(Duration::from_secs(2u64.pow(attempt))).await;
+            }
+
+            let mut request = client
+                .post(url)
+                .timeout(Duration::from_secs(10))
+                .content_type("application/json");
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature.as_str());
+            }
+
+            match request.send_body(body.clone()).await {
+                Ok(resp) if resp.status().is_success() => break,
+                Ok(resp) => warn!("Webhook {} returned {}", url, resp.status()),
+                Err(e) => warn!("Webhook {} failed: {}", url, e),
+            }
+
+            if attempt + 1 == ATTEMPTS {
+                error!("Giving up on webhook {} for {}", url, event.key);
+            }
+        }
+    }
+}
+
+/// Sign the body with the shared secret.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(body);
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
@@ -0,0 +1,32 @@
+use actix_web::client::Client;
+
+use log::{error, info};
+
+use std::time::Duration;
+
+use crate::SiteConfig;
+
+/// Tell the WebSub hub that the topic has changed, and trigger the site rebuild.
+pub async fn ping(site: SiteConfig, client: Client) {
+    if let (Some(hub), Some(topic)) = (site.websub_hub(), site.websub_topic()) {
+        let form = [("hub.mode", "publish"), ("hub.url", topic)];
+        match client
+            .post(hub)
+            .timeout(Duration::from_secs(10))
+            .send_form(&form)
+            .await
+        {
+            Ok(resp) if resp.status().is_success() => info!("Pinged WebSub hub {}", hub),
+            Ok(resp) => error!("WebSub hub {} returned {}", hub, resp.status()),
+            Err(e) => error!("Failed to ping WebSub hub {}: {}", hub, e),
+        }
+    }
+
+    if let Some(url) = site.rebuild_url() {
+        match client.post(url).timeout(Duration::from_secs(10)).send().await {
+            Ok(resp) if resp.status().is_success() => info!("Triggered rebuild at {}", url),
+            Ok(resp) => error!("Rebuild endpoint {} returned {}", url, resp.status()),
+            Err(e) => error!("Failed to trigger rebuild at {}: {}", url, e),
+        }
+    }
+}
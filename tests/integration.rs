@@ -0,0 +1,1570 @@
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{middleware, test, App};
+
+use image::{DynamicImage, GenericImageView, ImageFormat};
+
+use s3_media_endpoint_rs::test_support::{
+    endpoint, endpoint_with, MockS3, MockTokenEndpoint, BUCKET, MEDIA_URL,
+};
+
+const TOKEN: &str = "let-me-in";
+const BOUNDARY: &str = "----test-boundary";
+
+/// Headers clients send to negotiate images. None of them are honored, so
+/// none may change what's served without also showing up in Vary.
+const NEGOTIATION_HEADERS: &[(&str, &str)] = &[
+    ("accept-encoding", "gzip, br"),
+    ("accept", "image/avif,image/webp,*/*"),
+    ("dpr", "2"),
+    ("save-data", "on"),
+];
+
+fn token_endpoint() -> MockTokenEndpoint {
+    MockTokenEndpoint::start(TOKEN, "https://me.example/", "media delete")
+}
+
+/// A multipart body holding a single file.
+fn multipart(filename: &str, content_type: &str, data: &[u8]) -> Vec<u8> {
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+        BOUNDARY, filename, content_type
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    body
+}
+
+/// POST a multipart body to the media endpoint.
+async fn post_multipart<S, B, E>(app: &mut S, body: Vec<u8>) -> ServiceResponse<B>
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = E>,
+    E: std::fmt::Debug,
+{
+    let req = test::TestRequest::post()
+        .uri("/micropub/media")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .set_payload(body)
+        .to_request();
+    test::call_service(app, req).await
+}
+
+/// Upload a single file to the media endpoint.
+async fn upload<S, B, E>(
+    app: &mut S,
+    filename: &str,
+    content_type: &str,
+    data: &[u8],
+) -> ServiceResponse<B>
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = E>,
+    E: std::fmt::Debug,
+{
+    post_multipart(app, multipart(filename, content_type, data)).await
+}
+
+/// Upload `data` as the only part of a session opened with `query`, and
+/// return the response to completing it.
+async fn session_upload<S, B, E>(app: &mut S, query: &str, data: &[u8]) -> ServiceResponse<B>
+where
+    S: Service<Request = Request, Response = ServiceResponse<B>, Error = E>,
+    E: std::fmt::Debug,
+{
+    let req = test::TestRequest::post()
+        .uri(&format!("/micropub/media/session?{}", query))
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let session = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let req = test::TestRequest::put()
+        .uri(&format!("{}/part/1", session))
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_payload(data.to_vec())
+        .to_request();
+    let resp = test::call_service(app, req).await;
+    assert!(resp.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{}/complete", session))
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    test::call_service(app, req).await
+}
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    DynamicImage::new_rgb8(width, height)
+        .write_to(&mut data, ImageFormat::Png)
+        .unwrap();
+    data
+}
+
+/// A PNG with an eXIf chunk after its header, as cameras and phones write.
+fn png_with_exif(width: u32, height: u32) -> Vec<u8> {
+    let png = png(width, height);
+    // The signature and the IHDR chunk come first.
+    let (head, rest) = png.split_at(8 + 25);
+    let exif = b"Exif\0\0GPS";
+    let mut data = head.to_vec();
+    data.extend_from_slice(&(exif.len() as u32).to_be_bytes());
+    data.extend_from_slice(b"eXIf");
+    data.extend_from_slice(exif);
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(rest);
+    data
+}
+
+#[actix_rt::test]
+async fn upload_stores_the_file() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = upload(&mut app, "cat.png", "image/png", &png(4, 4)).await;
+
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(location.starts_with(&format!("{}/photo/", MEDIA_URL)));
+
+    let key = location.rsplit('/').next().unwrap();
+    let stored = s3.get(BUCKET, &format!("photo/{}", key)).unwrap();
+    assert_eq!(stored.content_type.as_deref(), Some("image/png"));
+    assert_eq!(
+        stored.metadata.get("author").map(String::as_str),
+        Some("https://me.example/")
+    );
+}
+
+#[actix_rt::test]
+async fn upload_without_a_token_is_unauthorized() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media")
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .set_payload(multipart("cat.png", "image/png", &png(4, 4)))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(s3.keys().is_empty());
+}
+
+#[actix_rt::test]
+async fn upload_with_a_bad_token_is_unauthorized() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media")
+        .header(header::AUTHORIZATION, "Bearer not-the-token")
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .set_payload(multipart("cat.png", "image/png", &png(4, 4)))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(s3.keys().is_empty());
+}
+
+#[actix_rt::test]
+async fn photos_are_resized() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "photo/cat.png", "image/png", png(20, 10));
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/photo/10x0/cat.png")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = test::read_body(resp).await;
+    let resized = image::load_from_memory(&body).unwrap();
+    assert_eq!(resized.dimensions(), (10, 5));
+}
+
+#[actix_rt::test]
+async fn ranges_are_honored() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "file/hello.txt",
+        "text/plain",
+        b"hello world".to_vec(),
+    );
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/file/hello.txt")
+        .header(header::RANGE, "bytes=6-")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_RANGE).unwrap(),
+        "bytes 6-10/11"
+    );
+    assert_eq!(test::read_body(resp).await, "world".as_bytes());
+}
+
+#[actix_rt::test]
+async fn photos_are_the_same_for_every_client() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "photo/cat.png", "image/png", png(20, 10));
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(
+        App::new()
+            .wrap(middleware::Compress::default())
+            .service(endpoint.scope("")),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/photo/10x0/cat.png")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers().get(header::ETAG).cloned();
+    let vary = resp.headers().get(header::VARY).cloned();
+    let body = test::read_body(resp).await;
+
+    for (name, value) in NEGOTIATION_HEADERS {
+        let req = test::TestRequest::get()
+            .uri("/media/photo/10x0/cat.png")
+            .header(*name, *value)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK, "{}", name);
+        assert_eq!(resp.headers().get(header::ETAG), etag.as_ref(), "{}", name);
+        assert_eq!(resp.headers().get(header::VARY), vary.as_ref(), "{}", name);
+        assert!(
+            !resp.headers().contains_key(header::CONTENT_ENCODING),
+            "{}",
+            name
+        );
+        assert_eq!(test::read_body(resp).await, body, "{}", name);
+    }
+}
+
+#[actix_rt::test]
+async fn compressible_files_vary_on_accept_encoding() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "file/hello.txt",
+        "text/plain",
+        b"hello world".to_vec(),
+    );
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(
+        App::new()
+            .wrap(middleware::Compress::default())
+            .service(endpoint.scope("")),
+    )
+    .await;
+
+    for accept_encoding in &["gzip", "identity"] {
+        let req = test::TestRequest::get()
+            .uri("/media/file/hello.txt")
+            .header(header::ACCEPT_ENCODING, *accept_encoding)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK, "{}", accept_encoding);
+        let vary = resp.headers().get(header::VARY).unwrap().to_str().unwrap();
+        assert!(
+            vary.to_ascii_lowercase().contains("accept-encoding"),
+            "{}",
+            accept_encoding
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn ranges_are_not_compressed() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "file/hello.txt",
+        "text/plain",
+        b"hello world".to_vec(),
+    );
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(
+        App::new()
+            .wrap(middleware::Compress::default())
+            .service(endpoint.scope("")),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/file/hello.txt")
+        .header(header::RANGE, "bytes=6-")
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_RANGE).unwrap(),
+        "bytes 6-10/11"
+    );
+    assert_eq!(test::read_body(resp).await, "world".as_bytes());
+}
+
+#[actix_rt::test]
+async fn html_files_are_downloaded() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "file/page.html",
+        "text/html",
+        b"<script>alert(1)</script>".to_vec(),
+    );
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/file/page.html")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/octet-stream"
+    );
+    assert_eq!(
+        resp.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"page.html\""
+    );
+}
+
+#[actix_rt::test]
+async fn version_is_reported() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get().uri("/version").to_request();
+    let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(body["features"], serde_json::json!(["test-support"]));
+}
+
+#[actix_rt::test]
+async fn robots_txt_keeps_crawlers_out_of_micropub() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope("/endpoint"))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/endpoint/robots.txt")
+        .to_request();
+    let body = test::read_response(&mut app, req).await;
+
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        "User-agent: *\nDisallow: /endpoint/micropub\n"
+    );
+}
+
+#[actix_rt::test]
+async fn media_endpoint_links_to_its_description() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope("/endpoint"))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/endpoint/micropub/media")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(
+        resp.headers().get(header::LINK).unwrap(),
+        "<../.well-known/micropub-media>; rel=\"describedby\""
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/endpoint/.well-known/micropub-media")
+        .to_request();
+    let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(body["media_endpoint"], "/endpoint/micropub/media");
+    assert_eq!(body["accepted_types"], serde_json::json!(["*/*"]));
+    assert_eq!(body["actions"], serde_json::json!(["delete", "undo"]));
+}
+
+#[actix_rt::test]
+async fn the_last_upload_can_be_undone() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = upload(&mut app, "notes.txt", "text/plain", b"oops").await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let key = location
+        .trim_start_matches(&format!("{}/", MEDIA_URL))
+        .to_string();
+    assert!(s3.get(BUCKET, &key).is_some());
+
+    let undo = || {
+        test::TestRequest::post()
+            .uri("/micropub/media?action=undo")
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .to_request()
+    };
+    let resp = test::call_service(&mut app, undo()).await;
+    assert!(resp.status().is_success());
+    assert!(s3.get(BUCKET, &key).is_none());
+
+    let resp = test::call_service(&mut app, undo()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn renamed_files_redirect_to_their_new_url() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/x7f3q.txt", "text/plain", b"notes".to_vec());
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/alias")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&serde_json::json!({
+            "from": format!("{}/file/x7f3q.txt", MEDIA_URL),
+            "to": "file/notes.txt",
+        }))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let req = test::TestRequest::get()
+        .uri("/media/file/x7f3q.txt")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(
+        resp.headers().get(header::LOCATION).unwrap(),
+        &format!("{}/file/notes.txt", MEDIA_URL)
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/media/file/notes.txt")
+        .to_request();
+    let body = test::read_response(&mut app, req).await;
+    assert_eq!(&body[..], b"notes");
+}
+
+#[actix_rt::test]
+async fn duplicates_are_independent_copies() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "file/abc/notes.txt",
+        "text/plain",
+        b"notes".to_vec(),
+    );
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/duplicate")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&serde_json::json!({
+            "url": format!("{}/file/abc/notes.txt", MEDIA_URL),
+        }))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let key = location.trim_start_matches(&format!("{}/", MEDIA_URL));
+    assert_ne!(key, "file/abc/notes.txt");
+    let copy = s3.get(BUCKET, key).unwrap();
+    assert_eq!(copy.data, b"notes");
+    assert_eq!(copy.content_type.as_deref(), Some("text/plain"));
+    assert!(s3.get(BUCKET, "file/abc/notes.txt").is_some());
+}
+
+#[actix_rt::test]
+async fn several_files_are_deleted_at_once() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/one.txt", "text/plain", b"one".to_vec());
+    s3.insert(BUCKET, "file/two.txt", "text/plain", b"two".to_vec());
+    s3.set_metadata(BUCKET, "file/one.txt", "author", "https://me.example/");
+    s3.set_metadata(BUCKET, "file/two.txt", "author", "https://me.example/");
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/delete")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&serde_json::json!([
+            format!("{}/file/one.txt", MEDIA_URL),
+            format!("{}/file/two.txt", MEDIA_URL),
+            format!("{}/file/three.txt", MEDIA_URL),
+        ]))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    let deleted: Vec<bool> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["deleted"].as_bool().unwrap())
+        .collect();
+    assert_eq!(deleted, vec![true, true, false]);
+    assert_eq!(body["results"][2]["error"], "not_found");
+    assert!(s3.get(BUCKET, "file/one.txt").is_none());
+    assert!(s3.get(BUCKET, "file/two.txt").is_none());
+}
+
+#[actix_rt::test]
+async fn files_without_an_author_are_not_deleted() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/notes.txt", "text/plain", b"notes".to_vec());
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri(&format!(
+            "/micropub/media?action=delete&url={}/file/notes.txt",
+            MEDIA_URL
+        ))
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(s3.get(BUCKET, "file/notes.txt").is_some());
+}
+
+#[actix_rt::test]
+async fn the_bucket_is_listed_a_page_at_a_time() {
+    let s3 = MockS3::start();
+    for name in &["a", "b", "c"] {
+        let key = format!("photo/{}.png", name);
+        s3.insert(BUCKET, &key, "image/png", b"png".to_vec());
+    }
+    s3.insert(BUCKET, "file/notes.txt", "text/plain", b"notes".to_vec());
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let list = |query: String| {
+        test::TestRequest::get()
+            .uri(&format!(
+                "/micropub/media/list?prefix=photo/&limit=2{}",
+                query
+            ))
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .to_request()
+    };
+    let resp = test::call_service(&mut app, list(String::new())).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let page: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    assert_eq!(page["items"][0]["key"], "photo/a.png");
+    assert_eq!(
+        page["items"][1]["url"],
+        format!("{}/photo/b.png", MEDIA_URL)
+    );
+    let next = page["next"].as_str().unwrap();
+
+    let resp = test::call_service(&mut app, list(format!("&continuation={}", next))).await;
+    let page: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    let items = page["items"].as_array().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0]["key"], "photo/c.png");
+    assert_eq!(items[0]["size"], 3);
+    assert!(page.get("next").is_none());
+}
+
+#[actix_rt::test]
+async fn expiry_must_be_one_of_the_configured_days() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"expires-in\"\r\n\r\n7d\r\n",
+        BOUNDARY
+    )
+    .into_bytes();
+    body.extend(multipart("shot.png", "image/png", &png(4, 4)));
+    let resp = post_multipart(&mut app, body).await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(s3.keys().is_empty());
+}
+
+#[actix_rt::test]
+async fn share_links_serve_the_object() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/notes.txt", "text/plain", b"notes".to_vec());
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/share")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&serde_json::json!({
+            "url": format!("{}/file/notes.txt", MEDIA_URL),
+            "expires_in": 60,
+        }))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    let url = body["url"].as_str().unwrap();
+    let path = &url[url.find("/share/").unwrap()..];
+
+    let req = test::TestRequest::get().uri(path).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("x-robots-tag").unwrap(), "noindex");
+    assert_eq!(test::read_body(resp).await, "notes".as_bytes());
+
+    let req = test::TestRequest::get()
+        .uri("/share/0000000000000000000000")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn captions_are_listed_with_their_video() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "video/clip.mp4",
+        "video/mp4",
+        b"not really".to_vec(),
+    );
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let srt = "1\r\n00:00:01,000 --> 00:00:02,500\r\nHello\r\n";
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"for\"\r\n\r\n{}/video/clip.mp4\r\n",
+        BOUNDARY, MEDIA_URL
+    )
+    .into_bytes();
+    body.extend(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"lang\"\r\n\r\nen\r\n",
+            BOUNDARY
+        )
+        .bytes(),
+    );
+    body.extend(multipart(
+        "clip.srt",
+        "application/x-subrip",
+        srt.as_bytes(),
+    ));
+    let resp = post_multipart(&mut app, body).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let req = test::TestRequest::get()
+        .uri("/media/info/video/clip.mp4")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let info: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    assert_eq!(info["captions"][0]["label"], "en");
+    let url = info["captions"][0]["url"].as_str().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&url[url.find("/media/").unwrap()..])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/vtt"
+    );
+    let vtt = test::read_body(resp).await;
+    assert!(vtt.starts_with(b"WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500\nHello\n"));
+}
+
+#[actix_rt::test]
+async fn lang_is_not_stored_as_metadata() {
+    let s3 = MockS3::start();
+    let tokens = token_endpoint();
+    let endpoint = endpoint_with(&s3, &tokens, &[("METADATA_FIELDS", "alt,lang")]).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"lang\"\r\n\r\nen\r\n",
+        BOUNDARY
+    )
+    .into_bytes();
+    body.extend(multipart("notes.txt", "text/plain", b"hello"));
+    let resp = post_multipart(&mut app, body).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let key = location.strip_prefix(&format!("{}/", MEDIA_URL)).unwrap();
+    let stored = s3.get(BUCKET, key).unwrap();
+    assert!(
+        !stored.metadata.contains_key("lang"),
+        "{:?}",
+        stored.metadata
+    );
+}
+
+#[actix_rt::test]
+async fn gps_traces_get_a_map_preview() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let gpx = r#"<?xml version="1.0"?>
+<gpx version="1.1"><trk><trkseg>
+<trkpt lat="47.6000" lon="-122.3300"/>
+<trkpt lat="47.6005" lon="-122.3290"/>
+<trkpt lat="47.6010" lon="-122.3280"/>
+<trkpt lat="47.6020" lon="-122.3300"/>
+</trkseg></trk></gpx>"#;
+    let resp = upload(&mut app, "ride.gpx", "application/gpx+xml", gpx.as_bytes()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(location.starts_with(&format!("{}/map/", MEDIA_URL)));
+    let filename = location.rsplit('/').next().unwrap();
+
+    let simplified = s3
+        .get(BUCKET, &format!("map/simplified/{}", filename))
+        .unwrap();
+    let geojson: serde_json::Value = serde_json::from_slice(&simplified.data).unwrap();
+    let line = &geojson["features"][0]["geometry"]["coordinates"][0];
+    // The middle of the straight stretch is dropped.
+    assert_eq!(line.as_array().unwrap().len(), 3);
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/media/map/preview/{}?width=200&height=100",
+            filename
+        ))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let preview = image::load_from_memory(&test::read_body(resp).await).unwrap();
+    assert_eq!(preview.dimensions(), (200, 100));
+}
+
+#[actix_rt::test]
+async fn gps_traces_must_have_points() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = upload(
+        &mut app,
+        "empty.geojson",
+        "application/geo+json",
+        br#"{"type": "FeatureCollection", "features": []}"#,
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(s3.keys().is_empty());
+}
+
+#[actix_rt::test]
+async fn imports_map_old_paths_to_new_urls() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    s3.insert("old-media", "uploads/2019/cat.png", "image/png", png(4, 3));
+    s3.insert("old-media", "uploads/2019/", "", Vec::new());
+
+    let report = endpoint
+        .import("s3://old-media/uploads/", "https://me.example/")
+        .await
+        .unwrap();
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.imported.len(), 1);
+    let (path, url) = &report.imported[0];
+    assert_eq!(path, "2019/cat.png");
+    assert!(url.starts_with(&format!("{}/photo/", MEDIA_URL)));
+
+    let imported: Vec<_> = s3
+        .keys()
+        .into_iter()
+        .filter(|key| key.starts_with(&format!("{}/photo/", BUCKET)))
+        .collect();
+    assert_eq!(imported.len(), 1);
+    let key = imported[0].splitn(2, '/').nth(1).unwrap();
+    assert_eq!(s3.get(BUCKET, key).unwrap().data, png(4, 3));
+}
+
+#[actix_rt::test]
+async fn backups_restore_originals_into_an_empty_bucket() {
+    let s3 = MockS3::start();
+    let empty = MockS3::start();
+    let restorer = endpoint(&empty, &token_endpoint()).await;
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    s3.insert(BUCKET, "photo/abc.png", "image/png", png(4, 3));
+    s3.insert(
+        BUCKET,
+        "video/hls/xyz/index.m3u8",
+        "application/x-mpegURL",
+        b"#EXTM3U".to_vec(),
+    );
+
+    let tarball = std::env::temp_dir().join("s3-media-endpoint-backup-test.tar");
+    let copied = endpoint.backup(tarball.to_str().unwrap()).await.unwrap();
+    // Generated derivatives are left out.
+    assert_eq!(copied, 1);
+
+    let restored = restorer.restore(tarball.to_str().unwrap()).await.unwrap();
+    std::fs::remove_file(&tarball).unwrap();
+    assert_eq!(restored, 1);
+    let object = empty.get(BUCKET, "photo/abc.png").unwrap();
+    assert_eq!(object.data, png(4, 3));
+    assert_eq!(object.content_type.as_deref(), Some("image/png"));
+    assert_eq!(empty.keys().len(), 1);
+}
+
+#[actix_rt::test]
+async fn form_uploads_are_only_completed_by_their_author() {
+    let s3 = MockS3::start();
+    // Stored by something other than a form signed for this token.
+    s3.insert(BUCKET, "staging/photo/abc.png", "image/png", png(4, 3));
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/form/complete?key=photo/abc.png")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/form/complete?key=photo/missing.png")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert!(s3.get(BUCKET, "staging/photo/abc.png").is_some());
+    assert!(s3.get(BUCKET, "photo/abc.png").is_none());
+}
+
+#[actix_rt::test]
+async fn form_uploads_are_published_from_staging_once_completed() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "staging/file/notes.txt",
+        "text/plain",
+        b"notes".to_vec(),
+    );
+    s3.set_metadata(
+        BUCKET,
+        "staging/file/notes.txt",
+        "author",
+        "https://me.example/",
+    );
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/form/complete?key=file/notes.txt")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let object = s3.get(BUCKET, "file/notes.txt").unwrap();
+    assert_eq!(object.data, b"notes".to_vec());
+    assert_eq!(
+        object.metadata.get("author").map(String::as_str),
+        Some("https://me.example/")
+    );
+    assert!(s3.get(BUCKET, "staging/file/notes.txt").is_none());
+}
+
+#[actix_rt::test]
+async fn administration_needs_the_admin_scope() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    for (method, uri) in vec![
+        (Method::POST, "/admin/purge?key=photo/x.jpg"),
+        (Method::PUT, "/admin/mode"),
+        (Method::POST, "/micropub/media/notifications"),
+    ] {
+        let req = test::TestRequest::default()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .set_json(&serde_json::json!({}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", uri);
+    }
+}
+
+#[actix_rt::test]
+async fn objects_stored_out_of_band_are_registered_once() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/notes.txt", "text/plain", b"notes".to_vec());
+    s3.insert(
+        BUCKET,
+        "video/hls/xyz/index.m3u8",
+        "application/x-mpegURL",
+        b"#EXTM3U".to_vec(),
+    );
+    let tokens = MockTokenEndpoint::start(TOKEN, "https://me.example/", "media admin");
+    let endpoint = endpoint(&s3, &tokens).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+    let notification = serde_json::json!({
+        "Records": [
+            {
+                "eventName": "ObjectCreated:Put",
+                "s3": { "bucket": { "name": BUCKET }, "object": { "key": "file/notes.txt" } },
+            },
+            {
+                "eventName": "ObjectCreated:Put",
+                "s3": {
+                    "bucket": { "name": BUCKET },
+                    "object": { "key": "video/hls/xyz/index.m3u8" },
+                },
+            },
+        ],
+    });
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/notifications")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&notification)
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    assert_eq!(
+        body["registered"],
+        serde_json::json!([format!("{}/file/notes.txt", MEDIA_URL)])
+    );
+    assert_eq!(body["skipped"], 1);
+    let object = s3.get(BUCKET, "file/notes.txt").unwrap();
+    assert!(object.metadata.contains_key("sha256"));
+    assert_eq!(object.data, b"notes".to_vec());
+
+    // Registering rewrites the object, which is notified again.
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/notifications")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&notification)
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    assert_eq!(body["registered"], serde_json::json!([]));
+    assert_eq!(body["skipped"], 2);
+}
+
+#[actix_rt::test]
+async fn stale_if_range_gets_the_whole_object() {
+    let s3 = MockS3::start();
+    s3.insert(
+        BUCKET,
+        "file/hello.txt",
+        "text/plain",
+        b"hello world".to_vec(),
+    );
+    let etag = s3.get(BUCKET, "file/hello.txt").unwrap().etag;
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/file/hello.txt")
+        .header(header::RANGE, "bytes=6-")
+        .header(header::IF_RANGE, etag.as_str())
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(test::read_body(resp).await, "world".as_bytes());
+
+    for validator in &["\"stale\"".to_string(), format!("W/{}", etag)] {
+        let req = test::TestRequest::get()
+            .uri("/media/file/hello.txt")
+            .header(header::RANGE, "bytes=6-")
+            .header(header::IF_RANGE, validator.as_str())
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "hello world".as_bytes());
+    }
+}
+
+#[actix_rt::test]
+async fn the_file_field_is_the_upload_without_a_filename() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"\r\nContent-Type: image/png\r\n\r\n",
+        BOUNDARY
+    )
+    .into_bytes();
+    body.extend_from_slice(&png(4, 4));
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    let resp = post_multipart(&mut app, body).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+}
+
+#[actix_rt::test]
+async fn truncated_uploads_are_rejected() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let mut body = multipart("notes.txt", "text/plain", b"notes");
+    // Cut off before the closing boundary.
+    body.truncate(body.len() - BOUNDARY.len() - 8);
+    let resp = post_multipart(&mut app, body).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(s3.keys().is_empty());
+}
+
+#[actix_rt::test]
+async fn quarantined_files_are_not_served_or_described() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "quarantine/file/x.txt", "text/plain", b"x".to_vec());
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    for uri in &[
+        "/media/quarantine/file/x.txt",
+        "/media/info/quarantine/file/x.txt",
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", uri);
+    }
+}
+
+#[actix_rt::test]
+async fn upload_without_the_media_scope_is_unauthorized() {
+    let s3 = MockS3::start();
+    let tokens = MockTokenEndpoint::start(TOKEN, "https://me.example/", "delete");
+    let endpoint = endpoint(&s3, &tokens).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = upload(&mut app, "cat.png", "image/png", &png(4, 4)).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(s3.keys().is_empty());
+}
+
+#[actix_rt::test]
+async fn old_versions_are_only_served_with_the_read_scope() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/notes.txt", "text/plain", b"notes".to_vec());
+    let media_token = token_endpoint();
+    let read_token = MockTokenEndpoint::start(TOKEN, "https://me.example/", "media media:read");
+    let uri = "/media/file/notes.txt?versionId=v1";
+
+    let endpoint = endpoint(&s3, &media_token).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+    let req = test::TestRequest::get().uri(uri).to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let req = test::TestRequest::get()
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let endpoint = endpoint(&s3, &read_token).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+    let req = test::TestRequest::get()
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CACHE_CONTROL).unwrap(),
+        "private"
+    );
+}
+
+#[actix_rt::test]
+async fn only_stored_files_get_the_content_security_policy() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/page.html", "text/html", b"<p>hi</p>".to_vec());
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/file/page.html")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    let csp = resp.headers().get(header::CONTENT_SECURITY_POLICY).unwrap();
+    assert!(csp.to_str().unwrap().contains("sandbox"));
+
+    let req = test::TestRequest::get()
+        .uri("/micropub/media/gallery")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let csp = resp.headers().get(header::CONTENT_SECURITY_POLICY);
+    assert!(csp.map_or(true, |csp| !csp.to_str().unwrap().contains("sandbox")));
+}
+
+#[actix_rt::test]
+async fn the_gallery_is_only_served_with_a_token() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/micropub/media/gallery")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    assert!(!String::from_utf8_lossy(&body).contains("<script"));
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/gallery")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .set_payload("access_token=wrong")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/gallery")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .set_payload(format!("access_token={}", TOKEN))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = String::from_utf8_lossy(&test::read_body(resp).await).into_owned();
+    assert!(body.contains(&format!("Bearer {}", TOKEN)));
+    assert!(!body.contains("localStorage"));
+}
+
+#[actix_rt::test]
+async fn shared_active_content_is_downloaded() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "file/page.html", "text/html", b"<p>hi</p>".to_vec());
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/share")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&serde_json::json!({
+            "url": format!("{}/file/page.html", MEDIA_URL),
+        }))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    let url = body["url"].as_str().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&url[url.find("/share/").unwrap()..])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/octet-stream"
+    );
+    assert!(resp
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+}
+
+#[actix_rt::test]
+async fn files_on_private_addresses_are_not_imported() {
+    let s3 = MockS3::start();
+    let tokens = token_endpoint();
+    let endpoint = endpoint(&s3, &tokens).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    for url in &[
+        "http://127.0.0.1/cat.png",
+        "http://[::1]:8080/cat.png",
+        "http://169.254.169.254/latest/meta-data/",
+        "http://10.0.0.1/cat.png",
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/micropub/media")
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .set_payload(format!("url={}", url))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", url);
+    }
+    assert!(s3.keys().is_empty());
+}
+
+#[actix_rt::test]
+async fn photos_uploaded_in_a_session_are_moderated() {
+    let s3 = MockS3::start();
+    let tokens = token_endpoint();
+    // `false` exits with 1, flagging every photo.
+    let endpoint = endpoint_with(&s3, &tokens, &[("MODERATION", "command:false")]).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = session_upload(
+        &mut app,
+        "content_type=image/png&filename=cat.png",
+        &png(4, 4),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(s3.keys().iter().all(|key| !key.contains("photo/")));
+}
+
+#[actix_rt::test]
+async fn photos_uploaded_in_a_session_are_published_once_inspected() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = session_upload(
+        &mut app,
+        "content_type=image/png&filename=cat.png",
+        &png(4, 4),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let keys = s3.keys();
+    assert!(
+        keys.iter().all(|key| !key.contains("staging/")),
+        "{:?}",
+        keys
+    );
+    let prefix = format!("{}/photo/", BUCKET);
+    let key = keys.iter().find(|key| key.starts_with(&prefix)).unwrap();
+    let key = key.trim_start_matches(&format!("{}/", BUCKET));
+    assert_eq!(s3.get(BUCKET, key).unwrap().data, png(4, 4));
+}
+
+#[actix_rt::test]
+async fn gps_traces_uploaded_in_a_session_get_a_map_preview() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let gpx = r#"<?xml version="1.0"?>
+<gpx version="1.1"><trk><trkseg>
+<trkpt lat="47.6000" lon="-122.3300"/>
+<trkpt lat="47.6005" lon="-122.3290"/>
+<trkpt lat="47.6010" lon="-122.3280"/>
+<trkpt lat="47.6020" lon="-122.3300"/>
+</trkseg></trk></gpx>"#;
+    let resp = session_upload(
+        &mut app,
+        "content_type=application/gpx%2Bxml&filename=ride.gpx",
+        gpx.as_bytes(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(location.starts_with(&format!("{}/map/", MEDIA_URL)));
+    let filename = location.rsplit('/').next().unwrap();
+    assert!(s3
+        .get(BUCKET, &format!("map/simplified/{}", filename))
+        .is_some());
+}
+
+#[actix_rt::test]
+async fn gps_traces_uploaded_in_a_session_must_have_points() {
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = session_upload(
+        &mut app,
+        "content_type=application/gpx%2Bxml&filename=ride.gpx",
+        br#"<gpx version="1.1"><trk><trkseg></trkseg></trk></gpx>"#,
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(s3.keys().is_empty(), "{:?}", s3.keys());
+}
+
+#[actix_rt::test]
+async fn archives_are_stored_a_file_at_a_time() {
+    use std::io::Write;
+
+    let s3 = MockS3::start();
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (path, data) in &[
+        ("notes/one.txt", &b"one"[..]),
+        ("notes/.DS_Store", &b"skipped"[..]),
+        ("two.txt", &b"two"[..]),
+    ] {
+        archive
+            .start_file(*path, zip::write::FileOptions::default())
+            .unwrap();
+        archive.write_all(data).unwrap();
+    }
+    let archive = archive.finish().unwrap().into_inner();
+
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"expand\"\r\n\r\ntrue\r\n",
+        BOUNDARY
+    )
+    .into_bytes();
+    body.extend(multipart("notes.zip", "application/zip", &archive));
+    let resp = post_multipart(&mut app, body).await;
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+
+    let entries = body["entries"].as_array().unwrap();
+    let names: Vec<&str> = entries
+        .iter()
+        .map(|e| e["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["one.txt", "two.txt"]);
+    assert!(entries.iter().all(|e| e["status"] == 201), "{}", body);
+}
+
+#[actix_rt::test]
+async fn classification_rules_place_uploads() {
+    let s3 = MockS3::start();
+    let tokens = token_endpoint();
+    let rules = [("CLASSIFICATION_RULES", "image/svg+xml=file")];
+    let endpoint = endpoint_with(&s3, &tokens, &rules).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+    let resp = upload(&mut app, "logo.svg", "image/svg+xml", svg).await;
+
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let key = location.strip_prefix(&format!("{}/", MEDIA_URL)).unwrap();
+    assert!(key.starts_with("file/"), "{}", key);
+    let stored = s3.get(BUCKET, key).unwrap();
+    assert_eq!(stored.data, svg.to_vec());
+}
+
+#[actix_rt::test]
+async fn key_templates_lay_out_new_keys() {
+    let s3 = MockS3::start();
+    let tokens = token_endpoint();
+    let template = [(
+        "KEY_TEMPLATE",
+        "{classification}/{yyyy}/{user}/{slug}.{ext}",
+    )];
+    let endpoint = endpoint_with(&s3, &tokens, &template).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let resp = upload(&mut app, "My Notes.txt", "text/plain", b"notes").await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let key = format!(
+        "file/{}/me-example/my-notes.txt",
+        chrono::Utc::now().format("%Y")
+    );
+    assert_eq!(s3.get(BUCKET, &key).unwrap().data, b"notes".to_vec());
+}
+
+#[actix_rt::test]
+async fn uploads_never_overwrite_a_taken_key() {
+    use sha2::{Digest, Sha256};
+
+    let s3 = MockS3::start();
+    let tokens = token_endpoint();
+    // Every attempt at a key for the same content collides.
+    let template = [("KEY_TEMPLATE", "{classification}/{checksum}.{ext}")];
+    let endpoint = endpoint_with(&s3, &tokens, &template).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let checksum: String = Sha256::digest(b"notes")
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let key = format!("file/{}.txt", checksum);
+    s3.insert(BUCKET, &key, "text/plain", b"someone else's".to_vec());
+
+    let resp = upload(&mut app, "notes.txt", "text/plain", b"notes").await;
+
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(
+        s3.get(BUCKET, &key).unwrap().data,
+        b"someone else's".to_vec()
+    );
+    assert_eq!(s3.keys().len(), 1);
+}
+
+#[actix_rt::test]
+async fn photos_are_served_without_exif_at_their_own_url() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "photo/cat.png", "image/png", png_with_exif(4, 4));
+    let tokens = token_endpoint();
+    let endpoint = endpoint_with(&s3, &tokens, &[("STRIP_EXIF", "true")]).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/photo/cat.png")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let etag = resp.headers().get(header::ETAG).unwrap().clone();
+    assert_eq!(test::read_body(resp).await, png(4, 4));
+
+    // HEAD describes what GET sends, not what's stored.
+    let req = test::TestRequest::default()
+        .method(Method::HEAD)
+        .uri("/media/photo/cat.png")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.headers().get(header::ETAG), Some(&etag));
+}
+
+#[actix_rt::test]
+async fn original_svgs_are_sent_as_downloads() {
+    let s3 = MockS3::start();
+    let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>".to_vec();
+    s3.insert(BUCKET, "photo/logo.svg", "image/svg+xml", svg);
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::get()
+        .uri("/media/photo/original/logo.svg")
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/octet-stream"
+    );
+    let disposition = resp.headers().get(header::CONTENT_DISPOSITION).unwrap();
+    assert!(disposition.to_str().unwrap().starts_with("attachment"));
+}
+
+#[actix_rt::test]
+async fn shared_photos_are_served_without_exif() {
+    let s3 = MockS3::start();
+    s3.insert(BUCKET, "photo/cat.png", "image/png", png_with_exif(4, 4));
+    let tokens = token_endpoint();
+    let endpoint = endpoint_with(&s3, &tokens, &[("STRIP_EXIF", "true")]).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/share")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&serde_json::json!({
+            "url": format!("{}/photo/cat.png", MEDIA_URL),
+        }))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert!(resp.status().is_success());
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+    let url = body["url"].as_str().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&url[url.find("/share/").unwrap()..])
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(test::read_body(resp).await, png(4, 4));
+}
+
+#[actix_rt::test]
+async fn files_named_by_their_uploader_are_moved_to_the_trash() {
+    let s3 = MockS3::start();
+    let key = "file/abc/My Report+v2.pdf";
+    s3.insert(BUCKET, key, "application/pdf", b"report".to_vec());
+    s3.set_metadata(BUCKET, key, "author", "https://me.example/");
+    let endpoint = endpoint(&s3, &token_endpoint()).await;
+    let mut app = test::init_service(App::new().service(endpoint.scope(""))).await;
+
+    let req = test::TestRequest::post()
+        .uri("/micropub/media/delete")
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .set_json(&serde_json::json!([format!("{}/{}", MEDIA_URL, key)]))
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    assert!(s3.get(BUCKET, key).is_none());
+    let trashed = s3.get(BUCKET, &format!("trash/{}", key)).unwrap();
+    assert_eq!(trashed.data, b"report".to_vec());
+}